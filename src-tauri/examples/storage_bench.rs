@@ -0,0 +1,129 @@
+// Compares the storage backends on the same made up torrent: whole pieces
+// written in order like a download, then 16 KiB blocks read in random order
+// like seeding. Run with
+//
+//     cargo run --release --example storage_bench -- [directory] [MiB]
+//
+// Reads mostly come from the page cache unless the data is larger than RAM.
+use defttorrent_lib::backend::file::{TorrentFile, TorrentInfo};
+use defttorrent_lib::backend::settings::{Preallocation, StorageBackendKind};
+use defttorrent_lib::backend::storage::pool::IoPool;
+use defttorrent_lib::backend::storage::{backend, FileLayout, Storage};
+use rand::seq::SliceRandom;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Instant;
+use tokio::task::JoinSet;
+
+const PIECE_LENGTH: u64 = 1 << 20;
+const BLOCK_LENGTH: u32 = 16 * 1024;
+const NUM_FILES: u64 = 8;
+const DISK_THREADS: usize = 4;
+// Operations waiting on the pool at once
+const IN_FLIGHT: usize = 32;
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let dir = args
+        .next()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| std::env::temp_dir().join("defttorrent-bench"));
+    let size: u64 = args.next().and_then(|s| s.parse().ok()).unwrap_or(512) << 20;
+
+    let file_length = size / NUM_FILES;
+    let info = TorrentInfo {
+        name: "bench".to_string(),
+        piece_length: PIECE_LENGTH as i64,
+        pieces: vec![[0; 20]; (file_length * NUM_FILES).div_ceil(PIECE_LENGTH) as usize],
+        length: None,
+        files: Some(
+            (0..NUM_FILES)
+                .map(|i| TorrentFile {
+                    length: file_length as i64,
+                    path: vec![format!("file{}", i)],
+                })
+                .collect(),
+        ),
+        private: false,
+    };
+    let pool = IoPool::new(DISK_THREADS);
+
+    println!(
+        "{} MiB in {} files at {}",
+        size >> 20,
+        NUM_FILES,
+        dir.display()
+    );
+    println!("{:<10}{:>14}{:>14}", "backend", "write MiB/s", "read MiB/s");
+    for kind in [
+        StorageBackendKind::Pread,
+        StorageBackendKind::Mmap,
+        StorageBackendKind::IoUring,
+    ] {
+        fs::remove_dir_all(&dir).ok();
+        // mmap refuses sparse files, every backend gets the same allocated
+        // ones so the writes compare fairly
+        let backend = backend::from_kind(kind, Preallocation::Full);
+        let layout = FileLayout::from_info(&info).unwrap();
+        let storage = Storage::new(dir.clone(), layout, Preallocation::Full, pool.clone())
+            .with_backend(backend.clone());
+        storage.allocate().await.unwrap();
+        let write = bench_writes(&storage).await;
+        let read = bench_reads(&storage).await;
+        println!("{:<10}{:>14.1}{:>14.1}", backend.name(), write, read);
+    }
+    fs::remove_dir_all(&dir).ok();
+}
+
+// MiB per second, flushing to disk included
+async fn bench_writes(storage: &Storage) -> f64 {
+    let layout = storage.layout();
+    let start = Instant::now();
+    let mut writing: JoinSet<io::Result<()>> = JoinSet::new();
+    for index in 0..layout.num_pieces() as u32 {
+        if writing.len() >= IN_FLIGHT {
+            writing.join_next().await.unwrap().unwrap().unwrap();
+        }
+        let data = vec![index as u8; layout.piece_size(index) as usize];
+        let storage = storage.clone();
+        writing.spawn(async move { storage.write_piece(index, data).await });
+    }
+    while let Some(result) = writing.join_next().await {
+        result.unwrap().unwrap();
+    }
+    storage.flush().await.unwrap();
+    rate(layout.total_length(), start)
+}
+
+async fn bench_reads(storage: &Storage) -> f64 {
+    let layout = storage.layout();
+    let mut blocks: Vec<(u32, u32)> = (0..layout.num_pieces() as u32)
+        .flat_map(|index| {
+            (0..layout.piece_size(index) as u32)
+                .step_by(BLOCK_LENGTH as usize)
+                .map(move |begin| (index, begin))
+        })
+        .collect();
+    blocks.shuffle(&mut rand::rng());
+
+    let start = Instant::now();
+    let mut reading: JoinSet<io::Result<Vec<u8>>> = JoinSet::new();
+    for (index, begin) in blocks {
+        if reading.len() >= IN_FLIGHT {
+            reading.join_next().await.unwrap().unwrap().unwrap();
+        }
+        let storage = storage.clone();
+        let length = BLOCK_LENGTH.min(layout.piece_size(index) as u32 - begin);
+        reading.spawn(async move { storage.read(index, begin, length).await });
+    }
+    while let Some(result) = reading.join_next().await {
+        result.unwrap().unwrap();
+    }
+    rate(layout.total_length(), start)
+}
+
+fn rate(bytes: u64, start: Instant) -> f64 {
+    (bytes >> 20) as f64 / start.elapsed().as_secs_f64()
+}
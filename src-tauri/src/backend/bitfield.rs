@@ -0,0 +1,81 @@
+// One bit per piece, most significant bit of the first byte is piece 0, exactly
+// as it is sent in the peer wire `bitfield` message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Bitfield {
+            bytes: vec![0u8; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Bitfield::new(len);
+        for index in 0..len {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self, &'static str> {
+        if bytes.len() != len.div_ceil(8) {
+            return Err("Bitfield has wrong length");
+        }
+        // Spare bits at the end must be cleared
+        if !len.is_multiple_of(8) {
+            let spare = 0xffu8 >> (len % 8);
+            if bytes[bytes.len() - 1] & spare != 0 {
+                return Err("Bitfield has spare bits set");
+            }
+        }
+        Ok(Bitfield {
+            bytes: bytes.to_vec(),
+            len,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn clear(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(move |&index| self.has(index))
+    }
+}
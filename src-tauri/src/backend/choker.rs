@@ -0,0 +1,366 @@
+use std::cmp::Ordering;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub const REGULAR_INTERVAL: Duration = Duration::from_secs(10);
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+// A peer that hasn't sent us a block for this long while we want data from it
+// is snubbing us and loses its regular upload slot.
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct ChokePeer {
+    pub addr: SocketAddr,
+    // They are interested in what we have
+    pub interested: bool,
+    // We are interested in what they have
+    pub am_interested: bool,
+    // We are choking them
+    pub choked: bool,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub connected_at: Instant,
+    pub last_piece: Option<Instant>,
+    pub unchoked_at: Option<Instant>,
+    pub snubbed: bool,
+}
+
+impl ChokePeer {
+    pub fn new(addr: SocketAddr, now: Instant) -> Self {
+        ChokePeer {
+            addr,
+            interested: false,
+            am_interested: false,
+            choked: true,
+            download_rate: 0.0,
+            upload_rate: 0.0,
+            connected_at: now,
+            last_piece: None,
+            unchoked_at: None,
+            snubbed: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Choker {
+    slots: usize,
+    optimistic: Option<SocketAddr>,
+    last_regular: Option<Instant>,
+    last_optimistic: Option<Instant>,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Choker {
+            slots,
+            optimistic: None,
+            last_regular: None,
+            last_optimistic: None,
+        }
+    }
+
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    pub fn set_slots(&mut self, slots: usize) {
+        self.slots = slots;
+        // Apply on the next tick instead of waiting out the interval
+        self.last_regular = None;
+    }
+
+    pub fn optimistic(&self) -> Option<SocketAddr> {
+        self.optimistic
+    }
+
+    // Runs whichever rounds are due and returns the peers whose choke state
+    // changed, `true` meaning they must now be choked.
+    pub fn tick(
+        &mut self,
+        now: Instant,
+        peers: &mut [ChokePeer],
+        seeding: bool,
+    ) -> Vec<(SocketAddr, bool)> {
+        update_snubbed(now, peers);
+
+        let optimistic_gone = self
+            .optimistic
+            .is_some_and(|addr| !peers.iter().any(|p| p.addr == addr && p.interested));
+        let optimistic_due = optimistic_gone
+            || self
+                .last_optimistic
+                .is_none_or(|last| now.duration_since(last) >= OPTIMISTIC_INTERVAL);
+        let regular_due = optimistic_due
+            || self
+                .last_regular
+                .is_none_or(|last| now.duration_since(last) >= REGULAR_INTERVAL);
+        if !regular_due {
+            return Vec::new();
+        }
+
+        let before: Vec<bool> = peers.iter().map(|p| p.choked).collect();
+        if optimistic_due {
+            self.optimistic = None;
+        }
+        let unchoked = self.regular_unchoke(peers, seeding);
+        if optimistic_due {
+            self.rotate_optimistic(now, peers, &unchoked);
+        }
+
+        for peer in peers.iter_mut() {
+            peer.choked = !(unchoked.contains(&peer.addr) || self.optimistic == Some(peer.addr));
+        }
+        self.last_regular = Some(now);
+
+        peers
+            .iter_mut()
+            .zip(before)
+            .filter(|(peer, was_choked)| peer.choked != *was_choked)
+            .map(|(peer, _)| {
+                if !peer.choked {
+                    peer.unchoked_at = Some(now);
+                }
+                (peer.addr, peer.choked)
+            })
+            .collect()
+    }
+
+    // One slot is kept back for the optimistic unchoke. Its peer keeps that
+    // one until the next rotation, rather than taking a regular slot too.
+    fn regular_unchoke(&self, peers: &[ChokePeer], seeding: bool) -> Vec<SocketAddr> {
+        let mut ranked: Vec<&ChokePeer> = peers
+            .iter()
+            .filter(|p| p.interested && (seeding || !p.snubbed))
+            .filter(|p| self.optimistic != Some(p.addr))
+            .collect();
+        if seeding {
+            // Seeding: favour peers we upload to fastest, and among equals
+            // the most recently unchoked so slots don't churn.
+            ranked.sort_by(|a, b| {
+                compare_rate(b.upload_rate, a.upload_rate).then(b.unchoked_at.cmp(&a.unchoked_at))
+            });
+        } else {
+            // Leeching: tit-for-tat, reward whoever gives us the most
+            ranked.sort_by(|a, b| compare_rate(b.download_rate, a.download_rate));
+        }
+
+        ranked
+            .into_iter()
+            .take(self.slots.saturating_sub(1))
+            .map(|p| p.addr)
+            .collect()
+    }
+
+    fn rotate_optimistic(&mut self, now: Instant, peers: &[ChokePeer], unchoked: &[SocketAddr]) {
+        self.last_optimistic = Some(now);
+        self.optimistic = None;
+        if self.slots == 0 {
+            return;
+        }
+
+        // Newly connected peers get three times the chance, they have nothing
+        // to offer yet and this is how they bootstrap.
+        let mut candidates = Vec::new();
+        for peer in peers {
+            if !peer.interested || unchoked.contains(&peer.addr) {
+                continue;
+            }
+            let weight = if now.duration_since(peer.connected_at) < OPTIMISTIC_INTERVAL * 3 {
+                3
+            } else {
+                1
+            };
+            candidates.extend(std::iter::repeat_n(peer.addr, weight));
+        }
+        if !candidates.is_empty() {
+            self.optimistic = Some(candidates[rand::random_range(0..candidates.len())]);
+        }
+    }
+}
+
+fn update_snubbed(now: Instant, peers: &mut [ChokePeer]) {
+    for peer in peers.iter_mut() {
+        let last = peer.last_piece.unwrap_or(peer.connected_at);
+        peer.snubbed = peer.am_interested && now.duration_since(last) >= SNUB_TIMEOUT;
+    }
+}
+
+fn compare_rate(a: f64, b: f64) -> Ordering {
+    a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+}
+
+// Splits the global slot budget between torrents, each capped by its own
+// limit. Slots a torrent can't use are handed to the others.
+pub fn distribute_slots(global: usize, limits: &[usize]) -> Vec<usize> {
+    let mut slots = vec![0; limits.len()];
+    let mut remaining = global;
+    loop {
+        let hungry: Vec<usize> = (0..limits.len())
+            .filter(|&i| slots[i] < limits[i])
+            .collect();
+        if hungry.is_empty() || remaining == 0 {
+            return slots;
+        }
+        let share = (remaining / hungry.len()).max(1);
+        for i in hungry {
+            let grant = share.min(limits[i] - slots[i]).min(remaining);
+            slots[i] += grant;
+            remaining -= grant;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u8, connected_at: Instant) -> ChokePeer {
+        let mut peer = ChokePeer::new(SocketAddr::from(([10, 0, 0, n], 6881)), connected_at);
+        peer.interested = true;
+        peer
+    }
+
+    fn addrs(peers: &[ChokePeer], ns: &[usize]) -> Vec<SocketAddr> {
+        ns.iter().map(|&n| peers[n].addr).collect()
+    }
+
+    fn unchoked(peers: &[ChokePeer]) -> Vec<SocketAddr> {
+        peers.iter().filter(|p| !p.choked).map(|p| p.addr).collect()
+    }
+
+    #[test]
+    fn leeching_ranks_by_download_rate() {
+        let start = Instant::now();
+        let mut peers: Vec<ChokePeer> = (0..5).map(|n| peer(n, start)).collect();
+        for (peer, rate) in peers.iter_mut().zip([1.0, 5.0, 3.0, 4.0, 2.0]) {
+            peer.download_rate = rate;
+            peer.upload_rate = 10.0 - rate;
+        }
+        let choker = Choker::new(4);
+        assert_eq!(
+            choker.regular_unchoke(&peers, false),
+            addrs(&peers, &[1, 3, 2])
+        );
+    }
+
+    #[test]
+    fn seeding_ranks_by_upload_rate_then_last_unchoke() {
+        let start = Instant::now();
+        let mut peers: Vec<ChokePeer> = (0..4).map(|n| peer(n, start)).collect();
+        for (peer, rate) in peers.iter_mut().zip([1.0, 5.0, 5.0, 10.0]) {
+            peer.upload_rate = rate;
+            peer.download_rate = 10.0 - rate;
+        }
+        peers[1].unchoked_at = Some(start);
+        peers[2].unchoked_at = Some(start + Duration::from_secs(5));
+        let choker = Choker::new(3);
+        assert_eq!(choker.regular_unchoke(&peers, true), addrs(&peers, &[3, 2]));
+    }
+
+    #[test]
+    fn snubbed_peers_skipped_while_leeching() {
+        let start = Instant::now();
+        let mut peers: Vec<ChokePeer> = (0..3).map(|n| peer(n, start)).collect();
+        for (peer, rate) in peers.iter_mut().zip([10.0, 1.0, 2.0]) {
+            peer.download_rate = rate;
+            peer.upload_rate = rate;
+        }
+        // We want its data but it sent nothing for a minute
+        peers[0].am_interested = true;
+        update_snubbed(start + SNUB_TIMEOUT, &mut peers);
+        assert!(peers[0].snubbed && !peers[1].snubbed);
+
+        let choker = Choker::new(4);
+        assert_eq!(
+            choker.regular_unchoke(&peers, false),
+            addrs(&peers, &[2, 1])
+        );
+        assert_eq!(
+            choker.regular_unchoke(&peers, true),
+            addrs(&peers, &[0, 2, 1])
+        );
+    }
+
+    #[test]
+    fn regular_every_10s_optimistic_every_30s() {
+        let start = Instant::now();
+        let now = start + Duration::from_secs(200);
+        let mut peers: Vec<ChokePeer> = (0..3).map(|n| peer(n, start)).collect();
+        peers[0].download_rate = 10.0;
+        let mut choker = Choker::new(2);
+
+        let changes = choker.tick(now, &mut peers, false);
+        assert_eq!(changes.len(), 2);
+        let optimistic = choker.optimistic().unwrap();
+        assert_ne!(optimistic, peers[0].addr);
+        let faster = peers
+            .iter()
+            .position(|p| p.addr != optimistic && p.choked)
+            .unwrap();
+        peers[faster].download_rate = 20.0;
+
+        // Not due yet, nothing moves
+        assert!(choker
+            .tick(now + Duration::from_secs(5), &mut peers, false)
+            .is_empty());
+        assert!(!peers[0].choked && peers[faster].choked);
+
+        let changes = choker.tick(now + REGULAR_INTERVAL, &mut peers, false);
+        assert_eq!(
+            changes,
+            vec![(peers[0].addr, true), (peers[faster].addr, false)]
+        );
+        assert!(peers[0].choked && !peers[faster].choked);
+        assert_eq!(choker.optimistic(), Some(optimistic));
+        assert_eq!(choker.last_optimistic, Some(now));
+
+        choker.tick(now + Duration::from_secs(20), &mut peers, false);
+        assert_eq!(choker.last_optimistic, Some(now));
+        choker.tick(now + OPTIMISTIC_INTERVAL, &mut peers, false);
+        assert_eq!(choker.last_optimistic, Some(now + OPTIMISTIC_INTERVAL));
+    }
+
+    #[test]
+    fn optimistic_peer_takes_no_regular_slot() {
+        let start = Instant::now();
+        let now = start + Duration::from_secs(200);
+        let mut peers = vec![peer(0, start), peer(1, start)];
+        peers[0].download_rate = 10.0;
+        let mut choker = Choker::new(2);
+        choker.tick(now, &mut peers, false);
+        assert_eq!(choker.optimistic(), Some(peers[1].addr));
+
+        // Now the fastest, but it is still the optimistic unchoke
+        peers[1].download_rate = 20.0;
+        choker.tick(now + REGULAR_INTERVAL, &mut peers, false);
+        assert_eq!(choker.optimistic(), Some(peers[1].addr));
+        assert_eq!(unchoked(&peers), addrs(&peers, &[0, 1]));
+    }
+
+    #[test]
+    fn new_peers_three_times_as_likely() {
+        let start = Instant::now();
+        let now = start + Duration::from_secs(200);
+        let old = peer(0, start);
+        let new = peer(1, now - Duration::from_secs(10));
+        let mut picked_new = 0;
+        for _ in 0..4000 {
+            // One slot, all of it optimistic
+            let mut choker = Choker::new(1);
+            choker.tick(now, &mut [old.clone(), new.clone()], false);
+            if choker.optimistic() == Some(new.addr) {
+                picked_new += 1;
+            }
+        }
+        assert!((2700..3300).contains(&picked_new), "{}", picked_new);
+    }
+
+    #[test]
+    fn unused_slots_go_to_other_torrents() {
+        assert_eq!(distribute_slots(10, &[2, 100, 100]), vec![2, 4, 4]);
+        assert_eq!(distribute_slots(3, &[0, 5]), vec![0, 3]);
+        assert_eq!(distribute_slots(10, &[1, 2]), vec![1, 2]);
+        assert_eq!(distribute_slots(1, &[5, 5, 5]).iter().sum::<usize>(), 1);
+    }
+}
@@ -1,7 +1,7 @@
 use core::fmt;
 use sha1::{Digest, Sha1};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum BencodeValue {
     String(Vec<u8>),
     Integer(i64),
@@ -9,6 +9,79 @@ pub enum BencodeValue {
     Dict(Vec<(Vec<u8>, BencodeValue)>),
 }
 
+impl BencodeValue {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            BencodeValue::String(bytes) => {
+                buf.extend(bytes.len().to_string().as_bytes());
+                buf.push(b':');
+                buf.extend(bytes);
+            }
+            BencodeValue::Integer(n) => {
+                buf.push(b'i');
+                buf.extend(n.to_string().as_bytes());
+                buf.push(b'e');
+            }
+            BencodeValue::List(list) => {
+                buf.push(b'l');
+                for value in list {
+                    value.encode_into(buf);
+                }
+                buf.push(b'e');
+            }
+            BencodeValue::Dict(dict) => {
+                // Keys must be written in sorted order
+                let mut entries: Vec<&(Vec<u8>, BencodeValue)> = dict.iter().collect();
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                buf.push(b'd');
+                for (key, value) in entries {
+                    BencodeValue::String(key.clone()).encode_into(buf);
+                    value.encode_into(buf);
+                }
+                buf.push(b'e');
+            }
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&BencodeValue> {
+        match self {
+            BencodeValue::Dict(dict) => dict.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BencodeValue::String(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            BencodeValue::Integer(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeValue]> {
+        match self {
+            BencodeValue::List(list) => Some(list),
+            _ => None,
+        }
+    }
+}
+
+// Nesting no real message comes near. Each level costs a stack frame, so
+// input from the network is parsed with this limit.
+pub const MAX_DEPTH: usize = 64;
+
 pub struct BencodeParser<'a> {
     data: &'a [u8],
     pos: usize,
@@ -20,15 +93,26 @@ impl<'a> BencodeParser<'a> {
     }
 
     pub fn parse(&mut self) -> Result<BencodeValue, &'static str> {
+        self.parse_with_depth(usize::MAX)
+    }
+
+    // Fails on lists and dictionaries nested more than `max_depth` deep
+    pub fn parse_with_depth(&mut self, max_depth: usize) -> Result<BencodeValue, &'static str> {
         match self.peek_byte()? {
             b'i' => self.parse_integer(),
-            b'l' => self.parse_list(),
-            b'd' => self.parse_dict(),
+            b'l' | b'd' if max_depth == 0 => Err("Bencode nested too deeply"),
+            b'l' => self.parse_list(max_depth - 1),
+            b'd' => self.parse_dict(max_depth - 1),
             b'0'..=b'9' => self.parse_string(),
             _ => Err("Invalid Bencode format"),
         }
     }
 
+    // Bytes consumed so far, anything after this is not part of the value
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
     // --- Helper methods ---
     fn peek_byte(&self) -> Result<u8, &'static str> {
         self.data.get(self.pos).copied().ok_or("Unexpected EOF")
@@ -64,7 +148,7 @@ impl<'a> BencodeParser<'a> {
             .parse::<usize>()
             .map_err(|_| "Invalid string length")?;
         self.consume_byte()?; // Consume ':'
-        let end = self.pos + len;
+        let end = self.pos.saturating_add(len);
         if end > self.data.len() {
             return Err("String exceeds data length");
         }
@@ -73,27 +157,27 @@ impl<'a> BencodeParser<'a> {
         Ok(BencodeValue::String(bytes))
     }
 
-    fn parse_list(&mut self) -> Result<BencodeValue, &'static str> {
+    fn parse_list(&mut self, max_depth: usize) -> Result<BencodeValue, &'static str> {
         self.consume_byte()?; // Consume 'l'
         let mut list = Vec::new();
         while self.peek_byte()? != b'e' {
-            let value = self.parse()?;
+            let value = self.parse_with_depth(max_depth)?;
             list.push(value);
         }
         self.consume_byte()?; // Consume 'e'
         Ok(BencodeValue::List(list))
     }
 
-    fn parse_dict(&mut self) -> Result<BencodeValue, &'static str> {
+    fn parse_dict(&mut self, max_depth: usize) -> Result<BencodeValue, &'static str> {
         self.consume_byte()?; // Consume 'd'
         let mut dict = Vec::new();
         while self.peek_byte()? != b'e' {
-            let key = if let BencodeValue::String(k) = self.parse()? {
+            let key = if let BencodeValue::String(k) = self.parse_with_depth(max_depth)? {
                 k
             } else {
                 return Err("Dictionary key must be a string");
             };
-            let value = self.parse()?;
+            let value = self.parse_with_depth(max_depth)?;
             dict.push((key, value));
         }
         self.consume_byte()?; // Consume 'e'
@@ -130,10 +214,11 @@ impl<'a> BencodeParser<'a> {
 
 #[derive(Debug)]
 pub struct Torrent {
-    pub announce: String,
+    pub announce: String, // Empty for trackerless torrents
     pub info: TorrentInfo,
     pub info_hash: [u8; 20],
     pub comment: Option<String>,
+    pub nodes: Vec<(String, u16)>, // BEP 5 DHT bootstrap nodes
 }
 
 #[derive(Debug)]
@@ -143,6 +228,7 @@ pub struct TorrentInfo {
     pub pieces: Vec<[u8; 20]>,
     pub length: Option<i64>,             // For single-file torrents
     pub files: Option<Vec<TorrentFile>>, // For multi-file torrents
+    pub private: bool,                   // BEP 27, no DHT/PEX/LSD
 }
 
 #[derive(Debug)]
@@ -161,6 +247,7 @@ impl Torrent {
         let mut announce = None;
         let mut info = None;
         let mut comment = None;
+        let mut nodes = Vec::new();
         let mut info_hash = [0u8; 20];
 
         for (key, value) in dict {
@@ -181,7 +268,6 @@ impl Torrent {
                     let info_bytes = &data[start..end];
                     let mut hasher = Sha1::new();
                     let info_hasher = hasher.clone();
-                    let mut info_hash = [0u8; 20];
                     hasher.update(info_bytes);
                     info_hash.copy_from_slice(&hasher.finalize());
                     println!("Info hash: {:02x?}", info_hash);
@@ -189,18 +275,35 @@ impl Torrent {
                     info = Some(TorrentInfo::from_bencode(value)?);
                 }
                 b"comment" => comment = Some(Torrent::parse_string(value)?),
+                b"nodes" => nodes = Torrent::parse_nodes(value),
                 _ => {}
             }
         }
 
         Ok(Torrent {
-            announce: announce.ok_or("Missing announce")?,
+            announce: announce.unwrap_or_default(),
             info: info.ok_or("Missing info")?,
             info_hash,
             comment,
+            nodes,
         })
     }
 
+    // `nodes` is a list of [host, port] pairs, malformed entries are skipped
+    fn parse_nodes(value: &BencodeValue) -> Vec<(String, u16)> {
+        value
+            .as_list()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|node| {
+                let pair = node.as_list()?;
+                let host = Torrent::parse_string(pair.first()?).ok()?;
+                let port = u16::try_from(pair.get(1)?.as_integer()?).ok()?;
+                Some((host, port))
+            })
+            .collect()
+    }
+
     fn parse_string(value: &BencodeValue) -> Result<String, &'static str> {
         if let BencodeValue::String(bytes) = value {
             String::from_utf8(bytes.clone()).map_err(|_| "Invalid UTF-8 string")
@@ -222,6 +325,7 @@ impl TorrentInfo {
         let mut pieces = None;
         let mut length = None;
         let mut files = None;
+        let mut private = false;
 
         for (key, value) in dict {
             match key.as_slice() {
@@ -230,6 +334,7 @@ impl TorrentInfo {
                 b"pieces" => pieces = Some(TorrentInfo::parse_pieces(value)?),
                 b"length" => length = Some(TorrentInfo::parse_integer(value)?),
                 b"files" => files = Some(TorrentInfo::parse_files(value)?),
+                b"private" => private = TorrentInfo::parse_integer(value)? == 1,
                 _ => {}
             }
         }
@@ -240,9 +345,31 @@ impl TorrentInfo {
             pieces: pieces.ok_or("Missing pieces")?,
             length,
             files,
+            private,
         })
     }
 
+    pub fn total_length(&self) -> i64 {
+        match &self.files {
+            Some(files) => files.iter().map(|f| f.length).sum(),
+            None => self.length.unwrap_or(0),
+        }
+    }
+
+    // File lengths in torrent order, a single-file torrent is one entry
+    pub fn file_lengths(&self) -> Vec<i64> {
+        match &self.files {
+            Some(files) => files.iter().map(|f| f.length).collect(),
+            None => vec![self.length.unwrap_or(0)],
+        }
+    }
+
+    // The last piece is usually shorter than piece_length
+    pub fn piece_size(&self, index: usize) -> i64 {
+        let start = index as i64 * self.piece_length;
+        (self.total_length() - start).clamp(0, self.piece_length)
+    }
+
     fn parse_integer(value: &BencodeValue) -> Result<i64, &'static str> {
         if let BencodeValue::Integer(n) = value {
             Ok(*n)
@@ -351,6 +478,12 @@ impl fmt::Display for TorrentInfo {
     }
 }
 
+// The same as `read_torrent_file` for a torrent already in memory
+pub fn torrent_from_bytes(data: &[u8]) -> Result<Torrent, &'static str> {
+    let bencode = BencodeParser::new(data).parse()?;
+    Torrent::from_bencode(&bencode, data)
+}
+
 pub fn read_torrent_file(file_path: &str) -> Result<Torrent, Box<dyn std::error::Error>> {
     println!("hi");
     //TODO: FIXME: The file path might not exist, in that case should show a dialog box
@@ -366,3 +499,37 @@ pub fn read_torrent_file(file_path: &str) -> Result<Torrent, Box<dyn std::error:
     println!("hi");
     Ok(torrent)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested(depth: usize) -> Vec<u8> {
+        let mut data = vec![b'l'; depth];
+        data.extend(vec![b'e'; depth]);
+        data
+    }
+
+    #[test]
+    fn depth_limit() {
+        assert!(BencodeParser::new(&nested(MAX_DEPTH))
+            .parse_with_depth(MAX_DEPTH)
+            .is_ok());
+        assert_eq!(
+            BencodeParser::new(&nested(MAX_DEPTH + 1)).parse_with_depth(MAX_DEPTH),
+            Err("Bencode nested too deeply")
+        );
+        let dicts = b"d1:ad1:ad1:ai1eeee";
+        assert!(BencodeParser::new(dicts).parse_with_depth(3).is_ok());
+        assert!(BencodeParser::new(dicts).parse_with_depth(2).is_err());
+    }
+
+    // A peer's worth of `llll...` fails instead of overflowing the stack
+    #[test]
+    fn deep_nesting_from_the_network() {
+        let data = vec![b'l'; 1 << 20];
+        assert!(BencodeParser::new(&data)
+            .parse_with_depth(MAX_DEPTH)
+            .is_err());
+    }
+}
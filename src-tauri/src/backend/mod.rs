@@ -1,2 +1,13 @@
+pub mod bitfield;
+pub mod choker;
 pub mod file;
+pub mod picker;
+pub mod pipeline;
+pub mod rate;
+pub mod resume;
+pub mod session;
+pub mod settings;
+pub mod storage;
+pub mod superseed;
 pub mod torrentlist;
+pub mod verify;
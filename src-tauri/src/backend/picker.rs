@@ -0,0 +1,649 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use super::bitfield::Bitfield;
+use crate::requests::peer::message::BLOCK_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilePriority {
+    Skip,
+    Low,
+    Normal,
+    High,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub piece: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum BlockState {
+    Open,
+    // More than one peer only while in endgame
+    Requested(Vec<SocketAddr>),
+    Received,
+}
+
+#[derive(Debug)]
+struct PartialPiece {
+    blocks: Vec<BlockState>,
+}
+
+#[derive(Debug, Default)]
+pub struct Received {
+    pub piece_complete: bool,
+    // Peers that were also asked for this block and should get a cancel
+    pub cancel: Vec<SocketAddr>,
+}
+
+#[derive(Debug)]
+pub struct PiecePicker {
+    piece_length: u32,
+    total_length: u64,
+    have: Bitfield,
+    availability: Vec<u32>,
+    priorities: Vec<FilePriority>,
+    // Fixed random value per piece so equally rare pieces are picked in a
+    // random but stable order
+    salt: Vec<u32>,
+    partials: HashMap<u32, PartialPiece>,
+    sequential: bool,
+    endgame: bool,
+}
+
+impl PiecePicker {
+    pub fn new(num_pieces: usize, piece_length: u32, total_length: u64) -> Self {
+        PiecePicker {
+            piece_length,
+            total_length,
+            have: Bitfield::new(num_pieces),
+            availability: vec![0; num_pieces],
+            priorities: vec![FilePriority::Normal; num_pieces],
+            salt: (0..num_pieces).map(|_| rand::random()).collect(),
+            partials: HashMap::new(),
+            sequential: false,
+            endgame: false,
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.availability.len()
+    }
+
+    pub fn piece_size(&self, index: u32) -> u32 {
+        let start = index as u64 * self.piece_length as u64;
+        self.total_length
+            .saturating_sub(start)
+            .min(self.piece_length as u64) as u32
+    }
+
+    pub fn block_count(&self, index: u32) -> usize {
+        self.piece_size(index).div_ceil(BLOCK_LEN) as usize
+    }
+
+    pub fn block(&self, piece: u32, block_index: usize) -> Block {
+        let begin = block_index as u32 * BLOCK_LEN;
+        Block {
+            piece,
+            begin,
+            length: (self.piece_size(piece) - begin).min(BLOCK_LEN),
+        }
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn mark_have(&mut self, index: u32) {
+        self.have.set(index as usize);
+        self.partials.remove(&index);
+    }
+
+    // Replaces what we have, after a recheck found out what is really on
+    // disk. Blocks of unfinished pieces can't be trusted then either.
+    pub fn set_have(&mut self, have: Bitfield) {
+        if have.len() == self.num_pieces() {
+            self.have = have;
+            self.partials.clear();
+            self.endgame = false;
+        }
+    }
+
+    // Unfinished pieces with one bit per block already received, for the
+    // resume data
+    pub fn received_blocks(&self) -> Vec<(u32, Bitfield)> {
+        let mut unfinished: Vec<(u32, Bitfield)> = self
+            .partials
+            .iter()
+            .filter(|(_, partial)| partial.blocks.contains(&BlockState::Received))
+            .map(|(index, partial)| {
+                let mut received = Bitfield::new(partial.blocks.len());
+                for (i, state) in partial.blocks.iter().enumerate() {
+                    if *state == BlockState::Received {
+                        received.set(i);
+                    }
+                }
+                (*index, received)
+            })
+            .collect();
+        unfinished.sort_by_key(|(index, _)| *index);
+        unfinished
+    }
+
+    // Puts back the received blocks of an unfinished piece from resume data
+    pub fn restore_blocks(&mut self, index: u32, received: &Bitfield) {
+        if index as usize >= self.num_pieces()
+            || self.have.has(index as usize)
+            || received.len() != self.block_count(index)
+        {
+            return;
+        }
+        let blocks = (0..received.len())
+            .map(|i| {
+                if received.has(i) {
+                    BlockState::Received
+                } else {
+                    BlockState::Open
+                }
+            })
+            .collect();
+        self.partials.insert(index, PartialPiece { blocks });
+    }
+
+    pub fn is_endgame(&self) -> bool {
+        self.endgame
+    }
+
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    pub fn is_sequential(&self) -> bool {
+        self.sequential
+    }
+
+    // --- Availability ---
+    pub fn peer_bitfield(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.ones() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count += 1;
+            }
+        }
+    }
+
+    // Records a have message in the peer's bitfield too. A piece the peer
+    // announced before is only counted once. Returns whether it was new.
+    pub fn peer_have(&mut self, peer_has: &mut Bitfield, index: u32) -> bool {
+        if peer_has.has(index as usize) {
+            return false;
+        }
+        let Some(count) = self.availability.get_mut(index as usize) else {
+            return false;
+        };
+        *count += 1;
+        peer_has.set(index as usize);
+        true
+    }
+
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability.get(index as usize).copied().unwrap_or(0)
+    }
+
+    // Forgets the pieces a peer had and gives its outstanding requests back
+    pub fn peer_disconnected(&mut self, peer: SocketAddr, bitfield: &Bitfield) {
+        for index in bitfield.ones() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+        for partial in self.partials.values_mut() {
+            for state in partial.blocks.iter_mut() {
+                release(state, peer);
+            }
+        }
+    }
+
+    // --- Priorities ---
+    pub fn set_piece_priority(&mut self, index: u32, priority: FilePriority) {
+        if let Some(p) = self.priorities.get_mut(index as usize) {
+            *p = priority;
+        }
+    }
+
+    // Pieces past the end are never downloaded
+    pub fn piece_priority(&self, index: u32) -> FilePriority {
+        self.priorities
+            .get(index as usize)
+            .copied()
+            .unwrap_or(FilePriority::Skip)
+    }
+
+    // A piece takes the highest priority of any file it overlaps, so a piece
+    // shared by a skipped and a wanted file is still downloaded.
+    pub fn set_file_priorities(&mut self, file_lengths: &[u64], priorities: &[FilePriority]) {
+        let mut piece_priorities = vec![FilePriority::Skip; self.num_pieces()];
+        let mut offset = 0u64;
+        for (length, priority) in file_lengths.iter().zip(priorities) {
+            if *length > 0 {
+                let first = offset / self.piece_length as u64;
+                let last = (offset + length - 1) / self.piece_length as u64;
+                for index in first..=last {
+                    if let Some(p) = piece_priorities.get_mut(index as usize) {
+                        *p = (*p).max(*priority);
+                    }
+                }
+            }
+            offset += length;
+        }
+        self.priorities = piece_priorities;
+        self.endgame = false;
+    }
+
+    pub fn is_wanted(&self, index: u32) -> bool {
+        !self.have.has(index as usize) && self.piece_priority(index) != FilePriority::Skip
+    }
+
+    pub fn is_interesting(&self, peer_has: &Bitfield) -> bool {
+        peer_has.ones().any(|index| self.is_wanted(index as u32))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        (0..self.num_pieces() as u32).all(|index| !self.is_wanted(index))
+    }
+
+    // --- Picking ---
+    pub fn pick(&mut self, peer: SocketAddr, peer_has: &Bitfield, max: usize) -> Vec<Block> {
+        let mut picked = Vec::new();
+        if max == 0 {
+            return picked;
+        }
+
+        // Finish pieces that are already started before opening new ones
+        let mut started: Vec<u32> = self
+            .partials
+            .keys()
+            .copied()
+            .filter(|&index| peer_has.has(index as usize) && self.is_wanted(index))
+            .collect();
+        started.sort_by_key(|&index| (Reverse(self.priorities[index as usize]), index));
+        for index in started {
+            self.pick_open_blocks(peer, index, max, &mut picked);
+            if picked.len() >= max {
+                return picked;
+            }
+        }
+
+        let mut candidates: Vec<u32> = (0..self.num_pieces() as u32)
+            .filter(|&index| {
+                peer_has.has(index as usize)
+                    && self.is_wanted(index)
+                    && !self.partials.contains_key(&index)
+            })
+            .collect();
+        if self.sequential {
+            candidates.sort_by_key(|&index| (Reverse(self.priorities[index as usize]), index));
+        } else {
+            candidates.sort_by_key(|&index| {
+                let i = index as usize;
+                (
+                    Reverse(self.priorities[i]),
+                    self.availability[i],
+                    self.salt[i],
+                )
+            });
+        }
+        for index in candidates {
+            let blocks = vec![BlockState::Open; self.block_count(index)];
+            self.partials.insert(index, PartialPiece { blocks });
+            self.pick_open_blocks(peer, index, max, &mut picked);
+            if picked.len() >= max {
+                return picked;
+            }
+        }
+
+        if picked.is_empty() {
+            self.update_endgame();
+            if self.endgame {
+                self.pick_endgame_blocks(peer, peer_has, max, &mut picked);
+            }
+        }
+        picked
+    }
+
+    fn pick_open_blocks(
+        &mut self,
+        peer: SocketAddr,
+        index: u32,
+        max: usize,
+        picked: &mut Vec<Block>,
+    ) {
+        let mut chosen = Vec::new();
+        if let Some(partial) = self.partials.get_mut(&index) {
+            for (block_index, state) in partial.blocks.iter_mut().enumerate() {
+                if picked.len() + chosen.len() >= max {
+                    break;
+                }
+                if *state == BlockState::Open {
+                    *state = BlockState::Requested(vec![peer]);
+                    chosen.push(block_index);
+                }
+            }
+        }
+        picked.extend(chosen.into_iter().map(|b| self.block(index, b)));
+    }
+
+    // Endgame: every missing block is already requested from someone, so ask
+    // this peer for them too and cancel the losers once one arrives.
+    fn pick_endgame_blocks(
+        &mut self,
+        peer: SocketAddr,
+        peer_has: &Bitfield,
+        max: usize,
+        picked: &mut Vec<Block>,
+    ) {
+        let mut indices: Vec<u32> = self.partials.keys().copied().collect();
+        indices.sort_unstable();
+        for index in indices {
+            if !peer_has.has(index as usize) || !self.is_wanted(index) {
+                continue;
+            }
+            let mut chosen = Vec::new();
+            if let Some(partial) = self.partials.get_mut(&index) {
+                for (block_index, state) in partial.blocks.iter_mut().enumerate() {
+                    if picked.len() + chosen.len() >= max {
+                        break;
+                    }
+                    if let BlockState::Requested(peers) = state {
+                        if !peers.contains(&peer) {
+                            peers.push(peer);
+                            chosen.push(block_index);
+                        }
+                    }
+                }
+            }
+            picked.extend(chosen.into_iter().map(|b| self.block(index, b)));
+            if picked.len() >= max {
+                return;
+            }
+        }
+    }
+
+    fn update_endgame(&mut self) {
+        let unstarted = (0..self.num_pieces() as u32)
+            .any(|index| self.is_wanted(index) && !self.partials.contains_key(&index));
+        let open = self
+            .partials
+            .iter()
+            .filter(|(index, _)| self.is_wanted(**index))
+            .any(|(_, partial)| partial.blocks.contains(&BlockState::Open));
+        self.endgame = !unstarted && !open && !self.partials.is_empty();
+    }
+
+    // --- Block bookkeeping ---
+    // Only whole blocks as we request them count, a block starting mid-way
+    // or of another length would mark data we never got as received
+    fn block_index(&self, block: Block) -> Option<usize> {
+        let block_index = block.begin / BLOCK_LEN;
+        if !block.begin.is_multiple_of(BLOCK_LEN)
+            || block_index as usize >= self.block_count(block.piece)
+            || block.length != self.block(block.piece, block_index as usize).length
+        {
+            return None;
+        }
+        Some(block_index as usize)
+    }
+
+    pub fn block_received(&mut self, peer: SocketAddr, block: Block) -> Received {
+        let mut received = Received::default();
+        let Some(block_index) = self.block_index(block) else {
+            return received;
+        };
+        let Some(partial) = self.partials.get_mut(&block.piece) else {
+            return received;
+        };
+        let Some(state) = partial.blocks.get_mut(block_index) else {
+            return received;
+        };
+
+        if let BlockState::Requested(peers) = state {
+            received.cancel = peers.iter().copied().filter(|p| *p != peer).collect();
+        }
+        *state = BlockState::Received;
+        received.piece_complete = partial.blocks.iter().all(|s| *s == BlockState::Received);
+        received
+    }
+
+    // The peer rejected, cancelled or timed out on a request
+    pub fn block_released(&mut self, peer: SocketAddr, block: Block) {
+        let Some(block_index) = self.block_index(block) else {
+            return;
+        };
+        if let Some(state) = self
+            .partials
+            .get_mut(&block.piece)
+            .and_then(|partial| partial.blocks.get_mut(block_index))
+        {
+            release(state, peer);
+        }
+    }
+
+    pub fn is_requested_from(&self, peer: SocketAddr, block: Block) -> bool {
+        let Some(block_index) = self.block_index(block) else {
+            return false;
+        };
+        matches!(
+            self.partials
+                .get(&block.piece)
+                .and_then(|partial| partial.blocks.get(block_index)),
+            Some(BlockState::Requested(peers)) if peers.contains(&peer)
+        )
+    }
+
+    pub fn piece_passed(&mut self, index: u32) {
+        self.mark_have(index);
+    }
+
+    // Hash check failed, every block of the piece has to be downloaded again
+    pub fn piece_failed(&mut self, index: u32) {
+        self.partials.remove(&index);
+        self.endgame = false;
+    }
+}
+
+fn release(state: &mut BlockState, peer: SocketAddr) {
+    if let BlockState::Requested(peers) = state {
+        peers.retain(|p| *p != peer);
+        if peers.is_empty() {
+            *state = BlockState::Open;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn have_counted_once() {
+        let mut picker = PiecePicker::new(4, BLOCK_LEN, 4 * BLOCK_LEN as u64);
+        let mut peer_has = Bitfield::new(4);
+        assert!(picker.peer_have(&mut peer_has, 2));
+        assert!(!picker.peer_have(&mut peer_has, 2));
+        assert!(!picker.peer_have(&mut peer_has, 9));
+        assert_eq!(picker.availability(2), 1);
+        picker.peer_disconnected("127.0.0.1:1".parse().unwrap(), &peer_has);
+        assert_eq!(picker.availability(2), 0);
+    }
+
+    #[test]
+    fn out_of_range_pieces() {
+        let picker = PiecePicker::new(4, BLOCK_LEN, 4 * BLOCK_LEN as u64);
+        assert!(!picker.is_wanted(4));
+        assert!(!picker.is_wanted(u32::MAX));
+        assert_eq!(picker.piece_priority(4), FilePriority::Skip);
+        let mut peer_has = Bitfield::new(16);
+        peer_has.set(10);
+        assert!(!picker.is_interesting(&peer_has));
+    }
+
+    fn peer(n: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], n))
+    }
+
+    fn all(num_pieces: usize) -> Bitfield {
+        let mut has = Bitfield::new(num_pieces);
+        for index in 0..num_pieces {
+            has.set(index);
+        }
+        has
+    }
+
+    // One block per piece, piece i is held by i + 1 peers
+    fn by_rarity(num_pieces: usize) -> PiecePicker {
+        let mut picker =
+            PiecePicker::new(num_pieces, BLOCK_LEN, num_pieces as u64 * BLOCK_LEN as u64);
+        for i in 0..num_pieces {
+            let mut has = Bitfield::new(num_pieces);
+            for index in 0..=i {
+                has.set(num_pieces - 1 - index);
+            }
+            picker.peer_bitfield(&has);
+        }
+        picker
+    }
+
+    fn pieces(blocks: &[Block]) -> Vec<u32> {
+        blocks.iter().map(|b| b.piece).collect()
+    }
+
+    #[test]
+    fn rarest_first() {
+        let mut picker = by_rarity(4);
+        assert_eq!(picker.availability(0), 1);
+        assert_eq!(picker.availability(3), 4);
+        assert_eq!(pieces(&picker.pick(peer(1), &all(4), 4)), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn equally_rare_pieces_in_random_order() {
+        let firsts: std::collections::HashSet<u32> = (0..50)
+            .map(|_| {
+                let mut picker = PiecePicker::new(8, BLOCK_LEN, 8 * BLOCK_LEN as u64);
+                picker.pick(peer(1), &all(8), 1)[0].piece
+            })
+            .collect();
+        assert!(firsts.len() > 1);
+    }
+
+    #[test]
+    fn priorities_before_rarity() {
+        let mut picker = by_rarity(4);
+        picker.set_piece_priority(0, FilePriority::Skip);
+        picker.set_piece_priority(3, FilePriority::High);
+        picker.set_piece_priority(1, FilePriority::Low);
+        assert_eq!(pieces(&picker.pick(peer(1), &all(4), 4)), vec![3, 2, 1]);
+        assert!(picker
+            .pick(peer(1), &all(4), 4)
+            .iter()
+            .all(|b| b.piece != 0));
+    }
+
+    #[test]
+    fn sequential_ignores_rarity() {
+        let mut picker = by_rarity(4);
+        // The most common piece first
+        picker.peer_bitfield(&{
+            let mut has = Bitfield::new(4);
+            has.set(0);
+            has
+        });
+        picker.set_sequential(true);
+        assert_eq!(pieces(&picker.pick(peer(1), &all(4), 4)), vec![0, 1, 2, 3]);
+
+        // Priorities still come first
+        let mut picker = by_rarity(4);
+        picker.set_sequential(true);
+        picker.set_piece_priority(2, FilePriority::High);
+        assert_eq!(pieces(&picker.pick(peer(1), &all(4), 4)), vec![2, 0, 1, 3]);
+    }
+
+    #[test]
+    fn started_pieces_finished_first() {
+        // Two pieces of four blocks, piece 1 is rarer
+        let mut picker = PiecePicker::new(2, 4 * BLOCK_LEN, 8 * BLOCK_LEN as u64);
+        picker.peer_bitfield(&all(2));
+        let mut only_first = Bitfield::new(2);
+        only_first.set(0);
+        picker.peer_bitfield(&only_first);
+
+        assert_eq!(
+            picker.pick(peer(1), &only_first, 2),
+            vec![picker.block(0, 0), picker.block(0, 1)]
+        );
+        // Another peer finishes piece 0 before starting the rarer one
+        let picked = picker.pick(peer(2), &all(2), 3);
+        assert_eq!(
+            picked,
+            vec![picker.block(0, 2), picker.block(0, 3), picker.block(1, 0)]
+        );
+    }
+
+    #[test]
+    fn endgame_duplicates_then_cancels() {
+        let mut picker = PiecePicker::new(1, 2 * BLOCK_LEN, 2 * BLOCK_LEN as u64);
+        let has = all(1);
+        let first = picker.pick(peer(1), &has, 4);
+        assert_eq!(first.len(), 2);
+        assert!(!picker.is_endgame());
+
+        // Nothing open is left, the second peer gets the same blocks
+        let second = picker.pick(peer(2), &has, 4);
+        assert!(picker.is_endgame());
+        assert_eq!(second, first);
+        // But never the same block twice from one peer
+        assert!(picker.pick(peer(2), &has, 4).is_empty());
+
+        let received = picker.block_received(peer(2), first[0]);
+        assert_eq!(received.cancel, vec![peer(1)]);
+        assert!(!received.piece_complete);
+        let received = picker.block_received(peer(1), first[1]);
+        assert_eq!(received.cancel, vec![peer(2)]);
+        assert!(received.piece_complete);
+    }
+
+    #[test]
+    fn misaligned_blocks_rejected() {
+        // The last block is short
+        let mut picker = PiecePicker::new(1, 2 * BLOCK_LEN, BLOCK_LEN as u64 + 100);
+        let blocks = picker.pick(peer(1), &all(1), 2);
+        assert_eq!(blocks[1].length, 100);
+
+        let shifted = Block {
+            begin: 1,
+            ..blocks[0]
+        };
+        let short = Block {
+            length: BLOCK_LEN - 1,
+            ..blocks[0]
+        };
+        let long = Block {
+            length: 200,
+            ..blocks[1]
+        };
+        for block in [shifted, short, long] {
+            assert!(!picker.is_requested_from(peer(1), block));
+            let received = picker.block_received(peer(1), block);
+            assert!(!received.piece_complete && received.cancel.is_empty());
+        }
+        assert!(picker.received_blocks().is_empty());
+
+        assert!(!picker.block_received(peer(1), blocks[0]).piece_complete);
+        assert!(picker.block_received(peer(1), blocks[1]).piece_complete);
+    }
+}
@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::bitfield::Bitfield;
+use super::picker::{Block, PiecePicker};
+use super::rate::RateMeter;
+use crate::requests::peer::message::BLOCK_LEN;
+
+pub const MIN_DEPTH: usize = 2;
+pub const INITIAL_DEPTH: usize = 4;
+// Default cap when the peer doesn't advertise `reqq`
+pub const MAX_DEPTH: usize = 250;
+
+const MIN_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+struct Outstanding {
+    block: Block,
+    sent: Instant,
+    // Nothing else was outstanding when it was sent
+    alone: bool,
+    // Asked for again after timing out, a reply could be to either request
+    retry: bool,
+}
+
+// Requests we have in flight to one peer. The queue is kept about one
+// bandwidth-delay product deep so the peer never sits idle waiting for our
+// next request.
+#[derive(Debug)]
+pub struct RequestQueue {
+    outstanding: Vec<Outstanding>,
+    depth: usize,
+    max_depth: usize,
+    srtt: Option<Duration>,
+    rate: RateMeter,
+    last_reply: Option<Instant>,
+    // Blocks that timed out and when, so a request for them again is known
+    // to be a retry
+    expired: HashMap<Block, Instant>,
+}
+
+impl RequestQueue {
+    pub fn new() -> Self {
+        RequestQueue {
+            outstanding: Vec::new(),
+            depth: INITIAL_DEPTH,
+            max_depth: MAX_DEPTH,
+            srtt: None,
+            rate: RateMeter::new(),
+            last_reply: None,
+            expired: HashMap::new(),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn len(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn download_rate(&self) -> f64 {
+        self.rate.rate()
+    }
+
+    // Peer told us how many requests it is willing to queue (`reqq`)
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth.max(MIN_DEPTH);
+        self.depth = self.depth.min(self.max_depth);
+    }
+
+    // How many more requests can be sent right now
+    pub fn free_slots(&self) -> usize {
+        self.depth.saturating_sub(self.outstanding.len())
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = Block> + '_ {
+        self.outstanding.iter().map(|o| o.block)
+    }
+
+    pub fn contains(&self, block: Block) -> bool {
+        self.outstanding.iter().any(|o| o.block == block)
+    }
+
+    pub fn push(&mut self, block: Block, now: Instant) {
+        let alone = self.outstanding.is_empty();
+        let retry = self.expired.remove(&block).is_some();
+        self.outstanding.push(Outstanding {
+            block,
+            sent: now,
+            alone,
+            retry,
+        });
+    }
+
+    // Returns false for blocks we never asked this peer for
+    pub fn received(&mut self, block: Block, now: Instant) -> bool {
+        let Some(pos) = self.outstanding.iter().position(|o| o.block == block) else {
+            return false;
+        };
+        let request = self.outstanding.remove(pos);
+        self.rate.add(block.length as u64);
+        let last_reply = self.last_reply.replace(now);
+
+        // Smoothed RTT the same way TCP does it, and like TCP (Karn) never
+        // from a retried request. A request that waited behind others at the
+        // peer measures our queue, not the RTT, and counting it would make a
+        // deeper queue look like a longer RTT and grow the queue further.
+        // So only requests that found the peer idle count: nothing else was
+        // outstanding when sent, or the reply came well after the previous
+        // one, longer than the peer takes to serve a block.
+        let service = block.length as f64 / self.rate.rate();
+        let idle = last_reply
+            .is_some_and(|last| now.saturating_duration_since(last).as_secs_f64() > service * 1.5);
+        if request.retry || !(request.alone || idle) {
+            return true;
+        }
+        let sample = now.saturating_duration_since(request.sent);
+        self.srtt = Some(match self.srtt {
+            None => sample,
+            Some(srtt) => (srtt * 7 + sample) / 8,
+        });
+        true
+    }
+
+    // Rejected by the peer or cancelled by us
+    pub fn remove(&mut self, block: Block) -> bool {
+        let before = self.outstanding.len();
+        self.outstanding.retain(|o| o.block != block);
+        self.outstanding.len() != before
+    }
+
+    // Being choked without the fast extension drops every pending request
+    pub fn clear(&mut self) -> Vec<Block> {
+        self.outstanding.drain(..).map(|o| o.block).collect()
+    }
+
+    pub fn timeout(&self) -> Duration {
+        match self.srtt {
+            Some(srtt) => (srtt * 4 + Duration::from_secs(1)).clamp(MIN_TIMEOUT, MAX_TIMEOUT),
+            None => MAX_TIMEOUT,
+        }
+    }
+
+    // Removes requests the peer has been sitting on for too long so they can be
+    // handed to someone else, and backs off the queue depth.
+    pub fn timed_out(&mut self, now: Instant) -> Vec<Block> {
+        let timeout = self.timeout();
+        let (stalled, waiting): (Vec<Outstanding>, Vec<Outstanding>) = self
+            .outstanding
+            .drain(..)
+            .partition(|o| now.saturating_duration_since(o.sent) >= timeout);
+        self.outstanding = waiting;
+        if !stalled.is_empty() {
+            self.depth = (self.depth / 2).max(MIN_DEPTH);
+        }
+        self.expired
+            .retain(|_, at| now.saturating_duration_since(*at) < MAX_TIMEOUT);
+        self.expired.extend(stalled.iter().map(|o| (o.block, now)));
+        stalled.into_iter().map(|o| o.block).collect()
+    }
+
+    // Called about once a second to resize the queue to the measured
+    // bandwidth-delay product. The 1.5x headroom lets the depth keep growing
+    // until the peer's bandwidth, not our queue, is the limit.
+    pub fn update(&mut self, now: Instant) {
+        self.rate.update(now);
+        let Some(srtt) = self.srtt else {
+            return;
+        };
+        let bdp = self.rate.rate() * srtt.as_secs_f64();
+        let target = (bdp * 1.5 / BLOCK_LEN as f64).ceil() as usize + MIN_DEPTH;
+        self.depth = target.clamp(MIN_DEPTH, self.max_depth);
+    }
+}
+
+impl Default for RequestQueue {
+    fn default() -> Self {
+        RequestQueue::new()
+    }
+}
+
+// Tops the peer's queue up from the picker and returns the requests to send
+pub fn fill(
+    queue: &mut RequestQueue,
+    picker: &mut PiecePicker,
+    peer: SocketAddr,
+    peer_has: &Bitfield,
+    now: Instant,
+) -> Vec<Block> {
+    let blocks = picker.pick(peer, peer_has, queue.free_slots());
+    for block in &blocks {
+        queue.push(*block, now);
+    }
+    blocks
+}
+
+// Gives stalled requests back to the picker so other peers can fetch them.
+// The returned blocks should be cancelled with this peer.
+pub fn expire(
+    queue: &mut RequestQueue,
+    picker: &mut PiecePicker,
+    peer: SocketAddr,
+    now: Instant,
+) -> Vec<Block> {
+    let stalled = queue.timed_out(now);
+    for block in &stalled {
+        picker.block_released(peer, *block);
+    }
+    stalled
+}
+
+// The peer rejected a request, or we cancelled it ourselves
+pub fn release(queue: &mut RequestQueue, picker: &mut PiecePicker, peer: SocketAddr, block: Block) {
+    if queue.remove(block) {
+        picker.block_released(peer, block);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    fn block(n: u32) -> Block {
+        Block {
+            piece: n,
+            begin: 0,
+            length: BLOCK_LEN,
+        }
+    }
+
+    // A peer serving our requests in order, one block at a time
+    struct SimulatedPeer {
+        // Arrival times back at us, in request order
+        replies: VecDeque<(Instant, Block)>,
+        free: Instant,
+        next: u32,
+        now: Instant,
+        next_update: Instant,
+    }
+
+    impl SimulatedPeer {
+        fn new(start: Instant) -> Self {
+            SimulatedPeer {
+                replies: VecDeque::new(),
+                free: start,
+                next: 0,
+                now: start,
+                next_update: start + Duration::from_secs(1),
+            }
+        }
+
+        // Keeps the queue full until `until`, `latency` each way
+        fn run(
+            &mut self,
+            queue: &mut RequestQueue,
+            until: Instant,
+            service: Duration,
+            latency: Duration,
+        ) {
+            while self.now < until {
+                while queue.free_slots() > 0 {
+                    queue.push(block(self.next), self.now);
+                    let done = (self.now + latency).max(self.free) + service;
+                    self.free = done;
+                    self.replies.push_back((done + latency, block(self.next)));
+                    self.next += 1;
+                }
+                let (arrival, received) = self.replies.pop_front().unwrap();
+                while self.next_update <= arrival {
+                    queue.update(self.next_update);
+                    self.next_update += Duration::from_secs(1);
+                }
+                self.now = arrival;
+                assert!(queue.received(received, self.now));
+            }
+        }
+    }
+
+    // 64 KiB/s, 100 ms away
+    #[test]
+    fn depth_settles_on_slow_peer() {
+        let start = Instant::now();
+        let mut queue = RequestQueue::new();
+        let mut peer = SimulatedPeer::new(start);
+        peer.run(
+            &mut queue,
+            start + Duration::from_secs(120),
+            Duration::from_millis(250),
+            Duration::from_millis(50),
+        );
+
+        // One block in flight covers the RTT, a few more cover the headroom
+        assert!(queue.depth() <= 8, "depth grew to {}", queue.depth());
+        let rtt = queue.rtt().unwrap();
+        assert!(rtt < Duration::from_millis(400), "rtt {:?}", rtt);
+    }
+
+    // 8 MiB/s, first 20 ms away and then 200 ms. The queue is never empty,
+    // the longer RTT has to show in replies that find the peer idle.
+    #[test]
+    fn depth_follows_longer_rtt() {
+        let service = Duration::from_millis(2);
+        let start = Instant::now();
+        let mut queue = RequestQueue::new();
+        let mut peer = SimulatedPeer::new(start);
+        peer.run(
+            &mut queue,
+            start + Duration::from_secs(30),
+            service,
+            Duration::from_millis(10),
+        );
+        let near = queue.depth();
+        assert!(queue.rtt().unwrap() < Duration::from_millis(30));
+
+        peer.run(
+            &mut queue,
+            start + Duration::from_secs(90),
+            service,
+            Duration::from_millis(100),
+        );
+        let rtt = queue.rtt().unwrap();
+        assert!(rtt > Duration::from_millis(150), "rtt {:?}", rtt);
+        // 8 MiB/s over 200 ms is 100 blocks in flight
+        assert!(
+            queue.depth() >= 100 && queue.depth() > near * 4,
+            "depth {}",
+            queue.depth()
+        );
+    }
+
+    #[test]
+    fn retried_request_gives_no_sample() {
+        let now = Instant::now();
+        let mut queue = RequestQueue::new();
+        queue.push(block(0), now);
+        let late = now + MAX_TIMEOUT;
+        assert_eq!(queue.timed_out(late), vec![block(0)]);
+        queue.push(block(0), late);
+        // Could be the answer to the first request
+        assert!(queue.received(block(0), late + Duration::from_millis(10)));
+        assert_eq!(queue.rtt(), None);
+    }
+
+    #[test]
+    fn queued_requests_give_no_sample() {
+        let now = Instant::now();
+        let mut queue = RequestQueue::new();
+        queue.push(block(0), now);
+        queue.push(block(1), now);
+        assert!(queue.received(block(0), now + Duration::from_millis(100)));
+        assert!(queue.received(block(1), now + Duration::from_secs(5)));
+        assert_eq!(queue.rtt(), Some(Duration::from_millis(100)));
+    }
+}
@@ -0,0 +1,55 @@
+use std::time::{Duration, Instant};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+// Weight given to the newest one second sample
+const SMOOTHING: f64 = 0.2;
+
+// Exponentially smoothed transfer rate in bytes per second
+#[derive(Debug, Clone)]
+pub struct RateMeter {
+    rate: f64,
+    pending: u64,
+    total: u64,
+    last_sample: Instant,
+}
+
+impl RateMeter {
+    pub fn new() -> Self {
+        RateMeter {
+            rate: 0.0,
+            pending: 0,
+            total: 0,
+            last_sample: Instant::now(),
+        }
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        self.pending += bytes;
+        self.total += bytes;
+    }
+
+    pub fn update(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_sample);
+        if elapsed < SAMPLE_INTERVAL {
+            return;
+        }
+        let sample = self.pending as f64 / elapsed.as_secs_f64();
+        self.rate += (sample - self.rate) * SMOOTHING;
+        self.pending = 0;
+        self.last_sample = now;
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        RateMeter::new()
+    }
+}
@@ -0,0 +1,410 @@
+use dirs::config_dir;
+use std::fs;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use super::file::{BencodeParser, BencodeValue};
+use super::picker::FilePriority;
+use super::storage::Storage;
+use crate::requests::compact;
+use crate::requests::dht::storage::to_hex;
+
+const FILE_FORMAT: &[u8] = b"defttorrent resume file";
+const FILE_VERSION: i64 = 1;
+
+// Everything needed to bring a torrent back after a restart without hashing
+// its data again. Saved as bencode, one file per torrent next to a copy of
+// its .torrent file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumeData {
+    // The frontend's id for the torrent
+    pub id: usize,
+    pub info_hash: [u8; 20],
+    pub save_path: PathBuf,
+    // Have bitfield as sent on the wire
+    pub pieces: Vec<u8>,
+    // Unfinished pieces and a bitfield of the blocks already written
+    pub unfinished: Vec<(u32, Vec<u8>)>,
+    pub file_priorities: Vec<FilePriority>,
+    // Size and modification time, in seconds, of each file when saved. The
+    // pieces are only trusted while the files still look like this.
+    pub file_sizes: Vec<(u64, i64)>,
+    // Paths of the files after renames, empty when none was renamed
+    pub mapped_files: Vec<String>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    // Unix time the torrent was added
+    pub added_time: i64,
+    // Announce URLs by tier
+    pub trackers: Vec<Vec<String>>,
+    pub peers: Vec<SocketAddr>,
+    // Upload slots set for this torrent, None uses the global setting
+    pub upload_slots: Option<usize>,
+    // Pieces are downloaded in order instead of rarest first
+    pub sequential: bool,
+}
+
+impl ResumeData {
+    pub fn encode(&self) -> Vec<u8> {
+        let string = |bytes: &[u8]| BencodeValue::String(bytes.to_vec());
+        let int = BencodeValue::Integer;
+        let (peers, peers6): (Vec<&SocketAddr>, Vec<&SocketAddr>) =
+            self.peers.iter().partition(|addr| addr.is_ipv4());
+
+        BencodeValue::Dict(vec![
+            (b"file-format".to_vec(), string(FILE_FORMAT)),
+            (b"file-version".to_vec(), int(FILE_VERSION)),
+            (b"id".to_vec(), int(self.id as i64)),
+            (b"info-hash".to_vec(), string(&self.info_hash)),
+            (
+                b"save_path".to_vec(),
+                string(&path_to_bytes(&self.save_path)),
+            ),
+            (b"pieces".to_vec(), string(&self.pieces)),
+            (
+                b"unfinished".to_vec(),
+                BencodeValue::List(
+                    self.unfinished
+                        .iter()
+                        .map(|(piece, blocks)| {
+                            BencodeValue::Dict(vec![
+                                (b"piece".to_vec(), int(*piece as i64)),
+                                (b"bitmask".to_vec(), string(blocks)),
+                            ])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                b"file_priority".to_vec(),
+                BencodeValue::List(
+                    self.file_priorities
+                        .iter()
+                        .map(|priority| int(priority_to_int(*priority)))
+                        .collect(),
+                ),
+            ),
+            (
+                b"file_sizes".to_vec(),
+                BencodeValue::List(
+                    self.file_sizes
+                        .iter()
+                        .map(|(size, mtime)| {
+                            BencodeValue::List(vec![int(*size as i64), int(*mtime)])
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                b"mapped_files".to_vec(),
+                BencodeValue::List(
+                    self.mapped_files
+                        .iter()
+                        .map(|path| string(path.as_bytes()))
+                        .collect(),
+                ),
+            ),
+            (b"total_uploaded".to_vec(), int(self.uploaded as i64)),
+            (b"total_downloaded".to_vec(), int(self.downloaded as i64)),
+            (b"added_time".to_vec(), int(self.added_time)),
+            (
+                b"trackers".to_vec(),
+                BencodeValue::List(
+                    self.trackers
+                        .iter()
+                        .map(|tier| {
+                            BencodeValue::List(
+                                tier.iter().map(|url| string(url.as_bytes())).collect(),
+                            )
+                        })
+                        .collect(),
+                ),
+            ),
+            (
+                b"peers".to_vec(),
+                string(
+                    &peers
+                        .into_iter()
+                        .flat_map(compact::encode)
+                        .collect::<Vec<u8>>(),
+                ),
+            ),
+            (
+                b"peers6".to_vec(),
+                string(
+                    &peers6
+                        .into_iter()
+                        .flat_map(compact::encode)
+                        .collect::<Vec<u8>>(),
+                ),
+            ),
+            (
+                b"max_uploads".to_vec(),
+                int(self.upload_slots.map_or(-1, |slots| slots as i64)),
+            ),
+            (b"sequential_download".to_vec(), int(self.sequential as i64)),
+        ])
+        .encode()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, &'static str> {
+        let dict = BencodeParser::new(data).parse()?;
+        if dict.get(b"file-format").and_then(|v| v.as_bytes()) != Some(FILE_FORMAT) {
+            return Err("Not a resume file");
+        }
+        if dict.get(b"file-version").and_then(|v| v.as_integer()) != Some(FILE_VERSION) {
+            return Err("Unsupported resume file version");
+        }
+        let bytes = |key: &[u8]| dict.get(key).and_then(|v| v.as_bytes());
+        let int = |key: &[u8]| dict.get(key).and_then(|v| v.as_integer());
+        let list = |key: &[u8]| dict.get(key).and_then(|v| v.as_list()).unwrap_or_default();
+
+        let info_hash = bytes(b"info-hash")
+            .and_then(|hash| <[u8; 20]>::try_from(hash).ok())
+            .ok_or("Missing info hash")?;
+        let save_path = bytes(b"save_path")
+            .and_then(path_from_bytes)
+            .ok_or("Missing save path")?;
+        let unfinished = list(b"unfinished")
+            .iter()
+            .filter_map(|entry| {
+                let piece = u32::try_from(entry.get(b"piece")?.as_integer()?).ok()?;
+                Some((piece, entry.get(b"bitmask")?.as_bytes()?.to_vec()))
+            })
+            .collect();
+        let file_priorities = list(b"file_priority")
+            .iter()
+            .map(|v| {
+                v.as_integer()
+                    .map_or(FilePriority::Normal, priority_from_int)
+            })
+            .collect();
+        let file_sizes = list(b"file_sizes")
+            .iter()
+            .map(|entry| {
+                let pair = entry.as_list().ok_or("Invalid file size")?;
+                let size = pair.first().and_then(|v| v.as_integer());
+                let mtime = pair.get(1).and_then(|v| v.as_integer());
+                match (size, mtime) {
+                    (Some(size), Some(mtime)) if size >= 0 => Ok((size as u64, mtime)),
+                    _ => Err("Invalid file size"),
+                }
+            })
+            .collect::<Result<_, &'static str>>()?;
+        let mapped_files = list(b"mapped_files")
+            .iter()
+            .map(|path| {
+                path.as_bytes()
+                    .and_then(|path| String::from_utf8(path.to_vec()).ok())
+                    .ok_or("Invalid mapped file")
+            })
+            .collect::<Result<_, &'static str>>()?;
+        let trackers = list(b"trackers")
+            .iter()
+            .map(|tier| {
+                tier.as_list()
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|url| String::from_utf8(url.as_bytes()?.to_vec()).ok())
+                    .collect()
+            })
+            .collect();
+        let mut peers = compact::parse_v4(bytes(b"peers").unwrap_or_default());
+        peers.extend(compact::parse_v6(bytes(b"peers6").unwrap_or_default()));
+
+        Ok(ResumeData {
+            id: int(b"id")
+                .and_then(|id| usize::try_from(id).ok())
+                .ok_or("Missing torrent id")?,
+            info_hash,
+            save_path,
+            pieces: bytes(b"pieces").unwrap_or_default().to_vec(),
+            unfinished,
+            file_priorities,
+            file_sizes,
+            mapped_files,
+            uploaded: int(b"total_uploaded").unwrap_or(0).max(0) as u64,
+            downloaded: int(b"total_downloaded").unwrap_or(0).max(0) as u64,
+            added_time: int(b"added_time").unwrap_or(0),
+            trackers,
+            peers,
+            upload_slots: int(b"max_uploads").and_then(|slots| usize::try_from(slots).ok()),
+            sequential: int(b"sequential_download").unwrap_or(0) != 0,
+        })
+    }
+}
+
+// Unix paths are kept byte for byte, they don't have to be UTF-8. Windows
+// ones always convert.
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(PathBuf::from(std::ffi::OsString::from_vec(bytes.to_vec())))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Option<PathBuf> {
+    std::str::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+fn priority_to_int(priority: FilePriority) -> i64 {
+    match priority {
+        FilePriority::Skip => 0,
+        FilePriority::Low => 1,
+        FilePriority::Normal => 4,
+        FilePriority::High => 7,
+    }
+}
+
+// Same scale as libtorrent, 0 to 7
+fn priority_from_int(value: i64) -> FilePriority {
+    match value {
+        i64::MIN..=0 => FilePriority::Skip,
+        1..=3 => FilePriority::Low,
+        4..=5 => FilePriority::Normal,
+        _ => FilePriority::High,
+    }
+}
+
+// Where resume files are kept, None without a config directory
+pub fn resume_dir() -> Option<PathBuf> {
+    config_dir().map(|mut path| {
+        path.push("defttorrent");
+        path.push("resume");
+        path
+    })
+}
+
+fn resume_path(dir: &Path, info_hash: &[u8; 20]) -> PathBuf {
+    dir.join(format!("{}.resume", to_hex(info_hash)))
+}
+
+fn torrent_path(dir: &Path, info_hash: &[u8; 20]) -> PathBuf {
+    dir.join(format!("{}.torrent", to_hex(info_hash)))
+}
+
+// Saves the resume data, and the .torrent file the first time. A crash
+// halfway leaves the previous file intact.
+pub fn save(dir: &Path, data: &ResumeData, torrent: Option<&[u8]>) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    if let Some(torrent) = torrent {
+        write_atomic(&torrent_path(dir, &data.info_hash), torrent)?;
+    }
+    write_atomic(&resume_path(dir, &data.info_hash), &data.encode())
+}
+
+pub fn remove(dir: &Path, info_hash: &[u8; 20]) -> io::Result<()> {
+    for path in [resume_path(dir, info_hash), torrent_path(dir, info_hash)] {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Every saved torrent with its .torrent file. Unreadable entries are
+// skipped, one bad file shouldn't lose the rest of the session.
+pub fn load_all(dir: &Path) -> Vec<(ResumeData, Vec<u8>)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut loaded = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "resume") {
+            continue;
+        }
+        let result = fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| ResumeData::decode(&data).map_err(str::to_string))
+            .and_then(|resume| {
+                fs::read(torrent_path(dir, &resume.info_hash))
+                    .map(|torrent| (resume, torrent))
+                    .map_err(|e| e.to_string())
+            });
+        match result {
+            Ok(entry) => loaded.push(entry),
+            Err(e) => println!("Skipping resume file {}: {}", path.display(), e),
+        }
+    }
+    loaded.sort_by_key(|(resume, _)| resume.id);
+    loaded
+}
+
+// Size and modification time of each of the torrent's files right now, zeros
+// for files that don't exist
+pub fn file_sizes(storage: &Storage) -> Vec<(u64, i64)> {
+    (0..storage.layout().files.len())
+        .map(|index| {
+            fs::metadata(storage.file_path(index))
+                .map(|metadata| {
+                    let mtime = metadata
+                        .modified()
+                        .ok()
+                        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                        .map_or(0, |since| since.as_secs() as i64);
+                    (metadata.len(), mtime)
+                })
+                .unwrap_or((0, 0))
+        })
+        .collect()
+}
+
+// Written next to the target and renamed over it, so readers only ever see
+// the old or the new contents
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn save_path_keeps_raw_bytes() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"/data/caf\xe9"));
+        let data = ResumeData {
+            id: 3,
+            info_hash: [7; 20],
+            save_path: path.to_path_buf(),
+            pieces: vec![0xf0],
+            unfinished: Vec::new(),
+            file_priorities: vec![FilePriority::Normal],
+            file_sizes: vec![(10, 20)],
+            mapped_files: Vec::new(),
+            uploaded: 0,
+            downloaded: 0,
+            added_time: 0,
+            trackers: Vec::new(),
+            peers: Vec::new(),
+            upload_slots: Some(2),
+            sequential: true,
+        };
+        let decoded = ResumeData::decode(&data.encode()).unwrap();
+        assert_eq!(decoded.save_path, path);
+        assert_eq!(decoded, data);
+    }
+}
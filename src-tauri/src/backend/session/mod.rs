@@ -0,0 +1,267 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::net::TcpListener;
+use tokio::time::{interval, Duration};
+
+mod peer;
+pub mod swarm;
+
+use super::choker::distribute_slots;
+use super::settings::{EncryptionPolicy, Settings};
+use super::storage::cache::DiskCache;
+use super::torrentlist::{TorrentItem, TorrentList};
+use crate::requests::dht::Dht;
+use crate::requests::peer::manager::{ConnectionManager, PeerSource};
+use crate::requests::peer::transport::PeerTransport;
+use crate::requests::peer::{self as wire, PeerConnection};
+use crate::requests::tracker;
+use crate::requests::utp::UtpSocket;
+
+const TICK: Duration = Duration::from_secs(1);
+// Connection attempts started per torrent each tick
+const DIALS_PER_TICK: usize = 2;
+
+struct Dial {
+    id: usize,
+    info_hash: [u8; 20],
+    addr: SocketAddr,
+    // Introduced through ut_holepunch, the peer is dialing us right now
+    holepunched: bool,
+}
+
+struct Announce {
+    info_hash: [u8; 20],
+    private: bool,
+    trackers: Vec<String>,
+    manager: Arc<Mutex<ConnectionManager>>,
+}
+
+// The peer side of every torrent. Dials the peers their connection managers
+// know about, accepts incoming ones, and once a second splits the global
+// upload slots between the torrents and runs each one's choker.
+#[derive(Clone)]
+pub struct Session {
+    torrents: Arc<Mutex<TorrentList>>,
+    cache: DiskCache,
+    peer_id: [u8; 20],
+    listen_port: u16,
+    encryption: EncryptionPolicy,
+    upload_slots: usize,
+    upload_slots_per_torrent: usize,
+    super_seeding: bool,
+    // Filled in once the UDP port is bound, None while it isn't or with
+    // uTP or the DHT turned off
+    utp: Arc<Mutex<Option<UtpSocket>>>,
+    dht: Arc<Mutex<Option<Dht>>>,
+}
+
+impl Session {
+    pub fn new(
+        torrents: Arc<Mutex<TorrentList>>,
+        utp: Arc<Mutex<Option<UtpSocket>>>,
+        dht: Arc<Mutex<Option<Dht>>>,
+    ) -> Session {
+        let settings = Settings::load();
+        let cache = torrents.lock().unwrap().cache().clone();
+        Session {
+            torrents,
+            cache,
+            peer_id: wire::generate_peer_id(),
+            listen_port: settings.listen_port,
+            encryption: settings.encryption,
+            upload_slots: settings.upload_slots,
+            upload_slots_per_torrent: settings.upload_slots_per_torrent,
+            super_seeding: settings.super_seeding,
+            utp,
+            dht,
+        }
+    }
+
+    // Listens for peers and runs the session's tick, never returns
+    pub async fn run(self) {
+        tokio::spawn(self.clone().listen());
+        let mut ticker = interval(TICK);
+        loop {
+            ticker.tick().await;
+            let (dials, announces) = self.tick(Instant::now());
+            for dial in dials {
+                tokio::spawn(self.clone().connect(dial));
+            }
+            for announce in announces {
+                tokio::spawn(self.clone().announce(announce));
+            }
+        }
+    }
+
+    // Takes the peers connecting over uTP, once the socket is bound
+    pub fn accept_utp(&self, utp: UtpSocket) {
+        let session = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match utp.accept().await {
+                    Ok(stream) => {
+                        tokio::spawn(session.clone().accept(PeerTransport::Utp(stream)));
+                    }
+                    Err(e) => {
+                        println!("uTP accept failed: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    fn with_torrent<T>(&self, id: usize, f: impl FnOnce(&mut TorrentItem) -> T) -> Option<T> {
+        self.torrents.lock().unwrap().list.get_mut(&id).map(f)
+    }
+
+    fn tick(&self, now: Instant) -> (Vec<Dial>, Vec<Announce>) {
+        let mut torrents = self.torrents.lock().unwrap();
+        let items: Vec<(&usize, &mut TorrentItem)> = torrents.list.iter_mut().collect();
+        let limits: Vec<usize> = items
+            .iter()
+            .map(|(_, item)| item.swarm.upload_limit(self.upload_slots_per_torrent))
+            .collect();
+        let slots = distribute_slots(self.upload_slots, &limits);
+
+        let mut dials = Vec::new();
+        let mut announces = Vec::new();
+        for ((id, item), slots) in items.into_iter().zip(slots) {
+            let seeding = item.picker.is_finished();
+            item.swarm
+                .tick(now, slots, seeding, self.super_seeding, &mut item.picker);
+            let info_hash = item.object.info_hash;
+            let ready = item
+                .swarm
+                .manager
+                .lock()
+                .unwrap()
+                .next_to_connect(now, DIALS_PER_TICK);
+            dials.extend(ready.into_iter().map(|addr| Dial {
+                id: *id,
+                info_hash,
+                addr,
+                holepunched: false,
+            }));
+            let mut punched = item.swarm.holepunch.lock().unwrap().take_connects();
+            punched.retain(|addr| !item.swarm.manager.lock().unwrap().is_connected(addr));
+            dials.extend(punched.into_iter().map(|addr| Dial {
+                id: *id,
+                info_hash,
+                addr,
+                holepunched: true,
+            }));
+            if item.swarm.announce_due(now) {
+                announces.push(Announce {
+                    info_hash,
+                    private: item.object.info.private,
+                    trackers: item.trackers.iter().flatten().cloned().collect(),
+                    manager: item.swarm.manager.clone(),
+                });
+            }
+        }
+        (dials, announces)
+    }
+
+    async fn listen(self) {
+        let listener = match TcpListener::bind((Ipv4Addr::UNSPECIFIED, self.listen_port)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("Failed to listen on port {}: {}", self.listen_port, e);
+                return;
+            }
+        };
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(self.clone().accept(PeerTransport::Tcp(stream)));
+                }
+                Err(e) => println!("Accept failed: {}", e),
+            }
+        }
+    }
+
+    async fn accept(self, transport: PeerTransport) {
+        let serving = self.torrents.lock().unwrap().info_hashes();
+        let conn = match PeerConnection::accept(transport, self.peer_id, self.encryption, &serving)
+            .await
+        {
+            Ok(conn) => conn,
+            Err(_) => return,
+        };
+        let id = self.torrents.lock().unwrap().find(&conn.remote.info_hash);
+        if let Some(id) = id {
+            peer::run(self, id, conn).await;
+        }
+    }
+
+    async fn connect(self, dial: Dial) {
+        let Dial {
+            id,
+            info_hash,
+            addr,
+            holepunched,
+        } = dial;
+        let utp = self.utp.lock().unwrap().clone();
+        let connected = match (holepunched, &utp) {
+            (false, _) => {
+                PeerConnection::connect(
+                    addr,
+                    utp.as_ref(),
+                    info_hash,
+                    self.peer_id,
+                    None,
+                    self.encryption,
+                )
+                .await
+            }
+            (true, Some(utp)) => {
+                PeerConnection::connect_holepunched(
+                    addr,
+                    utp,
+                    info_hash,
+                    self.peer_id,
+                    self.encryption,
+                )
+                .await
+            }
+            // Holepunching only works over uTP
+            (true, None) => return,
+        };
+        match connected {
+            Ok(conn) => peer::run(self, id, conn).await,
+            Err(_) if holepunched => {}
+            Err(_) => {
+                self.with_torrent(id, |item| item.swarm.on_failed(addr));
+            }
+        }
+    }
+
+    // Private torrents only get peers from their trackers
+    async fn announce(self, announce: Announce) {
+        for url in &announce.trackers {
+            let result =
+                tracker::request(url, &announce.info_hash, &self.peer_id, self.listen_port).await;
+            match result {
+                Ok(peers) => announce
+                    .manager
+                    .lock()
+                    .unwrap()
+                    .add_peers(&peers, PeerSource::Tracker),
+                Err(e) => println!("Announce to {} failed: {}", url, e),
+            }
+        }
+        let dht = self.dht.lock().unwrap().clone();
+        if let (false, Some(dht)) = (announce.private, dht) {
+            let peers = dht
+                .announce(announce.info_hash, Some(self.listen_port))
+                .await;
+            announce
+                .manager
+                .lock()
+                .unwrap()
+                .add_peers(&peers, PeerSource::Dht);
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::io;
+use std::net::SocketAddr;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::{timeout, Duration};
+
+use super::swarm::Action;
+use super::Session;
+use crate::backend::picker::Block;
+use crate::backend::storage::Storage;
+use crate::requests::peer::{PeerConnection, PeerReader};
+
+// Peers send a keep-alive at least every two minutes
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+
+// Runs one connection until either side closes it. Reading, writing and
+// serving requests from disk each get a task, so a slow disk or a slow peer
+// never holds up the other direction.
+pub(super) async fn run(session: Session, id: usize, conn: PeerConnection) {
+    let addr = conn.addr;
+    let (sender, mut outgoing) = mpsc::unbounded_channel();
+    let registered = session.with_torrent(id, |item| {
+        let (info_hash, private) = (item.object.info_hash, item.object.info.private);
+        item.swarm
+            .on_connected(
+                &conn,
+                sender,
+                &item.picker,
+                &info_hash,
+                private,
+                session.listen_port,
+            )
+            .map(|()| item.storage.clone())
+    });
+    let storage = match registered {
+        Some(Ok(storage)) => storage,
+        Some(Err(e)) => {
+            println!("Dropping peer {}: {}", addr, e);
+            return;
+        }
+        None => return,
+    };
+
+    let (mut reader, mut writer) = conn.into_split();
+    let mut writing = tokio::spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            if writer.send(&message).await.is_err() {
+                break;
+            }
+        }
+    });
+    let (uploads, requested) = mpsc::unbounded_channel();
+    let uploading = tokio::spawn(upload(
+        session.clone(),
+        id,
+        addr,
+        storage.clone(),
+        requested,
+    ));
+
+    // The writer stops when sending fails or the swarm dropped the peer
+    let result = tokio::select! {
+        result = receive(&session, id, addr, &storage, &mut reader, uploads) => result,
+        _ = &mut writing => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Connection closed")),
+    };
+    if let Err(e) = result {
+        println!("Disconnected from {}: {}", addr, e);
+    }
+    uploading.abort();
+    writing.abort();
+    session.with_torrent(id, |item| {
+        item.swarm.on_disconnected(addr, &mut item.picker)
+    });
+}
+
+async fn receive(
+    session: &Session,
+    id: usize,
+    addr: SocketAddr,
+    storage: &Storage,
+    reader: &mut PeerReader,
+    uploads: UnboundedSender<Block>,
+) -> io::Result<()> {
+    loop {
+        let message = timeout(IDLE_TIMEOUT, reader.recv())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Peer went silent"))??;
+        let action = session
+            .with_torrent(id, |item| {
+                let action = item.swarm.on_message(&mut item.picker, addr, message)?;
+                if let Some(Action::Write(block, _)) = &action {
+                    item.verifier.block_from(block.piece, addr.ip());
+                    item.downloaded += block.length as u64;
+                }
+                Ok::<_, io::Error>(action)
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Torrent was removed"))??;
+        match action {
+            Some(Action::Upload(block)) => {
+                uploads.send(block).ok();
+            }
+            // Stored before reading on, slow disks slow the peer down
+            Some(Action::Write(block, data)) => write(session, id, storage, block, data).await,
+            None => {}
+        }
+    }
+}
+
+// The cache hands back the piece with its last block, its hash check runs
+// on a task of its own so receiving goes on meanwhile
+async fn write(session: &Session, id: usize, storage: &Storage, block: Block, data: Vec<u8>) {
+    match session
+        .cache
+        .write_block(storage, block.piece, block.begin, data)
+        .await
+    {
+        Ok(Some(piece)) => {
+            tokio::spawn(verify(
+                session.clone(),
+                id,
+                storage.clone(),
+                block.piece,
+                piece,
+            ));
+        }
+        Ok(None) => {}
+        Err(e) => {
+            println!("Failed to write piece {}: {}", block.piece, e);
+            lose(session, id, storage, block.piece);
+        }
+    }
+}
+
+// Passed pieces are written before anyone is told we have them
+async fn verify(session: Session, id: usize, storage: Storage, index: u32, data: Vec<u8>) {
+    // The check doesn't hold the list locked while hashing
+    let Some(check) = session.with_torrent(id, |item| item.verifier.check(index, data.clone()))
+    else {
+        return;
+    };
+    let passed = match check.await {
+        Ok(passed) => passed,
+        Err(e) => {
+            println!("Failed to check piece {}: {}", index, e);
+            return lose(&session, id, &storage, index);
+        }
+    };
+    if !passed {
+        session.cache.discard(&storage, index);
+    } else if let Err(e) = session.cache.piece_passed(&storage, index, data).await {
+        println!("Failed to write piece {}: {}", index, e);
+        return lose(&session, id, &storage, index);
+    }
+    session.with_torrent(id, |item| item.piece_checked(index, passed));
+}
+
+// The piece is downloaded again, without holding it against the peers that
+// sent it
+fn lose(session: &Session, id: usize, storage: &Storage, index: u32) {
+    session.cache.discard(storage, index);
+    session.with_torrent(id, |item| item.picker.piece_failed(index));
+}
+
+// Serves the peer's requests in order, reading through the cache without
+// the list locked. Requests cancelled or choked away meanwhile are skipped.
+async fn upload(
+    session: Session,
+    id: usize,
+    addr: SocketAddr,
+    storage: Storage,
+    mut requested: UnboundedReceiver<Block>,
+) {
+    while let Some(block) = requested.recv().await {
+        let have = session.with_torrent(id, |item| {
+            item.swarm
+                .is_requested(addr, block)
+                .then(|| item.picker.have().clone())
+        });
+        let have = match have {
+            Some(Some(have)) => have,
+            Some(None) => continue,
+            None => return,
+        };
+        let data = match session
+            .cache
+            .read(&storage, &have, block.piece, block.begin, block.length)
+            .await
+        {
+            Ok(data) => data,
+            Err(e) => {
+                println!("Failed to read piece {} for {}: {}", block.piece, addr, e);
+                session.with_torrent(id, |item| item.swarm.reject(addr, block));
+                continue;
+            }
+        };
+        session.with_torrent(id, |item| {
+            if item.swarm.upload(addr, block, data) {
+                item.uploaded += block.length as u64;
+            }
+        });
+    }
+}
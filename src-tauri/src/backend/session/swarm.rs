@@ -0,0 +1,711 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::backend::bitfield::Bitfield;
+use crate::backend::choker::{ChokePeer, Choker};
+use crate::backend::picker::{Block, PiecePicker};
+use crate::backend::pipeline::{self, RequestQueue};
+use crate::backend::rate::RateMeter;
+use crate::backend::superseed::SuperSeeder;
+use crate::requests::peer::extension::{ExtensionRegistry, DEFAULT_REQQ};
+use crate::requests::peer::fast::{self, FastState};
+use crate::requests::peer::holepunch::{self, HolepunchHub};
+use crate::requests::peer::manager::{ConnectionManager, FLAG_ENCRYPTION, FLAG_UTP};
+use crate::requests::peer::message::{Message, MAX_REQUEST_LEN};
+use crate::requests::peer::{pex, PeerConnection};
+
+// Connections per torrent, incoming ones included
+pub const MAX_CONNECTIONS: usize = 50;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+// Peers drop connections that stay silent for two minutes
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+// What the connection has to do about a message, outside the list lock
+#[derive(Debug)]
+pub enum Action {
+    // A request we accepted, to read from disk and hand to `upload`
+    Upload(Block),
+    // A block we asked for, to hand to the disk cache
+    Write(Block, Vec<u8>),
+}
+
+struct Peer {
+    sender: UnboundedSender<Message>,
+    choke: ChokePeer,
+    // The peer is choking us
+    choked_by: bool,
+    has: Bitfield,
+    fast: FastState,
+    extensions: ExtensionRegistry,
+    // Their requests we accepted and haven't served yet
+    requests: Vec<Block>,
+    // Our requests to them
+    queue: RequestQueue,
+    upload: RateMeter,
+    last_sent: Instant,
+    // Connected while super-seeding, it only knows the pieces we revealed
+    super_seeded: bool,
+}
+
+impl Peer {
+    fn new(
+        sender: UnboundedSender<Message>,
+        addr: SocketAddr,
+        num_pieces: usize,
+        fast: bool,
+        super_seeded: bool,
+        now: Instant,
+    ) -> Self {
+        Peer {
+            sender,
+            choke: ChokePeer::new(addr, now),
+            choked_by: true,
+            has: Bitfield::new(num_pieces),
+            fast: FastState::new(fast),
+            extensions: ExtensionRegistry::new(),
+            requests: Vec::new(),
+            queue: RequestQueue::new(),
+            upload: RateMeter::new(),
+            last_sent: now,
+            super_seeded,
+        }
+    }
+
+    fn send(&mut self, message: Message) {
+        // Fails once the connection is closing, its task cleans up
+        self.sender.send(message).ok();
+        self.last_sent = Instant::now();
+    }
+
+    // Tells the peer whenever it starts or stops having pieces we want
+    fn update_interest(&mut self, picker: &PiecePicker) {
+        let interested = picker.is_interesting(&self.has);
+        if interested != self.choke.am_interested {
+            self.choke.am_interested = interested;
+            self.send(if interested {
+                Message::Interested
+            } else {
+                Message::NotInterested
+            });
+        }
+    }
+
+    // Tops up our requests. While the peer chokes us only its allowed-fast
+    // pieces may be asked for, and the pieces it suggested go first.
+    fn request(&mut self, picker: &mut PiecePicker, now: Instant) {
+        if !self.choke.am_interested {
+            return;
+        }
+        let allowed;
+        let pickable = if self.choked_by {
+            allowed = self.fast.choked_pickable(&self.has);
+            &allowed
+        } else {
+            &self.has
+        };
+        if pickable.count() == 0 {
+            return;
+        }
+        let addr = self.choke.addr;
+        let suggested = self.fast.suggested_pickable(pickable);
+        let mut blocks = Vec::new();
+        if suggested.count() > 0 {
+            blocks = pipeline::fill(&mut self.queue, picker, addr, &suggested, now);
+        }
+        blocks.extend(pipeline::fill(&mut self.queue, picker, addr, pickable, now));
+        for block in blocks {
+            self.send(Message::Request {
+                index: block.piece,
+                begin: block.begin,
+                length: block.length,
+            });
+        }
+    }
+
+    fn cancel(&mut self, block: Block) {
+        self.send(Message::Cancel {
+            index: block.piece,
+            begin: block.begin,
+            length: block.length,
+        });
+    }
+
+    // With the fast extension every request we won't serve gets a reject
+    fn drop_requests(&mut self) {
+        for block in std::mem::take(&mut self.requests) {
+            if let Some(reject) = fast::reject(&self.fast, block.piece, block.begin, block.length) {
+                self.send(reject);
+            }
+        }
+    }
+}
+
+// The connections of one torrent, what each peer has and which of them we
+// upload to. Messages for a peer go out through the sender its connection
+// task registered.
+pub struct Swarm {
+    pub manager: Arc<Mutex<ConnectionManager>>,
+    // Relays ut_holepunch messages between our peers and collects the ones
+    // we were told to dial
+    pub holepunch: Arc<Mutex<HolepunchHub>>,
+    peers: HashMap<SocketAddr, Peer>,
+    choker: Choker,
+    // Overrides the upload slots per torrent from the settings
+    upload_slots: Option<usize>,
+    next_announce: Option<Instant>,
+    // Set while we super-seed, peers connecting meanwhile go through it
+    superseed: Option<SuperSeeder>,
+    // Super-seeding happens once, after every piece is out we seed normally
+    distributed: bool,
+}
+
+impl Swarm {
+    pub fn new() -> Self {
+        Swarm {
+            manager: Arc::new(Mutex::new(ConnectionManager::new(MAX_CONNECTIONS))),
+            holepunch: Arc::new(Mutex::new(HolepunchHub::new())),
+            peers: HashMap::new(),
+            choker: Choker::new(0),
+            upload_slots: None,
+            next_announce: None,
+            superseed: None,
+            distributed: false,
+        }
+    }
+
+    pub fn upload_slots(&self) -> Option<usize> {
+        self.upload_slots
+    }
+
+    pub fn set_upload_slots(&mut self, slots: Option<usize>) {
+        self.upload_slots = slots;
+    }
+
+    // Slots this torrent could use right now, `distribute_slots` hands the
+    // rest to others. The choker keeps one back for the optimistic unchoke,
+    // so that is one more than it has interested peers.
+    pub fn upload_limit(&self, default: usize) -> usize {
+        let interested = self.peers.values().filter(|p| p.choke.interested).count();
+        if interested == 0 {
+            return 0;
+        }
+        self.upload_slots.unwrap_or(default).min(interested + 1)
+    }
+
+    // Trackers and the DHT are asked for peers right away, then every half
+    // hour
+    pub fn announce_due(&mut self, now: Instant) -> bool {
+        if self.next_announce.is_some_and(|next| now < next) {
+            return false;
+        }
+        self.next_announce = Some(now + ANNOUNCE_INTERVAL);
+        true
+    }
+
+    // Takes a connection that finished its handshake and queues what we send
+    // first: our pieces, the allowed-fast set and the extended handshake
+    pub fn on_connected(
+        &mut self,
+        conn: &PeerConnection,
+        sender: UnboundedSender<Message>,
+        picker: &PiecePicker,
+        info_hash: &[u8; 20],
+        private: bool,
+        listen_port: u16,
+    ) -> io::Result<()> {
+        let addr = conn.addr;
+        {
+            let mut manager = self.manager.lock().unwrap();
+            if manager.is_banned(&addr.ip()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "Peer is banned",
+                ));
+            }
+            if self.peers.contains_key(&addr) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "Already connected to this peer",
+                ));
+            }
+            if !manager.can_accept() {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    "Too many connections",
+                ));
+            }
+            let mut flags = 0;
+            if conn.is_encrypted() {
+                flags |= FLAG_ENCRYPTION;
+            }
+            if conn.is_utp() {
+                flags |= FLAG_UTP;
+            }
+            manager.on_connected(addr, flags, conn.is_incoming());
+        }
+
+        let now = Instant::now();
+        let mut peer = Peer::new(
+            sender,
+            addr,
+            picker.num_pieces(),
+            conn.supports_fast(),
+            self.superseed.is_some(),
+            now,
+        );
+        // Super-seeding peers get no allowed-fast set, it would let them
+        // fetch pieces we haven't revealed
+        let initial = match &mut self.superseed {
+            Some(seeder) => seeder.on_connected(addr, peer.fast.enabled),
+            None => peer.fast.initial_messages(picker.have(), addr, info_hash),
+        };
+        for message in initial {
+            peer.send(message);
+        }
+        if conn.supports_extensions() {
+            pex::register(&mut peer.extensions, self.manager.clone(), addr, private);
+            holepunch::register(&mut peer.extensions, self.holepunch.clone(), addr);
+            let handshake = peer
+                .extensions
+                .handshake(Some(listen_port), Some(addr.ip()));
+            peer.send(handshake);
+        }
+        self.peers.insert(addr, peer);
+        Ok(())
+    }
+
+    // A peer we couldn't reach may be behind a NAT. If we heard of it from
+    // a peer connected to both of us, that one is asked to introduce us.
+    pub fn on_failed(&self, addr: SocketAddr) {
+        let relay = {
+            let mut manager = self.manager.lock().unwrap();
+            manager.on_failed(addr);
+            manager
+                .candidate(&addr)
+                .and_then(|candidate| candidate.relay)
+        };
+        let mut hub = self.holepunch.lock().unwrap();
+        if let Some(relay) = relay.filter(|_| !hub.is_pending(&addr)) {
+            hub.rendezvous(relay, addr);
+        }
+    }
+
+    // Also drops peers we want gone, closing their sender ends the
+    // connection
+    pub fn on_disconnected(&mut self, addr: SocketAddr, picker: &mut PiecePicker) {
+        if let Some(peer) = self.peers.remove(&addr) {
+            picker.peer_disconnected(addr, &peer.has);
+        }
+        if let Some(seeder) = &mut self.superseed {
+            seeder.on_disconnected(addr);
+        }
+        self.manager.lock().unwrap().on_disconnected(addr);
+    }
+
+    // Handles one message from the peer, returning what is left for the
+    // connection to do. An error means the peer broke the protocol and is
+    // dropped.
+    pub fn on_message(
+        &mut self,
+        picker: &mut PiecePicker,
+        addr: SocketAddr,
+        message: Message,
+    ) -> io::Result<Option<Action>> {
+        let num_pieces = picker.num_pieces();
+        let now = Instant::now();
+        let peer = self
+            .peers
+            .get_mut(&addr)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Peer is not connected"))?;
+        match message {
+            Message::Choke => {
+                peer.choked_by = true;
+                fast::on_choked(&peer.fast, &mut peer.queue, picker, addr);
+                peer.request(picker, now);
+            }
+            Message::Unchoke => {
+                peer.choked_by = false;
+                peer.request(picker, now);
+            }
+            Message::Interested => peer.choke.interested = true,
+            Message::NotInterested => peer.choke.interested = false,
+            Message::Have(index) => {
+                picker.peer_have(&mut peer.has, index);
+                peer.update_interest(picker);
+                peer.request(picker, now);
+                // The peer passing a piece on frees whoever we revealed it to
+                if let Some(seeder) = &mut self.superseed {
+                    for (other, message) in seeder.on_have(addr, index) {
+                        if let Some(other) = self.peers.get_mut(&other) {
+                            other.send(message);
+                        }
+                    }
+                }
+            }
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
+                if peer.has.count() > 0 {
+                    return Err(invalid("Bitfield after the peer announced pieces"));
+                }
+                if let Some(has) = fast::peer_pieces(&peer.fast, &message, num_pieces) {
+                    let has = has.map_err(invalid)?;
+                    picker.peer_bitfield(&has);
+                    if let Some(offer) = self
+                        .superseed
+                        .as_mut()
+                        .and_then(|seeder| seeder.on_bitfield(addr, &has))
+                    {
+                        peer.send(offer);
+                    }
+                    peer.has = has;
+                    peer.update_interest(picker);
+                    peer.request(picker, now);
+                }
+            }
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => {
+                let block = Block {
+                    piece: index,
+                    begin,
+                    length,
+                };
+                let valid = length > 0
+                    && length <= MAX_REQUEST_LEN
+                    && picker.have().has(index as usize)
+                    && begin as u64 + length as u64 <= picker.piece_size(index) as u64;
+                let revealed = !peer.super_seeded
+                    || self
+                        .superseed
+                        .as_ref()
+                        .is_none_or(|seeder| seeder.may_upload(&addr, index));
+                let allowed =
+                    (!peer.choke.choked || peer.fast.is_allowed_for_them(index)) && revealed;
+                if valid
+                    && allowed
+                    && peer.requests.len() < DEFAULT_REQQ as usize
+                    && !peer.requests.contains(&block)
+                {
+                    peer.requests.push(block);
+                    return Ok(Some(Action::Upload(block)));
+                }
+                if let Some(reject) = fast::reject(&peer.fast, index, begin, length) {
+                    peer.send(reject);
+                }
+            }
+            // With the fast extension a cancelled request still gets an answer
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let block = Block {
+                    piece: index,
+                    begin,
+                    length,
+                };
+                if let Some(pos) = peer.requests.iter().position(|b| *b == block) {
+                    peer.requests.remove(pos);
+                    if let Some(reject) = fast::reject(&peer.fast, index, begin, length) {
+                        peer.send(reject);
+                    }
+                }
+            }
+            Message::SuggestPiece(_) | Message::AllowedFast(_) => {
+                peer.fast
+                    .on_message(&message, num_pieces as u32)
+                    .map_err(invalid)?;
+                peer.request(picker, now);
+            }
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                let block = Block {
+                    piece: index,
+                    begin,
+                    length,
+                };
+                fast::on_rejected(&peer.fast, &mut peer.queue, picker, addr, block)
+                    .map_err(invalid)?;
+            }
+            Message::Piece {
+                index,
+                begin,
+                block: data,
+            } => {
+                let block = Block {
+                    piece: index,
+                    begin,
+                    length: data.len() as u32,
+                };
+                // Late answers to requests we cancelled or gave up on
+                if !peer.queue.received(block, now) {
+                    return Ok(None);
+                }
+                peer.choke.last_piece = Some(now);
+                let received = picker.block_received(addr, block);
+                peer.request(picker, now);
+                // In endgame the same block was asked of others too
+                for other in received.cancel {
+                    if let Some(other) = self.peers.get_mut(&other) {
+                        if other.queue.remove(block) {
+                            other.cancel(block);
+                        }
+                    }
+                }
+                return Ok(Some(Action::Write(block, data)));
+            }
+            Message::Extended { id, payload } => {
+                for reply in peer.extensions.on_extended(id, &payload)? {
+                    peer.send(reply);
+                }
+                if let Some(reqq) = peer.extensions.remote_handshake().and_then(|h| h.reqq) {
+                    peer.queue.set_max_depth(reqq as usize);
+                }
+            }
+            // The rest needs no answer
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    // After a downloaded piece was hash checked. A passed piece is announced
+    // to every peer, the peers banned over a failed one are dropped.
+    pub fn on_checked(
+        &mut self,
+        index: u32,
+        passed: bool,
+        banned: &[IpAddr],
+        picker: &mut PiecePicker,
+    ) {
+        if passed {
+            for peer in self.peers.values_mut() {
+                peer.fast.remove_suggestion(index);
+                peer.send(Message::Have(index));
+                peer.update_interest(picker);
+            }
+        }
+        let dropped: Vec<SocketAddr> = self
+            .peers
+            .keys()
+            .filter(|addr| banned.contains(&addr.ip()))
+            .copied()
+            .collect();
+        for addr in dropped {
+            self.on_disconnected(addr, picker);
+        }
+    }
+
+    // Whether the peer still wants a block, it may have cancelled it or been
+    // choked since asking
+    pub fn is_requested(&self, addr: SocketAddr, block: Block) -> bool {
+        self.peers
+            .get(&addr)
+            .is_some_and(|peer| peer.requests.contains(&block))
+    }
+
+    // Sends a block read for the peer. False when it no longer wants it.
+    pub fn upload(&mut self, addr: SocketAddr, block: Block, data: Vec<u8>) -> bool {
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return false;
+        };
+        let Some(pos) = peer.requests.iter().position(|b| *b == block) else {
+            return false;
+        };
+        peer.requests.remove(pos);
+        peer.upload.add(data.len() as u64);
+        peer.send(Message::Piece {
+            index: block.piece,
+            begin: block.begin,
+            block: data,
+        });
+        true
+    }
+
+    // A request we can't serve after all, like when reading it failed
+    pub fn reject(&mut self, addr: SocketAddr, block: Block) {
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return;
+        };
+        if let Some(pos) = peer.requests.iter().position(|b| *b == block) {
+            peer.requests.remove(pos);
+            if let Some(reject) = fast::reject(&peer.fast, block.piece, block.begin, block.length) {
+                peer.send(reject);
+            }
+        }
+    }
+
+    // Runs about once a second. `slots` is this torrent's share of the
+    // global upload slots.
+    pub fn tick(
+        &mut self,
+        now: Instant,
+        slots: usize,
+        seeding: bool,
+        super_seeding: bool,
+        picker: &mut PiecePicker,
+    ) {
+        self.update_superseed(super_seeding && seeding, picker);
+        if self.choker.slots() != slots {
+            self.choker.set_slots(slots);
+        }
+        let mut states: Vec<ChokePeer> = self
+            .peers
+            .values_mut()
+            .map(|peer| {
+                peer.upload.update(now);
+                peer.queue.update(now);
+                peer.choke.upload_rate = peer.upload.rate();
+                peer.choke.download_rate = peer.queue.download_rate();
+                peer.choke.clone()
+            })
+            .collect();
+        let changes = self.choker.tick(now, &mut states, seeding);
+        for state in states {
+            if let Some(peer) = self.peers.get_mut(&state.addr) {
+                peer.choke = state;
+            }
+        }
+        for (addr, choke) in changes {
+            let Some(peer) = self.peers.get_mut(&addr) else {
+                continue;
+            };
+            if choke {
+                peer.send(Message::Choke);
+                peer.drop_requests();
+            } else {
+                peer.send(Message::Unchoke);
+            }
+        }
+
+        for peer in self.peers.values_mut() {
+            // Stalled requests go back to the picker for other peers
+            for block in pipeline::expire(&mut peer.queue, picker, peer.choke.addr, now) {
+                peer.cancel(block);
+            }
+            peer.request(picker, now);
+            for message in peer.extensions.tick(now) {
+                peer.send(message);
+            }
+            if now.saturating_duration_since(peer.last_sent) >= KEEPALIVE_INTERVAL {
+                peer.send(Message::KeepAlive);
+            }
+        }
+    }
+}
+
+impl Swarm {
+    // Super-seeding starts once we are seeding with it turned on. Peers
+    // already connected saw all our pieces and are left as they are. Once
+    // every piece is out, or it is turned off, the peers that were shown
+    // only a few pieces learn about the rest.
+    fn update_superseed(&mut self, enabled: bool, picker: &PiecePicker) {
+        match &self.superseed {
+            None if enabled && !self.distributed => {
+                self.superseed = Some(SuperSeeder::new(picker.num_pieces()));
+            }
+            Some(seeder) if !enabled || seeder.is_distributed() => {
+                self.distributed = seeder.is_distributed();
+                self.superseed = None;
+                for peer in self.peers.values_mut().filter(|p| p.super_seeded) {
+                    peer.super_seeded = false;
+                    for index in picker.have().ones() {
+                        if !peer.has.has(index) {
+                            peer.send(Message::Have(index as u32));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Default for Swarm {
+    fn default() -> Self {
+        Swarm::new()
+    }
+}
+
+// Connections hold channels and extension objects, only the addresses are
+// worth printing
+impl fmt::Debug for Swarm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Swarm")
+            .field("peers", &self.peers.keys().collect::<Vec<_>>())
+            .field("upload_slots", &self.upload_slots)
+            .finish_non_exhaustive()
+    }
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requests::peer::message::BLOCK_LEN;
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    // A peer with all of a torrent of 8 one-block pieces that we want
+    fn seeder(picker: &mut PiecePicker) -> (Peer, UnboundedReceiver<Message>) {
+        let (sender, outgoing) = mpsc::unbounded_channel();
+        let addr = SocketAddr::from(([10, 0, 0, 1], 6881));
+        let mut peer = Peer::new(sender, addr, 8, true, false, Instant::now());
+        peer.has = Bitfield::full(8);
+        picker.peer_bitfield(&peer.has);
+        peer.update_interest(picker);
+        (peer, outgoing)
+    }
+
+    // Pieces of the requests sent so far, in order
+    fn requested(outgoing: &mut UnboundedReceiver<Message>) -> Vec<u32> {
+        let mut pieces = Vec::new();
+        while let Ok(message) = outgoing.try_recv() {
+            if let Message::Request { index, .. } = message {
+                pieces.push(index);
+            }
+        }
+        pieces
+    }
+
+    #[test]
+    fn choked_peer_asked_for_allowed_fast_pieces() {
+        let mut picker = PiecePicker::new(8, BLOCK_LEN, 8 * BLOCK_LEN as u64);
+        let (mut peer, mut outgoing) = seeder(&mut picker);
+        let now = Instant::now();
+        peer.request(&mut picker, now);
+        assert!(requested(&mut outgoing).is_empty());
+
+        for index in [5, 2] {
+            peer.fast
+                .on_message(&Message::AllowedFast(index), 8)
+                .unwrap();
+        }
+        peer.request(&mut picker, now);
+        let mut pieces = requested(&mut outgoing);
+        pieces.sort();
+        assert_eq!(pieces, vec![2, 5]);
+    }
+
+    #[test]
+    fn suggested_pieces_go_first() {
+        let mut picker = PiecePicker::new(8, BLOCK_LEN, 8 * BLOCK_LEN as u64);
+        let (mut peer, mut outgoing) = seeder(&mut picker);
+        peer.choked_by = false;
+        peer.fast.on_message(&Message::SuggestPiece(6), 8).unwrap();
+        peer.request(&mut picker, Instant::now());
+        let pieces = requested(&mut outgoing);
+        assert_eq!(pieces[0], 6);
+        assert_eq!(pieces.iter().filter(|&&index| index == 6).count(), 1);
+    }
+}
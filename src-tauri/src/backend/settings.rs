@@ -0,0 +1,126 @@
+use dirs::{config_dir, download_dir, home_dir};
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+
+// Message Stream Encryption for peer connections. Prefer tries encrypted
+// first and falls back to plaintext, require refuses plaintext peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionPolicy {
+    Disabled,
+    #[default]
+    Prefer,
+    Require,
+}
+
+// How files are created. Sparse only sets the length and lets the disk fill
+// up as pieces arrive, full writes the whole file out up front.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preallocation {
+    #[default]
+    Sparse,
+    Full,
+}
+
+// How torrent data is read and written. Memory-mapped files and io_uring can
+// be faster on servers, pread works everywhere. io_uring only exists on
+// Linux and mmap needs full preallocation, otherwise they fall back to pread.
+// io_uring submits each read or write on its own, with no batching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackendKind {
+    #[default]
+    Pread,
+    Mmap,
+    IoUring,
+}
+
+// Backend view of settings.dft. The frontend owns the file and writes it as
+// JSON, so every field has a default and unknown keys are ignored.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Settings {
+    // Upload slots shared by all torrents
+    pub upload_slots: usize,
+    // Upload slots a single torrent may use unless overridden
+    pub upload_slots_per_torrent: usize,
+    pub dht_enabled: bool,
+    // host:port entries, the well-known routers are used when empty
+    pub dht_bootstrap_nodes: Vec<String>,
+    // Local Service Discovery, never used for private torrents
+    pub lsd_enabled: bool,
+    pub encryption: EncryptionPolicy,
+    // uTP peer connections, on the same UDP port as the DHT
+    pub utp_enabled: bool,
+    // Peers connect to us here over TCP, and over UDP for the DHT and uTP
+    pub listen_port: u16,
+    // Forward the listen port on the router with PCP, NAT-PMP or UPnP
+    pub port_mapping: bool,
+    // BEP 16, for when we are the first and only seed of a torrent
+    pub super_seeding: bool,
+    // Where torrents are saved, the system download directory when unset
+    pub download_dir: Option<String>,
+    pub preallocation: Preallocation,
+    // Threads doing blocking disk I/O
+    pub disk_threads: usize,
+    // Threads verifying piece hashes, 0 for one per CPU core
+    pub hash_threads: usize,
+    // MiB of downloaded blocks held until their piece is complete
+    pub write_cache_size: usize,
+    // MiB of pieces kept for seeding, 0 reads every block from disk
+    pub read_cache_size: usize,
+    // Pieces read ahead of the one a peer asks for
+    pub read_ahead: u32,
+    pub storage_backend: StorageBackendKind,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            upload_slots: 16,
+            upload_slots_per_torrent: 4,
+            dht_enabled: true,
+            dht_bootstrap_nodes: Vec::new(),
+            lsd_enabled: true,
+            encryption: EncryptionPolicy::default(),
+            utp_enabled: true,
+            listen_port: 6881,
+            port_mapping: true,
+            super_seeding: false,
+            download_dir: None,
+            preallocation: Preallocation::default(),
+            disk_threads: 4,
+            hash_threads: 0,
+            write_cache_size: 32,
+            read_cache_size: 64,
+            read_ahead: 1,
+            storage_backend: StorageBackendKind::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn load() -> Settings {
+        let Some(mut file_path) = config_dir() else {
+            return Settings::default();
+        };
+        file_path.push("defttorrent");
+        file_path.push("settings.dft");
+
+        fs::read_to_string(file_path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn download_path(&self) -> PathBuf {
+        match &self.download_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => download_dir()
+                .or_else(|| home_dir().map(|home| home.join("Downloads")))
+                .unwrap_or_else(|| PathBuf::from(".")),
+        }
+    }
+}
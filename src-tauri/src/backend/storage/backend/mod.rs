@@ -0,0 +1,197 @@
+use std::fmt::Debug;
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+
+mod mmap;
+#[cfg(target_os = "linux")]
+mod uring;
+
+use super::{read_at, write_at};
+use crate::backend::settings::{Preallocation, StorageBackendKind};
+pub use mmap::MmapBackend;
+#[cfg(target_os = "linux")]
+pub use uring::UringBackend;
+
+// How file data gets to and from the disk. Storage opens, sizes and caches
+// the files itself and hands each one to the backend, every read and write
+// after that goes through it.
+pub trait StorageBackend: Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Takes over a file opened for reading and writing, at least `length`
+    // bytes long
+    fn open(&self, file: File, length: u64) -> io::Result<Arc<dyn BackendFile>>;
+}
+
+// One open file. Called from any of the I/O pool's threads at once.
+pub trait BackendFile: Debug + Send + Sync {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    // Makes everything written so far durable
+    fn sync(&self) -> io::Result<()>;
+}
+
+// Plain positioned reads and writes, works everywhere
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PreadBackend;
+
+#[derive(Debug)]
+struct PreadFile(File);
+
+impl StorageBackend for PreadBackend {
+    fn name(&self) -> &'static str {
+        "pread"
+    }
+
+    fn open(&self, file: File, _length: u64) -> io::Result<Arc<dyn BackendFile>> {
+        Ok(Arc::new(PreadFile(file)))
+    }
+}
+
+impl BackendFile for PreadFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        read_at(&self.0, buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        write_at(&self.0, buf, offset)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.0.sync_data()
+    }
+}
+
+// The backend picked in the settings. One that can't be used here, like
+// io_uring outside Linux or on a kernel without it, falls back to pread. So
+// does mmap with sparse files: a write into a hole that finds the disk full
+// can't return an error and kills the process with SIGBUS instead.
+pub fn from_kind(
+    kind: StorageBackendKind,
+    preallocation: Preallocation,
+) -> Arc<dyn StorageBackend> {
+    match kind {
+        StorageBackendKind::Pread => Arc::new(PreadBackend),
+        StorageBackendKind::Mmap if preallocation == Preallocation::Sparse => {
+            println!("mmap needs full preallocation, using pread");
+            Arc::new(PreadBackend)
+        }
+        StorageBackendKind::Mmap => Arc::new(MmapBackend),
+        #[cfg(target_os = "linux")]
+        StorageBackendKind::IoUring => match UringBackend::new() {
+            Ok(backend) => Arc::new(backend),
+            Err(e) => {
+                println!("io_uring is not available, using pread: {}", e);
+                Arc::new(PreadBackend)
+            }
+        },
+        #[cfg(not(target_os = "linux"))]
+        StorageBackendKind::IoUring => {
+            println!("io_uring only exists on Linux, using pread");
+            Arc::new(PreadBackend)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{layout, TempDir};
+    use super::super::{IoPool, Storage};
+    use super::*;
+
+    // The backend of each kind itself, without the fallback from_kind does,
+    // or None when it can't be used here
+    fn backend(kind: StorageBackendKind) -> Option<Arc<dyn StorageBackend>> {
+        match kind {
+            StorageBackendKind::Pread => Some(Arc::new(PreadBackend)),
+            StorageBackendKind::Mmap => Some(Arc::new(MmapBackend)),
+            #[cfg(target_os = "linux")]
+            StorageBackendKind::IoUring => match UringBackend::new() {
+                Ok(backend) => Some(Arc::new(backend)),
+                Err(e) => {
+                    println!("skipping io_uring: {}", e);
+                    None
+                }
+            },
+            #[cfg(not(target_os = "linux"))]
+            StorageBackendKind::IoUring => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trip_across_files() {
+        let data: Vec<u8> = (0..100).map(|i| i as u8).collect();
+        for kind in [
+            StorageBackendKind::Pread,
+            StorageBackendKind::Mmap,
+            StorageBackendKind::IoUring,
+        ] {
+            let Some(backend) = backend(kind) else {
+                continue;
+            };
+            let dir = TempDir::new(&format!("backend-{}", backend.name()));
+            // Pieces of 32 over files of 10, 0, 25, 7 and 58 bytes, so blocks
+            // start, end and pass through the middle of files
+            let layout = layout(32, &[("a", 10), ("e", 0), ("b", 25), ("c", 7), ("d", 58)]);
+            let storage = Storage::new(
+                dir.0.clone(),
+                layout.clone(),
+                Preallocation::Full,
+                IoPool::new(2),
+            )
+            .with_backend(backend.clone());
+            storage.allocate().await.unwrap();
+
+            // Blocks of 8 covering every piece, the last one short
+            for index in 0..4u32 {
+                let start = index as usize * 32;
+                let end = (start + 32).min(data.len());
+                for begin in (0..end - start).step_by(8) {
+                    let block = &data[start + begin..(start + begin + 8).min(end)];
+                    storage
+                        .write(index, begin as u32, block.to_vec())
+                        .await
+                        .unwrap();
+                }
+            }
+            storage.flush().await.unwrap();
+
+            for index in 0..4u32 {
+                let start = index as usize * 32;
+                let end = (start + 32).min(data.len());
+                assert_eq!(
+                    storage.read_piece(index).await.unwrap(),
+                    &data[start..end],
+                    "{} piece {}",
+                    backend.name(),
+                    index
+                );
+            }
+            // From the end of a through b into c
+            assert_eq!(storage.read(0, 6, 26).await.unwrap(), &data[6..32]);
+            assert_eq!(storage.read(1, 0, 8).await.unwrap(), &data[32..40]);
+
+            // What went through the backend is on disk where plain reads
+            // find it. The empty file is never created.
+            let lengths = [10, 0, 25, 7, 58];
+            let mut offset = 0;
+            for (index, length) in lengths.into_iter().enumerate() {
+                let path = storage.file_path(index);
+                assert_eq!(
+                    std::fs::read(&path).unwrap_or_default(),
+                    &data[offset..offset + length],
+                    "{} {}",
+                    backend.name(),
+                    path.display()
+                );
+                offset += length;
+            }
+            let reopened = Storage::new(dir.0.clone(), layout, Preallocation::Full, IoPool::new(1))
+                .with_backend(backend.clone());
+            assert_eq!(reopened.read(2, 4, 28).await.unwrap(), &data[68..96]);
+        }
+    }
+}
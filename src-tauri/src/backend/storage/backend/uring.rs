@@ -0,0 +1,132 @@
+use io_uring::{opcode, squeue, types, IoUring};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::{BackendFile, StorageBackend};
+
+const RING_ENTRIES: u32 = 8;
+// Largest single read or write, the length field is 32 bits
+const MAX_OP: usize = 1 << 30;
+
+thread_local! {
+    // One ring per I/O thread, so threads never wait on each other's
+    // submissions. Created on first use.
+    static RING: RefCell<Option<IoUring>> = const { RefCell::new(None) };
+}
+
+// Reads and writes through io_uring on Linux. There is no batching: every
+// read or write of a file is its own submission, waited on before the next,
+// so a block spanning several files takes one round trip per file, as many
+// as pread would.
+#[derive(Debug, Clone, Copy)]
+pub struct UringBackend;
+
+#[derive(Debug)]
+struct UringFile(File);
+
+impl UringBackend {
+    // Fails on kernels without io_uring, or where it is blocked, like in
+    // many containers
+    pub fn new() -> io::Result<Self> {
+        IoUring::new(RING_ENTRIES)?;
+        Ok(UringBackend)
+    }
+}
+
+impl StorageBackend for UringBackend {
+    fn name(&self) -> &'static str {
+        "io_uring"
+    }
+
+    fn open(&self, file: File, _length: u64) -> io::Result<Arc<dyn BackendFile>> {
+        Ok(Arc::new(UringFile(file)))
+    }
+}
+
+// Submits one operation and waits for it. Safe to call with entries that
+// point into the caller's buffers: they stay borrowed until it returns, and
+// it only returns once the kernel is done with them.
+fn submit(entry: squeue::Entry) -> io::Result<usize> {
+    RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        if ring.is_none() {
+            *ring = Some(IoUring::new(RING_ENTRIES)?);
+        }
+        let uring = ring.as_mut().unwrap();
+        unsafe { uring.submission().push(&entry) }
+            .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+        // Only one operation is ever in the ring, so the first completion is
+        // ours. Once the kernel has taken the entry we can't return before
+        // it completes, whatever submit says, or it would write into a
+        // buffer that's gone.
+        let cqe = loop {
+            if let Some(cqe) = uring.completion().next() {
+                break cqe;
+            }
+            match uring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // Never taken, it would go out with the next submission long
+                // after its buffer is gone. The ring goes with it.
+                Err(e) if !uring.submission().is_empty() => {
+                    *ring = None;
+                    return Err(e);
+                }
+                // Taken, but waiting failed. Keep polling until it's done.
+                Err(_) => thread::sleep(Duration::from_millis(1)),
+            }
+        };
+        match cqe.result() {
+            result if result < 0 => Err(io::Error::from_raw_os_error(-result)),
+            result => Ok(result as usize),
+        }
+    })
+}
+
+impl BackendFile for UringFile {
+    fn read_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        let fd = types::Fd(self.0.as_raw_fd());
+        while !buf.is_empty() {
+            let length = buf.len().min(MAX_OP) as u32;
+            let entry = opcode::Read::new(fd, buf.as_mut_ptr(), length)
+                .offset(offset)
+                .build();
+            let n = submit(entry)?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            buf = &mut buf[n..];
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        let fd = types::Fd(self.0.as_raw_fd());
+        while !buf.is_empty() {
+            let length = buf.len().min(MAX_OP) as u32;
+            let entry = opcode::Write::new(fd, buf.as_ptr(), length)
+                .offset(offset)
+                .build();
+            let n = submit(entry)?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            buf = &buf[n..];
+            offset += n as u64;
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let entry = opcode::Fsync::new(types::Fd(self.0.as_raw_fd()))
+            .flags(types::FsyncFlags::DATASYNC)
+            .build();
+        submit(entry).map(|_| ())
+    }
+}
@@ -0,0 +1,508 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::Storage;
+use crate::backend::bitfield::Bitfield;
+
+// Storage id and piece index
+type Key = (u64, u32);
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    // Bytes of blocks waiting for the rest of their piece
+    pub write_bytes: usize,
+    pub write_limit: usize,
+    pub read_bytes: usize,
+    pub read_limit: usize,
+    pub read_hits: u64,
+    pub read_misses: u64,
+    // Blocks written out before their piece was complete, to stay under
+    // the write limit
+    pub blocks_evicted: u64,
+}
+
+// A piece still being downloaded
+#[derive(Debug)]
+struct PartialPiece {
+    storage: Storage,
+    // Blocks in memory by offset in the piece
+    blocks: BTreeMap<u32, Vec<u8>>,
+    // Offset and length of the blocks already written out
+    flushed: BTreeMap<u32, u32>,
+    // Its blocks are being written out, no one else picks it meanwhile
+    writing: bool,
+    used: u64,
+}
+
+impl PartialPiece {
+    // Bytes of the piece we have, in memory or on disk. Blocks a peer sent
+    // at odd offsets may overlap and are only counted once.
+    fn received(&self) -> u64 {
+        let in_memory = self
+            .blocks
+            .iter()
+            .map(|(begin, block)| (*begin, block.len() as u32));
+        let on_disk = self.flushed.iter().map(|(begin, length)| (*begin, *length));
+        covered(in_memory.chain(on_disk))
+    }
+
+    fn memory(&self) -> usize {
+        self.blocks.values().map(Vec::len).sum()
+    }
+
+    // The whole piece, with the blocks written out early read back
+    async fn assemble(self, index: u32, size: u64) -> io::Result<Vec<u8>> {
+        let mut data = vec![0u8; size as usize];
+        for (begin, length) in join_ranges(&self.flushed) {
+            let block = self.storage.read(index, begin, length).await?;
+            data[begin as usize..][..block.len()].copy_from_slice(&block);
+        }
+        for (begin, block) in self.blocks {
+            data[begin as usize..][..block.len()].copy_from_slice(&block);
+        }
+        Ok(data)
+    }
+}
+
+#[derive(Debug, Default)]
+struct WriteCache {
+    pieces: HashMap<Key, PartialPiece>,
+    bytes: usize,
+    clock: u64,
+    evicted: u64,
+}
+
+#[derive(Debug, Default)]
+struct ReadCache {
+    // Piece data and when it was last used
+    pieces: HashMap<Key, (Arc<Vec<u8>>, u64)>,
+    bytes: usize,
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+// Keeps disk access to whole pieces. Blocks are held in memory until their
+// piece is complete, so it is hashed without reading it back and written in
+// one go, and pieces read for seeding stay around for the requests for the
+// rest of them. Shared by every torrent, the limits are for all of them
+// together. No lock is held while waiting for the disk.
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    write: Arc<Mutex<WriteCache>>,
+    read: Arc<Mutex<ReadCache>>,
+    write_limit: usize,
+    read_limit: usize,
+    // Pieces read after the one a peer asked for
+    read_ahead: u32,
+}
+
+impl DiskCache {
+    // Limits in bytes. A write limit of zero sends every block straight to
+    // disk, a read limit of zero turns off the read cache.
+    pub fn new(write_limit: usize, read_limit: usize, read_ahead: u32) -> Self {
+        DiskCache {
+            write: Arc::new(Mutex::new(WriteCache::default())),
+            read: Arc::new(Mutex::new(ReadCache::default())),
+            write_limit,
+            read_limit,
+            read_ahead,
+        }
+    }
+
+    // Holds on to a block of a piece we don't have yet. Once every block of
+    // the piece is in it is handed back whole, for the caller to hash and
+    // pass to `piece_passed`.
+    pub async fn write_block(
+        &self,
+        storage: &Storage,
+        piece: u32,
+        begin: u32,
+        data: Vec<u8>,
+    ) -> io::Result<Option<Vec<u8>>> {
+        let size = storage.layout().piece_size(piece);
+        if data.is_empty() || begin as u64 + data.len() as u64 > size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Block is outside the piece",
+            ));
+        }
+        let key = (storage.id, piece);
+        let complete = {
+            let mut guard = self.write.lock().unwrap();
+            let cache = &mut *guard;
+            cache.clock += 1;
+            let entry = cache.pieces.entry(key).or_insert_with(|| PartialPiece {
+                storage: storage.clone(),
+                blocks: BTreeMap::new(),
+                flushed: BTreeMap::new(),
+                writing: false,
+                used: 0,
+            });
+            entry.used = cache.clock;
+            entry.flushed.remove(&begin);
+            cache.bytes += data.len();
+            if let Some(old) = entry.blocks.insert(begin, data) {
+                cache.bytes -= old.len();
+            }
+            if entry.received() >= size {
+                let entry = cache.pieces.remove(&key).unwrap();
+                cache.bytes -= entry.memory();
+                Some(entry)
+            } else {
+                None
+            }
+        };
+        match complete {
+            Some(entry) => entry.assemble(piece, size).await.map(Some),
+            None => {
+                self.evict().await?;
+                Ok(None)
+            }
+        }
+    }
+
+    // Blocks of an unfinished piece written out in an earlier session, by
+    // offset and length, so the piece completes once the rest comes in
+    pub fn restore(&self, storage: &Storage, piece: u32, blocks: &[(u32, u32)]) {
+        let mut cache = self.write.lock().unwrap();
+        let entry = cache
+            .pieces
+            .entry((storage.id, piece))
+            .or_insert_with(|| PartialPiece {
+                storage: storage.clone(),
+                blocks: BTreeMap::new(),
+                flushed: BTreeMap::new(),
+                writing: false,
+                used: 0,
+            });
+        entry.flushed.extend(blocks.iter().copied());
+    }
+
+    // Writes a piece that passed its hash check in a single write, and keeps
+    // it for the peers that will ask for it
+    pub async fn piece_passed(
+        &self,
+        storage: &Storage,
+        piece: u32,
+        data: Vec<u8>,
+    ) -> io::Result<()> {
+        self.discard(storage, piece);
+        if self.read_limit > 0 {
+            self.insert((storage.id, piece), Arc::new(data.clone()));
+        }
+        storage.write_piece(piece, data).await
+    }
+
+    // Drops what is held of a piece, like late blocks of a piece that is
+    // complete already
+    pub fn discard(&self, storage: &Storage, piece: u32) {
+        let mut cache = self.write.lock().unwrap();
+        if let Some(entry) = cache.pieces.remove(&(storage.id, piece)) {
+            cache.bytes -= entry.memory();
+        }
+    }
+
+    // A block for a peer. A miss reads the whole piece, and the next
+    // `read_ahead` pieces in `have` in the background.
+    pub async fn read(
+        &self,
+        storage: &Storage,
+        have: &Bitfield,
+        piece: u32,
+        begin: u32,
+        length: u32,
+    ) -> io::Result<Vec<u8>> {
+        if self.read_limit == 0 {
+            return storage.read(piece, begin, length).await;
+        }
+        let key = (storage.id, piece);
+        let data = match self.cached(key) {
+            Some(data) => data,
+            None => {
+                let data = Arc::new(storage.read_piece(piece).await?);
+                self.insert(key, data.clone());
+                self.read_ahead(storage, have, piece);
+                data
+            }
+        };
+        data.get(begin as usize..begin as usize + length as usize)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Read past the end of the piece",
+                )
+            })
+    }
+
+    // Writes every block only held in memory to disk, where the picker and
+    // resume data expect them. Called before resume data is saved and on
+    // shutdown.
+    pub async fn flush(&self) -> io::Result<()> {
+        loop {
+            let pending: Vec<(Key, bool)> = self
+                .write
+                .lock()
+                .unwrap()
+                .pieces
+                .iter()
+                .filter(|(_, entry)| !entry.blocks.is_empty())
+                .map(|(key, entry)| (*key, entry.writing))
+                .collect();
+            if pending.is_empty() {
+                return Ok(());
+            }
+            for (key, _) in pending.iter().filter(|(_, writing)| !writing) {
+                self.write_out(*key).await?;
+            }
+            // Pieces an eviction is writing out right now, wait for it
+            if pending.iter().any(|(_, writing)| *writing) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    }
+
+    // Drops everything of a torrent that is going away
+    pub fn forget(&self, storage: &Storage) {
+        {
+            let mut guard = self.write.lock().unwrap();
+            let cache = &mut *guard;
+            cache.pieces.retain(|(id, _), entry| {
+                if *id == storage.id {
+                    cache.bytes -= entry.memory();
+                }
+                *id != storage.id
+            });
+        }
+        let mut guard = self.read.lock().unwrap();
+        let cache = &mut *guard;
+        cache.pieces.retain(|(id, _), (data, _)| {
+            if *id == storage.id {
+                cache.bytes -= data.len();
+            }
+            *id != storage.id
+        });
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let write = self.write.lock().unwrap();
+        let read = self.read.lock().unwrap();
+        CacheStats {
+            write_bytes: write.bytes,
+            write_limit: self.write_limit,
+            read_bytes: read.bytes,
+            read_limit: self.read_limit,
+            read_hits: read.hits,
+            read_misses: read.misses,
+            blocks_evicted: write.evicted,
+        }
+    }
+
+    // Writes out the least recently used partial pieces until the blocks
+    // in memory fit the limit again
+    async fn evict(&self) -> io::Result<()> {
+        loop {
+            let key = {
+                let cache = self.write.lock().unwrap();
+                if cache.bytes <= self.write_limit {
+                    return Ok(());
+                }
+                let oldest = cache
+                    .pieces
+                    .iter()
+                    .filter(|(_, entry)| !entry.writing && !entry.blocks.is_empty())
+                    .min_by_key(|(_, entry)| entry.used);
+                match oldest {
+                    Some((key, _)) => *key,
+                    None => return Ok(()),
+                }
+            };
+            self.write_out(key).await?;
+        }
+    }
+
+    // Writes the blocks of a partial piece held in memory, neighbours joined
+    // up into one write. They stay readable from memory until written.
+    async fn write_out(&self, key: Key) -> io::Result<()> {
+        let (storage, written, runs) = {
+            let mut cache = self.write.lock().unwrap();
+            let Some(entry) = cache.pieces.get_mut(&key) else {
+                return Ok(());
+            };
+            if entry.writing || entry.blocks.is_empty() {
+                return Ok(());
+            }
+            entry.writing = true;
+            let written: Vec<(u32, usize)> = entry
+                .blocks
+                .iter()
+                .map(|(begin, block)| (*begin, block.len()))
+                .collect();
+            (entry.storage.clone(), written, join_blocks(&entry.blocks))
+        };
+
+        let mut result = Ok(());
+        for (begin, run) in runs {
+            if let Err(e) = storage.write(key.1, begin, run).await {
+                result = Err(e);
+                break;
+            }
+        }
+
+        let mut guard = self.write.lock().unwrap();
+        let cache = &mut *guard;
+        // Gone when the piece completed meanwhile
+        let Some(entry) = cache.pieces.get_mut(&key) else {
+            return result;
+        };
+        entry.writing = false;
+        if result.is_ok() {
+            for (begin, length) in written {
+                // Not when a newer copy of the block came in meanwhile
+                if entry
+                    .blocks
+                    .get(&begin)
+                    .is_some_and(|block| block.len() == length)
+                {
+                    entry.blocks.remove(&begin);
+                    entry.flushed.insert(begin, length as u32);
+                    cache.bytes -= length;
+                    cache.evicted += 1;
+                }
+            }
+        }
+        result
+    }
+
+    fn cached(&self, key: Key) -> Option<Arc<Vec<u8>>> {
+        let mut guard = self.read.lock().unwrap();
+        let cache = &mut *guard;
+        cache.clock += 1;
+        match cache.pieces.get_mut(&key) {
+            Some((data, used)) => {
+                *used = cache.clock;
+                cache.hits += 1;
+                Some(data.clone())
+            }
+            None => {
+                cache.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&self, key: Key, data: Arc<Vec<u8>>) {
+        if data.len() > self.read_limit {
+            return;
+        }
+        let mut guard = self.read.lock().unwrap();
+        let cache = &mut *guard;
+        cache.clock += 1;
+        cache.bytes += data.len();
+        if let Some((old, _)) = cache.pieces.insert(key, (data, cache.clock)) {
+            cache.bytes -= old.len();
+        }
+        while cache.bytes > self.read_limit {
+            let oldest = cache
+                .pieces
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| *key);
+            let Some((data, _)) = oldest.and_then(|key| cache.pieces.remove(&key)) else {
+                break;
+            };
+            cache.bytes -= data.len();
+        }
+    }
+
+    // Pieces we have right after `piece` are read into the cache without
+    // anyone waiting for them
+    fn read_ahead(&self, storage: &Storage, have: &Bitfield, piece: u32) {
+        let ahead: Vec<u32> = (piece + 1..)
+            .take(self.read_ahead as usize)
+            .filter(|index| have.has(*index as usize))
+            .filter(|index| {
+                !self
+                    .read
+                    .lock()
+                    .unwrap()
+                    .pieces
+                    .contains_key(&(storage.id, *index))
+            })
+            .collect();
+        if ahead.is_empty() {
+            return;
+        }
+        let (cache, storage) = (self.clone(), storage.clone());
+        tokio::spawn(async move {
+            for index in ahead {
+                match storage.read_piece(index).await {
+                    Ok(data) => cache.insert((storage.id, index), Arc::new(data)),
+                    Err(_) => break,
+                }
+            }
+        });
+    }
+}
+
+// Blocks that follow each other as one buffer each
+fn join_blocks(blocks: &BTreeMap<u32, Vec<u8>>) -> Vec<(u32, Vec<u8>)> {
+    let mut runs: Vec<(u32, Vec<u8>)> = Vec::new();
+    for (begin, block) in blocks {
+        match runs.last_mut() {
+            Some((start, run)) if *start as usize + run.len() == *begin as usize => {
+                run.extend_from_slice(block)
+            }
+            _ => runs.push((*begin, block.clone())),
+        }
+    }
+    runs
+}
+
+// Size of the union of (begin, length) ranges
+fn covered(ranges: impl Iterator<Item = (u32, u32)>) -> u64 {
+    let mut ranges: Vec<(u64, u64)> = ranges
+        .map(|(begin, length)| (begin as u64, begin as u64 + length as u64))
+        .collect();
+    ranges.sort_unstable();
+    let mut total = 0;
+    let mut end = 0;
+    for (start, stop) in ranges {
+        let start = start.max(end);
+        if stop > start {
+            total += stop - start;
+            end = stop;
+        }
+    }
+    total
+}
+
+fn join_ranges(ranges: &BTreeMap<u32, u32>) -> Vec<(u32, u32)> {
+    let mut joined: Vec<(u32, u32)> = Vec::new();
+    for (begin, length) in ranges {
+        match joined.last_mut() {
+            Some((start, total)) if *start + *total == *begin => *total += length,
+            _ => joined.push((*begin, *length)),
+        }
+    }
+    joined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_blocks_counted_once() {
+        assert_eq!(covered([(0, 16), (16, 16)].into_iter()), 32);
+        assert_eq!(covered([(0, 16), (8, 16)].into_iter()), 24);
+        assert_eq!(covered([(8, 4), (0, 16), (32, 8)].into_iter()), 24);
+        assert_eq!(covered([(0, 16), (0, 16)].into_iter()), 16);
+        assert_eq!(covered(std::iter::empty()), 0);
+    }
+}
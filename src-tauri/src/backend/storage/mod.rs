@@ -0,0 +1,870 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+pub mod backend;
+pub mod cache;
+pub mod partfile;
+pub mod pool;
+pub mod recheck;
+pub mod relocate;
+
+use super::bitfield::Bitfield;
+use super::file::TorrentInfo;
+use super::picker::FilePriority;
+use super::settings::Preallocation;
+use backend::{BackendFile, PreadBackend, StorageBackend};
+use partfile::PartFile;
+use pool::IoPool;
+
+// Open file handles kept per torrent, beyond this the oldest use is closed
+const MAX_OPEN_FILES: usize = 64;
+const ZERO_CHUNK: usize = 1 << 20;
+
+// Ranges (offset, length) written to each file, by file index
+type Written = HashMap<usize, Vec<(u64, u64)>>;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    // Relative to the storage root, already sanitized
+    pub path: PathBuf,
+    pub length: u64,
+    // Where the file starts in the torrent's concatenated data
+    pub offset: u64,
+}
+
+// Part of a piece that falls inside one file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    pub file: usize,
+    pub offset: u64,
+    pub length: u64,
+}
+
+// Where every byte of a torrent lives on disk. Pieces are cut from the files
+// laid end to end, so one piece can span any number of files.
+#[derive(Debug, Clone)]
+pub struct FileLayout {
+    pub files: Vec<FileEntry>,
+    piece_length: u64,
+    total_length: u64,
+}
+
+impl FileLayout {
+    pub fn from_info(info: &TorrentInfo) -> Result<Self, &'static str> {
+        if info.piece_length <= 0 {
+            return Err("Piece length must be positive");
+        }
+        let name = sanitize(&info.name).ok_or("Invalid torrent name")?;
+        let entries: Vec<(PathBuf, i64)> = match &info.files {
+            Some(files) => files
+                .iter()
+                .map(|file| {
+                    if file.path.is_empty() {
+                        return Err("Empty file path");
+                    }
+                    let mut path = PathBuf::from(&name);
+                    for component in &file.path {
+                        path.push(sanitize(component).ok_or("Invalid file path")?);
+                    }
+                    Ok((path, file.length))
+                })
+                .collect::<Result<_, &'static str>>()?,
+            None => vec![(PathBuf::from(&name), info.length.unwrap_or(0))],
+        };
+
+        let mut files = Vec::with_capacity(entries.len());
+        let mut offset = 0u64;
+        for (path, length) in entries {
+            let length = u64::try_from(length).map_err(|_| "Negative file length")?;
+            files.push(FileEntry {
+                path,
+                length,
+                offset,
+            });
+            offset += length;
+        }
+        Ok(FileLayout {
+            files,
+            piece_length: info.piece_length as u64,
+            total_length: offset,
+        })
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.total_length
+    }
+
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.total_length.div_ceil(self.piece_length) as usize
+    }
+
+    pub fn piece_size(&self, index: u32) -> u64 {
+        let start = index as u64 * self.piece_length;
+        self.total_length
+            .saturating_sub(start)
+            .min(self.piece_length)
+    }
+
+    // The file slices covering `length` bytes at `begin` within a piece.
+    // Empty files never appear, nothing is stored in them.
+    pub fn map(&self, piece: u32, begin: u64, length: u64) -> Vec<FileSlice> {
+        let start = piece as u64 * self.piece_length + begin;
+        let end = (start + length).min(self.total_length);
+        let mut slices = Vec::new();
+        if start >= end {
+            return slices;
+        }
+        // Last file starting at or before `start`
+        let first = self
+            .files
+            .partition_point(|file| file.offset <= start)
+            .saturating_sub(1);
+        for (index, file) in self.files.iter().enumerate().skip(first) {
+            if file.offset >= end {
+                break;
+            }
+            let from = start.max(file.offset);
+            let to = end.min(file.offset + file.length);
+            if from < to {
+                slices.push(FileSlice {
+                    file: index,
+                    offset: from - file.offset,
+                    length: to - from,
+                });
+            }
+        }
+        slices
+    }
+
+    // Bytes of each file covered by the pieces in `have`
+    pub fn file_progress(&self, have: &Bitfield) -> Vec<u64> {
+        let mut progress = vec![0u64; self.files.len()];
+        for index in have.ones() {
+            let index = index as u32;
+            for slice in self.map(index, 0, self.piece_size(index)) {
+                progress[slice.file] += slice.length;
+            }
+        }
+        progress
+    }
+
+    // Indexes of the files a piece touches
+    pub fn piece_files(&self, piece: u32) -> Vec<usize> {
+        self.map(piece, 0, self.piece_size(piece))
+            .into_iter()
+            .map(|slice| slice.file)
+            .collect()
+    }
+}
+
+// One path component from a torrent, made safe to use on disk. Torrents are
+// untrusted, a component must never climb out of the download directory.
+fn sanitize(component: &str) -> Option<String> {
+    // An absolute path in place of a name is as broken as `..`
+    let mut chars = component.chars();
+    let drive =
+        matches!((chars.next(), chars.next()), (Some(c), Some(':')) if c.is_ascii_alphabetic());
+    if drive || component.starts_with(['/', '\\']) {
+        return None;
+    }
+    let cleaned: String = component
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let cleaned = cleaned.trim_end_matches(['.', ' ']);
+    if cleaned.is_empty() {
+        return None;
+    }
+    Some(cleaned.to_string())
+}
+
+// Where the bytes of a slice are. Skipped files are only looked at under
+// the part file's lock, held for as long as the location is used, so a
+// migration is never seen half done.
+enum Location<'a> {
+    File(
+        Arc<dyn BackendFile>,
+        Option<MutexGuard<'a, Option<PartFile>>>,
+    ),
+    Part(MutexGuard<'a, Option<PartFile>>),
+}
+
+// Where the files are right now. Starts out as the save path and the
+// torrent's own names, moving and renaming change it.
+#[derive(Debug)]
+struct Place {
+    root: PathBuf,
+    // Relative to the root, one per file of the layout
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+struct OpenFiles {
+    handles: HashMap<usize, Arc<dyn BackendFile>>,
+    // File indexes from least to most recently used
+    order: Vec<usize>,
+}
+
+// Reads and writes piece data for one torrent. All file access runs on the
+// shared I/O pool.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    // Tells torrents apart in caches shared by all of them, clones share it
+    id: u64,
+    place: Arc<Mutex<Place>>,
+    layout: Arc<FileLayout>,
+    preallocation: Preallocation,
+    pool: IoPool,
+    backend: Arc<dyn StorageBackend>,
+    open: Arc<Mutex<OpenFiles>>,
+    // Files the user doesn't want. They are never created, but one that is
+    // already on disk is still read and written.
+    skipped: Arc<Mutex<Vec<bool>>>,
+    // The latest priorities asked for, `skipped` catches up once the data
+    // is moved on the I/O pool
+    wanted: Arc<Mutex<Vec<bool>>>,
+    // Where pieces shared with a wanted file keep their skipped parts. Its
+    // lock is held whenever a skipped file is touched.
+    part: Arc<Mutex<Option<PartFile>>>,
+    // Held for reading by every file access and for writing while files are
+    // switched to their new place or renamed, so none of them sees a file
+    // halfway there
+    gate: Arc<RwLock<()>>,
+    // Set while a move copies files. The ranges written to each file since
+    // then are replayed onto the copies before switching over.
+    moving: Arc<Mutex<Option<Written>>>,
+}
+
+impl Storage {
+    pub fn new(
+        root: PathBuf,
+        layout: FileLayout,
+        preallocation: Preallocation,
+        pool: IoPool,
+    ) -> Self {
+        let skipped = vec![false; layout.files.len()];
+        let paths = layout.files.iter().map(|file| file.path.clone()).collect();
+        Storage {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            place: Arc::new(Mutex::new(Place { root, paths })),
+            layout: Arc::new(layout),
+            preallocation,
+            pool,
+            backend: Arc::new(PreadBackend),
+            open: Arc::new(Mutex::new(OpenFiles::default())),
+            wanted: Arc::new(Mutex::new(vec![true; skipped.len()])),
+            skipped: Arc::new(Mutex::new(skipped)),
+            part: Arc::new(Mutex::new(None)),
+            gate: Arc::new(RwLock::new(())),
+            moving: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Replaces the default pread backend
+    pub fn with_backend(mut self, backend: Arc<dyn StorageBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    // Without a part file the bytes of skipped files in edge pieces are
+    // dropped. A part file that can't be loaded is left alone on disk.
+    pub fn with_part_file(self, path: PathBuf) -> Self {
+        let num_pieces = self.layout.num_pieces() as u32;
+        match PartFile::open(path.clone(), num_pieces, self.layout.piece_length) {
+            Ok(part) => *self.part.lock().unwrap() = Some(part),
+            Err(e) => println!("Ignoring part file {}: {}", path.display(), e),
+        }
+        self
+    }
+
+    pub fn root(&self) -> PathBuf {
+        self.place.lock().unwrap().root.clone()
+    }
+
+    pub fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    pub fn file_path(&self, index: usize) -> PathBuf {
+        let place = self.place.lock().unwrap();
+        place.root.join(&place.paths[index])
+    }
+
+    // Paths of the files relative to the root, renames included
+    pub fn file_paths(&self) -> Vec<PathBuf> {
+        self.place.lock().unwrap().paths.clone()
+    }
+
+    // Takes the new priorities right away and applies them on the I/O pool.
+    // Files that become wanted get their parts of edge pieces moved out of
+    // the part file, at most two pieces per file. Calls racing each other
+    // all end up applying the latest priorities.
+    pub fn set_file_priorities(
+        &self,
+        priorities: &[FilePriority],
+    ) -> impl Future<Output = io::Result<()>> + Send + 'static {
+        *self.wanted.lock().unwrap() = wanted(priorities, self.layout.files.len());
+        let storage = self.clone();
+        async move {
+            let pool = storage.pool.clone();
+            pool.run(move || storage.apply_priorities()).await
+        }
+    }
+
+    // Priorities from resume data, they were applied before it was saved so
+    // nothing has to move
+    pub fn restore_file_priorities(&self, priorities: &[FilePriority]) {
+        let _part = self.part.lock().unwrap();
+        let wanted = wanted(priorities, self.layout.files.len());
+        *self.skipped.lock().unwrap() = wanted.iter().map(|wanted| !wanted).collect();
+        *self.wanted.lock().unwrap() = wanted;
+    }
+
+    fn apply_priorities(&self) -> io::Result<()> {
+        let _gate = self.gate.read().unwrap();
+        let mut part = self.part.lock().unwrap();
+        let wanted = self.wanted.lock().unwrap().clone();
+        let enabled: Vec<usize> = {
+            let skipped = self.skipped.lock().unwrap();
+            (0..skipped.len())
+                .filter(|index| skipped[*index] && wanted[*index])
+                .collect()
+        };
+        if let Some(part) = part.as_mut() {
+            self.migrate(part, &enabled)?;
+        }
+        *self.skipped.lock().unwrap() = wanted.iter().map(|wanted| !wanted).collect();
+        if let Some(part) = part.as_mut() {
+            // Pieces with nothing left in files that don't exist
+            for piece in part.pieces() {
+                let slices = self.layout.map(piece, 0, self.layout.piece_size(piece));
+                if !slices.iter().any(|slice| self.is_absent(slice.file)) {
+                    part.free_piece(piece)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Copies what the part file holds of `files` into the real files, which
+    // are created by it. Files already on disk have their own data.
+    fn migrate(&self, part: &mut PartFile, files: &[usize]) -> io::Result<()> {
+        let files: Vec<usize> = files
+            .iter()
+            .copied()
+            .filter(|index| !self.is_created(*index))
+            .collect();
+        for piece in part.pieces() {
+            let piece_start = piece as u64 * self.layout.piece_length;
+            for slice in self.layout.map(piece, 0, self.layout.piece_size(piece)) {
+                if !files.contains(&slice.file) {
+                    continue;
+                }
+                let offset = self.layout.files[slice.file].offset + slice.offset - piece_start;
+                let mut buf = vec![0u8; slice.length as usize];
+                part.read(piece, offset, &mut buf)?;
+                self.file(slice.file)?.write_at(&buf, slice.offset)?;
+                self.journal(slice.file, slice.offset, slice.length);
+            }
+        }
+        Ok(())
+    }
+
+    // Remembers a write for a move copying the files right now
+    fn journal(&self, file: usize, offset: u64, length: u64) {
+        if let Some(written) = self.moving.lock().unwrap().as_mut() {
+            written.entry(file).or_default().push((offset, length));
+        }
+    }
+
+    fn is_skipped(&self, index: usize) -> bool {
+        self.skipped.lock().unwrap()[index]
+    }
+
+    fn is_created(&self, index: usize) -> bool {
+        self.open.lock().unwrap().handles.contains_key(&index) || self.file_path(index).exists()
+    }
+
+    // A skipped file that was never created, its data goes to the part file
+    fn is_absent(&self, index: usize) -> bool {
+        self.is_skipped(index) && !self.is_created(index)
+    }
+
+    fn locate(&self, index: usize) -> io::Result<Location<'_>> {
+        if !self.is_skipped(index) {
+            return Ok(Location::File(self.file(index)?, None));
+        }
+        let part = self.part.lock().unwrap();
+        if self.is_created(index) {
+            Ok(Location::File(self.file(index)?, Some(part)))
+        } else {
+            Ok(Location::Part(part))
+        }
+    }
+
+    // Creates every wanted directory and file up front. With full
+    // preallocation the files are filled with zeros so the space is really
+    // reserved, sparse ones only get their final length. The zeros go
+    // through plain writes, never through a memory map.
+    pub async fn allocate(&self) -> io::Result<()> {
+        let storage = self.clone();
+        self.pool
+            .run(move || {
+                let _gate = storage.gate.read().unwrap();
+                for index in 0..storage.layout.files.len() {
+                    if storage.is_skipped(index) {
+                        continue;
+                    }
+                    let file = storage.create(index)?;
+                    if storage.preallocation == Preallocation::Full {
+                        fill_zeros(&file, 0, storage.layout.files[index].length)?;
+                    }
+                }
+                Ok(())
+            })
+            .await
+    }
+
+    // Writes a whole verified piece, across as many files as it spans
+    pub async fn write_piece(&self, index: u32, data: Vec<u8>) -> io::Result<()> {
+        if data.len() as u64 != self.layout.piece_size(index) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Piece data has the wrong length",
+            ));
+        }
+        self.write(index, 0, data).await
+    }
+
+    pub async fn write(&self, index: u32, begin: u32, data: Vec<u8>) -> io::Result<()> {
+        let storage = self.clone();
+        self.pool
+            .run(move || storage.write_blocking(index, begin, &data))
+            .await
+    }
+
+    pub async fn read(&self, index: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        let storage = self.clone();
+        self.pool
+            .run(move || storage.read_blocking(index, begin, length))
+            .await
+    }
+
+    pub async fn read_piece(&self, index: u32) -> io::Result<Vec<u8>> {
+        self.read(index, 0, self.layout.piece_size(index) as u32)
+            .await
+    }
+
+    // Whether any of the torrent's files is already on disk, for instance
+    // because the torrent was pointed at data downloaded elsewhere
+    pub fn any_file_exists(&self) -> bool {
+        let _gate = self.gate.read().unwrap();
+        (0..self.layout.files.len()).any(|index| self.file_path(index).is_file())
+    }
+
+    // Reads a piece without creating anything. None when a file it needs is
+    // missing or too short to hold it.
+    pub async fn read_existing(&self, index: u32) -> io::Result<Option<Vec<u8>>> {
+        let storage = self.clone();
+        self.pool
+            .run(move || storage.read_existing_blocking(index))
+            .await
+    }
+
+    // Flushes everything written so far to the disk
+    pub async fn flush(&self) -> io::Result<()> {
+        let storage = self.clone();
+        self.pool
+            .run(move || {
+                let _gate = storage.gate.read().unwrap();
+                let handles: Vec<Arc<dyn BackendFile>> = storage
+                    .open
+                    .lock()
+                    .unwrap()
+                    .handles
+                    .values()
+                    .cloned()
+                    .collect();
+                for file in handles {
+                    file.sync()?;
+                }
+                match storage.part.lock().unwrap().as_ref() {
+                    Some(part) => part.flush(),
+                    None => Ok(()),
+                }
+            })
+            .await
+    }
+
+    fn write_blocking(&self, index: u32, begin: u32, data: &[u8]) -> io::Result<()> {
+        let _gate = self.gate.read().unwrap();
+        let slices = self.layout.map(index, begin as u64, data.len() as u64);
+        let mut pos = 0;
+        for slice in slices {
+            let end = pos + slice.length as usize;
+            let bytes = &data[pos..end];
+            match self.locate(slice.file)? {
+                Location::File(file, _lock) => {
+                    file.write_at(bytes, slice.offset)?;
+                    self.journal(slice.file, slice.offset, slice.length);
+                }
+                Location::Part(mut part) => {
+                    // Without a part file the piece just can't be read back
+                    if let Some(part) = part.as_mut() {
+                        part.write(index, begin as u64 + pos as u64, bytes)?;
+                    }
+                }
+            }
+            pos = end;
+        }
+        if pos != data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Write past the end of the torrent",
+            ));
+        }
+        Ok(())
+    }
+
+    fn read_blocking(&self, index: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        let _gate = self.gate.read().unwrap();
+        let slices = self.layout.map(index, begin as u64, length as u64);
+        let mut data = vec![0u8; length as usize];
+        let mut pos = 0;
+        for slice in slices {
+            let end = pos + slice.length as usize;
+            let buf = &mut data[pos..end];
+            match self.locate(slice.file)? {
+                Location::File(file, _lock) => file.read_at(buf, slice.offset)?,
+                Location::Part(mut part) => {
+                    let found = match part.as_mut() {
+                        Some(part) => part.read(index, begin as u64 + pos as u64, buf)?,
+                        None => false,
+                    };
+                    if !found {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            "Piece data is in a skipped file",
+                        ));
+                    }
+                }
+            }
+            pos = end;
+        }
+        if pos != data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Read past the end of the torrent",
+            ));
+        }
+        Ok(data)
+    }
+
+    fn read_existing_blocking(&self, index: u32) -> io::Result<Option<Vec<u8>>> {
+        let _gate = self.gate.read().unwrap();
+        let slices = self.layout.map(index, 0, self.layout.piece_size(index));
+        let mut data = vec![0u8; slices.iter().map(|slice| slice.length as usize).sum()];
+        let mut pos = 0;
+        for slice in slices {
+            // Not through the handle cache, those handles create and resize
+            let file = match File::open(self.file_path(slice.file)) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    let end = pos + slice.length as usize;
+                    let found = self.is_skipped(slice.file)
+                        && self
+                            .part
+                            .lock()
+                            .unwrap()
+                            .as_mut()
+                            .map(|part| part.read(index, pos as u64, &mut data[pos..end]))
+                            .transpose()?
+                            .unwrap_or(false);
+                    if !found {
+                        return Ok(None);
+                    }
+                    pos = end;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if file.metadata()?.len() < slice.offset + slice.length {
+                return Ok(None);
+            }
+            let end = pos + slice.length as usize;
+            read_at(&file, &mut data[pos..end], slice.offset)?;
+            pos = end;
+        }
+        Ok(Some(data))
+    }
+
+    // Opens a file, creating it and its directories on first use
+    fn file(&self, index: usize) -> io::Result<Arc<dyn BackendFile>> {
+        let mut open = self.open.lock().unwrap();
+        if let Some(file) = open.handles.get(&index).cloned() {
+            open.order.retain(|i| *i != index);
+            open.order.push(index);
+            return Ok(file);
+        }
+
+        let file = self.create(index)?;
+        let file = self.backend.open(file, self.layout.files[index].length)?;
+        if open.order.len() >= MAX_OPEN_FILES {
+            let oldest = open.order.remove(0);
+            open.handles.remove(&oldest);
+        }
+        open.handles.insert(index, file.clone());
+        open.order.push(index);
+        Ok(file)
+    }
+
+    // Files are sized right away so reads of parts not written yet return
+    // zeros. With full preallocation the new part is written out, so no
+    // backend ever sees a hole.
+    fn create(&self, index: usize) -> io::Result<File> {
+        let path = self.file_path(index);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let length = self.layout.files[index].length;
+        let current = file.metadata()?.len();
+        if current < length {
+            file.set_len(length)?;
+            if self.preallocation == Preallocation::Full {
+                fill_zeros(&file, current, length)?;
+            }
+        }
+        Ok(file)
+    }
+}
+
+// Files without a priority are wanted
+fn wanted(priorities: &[FilePriority], num_files: usize) -> Vec<bool> {
+    (0..num_files)
+        .map(|index| priorities.get(index) != Some(&FilePriority::Skip))
+        .collect()
+}
+
+// Writes real zeros from `offset` to `length`. Only the parts that read
+// back as zeros are rewritten, so it is safe on a file that already has data.
+fn fill_zeros(file: &File, mut offset: u64, length: u64) -> io::Result<()> {
+    let zeros = vec![0u8; ZERO_CHUNK];
+    let mut existing = vec![0u8; ZERO_CHUNK];
+    while offset < length {
+        let chunk = (length - offset).min(ZERO_CHUNK as u64) as usize;
+        read_at(file, &mut existing[..chunk], offset)?;
+        if existing[..chunk].iter().all(|b| *b == 0) {
+            write_at(file, &zeros[..chunk], offset)?;
+        }
+        offset += chunk as u64;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let n = file.seek_write(buf, offset)?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let n = file.seek_read(buf, offset)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf = &mut buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::file::TorrentFile;
+
+    // A directory of its own under the system temp dir, removed on drop
+    pub(super) struct TempDir(pub(super) PathBuf);
+
+    impl TempDir {
+        pub(super) fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("defttorrent-{}-{}", name, std::process::id()));
+            fs::remove_dir_all(&path).ok();
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    // A multi-file torrent named "t" with the given file paths and lengths
+    pub(super) fn layout(piece_length: i64, files: &[(&str, i64)]) -> FileLayout {
+        let files = files
+            .iter()
+            .map(|(name, length)| TorrentFile {
+                length: *length,
+                path: name.split('/').map(String::from).collect(),
+            })
+            .collect();
+        let info = TorrentInfo {
+            name: "t".to_string(),
+            piece_length,
+            pieces: Vec::new(),
+            length: None,
+            files: Some(files),
+            private: false,
+        };
+        FileLayout::from_info(&info).unwrap()
+    }
+
+    fn slice(file: usize, offset: u64, length: u64) -> FileSlice {
+        FileSlice {
+            file,
+            offset,
+            length,
+        }
+    }
+
+    #[test]
+    fn piece_spans_files() {
+        let layout = layout(
+            32,
+            &[
+                ("e0", 0),
+                ("a", 10),
+                ("e1", 0),
+                ("b", 5),
+                ("e2", 0),
+                ("c", 20),
+            ],
+        );
+        assert_eq!(layout.num_pieces(), 2);
+        assert_eq!(
+            layout.map(0, 0, 32),
+            vec![slice(1, 0, 10), slice(3, 0, 5), slice(5, 0, 17)]
+        );
+        assert_eq!(layout.map(0, 8, 4), vec![slice(1, 8, 2), slice(3, 0, 2)]);
+        assert_eq!(layout.piece_files(0), vec![1, 3, 5]);
+    }
+
+    #[test]
+    fn last_piece_is_short() {
+        let layout = layout(32, &[("a", 10), ("b", 25)]);
+        assert_eq!(layout.num_pieces(), 2);
+        assert_eq!(layout.piece_size(1), 3);
+        assert_eq!(layout.map(1, 0, 32), vec![slice(1, 22, 3)]);
+        assert!(layout.map(1, 3, 1).is_empty());
+        assert!(layout.map(2, 0, 32).is_empty());
+    }
+
+    #[test]
+    fn unsafe_components_rejected() {
+        for component in [
+            "..",
+            ".",
+            "",
+            " ",
+            "...",
+            "/etc",
+            "\\windows",
+            "C:",
+            "c:\\x",
+        ] {
+            assert_eq!(sanitize(component), None, "{:?}", component);
+        }
+        assert_eq!(sanitize("a/b"), Some("a_b".to_string()));
+        assert_eq!(sanitize("name. "), Some("name".to_string()));
+        assert_eq!(sanitize("..x"), Some("..x".to_string()));
+
+        let info = TorrentInfo {
+            name: "t".to_string(),
+            piece_length: 16,
+            pieces: Vec::new(),
+            length: None,
+            files: Some(vec![TorrentFile {
+                length: 1,
+                path: vec!["..".to_string(), "x".to_string()],
+            }]),
+            private: false,
+        };
+        assert!(FileLayout::from_info(&info).is_err());
+    }
+
+    // Bytes the file really takes up on disk
+    #[cfg(unix)]
+    fn allocated(path: &std::path::Path) -> u64 {
+        use std::os::unix::fs::MetadataExt;
+        fs::metadata(path).unwrap().blocks() * 512
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sparse_and_full_preallocation() {
+        const LENGTH: i64 = 4 << 20;
+        for (preallocation, name) in [
+            (Preallocation::Sparse, "sparse"),
+            (Preallocation::Full, "full"),
+        ] {
+            let dir = TempDir::new(&format!("prealloc-{}", name));
+            let layout = layout(1 << 16, &[("a", LENGTH), ("b", 100)]);
+            let storage = Storage::new(dir.0.clone(), layout, preallocation, IoPool::new(1));
+            storage.allocate().await.unwrap();
+            let path = storage.file_path(0);
+            assert_eq!(fs::metadata(&path).unwrap().len(), LENGTH as u64);
+            assert_eq!(fs::metadata(storage.file_path(1)).unwrap().len(), 100);
+            match preallocation {
+                Preallocation::Sparse => assert!(allocated(&path) < LENGTH as u64 / 2),
+                Preallocation::Full => assert!(allocated(&path) >= LENGTH as u64),
+            }
+        }
+    }
+}
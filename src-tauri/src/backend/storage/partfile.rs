@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{read_at, write_at};
+
+const MAGIC: &[u8; 4] = b"DTPF";
+const NO_PIECE: u32 = u32::MAX;
+// Slots start on a boundary after the header
+const ALIGNMENT: u64 = 4096;
+
+// Holds the parts of pieces that belong to skipped files. A piece shared by
+// a wanted and a skipped file still has to be downloaded and checked whole,
+// the bytes of the skipped file end up here instead of in a file the user
+// said they don't want.
+//
+// Layout: magic, number of pieces, piece length, then one entry per slot
+// with the piece stored in it. Slot n holds a whole piece at the n-th piece
+// sized offset after the header, only the skipped parts of it are used.
+#[derive(Debug)]
+pub struct PartFile {
+    path: PathBuf,
+    num_pieces: u32,
+    piece_length: u64,
+    // Piece index to slot
+    slots: HashMap<u32, u32>,
+    free: Vec<u32>,
+    // Slots in use or freed so far, new ones are appended after them
+    num_slots: u32,
+    file: Option<File>,
+}
+
+impl PartFile {
+    // Loads an existing part file, or prepares an empty one that is only
+    // created once something is written to it
+    pub fn open(path: PathBuf, num_pieces: u32, piece_length: u64) -> io::Result<Self> {
+        let mut part = PartFile {
+            path,
+            num_pieces,
+            piece_length,
+            slots: HashMap::new(),
+            free: Vec::new(),
+            num_slots: 0,
+            file: None,
+        };
+        let file = match OpenOptions::new().read(true).write(true).open(&part.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(part),
+            Err(e) => return Err(e),
+        };
+        let invalid = |msg: &'static str| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut header = vec![0u8; part.header_len() as usize];
+        read_at(&file, &mut header, 0).map_err(|_| invalid("Part file header is truncated"))?;
+        if &header[..4] != MAGIC {
+            return Err(invalid("Not a part file"));
+        }
+        let stored_pieces = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        let stored_length = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        if stored_pieces != num_pieces || stored_length as u64 != piece_length {
+            return Err(invalid("Part file belongs to a different torrent"));
+        }
+        for slot in 0..num_pieces {
+            let at = 12 + slot as usize * 4;
+            let piece = u32::from_be_bytes(header[at..at + 4].try_into().unwrap());
+            if piece == NO_PIECE {
+                continue;
+            }
+            if piece >= num_pieces || part.slots.contains_key(&piece) {
+                return Err(invalid("Corrupt part file"));
+            }
+            part.slots.insert(piece, slot);
+            part.num_slots = part.num_slots.max(slot + 1);
+        }
+        part.free = (0..part.num_slots)
+            .filter(|slot| !part.slots.values().any(|used| used == slot))
+            .collect();
+        part.file = Some(file);
+        Ok(part)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn has_piece(&self, piece: u32) -> bool {
+        self.slots.contains_key(&piece)
+    }
+
+    pub fn pieces(&self) -> Vec<u32> {
+        let mut pieces: Vec<u32> = self.slots.keys().copied().collect();
+        pieces.sort_unstable();
+        pieces
+    }
+
+    pub fn write(&mut self, piece: u32, offset: u64, data: &[u8]) -> io::Result<()> {
+        if offset + data.len() as u64 > self.piece_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Write past the end of the piece",
+            ));
+        }
+        let slot = match self.slots.get(&piece) {
+            Some(slot) => *slot,
+            None => self.allocate(piece)?,
+        };
+        let at = self.slot_offset(slot) + offset;
+        write_at(self.file()?, data, at)
+    }
+
+    // False when nothing of the piece was ever stored here
+    pub fn read(&mut self, piece: u32, offset: u64, buf: &mut [u8]) -> io::Result<bool> {
+        let Some(slot) = self.slots.get(&piece).copied() else {
+            return Ok(false);
+        };
+        let at = self.slot_offset(slot) + offset;
+        read_at(self.file()?, buf, at)?;
+        Ok(true)
+    }
+
+    // The piece's skipped parts are no longer needed here. Once the last
+    // piece is gone the file itself is removed.
+    pub fn free_piece(&mut self, piece: u32) -> io::Result<()> {
+        let Some(slot) = self.slots.remove(&piece) else {
+            return Ok(());
+        };
+        if self.slots.is_empty() {
+            self.file = None;
+            self.free.clear();
+            self.num_slots = 0;
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        self.free.push(slot);
+        self.write_entry(slot, NO_PIECE)
+    }
+
+    // Points the part file at a new path, with `mover` moving the data on
+    // disk from the old one while the handle is closed. On failure it stays
+    // where it was.
+    pub fn relocate(
+        &mut self,
+        path: PathBuf,
+        mover: impl FnOnce(&Path, &Path) -> io::Result<()>,
+    ) -> io::Result<()> {
+        // Nothing was written, there is no file to move yet
+        if self.file.is_none() {
+            self.path = path;
+            return Ok(());
+        }
+        self.file = None;
+        let moved = mover(&self.path, &path);
+        if moved.is_ok() {
+            self.path = path;
+        }
+        self.file = Some(OpenOptions::new().read(true).write(true).open(&self.path)?);
+        moved
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        match &self.file {
+            Some(file) => file.sync_data(),
+            None => Ok(()),
+        }
+    }
+
+    fn allocate(&mut self, piece: u32) -> io::Result<u32> {
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => {
+                self.num_slots += 1;
+                self.num_slots - 1
+            }
+        };
+        self.slots.insert(piece, slot);
+        self.write_entry(slot, piece)?;
+        Ok(slot)
+    }
+
+    fn write_entry(&mut self, slot: u32, piece: u32) -> io::Result<()> {
+        let at = 12 + slot as u64 * 4;
+        write_at(self.file()?, &piece.to_be_bytes(), at)
+    }
+
+    // Created with an empty slot table on first use
+    fn file(&mut self) -> io::Result<&File> {
+        if self.file.is_none() {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&self.path)?;
+            let mut header = vec![0xffu8; self.header_len() as usize];
+            header[..4].copy_from_slice(MAGIC);
+            header[4..8].copy_from_slice(&self.num_pieces.to_be_bytes());
+            header[8..12].copy_from_slice(&(self.piece_length as u32).to_be_bytes());
+            write_at(&file, &header, 0)?;
+            self.file = Some(file);
+        }
+        Ok(self.file.as_ref().unwrap())
+    }
+
+    fn header_len(&self) -> u64 {
+        (12 + self.num_pieces as u64 * 4).next_multiple_of(ALIGNMENT)
+    }
+
+    fn slot_offset(&self, slot: u32) -> u64 {
+        self.header_len() + slot as u64 * self.piece_length
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{layout, TempDir};
+    use super::super::{IoPool, Preallocation, Storage};
+    use super::*;
+    use crate::backend::picker::FilePriority::{Normal, Skip};
+
+    #[tokio::test]
+    async fn enabled_file_takes_its_data_from_the_part_file() {
+        let dir = TempDir::new("partfile-enable");
+        let part_path = dir.0.join("t.parts");
+        // Pieces of 16 bytes: the first is shared by a and b, the second by
+        // b and c, the last only covers c
+        let layout = layout(16, &[("a", 10), ("b", 12), ("c", 26)]);
+        let storage = Storage::new(dir.0.clone(), layout, Preallocation::Sparse, IoPool::new(1))
+            .with_part_file(part_path.clone());
+        storage
+            .set_file_priorities(&[Normal, Skip, Skip])
+            .await
+            .unwrap();
+        storage.allocate().await.unwrap();
+
+        let data: Vec<u8> = (0..32).map(|i| i as u8 + 1).collect();
+        storage.write_piece(0, data[..16].to_vec()).await.unwrap();
+        storage.write_piece(1, data[16..].to_vec()).await.unwrap();
+        storage.flush().await.unwrap();
+        assert_eq!(fs::read(storage.file_path(0)).unwrap(), &data[..10]);
+        assert!(!storage.file_path(1).exists() && !storage.file_path(2).exists());
+        assert_eq!(
+            storage.part.lock().unwrap().as_ref().unwrap().pieces(),
+            vec![0, 1]
+        );
+        assert_eq!(storage.read_piece(0).await.unwrap(), &data[..16]);
+
+        storage
+            .set_file_priorities(&[Normal, Normal, Skip])
+            .await
+            .unwrap();
+        assert_eq!(fs::read(storage.file_path(1)).unwrap(), &data[10..22]);
+        assert!(!storage.file_path(2).exists());
+        // The first piece is all in real files now, the second still has
+        // the part of c
+        assert_eq!(
+            storage.part.lock().unwrap().as_ref().unwrap().pieces(),
+            vec![1]
+        );
+        assert_eq!(storage.read_piece(0).await.unwrap(), &data[..16]);
+        assert_eq!(storage.read_piece(1).await.unwrap(), &data[16..]);
+        storage.flush().await.unwrap();
+        let reopened = PartFile::open(part_path.clone(), 3, 16).unwrap();
+        assert_eq!(reopened.pieces(), vec![1]);
+
+        // Nothing left to keep, the part file goes away
+        storage
+            .set_file_priorities(&[Normal, Normal, Normal])
+            .await
+            .unwrap();
+        assert_eq!(fs::read(storage.file_path(2)).unwrap()[..10], data[22..]);
+        assert!(!part_path.exists());
+    }
+}
@@ -0,0 +1,80 @@
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Dedicated threads for blocking work like disk I/O or hashing, so the async
+// runtime never waits on it. Cloning shares the same threads.
+#[derive(Debug, Clone)]
+pub struct IoPool {
+    sender: Sender<Job>,
+    threads: usize,
+}
+
+impl IoPool {
+    pub fn new(threads: usize) -> IoPool {
+        IoPool::named(threads, "disk-io")
+    }
+
+    pub fn named(threads: usize, name: &str) -> IoPool {
+        let threads = threads.max(1);
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for i in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("{}-{}", name, i))
+                .spawn(move || worker(receiver))
+                .expect("failed to spawn pool thread");
+        }
+        IoPool { sender, threads }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    // Runs `job` on one of the pool's threads without waiting for it. With
+    // one thread jobs run in the order they were spawned.
+    pub fn spawn<F: FnOnce() + Send + 'static>(&self, job: F) {
+        if self.sender.send(Box::new(job)).is_err() {
+            println!("Worker pool stopped, dropping a job");
+        }
+    }
+
+    // Runs `job` on one of the pool's threads and waits for it without
+    // blocking the caller's runtime thread
+    pub async fn run<F, T>(&self, job: F) -> io::Result<T>
+    where
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result_sender, result) = oneshot::channel();
+        self.sender
+            .send(Box::new(move || {
+                result_sender.send(job()).ok();
+            }))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Worker pool stopped"))?;
+        result
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Pool job was dropped"))?
+    }
+}
+
+fn worker(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        // The lock is only held while waiting, never while a job runs
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            // A panicking job only loses its own result, not the thread
+            Ok(job) => {
+                panic::catch_unwind(AssertUnwindSafe(job)).ok();
+            }
+            Err(_) => return,
+        }
+    }
+}
@@ -0,0 +1,290 @@
+use serde::Serialize;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use super::Storage;
+use crate::backend::bitfield::Bitfield;
+use crate::backend::verify::{HashPool, PieceHash};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    Run,
+    Pause,
+    Cancel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecheckState {
+    Checking,
+    Paused,
+    Cancelled,
+    Finished,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecheckProgress {
+    pub state: RecheckState,
+    pub num_pieces: usize,
+    pub pieces_checked: usize,
+    pub pieces_valid: usize,
+    pub bytes_checked: u64,
+    pub error: Option<String>,
+}
+
+// Controls a running recheck. Clones control the same one.
+#[derive(Debug, Clone)]
+pub struct RecheckHandle {
+    control: Arc<watch::Sender<Control>>,
+    progress: watch::Receiver<RecheckProgress>,
+}
+
+impl RecheckHandle {
+    pub fn pause(&self) {
+        self.set(Control::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.set(Control::Run);
+    }
+
+    pub fn cancel(&self) {
+        self.set(Control::Cancel);
+    }
+
+    pub fn progress(&self) -> RecheckProgress {
+        self.progress.borrow().clone()
+    }
+
+    // Sees every change of the progress, for forwarding it to the UI
+    pub fn subscribe(&self) -> watch::Receiver<RecheckProgress> {
+        self.progress.clone()
+    }
+
+    pub fn same(&self, other: &RecheckHandle) -> bool {
+        Arc::ptr_eq(&self.control, &other.control)
+    }
+
+    fn set(&self, control: Control) {
+        self.control.send_if_modified(|current| {
+            // A cancelled recheck stays cancelled
+            if *current == control || *current == Control::Cancel {
+                return false;
+            }
+            *current = control;
+            true
+        });
+    }
+}
+
+// Reads whatever data of the torrent is already on disk and hashes every
+// piece, to find out what doesn't have to be downloaded. Nothing is created
+// or resized, missing files just count as missing pieces. The returned
+// future does the work and resolves to the pieces that passed, or None when
+// cancelled.
+pub fn recheck(
+    storage: Storage,
+    hashes: Arc<Vec<PieceHash>>,
+    pool: HashPool,
+) -> (
+    RecheckHandle,
+    impl Future<Output = io::Result<Option<Bitfield>>> + Send + 'static,
+) {
+    let (control_sender, control) = watch::channel(Control::Run);
+    let (progress_sender, progress) = watch::channel(RecheckProgress {
+        state: RecheckState::Checking,
+        num_pieces: hashes.len(),
+        pieces_checked: 0,
+        pieces_valid: 0,
+        bytes_checked: 0,
+        error: None,
+    });
+    let handle = RecheckHandle {
+        control: Arc::new(control_sender),
+        progress,
+    };
+    let task = async move {
+        let result = run(&storage, hashes, pool, control, &progress_sender).await;
+        progress_sender.send_modify(|progress| match &result {
+            Ok(Some(_)) => progress.state = RecheckState::Finished,
+            Ok(None) => progress.state = RecheckState::Cancelled,
+            Err(e) => {
+                progress.state = RecheckState::Failed;
+                progress.error = Some(e.to_string());
+            }
+        });
+        result
+    };
+    (handle, task)
+}
+
+async fn run(
+    storage: &Storage,
+    hashes: Arc<Vec<PieceHash>>,
+    pool: HashPool,
+    mut control: watch::Receiver<Control>,
+    progress: &watch::Sender<RecheckProgress>,
+) -> io::Result<Option<Bitfield>> {
+    let layout = storage.layout();
+    let num_pieces = hashes.len();
+    if num_pieces != layout.num_pieces() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Piece hashes don't match the file layout",
+        ));
+    }
+
+    let mut have = Bitfield::new(num_pieces);
+    // Enough pieces in flight to keep every hashing thread busy while the
+    // next one is read
+    let max_hashing = pool.threads() * 2;
+    let mut hashing = JoinSet::new();
+    let mut next = 0;
+
+    loop {
+        if *control.borrow() == Control::Pause {
+            progress.send_modify(|progress| progress.state = RecheckState::Paused);
+            while *control.borrow_and_update() == Control::Pause {
+                // The handle is gone, nobody can resume
+                if control.changed().await.is_err() {
+                    return Ok(None);
+                }
+            }
+            progress.send_modify(|progress| progress.state = RecheckState::Checking);
+        }
+        if *control.borrow() == Control::Cancel {
+            return Ok(None);
+        }
+
+        if hashing.len() >= max_hashing || next == num_pieces {
+            let Some(joined) = hashing.join_next().await else {
+                break;
+            };
+            let (index, length, passed) = joined.map_err(io::Error::other)?;
+            let passed = passed?;
+            if passed {
+                have.set(index as usize);
+            }
+            progress.send_modify(|progress| {
+                progress.pieces_checked += 1;
+                progress.bytes_checked += length;
+                if passed {
+                    progress.pieces_valid += 1;
+                }
+            });
+            continue;
+        }
+
+        let index = next as u32;
+        next += 1;
+        match storage.read_existing(index).await? {
+            Some(data) => {
+                let expected = hashes[index as usize];
+                let pool = pool.clone();
+                hashing.spawn(async move {
+                    let length = data.len() as u64;
+                    (index, length, pool.verify(expected, data).await)
+                });
+            }
+            None => progress.send_modify(|progress| progress.pieces_checked += 1),
+        }
+    }
+
+    Ok(Some(have))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{layout, TempDir};
+    use super::super::{IoPool, Preallocation};
+    use super::*;
+    use sha1::{Digest, Sha1};
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+
+    const PIECE: usize = 16;
+
+    // Three pieces over files of 20, 20 and 8 bytes, the middle piece
+    // corrupted on disk where it covers the second file
+    async fn corrupted(dir: &TempDir) -> (Storage, Arc<Vec<PieceHash>>) {
+        let data: Vec<u8> = (0..48).map(|i| i as u8).collect();
+        let hashes = data
+            .chunks(PIECE)
+            .map(|piece| PieceHash::Sha1(Sha1::digest(piece).into()))
+            .collect();
+        let layout = layout(PIECE as i64, &[("a", 20), ("b", 20), ("c", 8)]);
+        let storage = Storage::new(dir.0.clone(), layout, Preallocation::Sparse, IoPool::new(1));
+        storage.allocate().await.unwrap();
+        for (index, piece) in data.chunks(PIECE).enumerate() {
+            storage
+                .write_piece(index as u32, piece.to_vec())
+                .await
+                .unwrap();
+        }
+        storage.flush().await.unwrap();
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(storage.file_path(1))
+            .unwrap();
+        file.seek(SeekFrom::Start(5)).unwrap();
+        file.write_all(b"x").unwrap();
+        (storage, Arc::new(hashes))
+    }
+
+    #[tokio::test]
+    async fn finds_corrupted_piece() {
+        let dir = TempDir::new("recheck-corrupted");
+        let (storage, hashes) = corrupted(&dir).await;
+        let (handle, task) = recheck(storage.clone(), hashes, HashPool::new(2));
+        let have = task.await.unwrap().unwrap();
+        assert_eq!(have.ones().collect::<Vec<_>>(), vec![0, 2]);
+
+        let progress = handle.progress();
+        assert_eq!(progress.state, RecheckState::Finished);
+        assert_eq!((progress.pieces_checked, progress.pieces_valid), (3, 2));
+        assert_eq!(progress.bytes_checked, 48);
+        // Only the last file is complete, the first misses its last 4 bytes
+        // and the second all but the 8 in the last piece
+        assert_eq!(storage.layout().file_progress(&have), vec![16, 8, 8]);
+    }
+
+    #[tokio::test]
+    async fn cancelled() {
+        let dir = TempDir::new("recheck-cancelled");
+        let (storage, hashes) = corrupted(&dir).await;
+        let (handle, task) = recheck(storage, hashes, HashPool::new(1));
+        handle.cancel();
+        // Stays cancelled
+        handle.resume();
+        assert!(task.await.unwrap().is_none());
+        assert_eq!(handle.progress().state, RecheckState::Cancelled);
+        assert_eq!(handle.progress().pieces_checked, 0);
+    }
+
+    #[tokio::test]
+    async fn paused_and_resumed() {
+        let dir = TempDir::new("recheck-paused");
+        let (storage, hashes) = corrupted(&dir).await;
+        let (handle, task) = recheck(storage, hashes, HashPool::new(1));
+        handle.pause();
+        let task = tokio::spawn(task);
+
+        let mut progress = handle.subscribe();
+        progress
+            .wait_for(|progress| progress.state == RecheckState::Paused)
+            .await
+            .unwrap();
+        assert_eq!(handle.progress().pieces_checked, 0);
+        assert!(!task.is_finished());
+
+        handle.resume();
+        let have = task.await.unwrap().unwrap().unwrap();
+        assert_eq!(have.ones().collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(handle.progress().state, RecheckState::Finished);
+    }
+}
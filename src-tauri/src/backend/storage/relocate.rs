@@ -0,0 +1,770 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use super::{read_at, sanitize, write_at, Storage};
+
+const COPY_CHUNK: usize = 1 << 20;
+// Progress is passed on at most this often
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+// What to do about files already at the destination of a move
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    // They are overwritten
+    Replace,
+    // They are used instead of ours, which stay behind. Their data has to be
+    // checked again.
+    Keep,
+    // Nothing is moved
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MoveProgress {
+    pub bytes_moved: u64,
+    pub total_bytes: u64,
+    pub files_moved: usize,
+    pub num_files: usize,
+}
+
+struct Reporter<'a> {
+    progress: MoveProgress,
+    callback: &'a dyn Fn(MoveProgress),
+    last: Instant,
+}
+
+impl Reporter<'_> {
+    fn add(&mut self, bytes: u64) {
+        self.progress.bytes_moved += bytes;
+        if self.last.elapsed() >= PROGRESS_INTERVAL {
+            self.send();
+        }
+    }
+
+    fn file_done(&mut self) {
+        self.progress.files_moved += 1;
+        self.add(0);
+    }
+
+    fn send(&mut self) {
+        self.last = Instant::now();
+        (self.callback)(self.progress);
+    }
+}
+
+// A file copied or linked to its new place under a temporary name while the
+// torrent keeps running, waiting for the switch over
+struct Staged {
+    index: usize,
+    from: PathBuf,
+    to: PathBuf,
+    tmp: PathBuf,
+    // A copy misses the writes made since, a hard link shares them
+    copied: bool,
+}
+
+impl Storage {
+    // Moves the torrent's files, and its part file, under a new root. The
+    // files are linked or copied to the new place while reads and writes go
+    // on, only the switch over at the end holds them up. On failure the
+    // torrent stays where it was. Resolves to whether files already at the
+    // destination were kept, the data then has to be checked again.
+    pub async fn move_to(
+        &self,
+        root: PathBuf,
+        policy: ConflictPolicy,
+        progress: impl Fn(MoveProgress) + Send + 'static,
+    ) -> io::Result<bool> {
+        let storage = self.clone();
+        self.pool
+            .run(move || storage.move_blocking(root, policy, &progress))
+            .await
+    }
+
+    fn move_blocking(
+        &self,
+        root: PathBuf,
+        policy: ConflictPolicy,
+        callback: &dyn Fn(MoveProgress),
+    ) -> io::Result<bool> {
+        {
+            let mut moving = self.moving.lock().unwrap();
+            if moving.is_some() {
+                return Err(io::Error::other("The files are already being moved"));
+            }
+            *moving = Some(HashMap::new());
+        }
+        let result = self.move_files(root, policy, callback);
+        *self.moving.lock().unwrap() = None;
+        result
+    }
+
+    fn move_files(
+        &self,
+        root: PathBuf,
+        policy: ConflictPolicy,
+        callback: &dyn Fn(MoveProgress),
+    ) -> io::Result<bool> {
+        // Renames wait for the move, so the paths stay as they are
+        let (old_root, paths) = {
+            let place = self.place.lock().unwrap();
+            (place.root.clone(), place.paths.clone())
+        };
+        if root == old_root {
+            return Ok(false);
+        }
+
+        let mut moves = Vec::new();
+        let mut kept = Vec::new();
+        for (index, path) in paths.iter().enumerate() {
+            let (from, to) = (old_root.join(path), root.join(path));
+            if to.exists() {
+                match policy {
+                    ConflictPolicy::Replace => {}
+                    ConflictPolicy::Keep => {
+                        kept.push(index);
+                        continue;
+                    }
+                    ConflictPolicy::Fail => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("{} already exists", to.display()),
+                        ))
+                    }
+                }
+            }
+            if from.is_file() {
+                moves.push((index, from, to));
+            }
+        }
+        let part_size = self
+            .part
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|part| fs::metadata(part.path()).ok())
+            .map_or(0, |metadata| metadata.len());
+        let total_bytes = moves
+            .iter()
+            .map(|(_, from, _)| fs::metadata(from).map_or(0, |metadata| metadata.len()))
+            .sum::<u64>()
+            + part_size;
+        let mut reporter = Reporter {
+            progress: MoveProgress {
+                bytes_moved: 0,
+                total_bytes,
+                files_moved: 0,
+                num_files: moves.len(),
+            },
+            callback,
+            last: Instant::now(),
+        };
+        reporter.send();
+
+        let mut staged = Vec::new();
+        for (index, from, to) in moves {
+            let mut tmp = to.as_os_str().to_owned();
+            tmp.push(".moving");
+            let tmp = PathBuf::from(tmp);
+            match stage_file(&from, &tmp, &mut |bytes| reporter.add(bytes)) {
+                Ok(copied) => staged.push(Staged {
+                    index,
+                    from,
+                    to,
+                    tmp,
+                    copied,
+                }),
+                Err(e) => {
+                    discard(&root, &staged, 0);
+                    return Err(e);
+                }
+            }
+            reporter.file_done();
+        }
+
+        self.switch_over(&old_root, &root, &staged, &kept, &mut reporter)?;
+        reporter.send();
+        Ok(!kept.is_empty())
+    }
+
+    // Brings the staged files up to date and puts them in place, with every
+    // read and write waiting
+    fn switch_over(
+        &self,
+        old_root: &Path,
+        root: &Path,
+        staged: &[Staged],
+        kept: &[usize],
+        reporter: &mut Reporter,
+    ) -> io::Result<()> {
+        let _gate = self.gate.write().unwrap();
+        let mut part = self.part.lock().unwrap();
+        let written = self.moving.lock().unwrap().take().unwrap_or_default();
+        // Windows can't rename files that are open
+        self.close_files();
+
+        let mut renamed = 0;
+        let mut result = Ok(());
+        for file in staged {
+            if file.copied {
+                let ranges = written.get(&file.index).map_or(&[][..], Vec::as_slice);
+                result = replay(&file.from, &file.tmp, ranges);
+            }
+            if result.is_err() {
+                break;
+            }
+        }
+        if result.is_ok() {
+            for file in staged {
+                if let Err(e) = fs::rename(&file.tmp, &file.to) {
+                    result = Err(e);
+                    break;
+                }
+                renamed += 1;
+            }
+        }
+
+        // Files created while the others were being copied
+        let mut late = Vec::new();
+        let paths = self.place.lock().unwrap().paths.clone();
+        for (index, path) in paths.iter().enumerate() {
+            if result.is_err() {
+                break;
+            }
+            let (from, to) = (old_root.join(path), root.join(path));
+            if kept.contains(&index) || staged.iter().any(|file| file.index == index) {
+                continue;
+            }
+            if from.is_file() {
+                result = move_file(&from, &to, &mut |bytes| reporter.add(bytes));
+                if result.is_ok() {
+                    late.push((from, to));
+                }
+            }
+        }
+        if let (Ok(()), Some(part)) = (&result, part.as_mut()) {
+            let to = root.join(part.path().file_name().unwrap_or_default());
+            result = part.relocate(to, |from, to| {
+                move_file(from, to, &mut |bytes| reporter.add(bytes))
+            });
+        }
+
+        if let Err(e) = result {
+            for (from, to) in late.into_iter().rev() {
+                if let Err(e) = move_file(&to, &from, &mut |_| {}) {
+                    println!("Failed to move {} back: {}", to.display(), e);
+                }
+            }
+            discard(root, staged, renamed);
+            return Err(e);
+        }
+        for file in staged {
+            if let Err(e) = fs::remove_file(&file.from) {
+                println!("Failed to remove {}: {}", file.from.display(), e);
+            }
+        }
+        for (index, path) in paths.iter().enumerate() {
+            if !kept.contains(&index) {
+                remove_empty_dirs(old_root, &old_root.join(path));
+            }
+        }
+        self.place.lock().unwrap().root = root.to_path_buf();
+        Ok(())
+    }
+
+    // Gives one file a new path under the root, renaming it on disk if it
+    // was created already
+    pub async fn rename_file(&self, index: usize, name: String) -> io::Result<()> {
+        let storage = self.clone();
+        self.pool
+            .run(move || storage.rename_file_blocking(index, &name))
+            .await
+    }
+
+    fn rename_file_blocking(&self, index: usize, name: &str) -> io::Result<()> {
+        let path = relative_path(name)?;
+        let _gate = self.gate.write().unwrap();
+        self.check_not_moving()?;
+        let mut place = self.place.lock().unwrap();
+        let current = place
+            .paths
+            .get(index)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No such file"))?;
+        if current == path {
+            return Ok(());
+        }
+        let taken = place
+            .paths
+            .iter()
+            .enumerate()
+            .any(|(other, other_path)| other != index && overlaps(other_path, &path));
+        if taken {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is taken by another file", path.display()),
+            ));
+        }
+
+        let (from, to) = (place.root.join(&current), place.root.join(&path));
+        if to.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", to.display()),
+            ));
+        }
+        self.close_files();
+        if from.exists() {
+            if let Some(dir) = to.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::rename(&from, &to)?;
+            remove_empty_dirs(&place.root, &from);
+        }
+        place.paths[index] = path;
+        Ok(())
+    }
+
+    // Renames a directory of the torrent, every file below it moves along
+    pub async fn rename_folder(&self, folder: String, name: String) -> io::Result<()> {
+        let storage = self.clone();
+        self.pool
+            .run(move || storage.rename_folder_blocking(&folder, &name))
+            .await
+    }
+
+    fn rename_folder_blocking(&self, folder: &str, name: &str) -> io::Result<()> {
+        let (from, to) = (relative_path(folder)?, relative_path(name)?);
+        let _gate = self.gate.write().unwrap();
+        self.check_not_moving()?;
+        let mut place = self.place.lock().unwrap();
+        let inside: Vec<usize> = (0..place.paths.len())
+            .filter(|index| {
+                let path = &place.paths[*index];
+                path.starts_with(&from) && *path != from
+            })
+            .collect();
+        if inside.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No such folder"));
+        }
+        if from == to {
+            return Ok(());
+        }
+        if to.starts_with(&from) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A folder can't be moved into itself",
+            ));
+        }
+        let taken = place
+            .paths
+            .iter()
+            .enumerate()
+            .any(|(index, path)| !inside.contains(&index) && overlaps(path, &to));
+        if taken {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is taken by another file", to.display()),
+            ));
+        }
+
+        let (dir_from, dir_to) = (place.root.join(&from), place.root.join(&to));
+        if dir_to.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", dir_to.display()),
+            ));
+        }
+        self.close_files();
+        if dir_from.is_dir() {
+            if let Some(dir) = dir_to.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::rename(&dir_from, &dir_to)?;
+            remove_empty_dirs(&place.root, &dir_from);
+        }
+        for index in inside {
+            let rest = place.paths[index]
+                .strip_prefix(&from)
+                .unwrap()
+                .to_path_buf();
+            place.paths[index] = to.join(rest);
+        }
+        Ok(())
+    }
+
+    // The file paths to save when any file was renamed, relative and with
+    // `/` between components. Empty while every file has its own name.
+    pub fn mapped_files(&self) -> Vec<String> {
+        let place = self.place.lock().unwrap();
+        let renamed = place
+            .paths
+            .iter()
+            .zip(&self.layout.files)
+            .any(|(path, file)| *path != file.path);
+        if !renamed {
+            return Vec::new();
+        }
+        place
+            .paths
+            .iter()
+            .map(|path| {
+                path.iter()
+                    .map(|component| component.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/")
+            })
+            .collect()
+    }
+
+    // Brings back paths saved from `mapped_files`. Only the mapping changes,
+    // the files are expected to be there already.
+    pub fn set_mapped_files(&self, files: &[String]) -> io::Result<()> {
+        if files.is_empty() {
+            return Ok(());
+        }
+        let invalid = |msg: &'static str| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if files.len() != self.layout.files.len() {
+            return Err(invalid("Wrong number of mapped files"));
+        }
+        let paths = files
+            .iter()
+            .map(|file| relative_path(file))
+            .collect::<io::Result<Vec<_>>>()?;
+        // A path and everything below it sort next to each other
+        let mut sorted: Vec<&PathBuf> = paths.iter().collect();
+        sorted.sort();
+        if sorted.windows(2).any(|pair| overlaps(pair[0], pair[1])) {
+            return Err(invalid("Mapped files overlap"));
+        }
+        let _gate = self.gate.write().unwrap();
+        self.check_not_moving()?;
+        self.close_files();
+        self.place.lock().unwrap().paths = paths;
+        Ok(())
+    }
+
+    // Paths stay put while a move copies the files
+    fn check_not_moving(&self) -> io::Result<()> {
+        match self.moving.lock().unwrap().is_some() {
+            true => Err(io::Error::other("The files are being moved")),
+            false => Ok(()),
+        }
+    }
+
+    fn close_files(&self) {
+        let mut open = self.open.lock().unwrap();
+        open.handles.clear();
+        open.order.clear();
+    }
+}
+
+// A path for a file of the torrent given by the user, made safe the same way
+// as the torrent's own names
+fn relative_path(name: &str) -> io::Result<PathBuf> {
+    let path: Option<PathBuf> = name
+        .split(['/', '\\'])
+        .filter(|component| !component.is_empty())
+        .map(sanitize)
+        .collect();
+    match path {
+        Some(path) if !path.as_os_str().is_empty() => Ok(path),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid file name {:?}", name),
+        )),
+    }
+}
+
+// Two files can't be at the same path or one below the other
+fn overlaps(a: &Path, b: &Path) -> bool {
+    a.starts_with(b) || b.starts_with(a)
+}
+
+// Renames, or copies and deletes when that fails, like across file systems
+// or drives
+fn move_file(from: &Path, to: &Path, moved: &mut dyn FnMut(u64)) -> io::Result<()> {
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let length = fs::metadata(from)?.len();
+    if fs::rename(from, to).is_ok() {
+        moved(length);
+        return Ok(());
+    }
+    copy_and_remove(from, to, moved)
+}
+
+fn copy_and_remove(from: &Path, to: &Path, moved: &mut dyn FnMut(u64)) -> io::Result<()> {
+    if let Err(e) = copy_file(from, to, moved) {
+        fs::remove_file(to).ok();
+        return Err(e);
+    }
+    fs::remove_file(from)
+}
+
+// Puts `from` at `tmp` as well, leaving it where it is. A hard link where
+// both are on the same file system, it costs nothing and shares every later
+// write. Returns whether the data had to be copied instead.
+fn stage_file(from: &Path, tmp: &Path, moved: &mut dyn FnMut(u64)) -> io::Result<bool> {
+    if let Some(dir) = tmp.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Left over from a move that was interrupted
+    fs::remove_file(tmp).ok();
+    let length = fs::metadata(from)?.len();
+    if fs::hard_link(from, tmp).is_ok() {
+        moved(length);
+        return Ok(false);
+    }
+    if let Err(e) = copy_file(from, tmp, moved) {
+        fs::remove_file(tmp).ok();
+        return Err(e);
+    }
+    Ok(true)
+}
+
+// Brings a copy up to date with the ranges written to the original since it
+// was made. The original may also have grown in the meantime.
+fn replay(from: &Path, tmp: &Path, ranges: &[(u64, u64)]) -> io::Result<()> {
+    let source = File::open(from)?;
+    let target = OpenOptions::new().write(true).open(tmp)?;
+    let metadata = source.metadata()?;
+    target.set_len(metadata.len())?;
+    let mut buf = Vec::new();
+    for (offset, length) in ranges {
+        buf.resize(*length as usize, 0);
+        read_at(&source, &mut buf, *offset)?;
+        write_at(&target, &buf, *offset)?;
+    }
+    target.set_modified(metadata.modified()?)?;
+    target.sync_all()
+}
+
+// Undoes the staging of a failed move, the first `renamed` files already
+// made it to their final name
+fn discard(root: &Path, staged: &[Staged], renamed: usize) {
+    for (i, file) in staged.iter().enumerate() {
+        let path = if i < renamed { &file.to } else { &file.tmp };
+        if let Err(e) = fs::remove_file(path) {
+            println!("Failed to remove {}: {}", path.display(), e);
+        }
+        remove_empty_dirs(root, path);
+    }
+}
+
+// Keeps the modification time, resume data compares it
+fn copy_file(from: &Path, to: &Path, copied: &mut dyn FnMut(u64)) -> io::Result<()> {
+    let mut source = File::open(from)?;
+    let mut target = File::create(to)?;
+    let mut buf = vec![0u8; COPY_CHUNK];
+    loop {
+        let n = match source.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        target.write_all(&buf[..n])?;
+        copied(n as u64);
+    }
+    let metadata = source.metadata()?;
+    target.set_permissions(metadata.permissions())?;
+    target.set_modified(metadata.modified()?)?;
+    target.sync_all()
+}
+
+// Removes the directories a moved file leaves empty, up to the root
+fn remove_empty_dirs(root: &Path, file: &Path) {
+    let mut dir = file.parent();
+    while let Some(path) = dir {
+        if path == root || !path.starts_with(root) || fs::remove_dir(path).is_err() {
+            break;
+        }
+        dir = path.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{layout, TempDir};
+    use super::super::{IoPool, Preallocation};
+    use super::*;
+    use crate::backend::resume::ResumeData;
+
+    const DATA: &[u8] = b"0123456789abcdefghij";
+
+    // Two files in a folder and one beside it, all in one piece
+    async fn storage(root: PathBuf) -> Storage {
+        let layout = layout(32, &[("d/a", 10), ("d/b", 5), ("c", 5)]);
+        let storage = Storage::new(root, layout, Preallocation::Sparse, IoPool::new(1));
+        storage.allocate().await.unwrap();
+        storage.write_piece(0, DATA.to_vec()).await.unwrap();
+        storage.flush().await.unwrap();
+        storage
+    }
+
+    async fn move_to(storage: &Storage, root: &Path, policy: ConflictPolicy) -> io::Result<bool> {
+        storage.move_to(root.to_path_buf(), policy, |_| {}).await
+    }
+
+    #[tokio::test]
+    async fn conflict_policies() {
+        let dir = TempDir::new("relocate-conflicts");
+        for (policy, name) in [
+            (ConflictPolicy::Fail, "fail"),
+            (ConflictPolicy::Keep, "keep"),
+            (ConflictPolicy::Replace, "replace"),
+        ] {
+            let (from, to) = (dir.0.join(name), dir.0.join(format!("{}-to", name)));
+            let storage = storage(from.clone()).await;
+            fs::create_dir_all(to.join("t/d")).unwrap();
+            fs::write(to.join("t/d/a"), b"theirs").unwrap();
+
+            let result = move_to(&storage, &to, policy).await;
+            let theirs = fs::read(to.join("t/d/a")).unwrap();
+            match policy {
+                ConflictPolicy::Fail => {
+                    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+                    assert_eq!(storage.root(), from);
+                    assert_eq!(theirs, b"theirs");
+                    assert!(!to.join("t/d/b").exists());
+                }
+                ConflictPolicy::Keep => {
+                    // Their file has to be checked again, ours stays behind
+                    assert!(result.unwrap());
+                    assert_eq!(storage.root(), to);
+                    assert_eq!(theirs, b"theirs");
+                    assert_eq!(fs::read(from.join("t/d/a")).unwrap(), &DATA[..10]);
+                    assert!(!from.join("t/d/b").exists());
+                }
+                ConflictPolicy::Replace => {
+                    assert!(!result.unwrap());
+                    assert_eq!(storage.root(), to);
+                    assert_eq!(theirs, &DATA[..10]);
+                    assert!(!from.join("t").exists());
+                }
+            }
+            assert_eq!(fs::read(storage.file_path(2)).unwrap(), &DATA[15..]);
+        }
+    }
+
+    #[test]
+    fn copy_and_delete_when_rename_fails() {
+        let dir = TempDir::new("relocate-copy");
+        let (from, to) = (dir.0.join("from"), dir.0.join("to"));
+        fs::write(&from, DATA).unwrap();
+        let modified = fs::metadata(&from).unwrap().modified().unwrap();
+        let mut moved = 0;
+        copy_and_remove(&from, &to, &mut |bytes| moved += bytes).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), DATA);
+        assert_eq!(moved, DATA.len() as u64);
+        // Resume data compares it
+        assert_eq!(fs::metadata(&to).unwrap().modified().unwrap(), modified);
+    }
+
+    // Where /dev/shm is a file system of its own, a whole move across it has
+    // to copy
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn move_across_file_systems() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = TempDir::new("relocate-across");
+        let shm = Path::new("/dev/shm");
+        let other = match fs::metadata(shm) {
+            Ok(metadata) => metadata.dev() != fs::metadata(&dir.0).unwrap().dev(),
+            Err(_) => false,
+        };
+        if !other {
+            return;
+        }
+        let target = TempDir(shm.join(format!("defttorrent-across-{}", std::process::id())));
+        let storage = storage(dir.0.join("from")).await;
+        assert!(!move_to(&storage, &target.0, ConflictPolicy::Fail)
+            .await
+            .unwrap());
+        assert!(!dir.0.join("from/t").exists());
+        assert_eq!(storage.read_piece(0).await.unwrap(), DATA);
+    }
+
+    #[tokio::test]
+    async fn rename_file_and_folder() {
+        let dir = TempDir::new("relocate-rename");
+        let storage = storage(dir.0.clone()).await;
+
+        storage.rename_file(2, "t/e/c2".to_string()).await.unwrap();
+        assert_eq!(storage.file_path(2), dir.0.join("t/e/c2"));
+        assert!(!dir.0.join("t/c").exists());
+        // Another file's path, or one below it, is taken
+        for taken in ["t/d/a", "t/d/a/x"] {
+            let error = storage.rename_file(2, taken.to_string()).await.unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        }
+
+        storage
+            .rename_folder("t/d".to_string(), "t/f/g".to_string())
+            .await
+            .unwrap();
+        assert_eq!(storage.file_path(0), dir.0.join("t/f/g/a"));
+        assert_eq!(storage.file_path(1), dir.0.join("t/f/g/b"));
+        assert!(!dir.0.join("t/d").exists());
+        let error = storage
+            .rename_folder("t/f".to_string(), "t/f/g/h".to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(storage.read_piece(0).await.unwrap(), DATA);
+    }
+
+    #[tokio::test]
+    async fn renames_survive_resume_data() {
+        let dir = TempDir::new("relocate-resume");
+        let storage = storage(dir.0.clone()).await;
+        assert!(storage.mapped_files().is_empty());
+        storage.rename_file(0, "t/a1".to_string()).await.unwrap();
+        storage
+            .rename_folder("t/d".to_string(), "t/x".to_string())
+            .await
+            .unwrap();
+        let mapped = storage.mapped_files();
+        assert_eq!(mapped, vec!["t/a1", "t/x/b", "t/c"]);
+
+        let resume = ResumeData {
+            id: 0,
+            info_hash: [0; 20],
+            save_path: dir.0.clone(),
+            pieces: vec![0x80],
+            unfinished: Vec::new(),
+            file_priorities: Vec::new(),
+            file_sizes: Vec::new(),
+            mapped_files: mapped,
+            uploaded: 0,
+            downloaded: 0,
+            added_time: 0,
+            trackers: Vec::new(),
+            peers: Vec::new(),
+            upload_slots: None,
+            sequential: false,
+        };
+        let decoded = ResumeData::decode(&resume.encode()).unwrap();
+
+        let layout = layout(32, &[("d/a", 10), ("d/b", 5), ("c", 5)]);
+        let restored = Storage::new(dir.0.clone(), layout, Preallocation::Sparse, IoPool::new(1));
+        restored.set_mapped_files(&decoded.mapped_files).unwrap();
+        assert_eq!(restored.file_paths(), storage.file_paths());
+        assert_eq!(restored.read_piece(0).await.unwrap(), DATA);
+
+        let overlapping = vec!["t/a".to_string(), "t/a/b".to_string(), "t/c".to_string()];
+        assert!(restored.set_mapped_files(&overlapping).is_err());
+        assert!(restored
+            .set_mapped_files(&decoded.mapped_files[..2])
+            .is_err());
+    }
+}
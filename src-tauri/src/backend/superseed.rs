@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use super::bitfield::Bitfield;
+use crate::requests::peer::message::Message;
+
+#[derive(Debug)]
+struct SeedPeer {
+    has: Bitfield,
+    // The one piece we revealed to this peer and are waiting to see spread
+    offered: Option<u32>,
+}
+
+// BEP 16 super-seeding for the initial seed of a torrent. Peers are told we
+// have nothing and are then shown one piece each, the rarest we can find. A
+// peer only gets its next piece once some other peer announces the last one,
+// so our upload goes to peers that pass pieces on.
+#[derive(Debug)]
+pub struct SuperSeeder {
+    num_pieces: usize,
+    peers: HashMap<SocketAddr, SeedPeer>,
+    // Copies of each piece among connected peers
+    availability: Vec<u32>,
+    // How often each piece has been revealed, to spread offers evenly
+    offers: Vec<u32>,
+    salt: Vec<u32>,
+}
+
+impl SuperSeeder {
+    pub fn new(num_pieces: usize) -> Self {
+        SuperSeeder {
+            num_pieces,
+            peers: HashMap::new(),
+            availability: vec![0; num_pieces],
+            offers: vec![0; num_pieces],
+            salt: (0..num_pieces).map(|_| rand::random()).collect(),
+        }
+    }
+
+    // Sent instead of our real bitfield: nothing at all, then the first piece
+    pub fn on_connected(&mut self, addr: SocketAddr, fast: bool) -> Vec<Message> {
+        self.peers.insert(
+            addr,
+            SeedPeer {
+                has: Bitfield::new(self.num_pieces),
+                offered: None,
+            },
+        );
+        let mut messages = Vec::new();
+        if fast {
+            messages.push(Message::HaveNone);
+        }
+        messages.extend(self.offer(addr));
+        messages
+    }
+
+    pub fn on_disconnected(&mut self, addr: SocketAddr) {
+        if let Some(peer) = self.peers.remove(&addr) {
+            for index in peer.has.ones() {
+                self.availability[index] -= 1;
+            }
+        }
+    }
+
+    // The peer's bitfield, HaveAll or HaveNone. If it already has what we
+    // offered it gets something else.
+    pub fn on_bitfield(&mut self, addr: SocketAddr, has: &Bitfield) -> Option<Message> {
+        let peer = self.peers.get_mut(&addr)?;
+        for index in peer.has.ones() {
+            self.availability[index] -= 1;
+        }
+        for index in has.ones() {
+            self.availability[index] += 1;
+        }
+        peer.has = has.clone();
+        if peer.offered.is_some_and(|index| has.has(index as usize)) {
+            peer.offered = None;
+        }
+        self.offer(addr)
+    }
+
+    // A Have from `addr`. Returns the messages to send, to whichever peer,
+    // as a result: every other peer we offered this piece to has now shown
+    // it passed it on and is offered the next one.
+    pub fn on_have(&mut self, addr: SocketAddr, index: u32) -> Vec<(SocketAddr, Message)> {
+        let i = index as usize;
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return Vec::new();
+        };
+        if i >= self.num_pieces || peer.has.has(i) {
+            return Vec::new();
+        }
+        peer.has.set(i);
+        self.availability[i] += 1;
+
+        let mut spread: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|(other, peer)| **other != addr && peer.offered == Some(index))
+            .map(|(other, _)| *other)
+            .collect();
+        // The peer finished its own piece but nobody left lacks it, waiting
+        // for it to spread would stall the peer for good
+        let others_lack = self
+            .peers
+            .iter()
+            .any(|(other, peer)| *other != addr && !peer.has.has(i));
+        if self.peers[&addr].offered == Some(index) && !others_lack {
+            spread.push(addr);
+        }
+        let mut messages = Vec::new();
+        for other in spread {
+            if let Some(peer) = self.peers.get_mut(&other) {
+                peer.offered = None;
+            }
+            messages.extend(self.offer(other).into_iter().map(|m| (other, m)));
+        }
+        messages
+    }
+
+    // Requests are only served for the piece we revealed to that peer
+    pub fn may_upload(&self, addr: &SocketAddr, index: u32) -> bool {
+        self.peers
+            .get(addr)
+            .is_some_and(|peer| peer.offered == Some(index))
+    }
+
+    pub fn offered(&self, addr: &SocketAddr) -> Option<u32> {
+        self.peers.get(addr).and_then(|peer| peer.offered)
+    }
+
+    // Every piece is out there at least once, super-seeding has done its job
+    // and the caller can switch to normal seeding.
+    pub fn is_distributed(&self) -> bool {
+        self.availability.iter().all(|count| *count > 0)
+    }
+
+    // Reveals the rarest piece the peer lacks, preferring ones shown to few
+    // other peers. Nothing when it already has an offer outstanding or is a
+    // seed itself.
+    fn offer(&mut self, addr: SocketAddr) -> Option<Message> {
+        let peer = self.peers.get(&addr)?;
+        if peer.offered.is_some() || peer.has.is_complete() {
+            return None;
+        }
+        let index = (0..self.num_pieces)
+            .filter(|index| !peer.has.has(*index))
+            .min_by_key(|index| {
+                (
+                    self.availability[*index],
+                    self.offers[*index],
+                    self.salt[*index],
+                )
+            })?;
+        self.offers[index] += 1;
+        self.peers.get_mut(&addr)?.offered = Some(index as u32);
+        Some(Message::Have(index as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], n))
+    }
+
+    fn revealed(messages: &[Message]) -> Vec<u32> {
+        messages
+            .iter()
+            .filter_map(|m| match m {
+                Message::Have(index) => Some(*index),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn one_piece_revealed_per_peer() {
+        let mut seeder = SuperSeeder::new(4);
+        let first = seeder.on_connected(peer(1), true);
+        assert_eq!(first[0], Message::HaveNone);
+        assert_eq!(revealed(&first).len(), 1);
+        let second = revealed(&seeder.on_connected(peer(2), false));
+        assert_eq!(second.len(), 1);
+        // Each peer is shown a different piece
+        assert_ne!(revealed(&first), second);
+        assert_eq!(seeder.offered(&peer(2)), Some(second[0]));
+    }
+
+    #[test]
+    fn next_piece_once_another_peer_has_it() {
+        let mut seeder = SuperSeeder::new(4);
+        let a = revealed(&seeder.on_connected(peer(1), true))[0];
+        let b = revealed(&seeder.on_connected(peer(2), true))[0];
+
+        // Downloading it isn't enough, it has to reach someone else
+        assert!(seeder.on_have(peer(1), a).is_empty());
+        assert_eq!(seeder.offered(&peer(1)), Some(a));
+        // Another peer announcing something else changes nothing either
+        assert!(seeder.on_have(peer(2), b).is_empty());
+
+        let messages = seeder.on_have(peer(2), a);
+        assert_eq!(messages.len(), 1);
+        let (to, Message::Have(next)) = messages[0] else {
+            panic!("Expected a have");
+        };
+        assert_eq!(to, peer(1));
+        assert!(next != a);
+        assert_eq!(seeder.offered(&peer(1)), Some(next));
+        assert_eq!(seeder.offered(&peer(2)), Some(b));
+    }
+
+    #[test]
+    fn uploads_only_the_offered_piece() {
+        let mut seeder = SuperSeeder::new(4);
+        let a = revealed(&seeder.on_connected(peer(1), true))[0];
+        let b = revealed(&seeder.on_connected(peer(2), true))[0];
+        assert!(seeder.may_upload(&peer(1), a));
+        assert!(!seeder.may_upload(&peer(1), b));
+        assert!((0..4).filter(|i| seeder.may_upload(&peer(2), *i)).eq([b]));
+        assert!(!seeder.may_upload(&peer(3), a));
+    }
+}
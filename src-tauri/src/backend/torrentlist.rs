@@ -5,37 +5,542 @@ use std::net::{SocketAddr, ToSocketAddrs};
 use tokio::net::UdpSocket;
 use tokio::time::{timeout, Duration};
 
+use serde::Serialize;
+use std::fs;
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::bitfield::Bitfield;
 use super::file;
+use super::picker::{FilePriority, PiecePicker};
+use super::resume::{self, ResumeData};
+use super::session::swarm::Swarm;
+use super::settings::Settings;
+use super::storage::backend;
+use super::storage::cache::DiskCache;
+use super::storage::pool::IoPool;
+use super::storage::recheck::{self, RecheckHandle};
+use super::storage::{FileLayout, Storage};
+use super::verify::{HashPool, PieceVerifier};
+use crate::requests::dht::storage::to_hex;
+use crate::requests::peer::manager::PeerSource;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileStatus {
+    pub path: String,
+    pub length: u64,
+    // Verified bytes
+    pub done: u64,
+    pub priority: FilePriority,
+}
 
 #[derive(Debug)]
 pub struct TorrentItem {
-    object: file::Torrent,
+    pub object: file::Torrent,
     status: String,
     id: usize,
+    pub storage: Storage,
+    pub verifier: PieceVerifier,
+    pub picker: PiecePicker,
+    file_priorities: Vec<FilePriority>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    // Unix time
+    added_time: i64,
+    pub trackers: Vec<Vec<String>>,
+    // Known peers, handed back to the connection manager after a restart
+    peers: Vec<SocketAddr>,
+    recheck: Option<RecheckHandle>,
+    // Connected peers, driven by the session
+    pub swarm: Swarm,
+}
+
+impl TorrentItem {
+    fn resume_data(&self) -> ResumeData {
+        ResumeData {
+            id: self.id,
+            info_hash: self.object.info_hash,
+            save_path: self.storage.root(),
+            pieces: self.picker.have().as_bytes().to_vec(),
+            unfinished: self
+                .picker
+                .received_blocks()
+                .into_iter()
+                .map(|(index, blocks)| (index, blocks.as_bytes().to_vec()))
+                .collect(),
+            file_priorities: self.file_priorities.clone(),
+            // Read from disk when the data is written, not under the lock
+            file_sizes: Vec::new(),
+            mapped_files: self.storage.mapped_files(),
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            added_time: self.added_time,
+            trackers: self.trackers.clone(),
+            peers: self.peers.clone(),
+            upload_slots: self.swarm.upload_slots(),
+            sequential: self.picker.is_sequential(),
+        }
+    }
+
+    // Picks up where the resume data left off. False when the files changed
+    // since it was saved, the data on disk then has to be checked again.
+    fn apply_resume_data(&mut self, resume: &ResumeData) -> bool {
+        let files = self.storage.layout().files.len();
+        // Before anything looks at the files
+        if let Err(e) = self.storage.set_mapped_files(&resume.mapped_files) {
+            println!("Ignoring renamed files of torrent {}: {}", self.id, e);
+        }
+        if resume.file_priorities.len() == files {
+            self.file_priorities = resume.file_priorities.clone();
+            self.update_picker_priorities();
+            self.storage.restore_file_priorities(&self.file_priorities);
+        }
+        self.uploaded = resume.uploaded;
+        self.downloaded = resume.downloaded;
+        self.added_time = resume.added_time;
+        if !resume.trackers.is_empty() {
+            self.trackers = resume.trackers.clone();
+        }
+        self.peers = resume.peers.clone();
+        self.swarm
+            .manager
+            .lock()
+            .unwrap()
+            .add_peers(&self.peers, PeerSource::Resume);
+        self.swarm.set_upload_slots(resume.upload_slots);
+        self.picker.set_sequential(resume.sequential);
+
+        if resume.file_sizes.len() != files
+            || resume.file_sizes != resume::file_sizes(&self.storage)
+        {
+            return false;
+        }
+        let Ok(have) = Bitfield::from_bytes(&resume.pieces, self.picker.num_pieces()) else {
+            return false;
+        };
+        self.picker.set_have(have);
+        for (index, blocks) in &resume.unfinished {
+            let count = self.picker.block_count(*index);
+            if let Ok(blocks) = Bitfield::from_bytes(blocks, count) {
+                self.picker.restore_blocks(*index, &blocks);
+            }
+        }
+        true
+    }
+
+    // Applies the hash check of a downloaded piece, banning whoever keeps
+    // sending bad data
+    pub fn piece_checked(&mut self, index: u32, passed: bool) {
+        let banned = self.verifier.on_checked(
+            index,
+            passed,
+            &mut self.picker,
+            &mut self.swarm.manager.lock().unwrap(),
+        );
+        self.swarm
+            .on_checked(index, passed, &banned, &mut self.picker);
+        if passed {
+            self.status = self.progress_status();
+        }
+    }
+
+    fn update_picker_priorities(&mut self) {
+        let lengths: Vec<u64> = self
+            .storage
+            .layout()
+            .files
+            .iter()
+            .map(|file| file.length)
+            .collect();
+        self.picker
+            .set_file_priorities(&lengths, &self.file_priorities);
+    }
+
+    // Only pieces of wanted files count, a torrent with every wanted file
+    // done is complete
+    fn progress_status(&self) -> String {
+        let wanted: Vec<u32> = (0..self.picker.num_pieces() as u32)
+            .filter(|index| self.picker.piece_priority(*index) != FilePriority::Skip)
+            .collect();
+        let done = wanted
+            .iter()
+            .filter(|index| self.picker.have().has(**index as usize))
+            .count();
+        format!("{} of {} pieces complete", done, wanted.len())
+    }
 }
 
 pub struct TorrentList {
     pub list: HashMap<usize, TorrentItem>,
+    disk: IoPool,
+    hasher: HashPool,
+    cache: DiskCache,
+    // Where resume data goes, None keeps the session in memory only
+    resume_dir: Option<PathBuf>,
+    // One thread, so saves and removals of a torrent land in order
+    resume_writer: IoPool,
 }
 
 impl TorrentList {
-    pub fn new() -> TorrentList {
+    pub fn new(disk: IoPool, hasher: HashPool, cache: DiskCache) -> TorrentList {
         TorrentList {
             list: HashMap::new(),
+            disk,
+            hasher,
+            cache,
+            resume_dir: resume::resume_dir(),
+            resume_writer: IoPool::named(1, "resume"),
         }
     }
 
-    pub fn push_with_id_and_url(&mut self, id: usize, url: String) {
-        let data = file::read_torrent_file(&url).unwrap();
+    pub fn push_with_id_and_url(&mut self, id: usize, url: String) -> Result<(), String> {
+        let bytes = fs::read(&url).map_err(|e| format!("Failed to read {}: {}", url, e))?;
+        let data = file::torrent_from_bytes(&bytes)?;
+        let item = self.new_item(id, data, Settings::load().download_path())?;
+        self.list.insert(id, item);
+        self.save_resume_data(&id, Some(&bytes));
+        Ok(())
+    }
+
+    fn new_item(
+        &self,
+        id: usize,
+        data: file::Torrent,
+        save_path: PathBuf,
+    ) -> Result<TorrentItem, String> {
+        let layout = FileLayout::from_info(&data.info)?;
+        // Next to the torrent's files, not among them
+        let part_path = save_path.join(format!(".{}.parts", to_hex(&data.info_hash)));
+        let settings = Settings::load();
+        let storage = Storage::new(save_path, layout, settings.preallocation, self.disk.clone())
+            .with_backend(backend::from_kind(
+                settings.storage_backend,
+                settings.preallocation,
+            ))
+            .with_part_file(part_path);
+        let verifier = PieceVerifier::from_info(&data.info, self.hasher.clone());
+        let picker = PiecePicker::new(
+            data.info.pieces.len(),
+            data.info.piece_length as u32,
+            storage.layout().total_length(),
+        );
+        let trackers = match data.announce.as_str() {
+            "" => Vec::new(),
+            announce => vec![vec![announce.to_string()]],
+        };
+        let added_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as i64);
         let status = "Test Message Hello".to_string();
-        self.list.insert(
+        Ok(TorrentItem {
+            file_priorities: vec![FilePriority::Normal; storage.layout().files.len()],
+            object: data,
+            status,
             id,
-            TorrentItem {
-                object: data,
-                status,
-                id,
-            },
+            storage,
+            verifier,
+            picker,
+            uploaded: 0,
+            downloaded: 0,
+            added_time,
+            trackers,
+            peers: Vec::new(),
+            recheck: None,
+            swarm: Swarm::new(),
+        })
+    }
+
+    // Brings back the torrents of the last session. Returns the ones whose
+    // files changed while we were away, those need a recheck.
+    pub fn restore(&mut self) -> Vec<usize> {
+        let Some(dir) = self.resume_dir.clone() else {
+            return Vec::new();
+        };
+        let mut changed = Vec::new();
+        for (resume, bytes) in resume::load_all(&dir) {
+            if self.list.contains_key(&resume.id) {
+                continue;
+            }
+            let item = file::torrent_from_bytes(&bytes)
+                .map_err(str::to_string)
+                .and_then(|data| {
+                    if data.info_hash != resume.info_hash {
+                        return Err("Info hash doesn't match".to_string());
+                    }
+                    self.new_item(resume.id, data, resume.save_path.clone())
+                });
+            let mut item = match item {
+                Ok(item) => item,
+                Err(e) => {
+                    println!("Failed to restore torrent {}: {}", resume.id, e);
+                    continue;
+                }
+            };
+            if item.apply_resume_data(&resume) {
+                item.status = item.progress_status();
+                self.restore_cached_blocks(&item);
+            } else if item.storage.any_file_exists() {
+                changed.push(resume.id);
+            }
+            self.list.insert(resume.id, item);
+        }
+        changed
+    }
+
+    // The cache completes pieces by the blocks it saw, so it is told about
+    // the ones of unfinished pieces already on disk
+    fn restore_cached_blocks(&self, item: &TorrentItem) {
+        for (index, received) in item.picker.received_blocks() {
+            let blocks: Vec<(u32, u32)> = received
+                .ones()
+                .map(|i| item.picker.block(index, i))
+                .map(|block| (block.begin, block.length))
+                .collect();
+            self.cache.restore(&item.storage, index, &blocks);
+        }
+    }
+
+    // Writes the torrent's resume data, along with its .torrent file when
+    // given. The state is taken now and written on the resume thread, so
+    // the list is never locked across a sync. Failing to save only costs a
+    // recheck later, so it is logged.
+    pub fn save_resume_data(&self, id: &usize, torrent: Option<&[u8]>) {
+        let (Some(dir), Some(item)) = (self.resume_dir.clone(), self.list.get(id)) else {
+            return;
+        };
+        let (id, mut data, storage) = (*id, item.resume_data(), item.storage.clone());
+        let torrent = torrent.map(<[u8]>::to_vec);
+        self.resume_writer.spawn(move || {
+            data.file_sizes = resume::file_sizes(&storage);
+            if let Err(e) = resume::save(&dir, &data, torrent.as_deref()) {
+                println!("Failed to save resume data of torrent {}: {}", id, e);
+            }
+        });
+    }
+
+    // Resolves once every torrent's resume data, and anything queued before
+    // it, is on disk. Await it without the list locked.
+    pub fn save_all(&self) -> impl Future<Output = ()> + Send + 'static {
+        for id in self.list.keys() {
+            self.save_resume_data(id, None);
+        }
+        let writer = self.resume_writer.clone();
+        async move {
+            writer.run(|| Ok(())).await.ok();
+        }
+    }
+
+    pub fn cache(&self) -> &DiskCache {
+        &self.cache
+    }
+
+    pub fn storage(&self, id: &usize) -> Option<Storage> {
+        self.list.get(id).map(|item| item.storage.clone())
+    }
+
+    pub fn info_hashes(&self) -> Vec<[u8; 20]> {
+        self.list
+            .values()
+            .map(|item| item.object.info_hash)
+            .collect()
+    }
+
+    pub fn find(&self, info_hash: &[u8; 20]) -> Option<usize> {
+        self.list
+            .iter()
+            .find(|(_, item)| &item.object.info_hash == info_hash)
+            .map(|(id, _)| *id)
+    }
+
+    // None goes back to the upload slots per torrent from the settings
+    pub fn set_upload_slots(&mut self, id: &usize, slots: Option<usize>) -> Result<(), String> {
+        let item = self.list.get_mut(id).ok_or("No such torrent")?;
+        item.swarm.set_upload_slots(slots);
+        self.save_resume_data(id, None);
+        Ok(())
+    }
+
+    // In order instead of rarest first, for playing media while it downloads
+    pub fn set_sequential(&mut self, id: &usize, sequential: bool) -> Result<(), String> {
+        let item = self.list.get_mut(id).ok_or("No such torrent")?;
+        item.picker.set_sequential(sequential);
+        self.save_resume_data(id, None);
+        Ok(())
+    }
+
+    pub fn storages(&self) -> Vec<Storage> {
+        self.list
+            .values()
+            .map(|item| item.storage.clone())
+            .collect()
+    }
+
+    pub fn remove(&mut self, id: &usize) {
+        let Some(item) = self.list.remove(id) else {
+            return;
+        };
+        if let Some(recheck) = &item.recheck {
+            recheck.cancel();
+        }
+        self.cache.forget(&item.storage);
+        if let Some(dir) = self.resume_dir.clone() {
+            let (id, info_hash) = (*id, item.object.info_hash);
+            self.resume_writer.spawn(move || {
+                if let Err(e) = resume::remove(&dir, &info_hash) {
+                    println!("Failed to remove resume data of torrent {}: {}", id, e);
+                }
+            });
+        }
+    }
+
+    // Added torrents pointed at data that is already there get checked
+    // before anything is downloaded
+    pub fn has_existing_data(&self, id: &usize) -> bool {
+        self.list
+            .get(id)
+            .is_some_and(|item| item.storage.any_file_exists())
+    }
+
+    // Starts hashing the data on disk, replacing a recheck already running.
+    // The caller runs the returned future and hands its result to
+    // `finish_recheck`.
+    pub fn start_recheck(
+        &mut self,
+        id: &usize,
+    ) -> Result<
+        (
+            RecheckHandle,
+            impl Future<Output = io::Result<Option<Bitfield>>> + Send + 'static,
+        ),
+        String,
+    > {
+        let item = self.list.get_mut(id).ok_or("No such torrent")?;
+        if let Some(running) = item.recheck.take() {
+            running.cancel();
+        }
+        let (handle, task) = recheck::recheck(
+            item.storage.clone(),
+            item.verifier.hashes(),
+            item.verifier.pool().clone(),
         );
+        item.recheck = Some(handle.clone());
+        item.status = "Checking".to_string();
+        Ok((handle, task))
+    }
+
+    pub fn recheck_handle(&self, id: &usize) -> Option<&RecheckHandle> {
+        self.list.get(id).and_then(|item| item.recheck.as_ref())
+    }
+
+    // `handle` tells apart the recheck that finished from a newer one
+    // started in the meantime
+    pub fn finish_recheck(
+        &mut self,
+        id: &usize,
+        handle: &RecheckHandle,
+        result: io::Result<Option<Bitfield>>,
+    ) {
+        let Some(item) = self.list.get_mut(id) else {
+            return;
+        };
+        if !item.recheck.as_ref().is_some_and(|r| r.same(handle)) {
+            return;
+        }
+        item.recheck = None;
+        match result {
+            Ok(Some(have)) => {
+                item.picker.set_have(have);
+                item.status = item.progress_status();
+                self.save_resume_data(id, None);
+            }
+            Ok(None) => item.status = "Check cancelled".to_string(),
+            Err(e) => {
+                println!("Recheck of torrent {} failed: {}", id, e);
+                item.status = format!("Check failed: {}", e);
+            }
+        }
+    }
+
+    pub fn file_status(&self, id: &usize) -> Option<Vec<FileStatus>> {
+        let item = self.list.get(id)?;
+        let layout = item.storage.layout();
+        Some(
+            layout
+                .files
+                .iter()
+                .zip(item.storage.file_paths())
+                .zip(layout.file_progress(item.picker.have()))
+                .zip(&item.file_priorities)
+                .map(|(((file, path), done), priority)| FileStatus {
+                    path: path.to_string_lossy().into_owned(),
+                    length: file.length,
+                    done,
+                    priority: *priority,
+                })
+                .collect(),
+        )
+    }
+
+    // The picker follows the new priorities right away. Moving data out of
+    // the part file is left to the returned future, to run without the
+    // list locked and followed by `save_resume_data`.
+    pub fn set_file_priorities(
+        &mut self,
+        id: &usize,
+        priorities: Vec<FilePriority>,
+    ) -> Result<impl Future<Output = io::Result<()>> + Send + 'static, String> {
+        let item = self.list.get_mut(id).ok_or("No such torrent")?;
+        if priorities.len() != item.file_priorities.len() {
+            return Err(format!(
+                "Expected {} file priorities, got {}",
+                item.file_priorities.len(),
+                priorities.len()
+            ));
+        }
+        item.file_priorities = priorities;
+        item.update_picker_priorities();
+        if item.recheck.is_none() {
+            item.status = item.progress_status();
+        }
+        Ok(item.storage.set_file_priorities(&item.file_priorities))
+    }
+
+    pub fn set_file_priority(
+        &mut self,
+        id: &usize,
+        file: usize,
+        priority: FilePriority,
+    ) -> Result<impl Future<Output = io::Result<()>> + Send + 'static, String> {
+        let item = self.list.get(id).ok_or("No such torrent")?;
+        let mut priorities = item.file_priorities.clone();
+        *priorities.get_mut(file).ok_or("No such file")? = priority;
+        self.set_file_priorities(id, priorities)
+    }
+
+    // Marks the torrent as moving and hands out its storage for `move_to`,
+    // which runs without the list locked
+    pub fn start_move(&mut self, id: &usize) -> Result<Storage, String> {
+        let item = self.list.get_mut(id).ok_or("No such torrent")?;
+        item.status = "Moving".to_string();
+        Ok(item.storage.clone())
+    }
+
+    // Takes the outcome of `move_to` and saves the new location. Returns
+    // whether the data has to be checked again.
+    pub fn finish_move(&mut self, id: &usize, result: io::Result<bool>) -> Result<bool, String> {
+        let item = self.list.get_mut(id).ok_or("No such torrent")?;
+        if item.recheck.is_none() {
+            item.status = item.progress_status();
+        }
+        match result {
+            Ok(recheck) => {
+                self.save_resume_data(id, None);
+                Ok(recheck)
+            }
+            Err(e) => Err(format!("Failed to move torrent {}: {}", id, e)),
+        }
     }
 
     pub fn get_status(&mut self, id: &usize) -> String {
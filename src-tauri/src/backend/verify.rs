@@ -0,0 +1,257 @@
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use super::file::TorrentInfo;
+use super::picker::PiecePicker;
+use super::rate::RateMeter;
+use super::storage::pool::IoPool;
+use crate::requests::peer::manager::ConnectionManager;
+
+// Trust a peer earns per good piece it helped with and loses per bad one.
+// Pieces usually come from several peers, so an honest peer that shared a
+// bad piece with a liar has earned enough by then not to be banned with it.
+const TRUST_PASSED: i32 = 1;
+const TRUST_FAILED: i32 = 2;
+const MAX_TRUST: i32 = 8;
+const BAN_TRUST: i32 = -7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceHash {
+    // v1 torrents, from `TorrentInfo.pieces`
+    Sha1([u8; 20]),
+    // v2 torrents, the piece layer hashes
+    Sha256([u8; 32]),
+}
+
+impl PieceHash {
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            PieceHash::Sha1(hash) => Sha1::digest(data).as_slice() == hash,
+            PieceHash::Sha256(hash) => Sha256::digest(data).as_slice() == hash,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyStats {
+    pub pieces_passed: u64,
+    pub pieces_failed: u64,
+    pub bytes_hashed: u64,
+    // Bytes per second
+    pub rate: f64,
+    // Pieces queued or being hashed right now
+    pub pending: usize,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    meter: RateMeter,
+    passed: u64,
+    failed: u64,
+    pending: usize,
+}
+
+// One piece in `Counters::pending` until dropped
+#[derive(Debug)]
+struct PendingJob(Arc<Mutex<Counters>>);
+
+impl Drop for PendingJob {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().pending -= 1;
+    }
+}
+
+// Threads hashing piece data, shared by every torrent. Hashing is CPU bound,
+// so it gets its own threads rather than holding up disk I/O.
+#[derive(Debug, Clone)]
+pub struct HashPool {
+    pool: IoPool,
+    counters: Arc<Mutex<Counters>>,
+}
+
+impl HashPool {
+    // Zero threads means one per CPU core
+    pub fn new(threads: usize) -> Self {
+        let threads = match threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        HashPool {
+            pool: IoPool::named(threads, "hasher"),
+            counters: Arc::new(Mutex::new(Counters::default())),
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.pool.threads()
+    }
+
+    pub async fn verify(&self, expected: PieceHash, data: Vec<u8>) -> io::Result<bool> {
+        self.counters.lock().unwrap().pending += 1;
+        let pending = PendingJob(self.counters.clone());
+        // Counted down on the pool thread, a job still runs when the caller
+        // stopped waiting for it. A job that never gets to run, because the
+        // pool is gone, counts down when it is dropped.
+        self.pool
+            .run(move || {
+                let passed = expected.matches(&data);
+                let mut counters = pending.0.lock().unwrap();
+                counters.meter.add(data.len() as u64);
+                if passed {
+                    counters.passed += 1;
+                } else {
+                    counters.failed += 1;
+                }
+                Ok(passed)
+            })
+            .await
+    }
+
+    pub fn stats(&self) -> VerifyStats {
+        let mut counters = self.counters.lock().unwrap();
+        counters.meter.update(Instant::now());
+        VerifyStats {
+            pieces_passed: counters.passed,
+            pieces_failed: counters.failed,
+            bytes_hashed: counters.meter.total(),
+            rate: counters.meter.rate(),
+            pending: counters.pending,
+        }
+    }
+}
+
+// Checks the completed pieces of one torrent and keeps track of who sent
+// them, so peers that keep sending bad data can be banned.
+#[derive(Debug)]
+pub struct PieceVerifier {
+    hashes: Arc<Vec<PieceHash>>,
+    pool: HashPool,
+    // Addresses that sent blocks of each piece not verified yet
+    contributors: HashMap<u32, HashSet<IpAddr>>,
+    trust: HashMap<IpAddr, i32>,
+}
+
+impl PieceVerifier {
+    pub fn new(hashes: Vec<PieceHash>, pool: HashPool) -> Self {
+        PieceVerifier {
+            hashes: Arc::new(hashes),
+            pool,
+            contributors: HashMap::new(),
+            trust: HashMap::new(),
+        }
+    }
+
+    pub fn from_info(info: &TorrentInfo, pool: HashPool) -> Self {
+        let hashes = info
+            .pieces
+            .iter()
+            .map(|hash| PieceHash::Sha1(*hash))
+            .collect();
+        PieceVerifier::new(hashes, pool)
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn hashes(&self) -> Arc<Vec<PieceHash>> {
+        self.hashes.clone()
+    }
+
+    pub fn pool(&self) -> &HashPool {
+        &self.pool
+    }
+
+    // Call for every block received, before the piece is verified
+    pub fn block_from(&mut self, index: u32, ip: IpAddr) {
+        self.contributors.entry(index).or_default().insert(ip);
+    }
+
+    // Hashes a complete piece on the pool. The future doesn't borrow the
+    // verifier, so blocks of other pieces can be recorded meanwhile.
+    pub fn check(
+        &self,
+        index: u32,
+        data: Vec<u8>,
+    ) -> impl Future<Output = io::Result<bool>> + Send + 'static {
+        let expected = self.hashes.get(index as usize).copied();
+        let pool = self.pool.clone();
+        async move {
+            let expected = expected.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Piece index out of range")
+            })?;
+            pool.verify(expected, data).await
+        }
+    }
+
+    // Applies the outcome of `check`. A failed piece is downloaded again and
+    // every peer that sent part of it loses trust. Returns the addresses
+    // banned because of it.
+    pub fn on_checked(
+        &mut self,
+        index: u32,
+        passed: bool,
+        picker: &mut PiecePicker,
+        manager: &mut ConnectionManager,
+    ) -> Vec<IpAddr> {
+        let contributors = self.contributors.remove(&index).unwrap_or_default();
+        if passed {
+            picker.piece_passed(index);
+            for ip in contributors {
+                let trust = self.trust.entry(ip).or_insert(0);
+                *trust = (*trust + TRUST_PASSED).min(MAX_TRUST);
+            }
+            return Vec::new();
+        }
+        picker.piece_failed(index);
+
+        let mut banned = Vec::new();
+        for ip in contributors {
+            let trust = self.trust.entry(ip).or_insert(0);
+            *trust -= TRUST_FAILED;
+            if *trust <= BAN_TRUST {
+                self.trust.remove(&ip);
+                manager.ban(ip);
+                banned.push(ip);
+            }
+        }
+        if !banned.is_empty() {
+            println!("Piece {} failed the hash check, banned {:?}", index, banned);
+        }
+        banned
+    }
+
+    pub fn trust(&self, ip: &IpAddr) -> i32 {
+        self.trust.get(ip).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pending_counts_down() {
+        let pool = HashPool::new(2);
+        let data = b"piece".to_vec();
+        let hash = PieceHash::Sha1(Sha1::digest(&data).into());
+        assert!(pool.verify(hash, data.clone()).await.unwrap());
+        assert!(!pool.verify(hash, b"other".to_vec()).await.unwrap());
+        let stats = pool.stats();
+        assert_eq!(stats.pending, 0);
+        assert_eq!((stats.pieces_passed, stats.pieces_failed), (1, 1));
+
+        // Dropped without ever running
+        pool.counters.lock().unwrap().pending += 1;
+        drop(PendingJob(pool.counters.clone()));
+        assert_eq!(pool.stats().pending, 0);
+    }
+}
@@ -1,17 +1,40 @@
 pub mod backend;
 pub mod requests;
 
+use backend::picker::FilePriority;
+use backend::session::Session;
+use backend::storage::cache::{CacheStats, DiskCache};
+use backend::storage::pool::IoPool;
+use backend::storage::recheck::{RecheckHandle, RecheckProgress};
+use backend::storage::relocate::{ConflictPolicy, MoveProgress};
+use backend::storage::Storage;
+use backend::torrentlist::{FileStatus, TorrentItem, TorrentList};
+use backend::verify::{HashPool, VerifyStats};
 use dirs::config_dir;
+use requests::dht::storage::{self, Item};
+use requests::dht::{Dht, DhtConfig};
+use requests::lsd::Lsd;
+use requests::portmap::{MappingStatus, PortMapConfig, PortMapper, Protocol};
+use requests::utp::UtpSocket;
+use serde::Serialize;
 use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
-use tauri::State;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, RunEvent, State};
 
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 
+// The frontend only sends the keys it knows about. They are merged into the
+// file, so the backend's settings in it survive.
 #[tauri::command]
 async fn store_settings(settings: String) -> Result<(), String> {
     println!("Storing Settings...");
+    let update: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&settings)
+        .map_err(|e| format!("Settings must be a JSON object: {}", e))?;
     let mut file_path = config_dir().unwrap();
     file_path.push("defttorrent");
     fs::create_dir_all(&file_path)
@@ -19,7 +42,12 @@ async fn store_settings(settings: String) -> Result<(), String> {
 
     file_path.push("settings.dft");
 
-    fs::write(file_path, settings)
+    let mut merged: serde_json::Map<String, serde_json::Value> = fs::read_to_string(&file_path)
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default();
+    merged.extend(update);
+    fs::write(file_path, serde_json::Value::Object(merged).to_string())
         .map_err(|e| format!("Failed to write settings to file: {}", e))?;
     Ok(())
 }
@@ -49,27 +77,600 @@ fn torrent_status(state: State<AppState>, id: usize) -> String {
 }
 
 #[tauri::command]
-fn add_torrent(state: State<AppState>, id: usize, url: String) -> String {
-    let mut torrents = state.torrent_list.lock().unwrap();
-    torrents.push_with_id_and_url(id, url);
-    id.to_string()
+fn add_torrent(
+    app: AppHandle,
+    state: State<AppState>,
+    id: usize,
+    url: String,
+) -> Result<String, String> {
+    let (existing, storage) = {
+        let mut torrents = state.torrent_list.lock().unwrap();
+        torrents.push_with_id_and_url(id, url)?;
+        let lsd = state.lsd.lock().unwrap();
+        if let (Some(lsd), Some(item)) = (lsd.as_ref(), torrents.list.get(&id)) {
+            register_lsd(lsd, item);
+        }
+        (torrents.has_existing_data(&id), torrents.storage(&id))
+    };
+    if existing {
+        spawn_recheck(app, state.torrent_list.clone(), id)?;
+    }
+    // Files are created, and with full preallocation filled, on the I/O
+    // pool while the torrent starts. Failing only means they're created on
+    // first write instead.
+    if let Some(storage) = storage {
+        tauri::async_runtime::spawn(async move {
+            if let Err(e) = storage.allocate().await {
+                println!("Failed to allocate files of torrent {}: {}", id, e);
+            }
+        });
+    }
+    Ok(id.to_string())
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RecheckEvent {
+    id: usize,
+    #[serde(flatten)]
+    progress: RecheckProgress,
+}
+
+// Runs a recheck in the background, sending its progress to the frontend as
+// `recheck-progress` events
+fn spawn_recheck(
+    app: AppHandle,
+    torrents: Arc<Mutex<TorrentList>>,
+    id: usize,
+) -> Result<(), String> {
+    let (handle, task) = torrents.lock().unwrap().start_recheck(&id)?;
+    let mut progress = handle.subscribe();
+    tauri::async_runtime::spawn(async move {
+        // Ends once the recheck is done and its last state was sent
+        while progress.changed().await.is_ok() {
+            let update = progress.borrow_and_update().clone();
+            app.emit(
+                "recheck-progress",
+                RecheckEvent {
+                    id,
+                    progress: update,
+                },
+            )
+            .ok();
+        }
+    });
+    tauri::async_runtime::spawn(async move {
+        let result = task.await;
+        torrents
+            .lock()
+            .unwrap()
+            .finish_recheck(&id, &handle, result);
+    });
+    Ok(())
+}
+
+#[tauri::command]
+fn recheck_torrent(app: AppHandle, state: State<AppState>, id: usize) -> Result<(), String> {
+    spawn_recheck(app, state.torrent_list.clone(), id)
+}
+
+fn recheck_handle(state: &State<AppState>, id: usize) -> Result<RecheckHandle, String> {
+    state
+        .torrent_list
+        .lock()
+        .unwrap()
+        .recheck_handle(&id)
+        .cloned()
+        .ok_or_else(|| "No recheck running".to_string())
+}
+
+#[tauri::command]
+fn pause_recheck(state: State<AppState>, id: usize) -> Result<(), String> {
+    recheck_handle(&state, id)?.pause();
+    Ok(())
+}
+
+#[tauri::command]
+fn resume_recheck(state: State<AppState>, id: usize) -> Result<(), String> {
+    recheck_handle(&state, id)?.resume();
+    Ok(())
+}
+
+#[tauri::command]
+fn cancel_recheck(state: State<AppState>, id: usize) -> Result<(), String> {
+    recheck_handle(&state, id)?.cancel();
+    Ok(())
+}
+
+#[tauri::command]
+fn file_status(state: State<AppState>, id: usize) -> Result<Vec<FileStatus>, String> {
+    state
+        .torrent_list
+        .lock()
+        .unwrap()
+        .file_status(&id)
+        .ok_or_else(|| "No such torrent".to_string())
+}
+
+#[tauri::command]
+async fn set_file_priority(
+    state: State<'_, AppState>,
+    id: usize,
+    file: usize,
+    priority: FilePriority,
+) -> Result<(), String> {
+    let task = state
+        .torrent_list
+        .lock()
+        .unwrap()
+        .set_file_priority(&id, file, priority)?;
+    finish_file_priorities(&state.torrent_list, id, task).await
+}
+
+#[tauri::command]
+async fn set_file_priorities(
+    state: State<'_, AppState>,
+    id: usize,
+    priorities: Vec<FilePriority>,
+) -> Result<(), String> {
+    let task = state
+        .torrent_list
+        .lock()
+        .unwrap()
+        .set_file_priorities(&id, priorities)?;
+    finish_file_priorities(&state.torrent_list, id, task).await
+}
+
+// Data moves out of the part file on the I/O pool, the resume data is saved
+// once it has
+async fn finish_file_priorities(
+    torrents: &Arc<Mutex<TorrentList>>,
+    id: usize,
+    task: impl Future<Output = io::Result<()>>,
+) -> Result<(), String> {
+    let result = task.await;
+    torrents.lock().unwrap().save_resume_data(&id, None);
+    result.map_err(|e| format!("Failed to move data out of the part file: {}", e))
+}
+
+#[derive(Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MoveEvent {
+    id: usize,
+    #[serde(flatten)]
+    progress: MoveProgress,
+}
+
+// Moves the torrent's files to `path`, sending `move-progress` events on
+// the way. Files kept at the destination get rechecked afterwards.
+#[tauri::command]
+async fn move_storage(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    id: usize,
+    path: String,
+    policy: ConflictPolicy,
+) -> Result<(), String> {
+    let torrents = state.torrent_list.clone();
+    let storage = torrents.lock().unwrap().start_move(&id)?;
+    let emitter = app.clone();
+    let result = storage
+        .move_to(PathBuf::from(path), policy, move |progress| {
+            emitter
+                .emit("move-progress", MoveEvent { id, progress })
+                .ok();
+        })
+        .await;
+    let recheck = torrents.lock().unwrap().finish_move(&id, result)?;
+    if recheck {
+        spawn_recheck(app, torrents, id)?;
+    }
+    Ok(())
+}
+
+fn torrent_storage(state: &State<'_, AppState>, id: usize) -> Result<Storage, String> {
+    state
+        .torrent_list
+        .lock()
+        .unwrap()
+        .storage(&id)
+        .ok_or_else(|| "No such torrent".to_string())
+}
+
+#[tauri::command]
+async fn rename_file(
+    state: State<'_, AppState>,
+    id: usize,
+    file: usize,
+    name: String,
+) -> Result<(), String> {
+    torrent_storage(&state, id)?
+        .rename_file(file, name)
+        .await
+        .map_err(|e| format!("Failed to rename file: {}", e))?;
+    state.torrent_list.lock().unwrap().save_resume_data(&id, None);
+    Ok(())
+}
+
+#[tauri::command]
+async fn rename_folder(
+    state: State<'_, AppState>,
+    id: usize,
+    folder: String,
+    name: String,
+) -> Result<(), String> {
+    torrent_storage(&state, id)?
+        .rename_folder(folder, name)
+        .await
+        .map_err(|e| format!("Failed to rename folder: {}", e))?;
+    state.torrent_list.lock().unwrap().save_resume_data(&id, None);
+    Ok(())
+}
+
+// None goes back to the upload slots per torrent from the settings
+#[tauri::command]
+fn set_upload_slots(state: State<AppState>, id: usize, slots: Option<usize>) -> Result<(), String> {
+    state
+        .torrent_list
+        .lock()
+        .unwrap()
+        .set_upload_slots(&id, slots)
+}
+
+#[tauri::command]
+fn set_sequential(state: State<AppState>, id: usize, sequential: bool) -> Result<(), String> {
+    state
+        .torrent_list
+        .lock()
+        .unwrap()
+        .set_sequential(&id, sequential)
 }
 
 #[tauri::command]
 fn remove_torrent(state: State<AppState>, id: usize) {
     let mut torrents = state.torrent_list.lock().unwrap();
-    torrents.list.remove(&id);
+    let lsd = state.lsd.lock().unwrap();
+    if let (Some(lsd), Some(item)) = (lsd.as_ref(), torrents.list.get(&id)) {
+        lsd.remove_torrent(&item.object.info_hash);
+    }
+    torrents.remove(&id);
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DhtMutableItem {
+    public_key: String,
+    seq: i64,
+    value: String,
+}
+
+fn running_dht(state: &State<'_, AppState>) -> Result<Dht, String> {
+    state
+        .dht
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or_else(|| "DHT is not running".to_string())
+}
+
+// Values are stored as bencoded byte strings, shown lossily as UTF-8
+fn value_to_string(value: &backend::file::BencodeValue) -> String {
+    match value.as_bytes() {
+        Some(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        None => String::from_utf8_lossy(&value.encode()).into_owned(),
+    }
+}
+
+fn mutable_item(item: &Item) -> Option<DhtMutableItem> {
+    let Item::Mutable { v, k, seq, .. } = item else {
+        return None;
+    };
+    Some(DhtMutableItem {
+        public_key: storage::to_hex(k),
+        seq: *seq,
+        value: value_to_string(v),
+    })
+}
+
+#[tauri::command]
+async fn dht_put_immutable(state: State<'_, AppState>, value: String) -> Result<String, String> {
+    let dht = running_dht(&state)?;
+    let target = dht
+        .put_immutable(backend::file::BencodeValue::String(value.into_bytes()))
+        .await
+        .map_err(|e| format!("Failed to store DHT item: {}", e))?;
+    Ok(storage::to_hex(&target))
+}
+
+#[tauri::command]
+async fn dht_get_immutable(
+    state: State<'_, AppState>,
+    target: String,
+) -> Result<Option<String>, String> {
+    let dht = running_dht(&state)?;
+    let target: [u8; 20] = storage::from_hex(&target)
+        .and_then(|t| t.try_into().ok())
+        .ok_or_else(|| "Target must be 40 hex characters".to_string())?;
+    Ok(dht.get_immutable(target).await.map(|v| value_to_string(&v)))
+}
+
+#[tauri::command]
+async fn dht_put_mutable(
+    state: State<'_, AppState>,
+    value: String,
+    salt: String,
+) -> Result<DhtMutableItem, String> {
+    let dht = running_dht(&state)?;
+    let key =
+        storage::load_or_create_key().map_err(|e| format!("Failed to load DHT key: {}", e))?;
+    let item = dht
+        .put_mutable(
+            &key,
+            backend::file::BencodeValue::String(value.into_bytes()),
+            salt.as_bytes(),
+        )
+        .await
+        .map_err(|e| format!("Failed to store DHT item: {}", e))?;
+    mutable_item(&item).ok_or_else(|| "Stored item is not mutable".to_string())
+}
+
+#[tauri::command]
+async fn dht_get_mutable(
+    state: State<'_, AppState>,
+    public_key: String,
+    salt: String,
+) -> Result<Option<DhtMutableItem>, String> {
+    let dht = running_dht(&state)?;
+    let k: [u8; 32] = storage::from_hex(&public_key)
+        .and_then(|k| k.try_into().ok())
+        .ok_or_else(|| "Public key must be 64 hex characters".to_string())?;
+    Ok(dht
+        .get_mutable(k, salt.as_bytes())
+        .await
+        .as_ref()
+        .and_then(mutable_item))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NetworkStatus {
+    dht_nodes: Option<usize>,
+    utp_connections: Option<usize>,
+}
+
+#[tauri::command]
+fn network_status(state: State<AppState>) -> NetworkStatus {
+    NetworkStatus {
+        dht_nodes: state
+            .dht
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|dht| dht.num_nodes()),
+        utp_connections: state
+            .utp
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|utp| utp.num_connections()),
+    }
+}
+
+// None with port mapping turned off
+#[tauri::command]
+fn port_mappings(state: State<AppState>) -> Option<Vec<MappingStatus>> {
+    state
+        .portmap
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|mapper| mapper.status())
+}
+
+// Piece verification across all torrents
+#[tauri::command]
+fn verify_stats(state: State<AppState>) -> VerifyStats {
+    state.hasher.stats()
+}
+
+#[tauri::command]
+fn cache_stats(state: State<AppState>) -> CacheStats {
+    state.cache.stats()
 }
 
 struct AppState {
-    torrent_list: Arc<Mutex<backend::torrentlist::TorrentList>>,
+    torrent_list: Arc<Mutex<TorrentList>>,
+    // Set once the node is bound, stays None with the DHT disabled
+    dht: Arc<Mutex<Option<Dht>>>,
+    // Shares the DHT's UDP port, None with uTP disabled
+    utp: Arc<Mutex<Option<UtpSocket>>>,
+    // Removed from the gateway again on exit
+    portmap: Arc<Mutex<Option<PortMapper>>>,
+    // Set once the multicast sockets are bound, None with LSD disabled
+    lsd: Arc<Mutex<Option<Lsd>>>,
+    hasher: HashPool,
+    cache: DiskCache,
+}
+
+fn start_udp(
+    dht_slot: Arc<Mutex<Option<Dht>>>,
+    utp_slot: Arc<Mutex<Option<UtpSocket>>>,
+    session: Session,
+) {
+    let settings = backend::settings::Settings::load();
+    if !settings.dht_enabled && !settings.utp_enabled {
+        return;
+    }
+    tauri::async_runtime::spawn(async move {
+        let config = DhtConfig::from_settings(&settings);
+        if !settings.dht_enabled {
+            match UtpSocket::bind(config.bind).await {
+                Ok(utp) => {
+                    session.accept_utp(utp.clone());
+                    *utp_slot.lock().unwrap() = Some(utp);
+                }
+                Err(e) => println!("Failed to start uTP: {}", e),
+            }
+            return;
+        }
+
+        let (dht, utp) = match requests::utp::bind_with_dht(config).await {
+            Ok(bound) => bound,
+            Err(e) => {
+                println!("Failed to start DHT: {}", e);
+                return;
+            }
+        };
+        if settings.utp_enabled {
+            session.accept_utp(utp.clone());
+            *utp_slot.lock().unwrap() = Some(utp);
+        }
+        *dht_slot.lock().unwrap() = Some(dht.clone());
+        match dht.bootstrap().await {
+            Ok(nodes) => println!("DHT bootstrapped with {} nodes", nodes),
+            Err(e) => println!("DHT bootstrap failed: {}", e),
+        }
+        dht.maintain().await;
+    });
+}
+
+fn start_port_mapping(slot: Arc<Mutex<Option<PortMapper>>>) {
+    let settings = backend::settings::Settings::load();
+    if !settings.port_mapping {
+        return;
+    }
+    let mut ports = vec![(Protocol::Tcp, settings.listen_port)];
+    if settings.dht_enabled || settings.utp_enabled {
+        ports.push((Protocol::Udp, settings.listen_port));
+    }
+    tauri::async_runtime::spawn(async move {
+        *slot.lock().unwrap() = Some(PortMapper::start(&ports, PortMapConfig::default()));
+    });
+}
+
+// Private torrents are skipped by `Lsd::add_torrent` itself
+fn register_lsd(lsd: &Lsd, item: &TorrentItem) {
+    lsd.add_torrent(
+        item.object.info_hash,
+        item.object.info.private,
+        item.swarm.manager.clone(),
+    );
+}
+
+// Torrents restored or added before the sockets were bound are registered
+// here, the list stays locked until the slot is set so none is missed
+fn start_lsd(slot: Arc<Mutex<Option<Lsd>>>, torrents: Arc<Mutex<TorrentList>>) {
+    let settings = backend::settings::Settings::load();
+    if !settings.lsd_enabled {
+        return;
+    }
+    tauri::async_runtime::spawn(async move {
+        let lsd = match Lsd::start(settings.listen_port).await {
+            Ok(lsd) => lsd,
+            Err(e) => {
+                println!("Failed to start LSD: {}", e);
+                return;
+            }
+        };
+        let torrents = torrents.lock().unwrap();
+        for item in torrents.list.values() {
+            register_lsd(&lsd, item);
+        }
+        *slot.lock().unwrap() = Some(lsd);
+    });
+}
+
+// Resume data is saved this often, what was written since is rechecked
+// after a crash
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Brings back the torrents of the last session and keeps their resume data
+// up to date from then on
+fn restore_session(app: AppHandle, torrents: Arc<Mutex<TorrentList>>) {
+    let changed = torrents.lock().unwrap().restore();
+    for id in changed {
+        if let Err(e) = spawn_recheck(app.clone(), torrents.clone(), id) {
+            println!("Failed to recheck restored torrent {}: {}", id, e);
+        }
+    }
+    tauri::async_runtime::spawn(async move {
+        loop {
+            tokio::time::sleep(RESUME_SAVE_INTERVAL).await;
+            flush_all(&torrents).await;
+            let saved = torrents.lock().unwrap().save_all();
+            saved.await;
+        }
+    });
+}
+
+fn save_session(torrents: &Arc<Mutex<TorrentList>>) {
+    tauri::async_runtime::block_on(flush_all(torrents));
+    let saved = torrents.lock().unwrap().save_all();
+    tauri::async_runtime::block_on(saved);
+}
+
+// Written data, the blocks still in the cache included, is flushed before
+// the resume data claims it is there
+async fn flush_all(torrents: &Arc<Mutex<TorrentList>>) {
+    let (cache, storages) = {
+        let torrents = torrents.lock().unwrap();
+        (torrents.cache().clone(), torrents.storages())
+    };
+    if let Err(e) = cache.flush().await {
+        println!("Failed to write out the disk cache: {}", e);
+    }
+    for storage in storages {
+        if let Err(e) = storage.flush().await {
+            println!("Failed to flush {}: {}", storage.root().display(), e);
+        }
+    }
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
+    let dht = Arc::new(Mutex::new(None));
+    let utp = Arc::new(Mutex::new(None));
+    let portmap = Arc::new(Mutex::new(None));
+    let lsd = Arc::new(Mutex::new(None));
+    let (dht_slot, utp_slot, portmap_slot) = (dht.clone(), utp.clone(), portmap.clone());
+    let lsd_slot = lsd.clone();
+    let portmap_on_exit = portmap.clone();
+    let settings = backend::settings::Settings::load();
+    let hasher = HashPool::new(settings.hash_threads);
+    let disk = IoPool::new(settings.disk_threads);
+    let cache = DiskCache::new(
+        settings.write_cache_size << 20,
+        settings.read_cache_size << 20,
+        settings.read_ahead,
+    );
+    let torrent_list = Arc::new(Mutex::new(TorrentList::new(
+        disk,
+        hasher.clone(),
+        cache.clone(),
+    )));
+    let (torrents_on_setup, torrents_on_exit) = (torrent_list.clone(), torrent_list.clone());
     tauri::Builder::default()
         .manage(AppState {
-            torrent_list: Arc::new(Mutex::new(backend::torrentlist::TorrentList::new())),
+            torrent_list,
+            dht,
+            utp,
+            portmap,
+            lsd,
+            hasher,
+            cache,
+        })
+        .setup(move |app| {
+            let session = Session::new(
+                torrents_on_setup.clone(),
+                utp_slot.clone(),
+                dht_slot.clone(),
+            );
+            start_udp(dht_slot, utp_slot, session.clone());
+            start_port_mapping(portmap_slot);
+            restore_session(app.handle().clone(), torrents_on_setup.clone());
+            start_lsd(lsd_slot, torrents_on_setup);
+            tauri::async_runtime::spawn(session.run());
+            Ok(())
         })
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_dialog::init())
@@ -81,9 +682,38 @@ pub fn run() {
             console_log,
             add_torrent,
             remove_torrent,
+            dht_put_immutable,
+            dht_get_immutable,
+            dht_put_mutable,
+            dht_get_mutable,
+            network_status,
+            port_mappings,
+            verify_stats,
+            cache_stats,
+            recheck_torrent,
+            pause_recheck,
+            resume_recheck,
+            cancel_recheck,
+            file_status,
+            set_file_priority,
+            set_file_priorities,
+            move_storage,
+            rename_file,
+            rename_folder,
+            set_upload_slots,
+            set_sequential,
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(move |_, event| {
+            if let RunEvent::Exit = event {
+                save_session(&torrents_on_exit);
+                let mapper = portmap_on_exit.lock().unwrap().take();
+                if let Some(mapper) = mapper {
+                    tauri::async_runtime::block_on(mapper.unmap_all());
+                }
+            }
+        });
 }
 
 #[tokio::main]
@@ -96,7 +726,9 @@ async fn torrent_main() -> Result<(), Box<dyn std::error::Error>> {
     let announce_url = "http://tracker.opentrackr.org:1337/announce";
     println!("Announcing to tracker: {}", announce_url);
     println!("Tracker in torrent: {:?}", data.announce);
-    let peers = requests::announce(&data.info_hash, &data.announce).await?;
+    let peer_id = requests::peer::generate_peer_id();
+    let port = backend::settings::Settings::load().listen_port;
+    let peers = requests::announce(&data.info_hash, &data.announce, &peer_id, port).await?;
     // println!("{:?}", peers);
     Ok(())
 }
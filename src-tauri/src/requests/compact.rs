@@ -0,0 +1,34 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+
+// "Compact" peer info used by trackers, PEX and the DHT: the address in
+// network byte order followed by a two byte port.
+
+pub fn parse_v4(buf: &[u8]) -> Vec<SocketAddr> {
+    buf.chunks_exact(6)
+        .map(|chunk| {
+            let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+            let port = u16::from_be_bytes([chunk[4], chunk[5]]);
+            SocketAddr::V4(SocketAddrV4::new(ip, port))
+        })
+        .collect()
+}
+
+pub fn parse_v6(buf: &[u8]) -> Vec<SocketAddr> {
+    buf.chunks_exact(18)
+        .map(|chunk| {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&chunk[..16]);
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(octets), port, 0, 0))
+        })
+        .collect()
+}
+
+pub fn encode(addr: &SocketAddr) -> Vec<u8> {
+    let mut buf = match addr {
+        SocketAddr::V4(a) => a.ip().octets().to_vec(),
+        SocketAddr::V6(a) => a.ip().octets().to_vec(),
+    };
+    buf.extend(addr.port().to_be_bytes());
+    buf
+}
@@ -0,0 +1,387 @@
+use std::io;
+use std::net::SocketAddr;
+
+use super::routing::NodeId;
+use super::storage::Item;
+use crate::backend::file::{BencodeParser, BencodeValue, MAX_DEPTH};
+use crate::requests::compact;
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_SERVER: i64 = 202;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+// BEP 44
+pub const ERROR_MESSAGE_TOO_BIG: i64 = 205;
+pub const ERROR_INVALID_SIGNATURE: i64 = 206;
+pub const ERROR_SALT_TOO_BIG: i64 = 207;
+pub const ERROR_CAS_MISMATCH: i64 = 301;
+pub const ERROR_SEQ_TOO_LOW: i64 = 302;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Ping,
+    FindNode {
+        target: NodeId,
+    },
+    GetPeers {
+        info_hash: [u8; 20],
+    },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        token: Vec<u8>,
+        implied_port: bool,
+    },
+    // `seq` asks to leave out mutable values that aren't newer than it
+    Get {
+        target: NodeId,
+        seq: Option<i64>,
+    },
+    Put {
+        token: Vec<u8>,
+        item: Item,
+        cas: Option<i64>,
+    },
+}
+
+impl Query {
+    pub fn method(&self) -> &'static [u8] {
+        match self {
+            Query::Ping => b"ping",
+            Query::FindNode { .. } => b"find_node",
+            Query::GetPeers { .. } => b"get_peers",
+            Query::AnnouncePeer { .. } => b"announce_peer",
+            Query::Get { .. } => b"get",
+            Query::Put { .. } => b"put",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<(NodeId, SocketAddr)>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+    // Stored item returned by get, k and sig only for mutable ones
+    pub v: Option<BencodeValue>,
+    pub k: Option<[u8; 32]>,
+    pub sig: Option<[u8; 64]>,
+    pub seq: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Body {
+    Query { id: NodeId, query: Query },
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KrpcMessage {
+    pub transaction: Vec<u8>,
+    pub body: Body,
+    // BEP 42: the address the sender saw us at, only set in responses
+    pub ip: Option<SocketAddr>,
+}
+
+// Compact node info: 20 byte node ID followed by compact IPv4 address
+pub fn parse_nodes(buf: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    buf.chunks_exact(26)
+        .filter_map(|chunk| {
+            let mut id = [0u8; 20];
+            id.copy_from_slice(&chunk[..20]);
+            let addr = *compact::parse_v4(&chunk[20..]).first()?;
+            Some((id, addr))
+        })
+        .collect()
+}
+
+pub fn encode_nodes(nodes: &[(NodeId, SocketAddr)]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(nodes.len() * 26);
+    for (id, addr) in nodes.iter().filter(|(_, addr)| addr.is_ipv4()) {
+        buf.extend(id);
+        buf.extend(compact::encode(addr));
+    }
+    buf
+}
+
+fn string(bytes: &[u8]) -> BencodeValue {
+    BencodeValue::String(bytes.to_vec())
+}
+
+fn entry(key: &[u8], value: BencodeValue) -> (Vec<u8>, BencodeValue) {
+    (key.to_vec(), value)
+}
+
+impl KrpcMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = vec![entry(b"t", string(&self.transaction))];
+        match &self.body {
+            Body::Query { id, query } => {
+                let mut args = vec![entry(b"id", string(id))];
+                match query {
+                    Query::Ping => {}
+                    Query::FindNode { target } => args.push(entry(b"target", string(target))),
+                    Query::GetPeers { info_hash } => {
+                        args.push(entry(b"info_hash", string(info_hash)))
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        token,
+                        implied_port,
+                    } => {
+                        args.push(entry(b"info_hash", string(info_hash)));
+                        args.push(entry(b"port", BencodeValue::Integer(*port as i64)));
+                        args.push(entry(b"token", string(token)));
+                        if *implied_port {
+                            args.push(entry(b"implied_port", BencodeValue::Integer(1)));
+                        }
+                    }
+                    Query::Get { target, seq } => {
+                        args.push(entry(b"target", string(target)));
+                        if let Some(seq) = seq {
+                            args.push(entry(b"seq", BencodeValue::Integer(*seq)));
+                        }
+                    }
+                    Query::Put { token, item, cas } => {
+                        args.push(entry(b"token", string(token)));
+                        args.push(entry(b"v", item.value().clone()));
+                        if let Item::Mutable {
+                            k, sig, seq, salt, ..
+                        } = item
+                        {
+                            args.push(entry(b"k", string(k)));
+                            args.push(entry(b"sig", string(sig)));
+                            args.push(entry(b"seq", BencodeValue::Integer(*seq)));
+                            if !salt.is_empty() {
+                                args.push(entry(b"salt", string(salt)));
+                            }
+                        }
+                        if let Some(cas) = cas {
+                            args.push(entry(b"cas", BencodeValue::Integer(*cas)));
+                        }
+                    }
+                }
+                dict.push(entry(b"y", string(b"q")));
+                dict.push(entry(b"q", string(query.method())));
+                dict.push(entry(b"a", BencodeValue::Dict(args)));
+            }
+            Body::Response(response) => {
+                let mut r = vec![entry(b"id", string(&response.id))];
+                if !response.nodes.is_empty() {
+                    r.push(entry(b"nodes", string(&encode_nodes(&response.nodes))));
+                }
+                if !response.values.is_empty() {
+                    let values = response
+                        .values
+                        .iter()
+                        .map(|addr| string(&compact::encode(addr)))
+                        .collect();
+                    r.push(entry(b"values", BencodeValue::List(values)));
+                }
+                if let Some(token) = &response.token {
+                    r.push(entry(b"token", string(token)));
+                }
+                if let Some(v) = &response.v {
+                    r.push(entry(b"v", v.clone()));
+                }
+                if let Some(k) = &response.k {
+                    r.push(entry(b"k", string(k)));
+                }
+                if let Some(sig) = &response.sig {
+                    r.push(entry(b"sig", string(sig)));
+                }
+                if let Some(seq) = response.seq {
+                    r.push(entry(b"seq", BencodeValue::Integer(seq)));
+                }
+                dict.push(entry(b"y", string(b"r")));
+                dict.push(entry(b"r", BencodeValue::Dict(r)));
+            }
+            Body::Error { code, message } => {
+                dict.push(entry(b"y", string(b"e")));
+                dict.push(entry(
+                    b"e",
+                    BencodeValue::List(vec![
+                        BencodeValue::Integer(*code),
+                        string(message.as_bytes()),
+                    ]),
+                ));
+            }
+        }
+        if let Some(ip) = &self.ip {
+            dict.push(entry(b"ip", string(&compact::encode(ip))));
+        }
+        BencodeValue::Dict(dict).encode()
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &'static str| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let value = BencodeParser::new(buf)
+            .parse_with_depth(MAX_DEPTH)
+            .map_err(invalid)?;
+
+        let transaction = value
+            .get(b"t")
+            .and_then(|v| v.as_bytes())
+            .ok_or_else(|| invalid("Missing transaction ID"))?
+            .to_vec();
+        let ip = value
+            .get(b"ip")
+            .and_then(|v| v.as_bytes())
+            .and_then(|b| match b.len() {
+                6 => compact::parse_v4(b).first().copied(),
+                18 => compact::parse_v6(b).first().copied(),
+                _ => None,
+            });
+
+        let body = match value.get(b"y").and_then(|v| v.as_bytes()) {
+            Some(b"q") => {
+                let args = value
+                    .get(b"a")
+                    .ok_or_else(|| invalid("Missing arguments"))?;
+                let id = node_id(args.get(b"id")).ok_or_else(|| invalid("Missing node ID"))?;
+                let method = value
+                    .get(b"q")
+                    .and_then(|v| v.as_bytes())
+                    .ok_or_else(|| invalid("Missing method"))?;
+                Body::Query {
+                    id,
+                    query: decode_query(method, args)?,
+                }
+            }
+            Some(b"r") => {
+                let r = value.get(b"r").ok_or_else(|| invalid("Missing response"))?;
+                Body::Response(Response {
+                    id: node_id(r.get(b"id")).ok_or_else(|| invalid("Missing node ID"))?,
+                    nodes: r
+                        .get(b"nodes")
+                        .and_then(|v| v.as_bytes())
+                        .map(parse_nodes)
+                        .unwrap_or_default(),
+                    values: r
+                        .get(b"values")
+                        .and_then(|v| v.as_list())
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(|v| v.as_bytes())
+                        .flat_map(|b| match b.len() {
+                            18 => compact::parse_v6(b),
+                            _ => compact::parse_v4(b),
+                        })
+                        .collect(),
+                    token: r
+                        .get(b"token")
+                        .and_then(|v| v.as_bytes())
+                        .map(|t| t.to_vec()),
+                    v: r.get(b"v").cloned(),
+                    k: fixed(r.get(b"k")),
+                    sig: fixed(r.get(b"sig")),
+                    seq: r.get(b"seq").and_then(|v| v.as_integer()),
+                })
+            }
+            Some(b"e") => {
+                let e = value
+                    .get(b"e")
+                    .and_then(|v| v.as_list())
+                    .unwrap_or_default();
+                Body::Error {
+                    code: e
+                        .first()
+                        .and_then(|v| v.as_integer())
+                        .unwrap_or(ERROR_GENERIC),
+                    message: e
+                        .get(1)
+                        .and_then(|v| v.as_bytes())
+                        .map(|b| String::from_utf8_lossy(b).into_owned())
+                        .unwrap_or_default(),
+                }
+            }
+            _ => return Err(invalid("Unknown message type")),
+        };
+
+        Ok(KrpcMessage {
+            transaction,
+            body,
+            ip,
+        })
+    }
+}
+
+fn node_id(value: Option<&BencodeValue>) -> Option<NodeId> {
+    fixed(value)
+}
+
+fn fixed<const N: usize>(value: Option<&BencodeValue>) -> Option<[u8; N]> {
+    value?.as_bytes()?.try_into().ok()
+}
+
+fn decode_query(method: &[u8], args: &BencodeValue) -> io::Result<Query> {
+    let invalid = |msg: &'static str| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let info_hash = || node_id(args.get(b"info_hash")).ok_or_else(|| invalid("Missing info_hash"));
+
+    Ok(match method {
+        b"ping" => Query::Ping,
+        b"find_node" => Query::FindNode {
+            target: node_id(args.get(b"target")).ok_or_else(|| invalid("Missing target"))?,
+        },
+        b"get_peers" => Query::GetPeers {
+            info_hash: info_hash()?,
+        },
+        b"announce_peer" => Query::AnnouncePeer {
+            info_hash: info_hash()?,
+            port: args
+                .get(b"port")
+                .and_then(|v| v.as_integer())
+                .and_then(|p| u16::try_from(p).ok())
+                .unwrap_or(0),
+            token: args
+                .get(b"token")
+                .and_then(|v| v.as_bytes())
+                .ok_or_else(|| invalid("Missing token"))?
+                .to_vec(),
+            implied_port: args.get(b"implied_port").and_then(|v| v.as_integer()) == Some(1),
+        },
+        b"get" => Query::Get {
+            target: node_id(args.get(b"target")).ok_or_else(|| invalid("Missing target"))?,
+            seq: args.get(b"seq").and_then(|v| v.as_integer()),
+        },
+        b"put" => Query::Put {
+            token: args
+                .get(b"token")
+                .and_then(|v| v.as_bytes())
+                .ok_or_else(|| invalid("Missing token"))?
+                .to_vec(),
+            item: decode_item(args)?,
+            cas: args.get(b"cas").and_then(|v| v.as_integer()),
+        },
+        _ => return Err(io::Error::new(io::ErrorKind::Unsupported, "Method unknown")),
+    })
+}
+
+// Put arguments carry a mutable item when there is a public key
+fn decode_item(args: &BencodeValue) -> io::Result<Item> {
+    let invalid = |msg: &'static str| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let v = args
+        .get(b"v")
+        .cloned()
+        .ok_or_else(|| invalid("Missing value"))?;
+    let Some(k) = fixed(args.get(b"k")) else {
+        return Ok(Item::Immutable { v });
+    };
+    Ok(Item::Mutable {
+        v,
+        k,
+        sig: fixed(args.get(b"sig")).ok_or_else(|| invalid("Missing signature"))?,
+        seq: args
+            .get(b"seq")
+            .and_then(|v| v.as_integer())
+            .ok_or_else(|| invalid("Missing sequence number"))?,
+        salt: args
+            .get(b"salt")
+            .and_then(|v| v.as_bytes())
+            .unwrap_or_default()
+            .to_vec(),
+    })
+}
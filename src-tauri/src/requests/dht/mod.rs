@@ -0,0 +1,810 @@
+use ed25519_dalek::SigningKey;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::oneshot;
+use tokio::task::JoinSet;
+use tokio::time::{timeout, Duration};
+
+pub mod krpc;
+pub mod routing;
+pub mod security;
+pub mod storage;
+
+use crate::backend::file::{BencodeParser, BencodeValue, MAX_DEPTH};
+use crate::backend::settings::Settings;
+use krpc::{Body, KrpcMessage, Query, Response};
+use routing::{distance, Insert, NodeId, RoutingTable, K};
+use security::{ExternalIpVoter, QueryRateLimiter};
+use storage::{Item, ItemStore, PeerStore};
+
+pub const DEFAULT_BOOTSTRAP: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+// Parallel queries per lookup step
+const ALPHA: usize = 3;
+const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    pub bind: SocketAddr,
+    pub bootstrap: Vec<String>,
+    // Where the routing table is kept between runs, None to not persist
+    pub state_path: Option<PathBuf>,
+    // BEP 42: only let nodes whose ID matches their IP into the routing table
+    pub enforce_node_id: bool,
+    // Our external address if already known, otherwise learned from votes
+    pub external_ip: Option<IpAddr>,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        let state_path = dirs::config_dir().map(|mut path| {
+            path.push("defttorrent");
+            path.push("dht.dat");
+            path
+        });
+        DhtConfig {
+            bind: SocketAddr::from(([0, 0, 0, 0], 6881)),
+            bootstrap: DEFAULT_BOOTSTRAP.iter().map(|s| s.to_string()).collect(),
+            state_path,
+            enforce_node_id: true,
+            external_ip: None,
+        }
+    }
+}
+
+impl DhtConfig {
+    pub fn from_settings(settings: &Settings) -> Self {
+        let mut config = DhtConfig::default();
+        config.bind.set_port(settings.listen_port);
+        if !settings.dht_bootstrap_nodes.is_empty() {
+            config.bootstrap = settings.dht_bootstrap_nodes.clone();
+        }
+        config
+    }
+}
+
+struct DhtState {
+    table: RoutingTable,
+    pending: HashMap<Vec<u8>, (SocketAddr, oneshot::Sender<io::Result<Response>>)>,
+    next_transaction: u16,
+    peers: PeerStore,
+    secret: [u8; 20],
+    previous_secret: [u8; 20],
+    secret_rotated: Instant,
+    voter: ExternalIpVoter,
+    limiter: QueryRateLimiter,
+    enforce_node_id: bool,
+    items: ItemStore,
+    // Questionable nodes to ping because a full bucket has a replacement
+    // waiting, and those pings already in flight
+    to_ping: Vec<SocketAddr>,
+    evicting: HashSet<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Lookup {
+    FindNode,
+    GetPeers,
+    Get(Option<i64>),
+}
+
+#[derive(Debug, Default)]
+struct LookupResult {
+    // K closest nodes that answered, with the tokens they gave us
+    closest: Vec<(NodeId, SocketAddr, Option<Vec<u8>>)>,
+    peers: Vec<SocketAddr>,
+    // get responses that carried a value, still unverified
+    items: Vec<Response>,
+}
+
+// A DHT node. Cheap to clone, every clone talks through the same socket and
+// routing table.
+#[derive(Clone)]
+pub struct Dht {
+    socket: Arc<UdpSocket>,
+    state: Arc<Mutex<DhtState>>,
+    config: Arc<DhtConfig>,
+}
+
+impl Dht {
+    // Binds the socket, restores the saved routing table if there is one and
+    // starts answering queries. Call `bootstrap` afterwards to join the network.
+    pub async fn bind(config: DhtConfig) -> io::Result<Dht> {
+        let socket = UdpSocket::bind(config.bind).await?;
+        let dht = Dht::with_socket(Arc::new(socket), config);
+        let receiver = dht.clone();
+        tokio::spawn(async move { receiver.receive_loop().await });
+        Ok(dht)
+    }
+
+    // For callers that own the socket and hand packets over through
+    // `handle_packet` themselves.
+    pub fn with_socket(socket: Arc<UdpSocket>, config: DhtConfig) -> Dht {
+        let (mut id, nodes) = config
+            .state_path
+            .as_deref()
+            .and_then(load_state)
+            .unwrap_or_else(|| (rand::random(), Vec::new()));
+        if let Some(ip) = config.external_ip {
+            if !security::is_valid_id(&id, &ip) {
+                id = security::generate_id(&ip);
+            }
+        }
+        let mut table = RoutingTable::new(id);
+        for (node_id, addr) in nodes {
+            table.insert(node_id, addr);
+        }
+
+        Dht {
+            socket,
+            state: Arc::new(Mutex::new(DhtState {
+                table,
+                pending: HashMap::new(),
+                next_transaction: rand::random(),
+                peers: PeerStore::new(),
+                secret: rand::random(),
+                previous_secret: rand::random(),
+                secret_rotated: Instant::now(),
+                voter: ExternalIpVoter::new(),
+                limiter: QueryRateLimiter::new(),
+                enforce_node_id: config.enforce_node_id,
+                items: ItemStore::new(),
+                to_ping: Vec::new(),
+                evicting: HashSet::new(),
+            })),
+            config: Arc::new(config),
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        *self.state.lock().unwrap().table.own_id()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn external_ip(&self) -> Option<IpAddr> {
+        self.state.lock().unwrap().voter.current()
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        self.state.lock().unwrap().table.len()
+    }
+
+    async fn receive_loop(&self) {
+        let mut buf = [0u8; 2048];
+        loop {
+            match self.socket.recv_from(&mut buf).await {
+                Ok((amt, from)) => self.handle_packet(&buf[..amt], from).await,
+                Err(e) => println!("DHT receive error: {}", e),
+            }
+        }
+    }
+
+    pub async fn handle_packet(&self, buf: &[u8], from: SocketAddr) {
+        let message = match KrpcMessage::decode(buf) {
+            Ok(message) => message,
+            Err(e) => {
+                // Unknown methods still get an answer if we can find the transaction
+                if let Some(transaction) = transaction_id(buf) {
+                    let code = if e.kind() == io::ErrorKind::Unsupported {
+                        krpc::ERROR_METHOD_UNKNOWN
+                    } else {
+                        krpc::ERROR_PROTOCOL
+                    };
+                    self.send_error(from, transaction, code, &e.to_string())
+                        .await;
+                }
+                return;
+            }
+        };
+
+        match message.body {
+            Body::Query { id, query } => {
+                if !self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .limiter
+                    .allow(from.ip(), Instant::now())
+                {
+                    return;
+                }
+                let reply = self.handle_query(id, query, from);
+                let reply = KrpcMessage {
+                    transaction: message.transaction,
+                    body: reply,
+                    ip: Some(from),
+                };
+                self.socket.send_to(&reply.encode(), from).await.ok();
+            }
+            Body::Response(response) => {
+                let mut state = self.state.lock().unwrap();
+                if let Some((addr, sender)) = state.pending.remove(&message.transaction) {
+                    if addr == from {
+                        state.admit(response.id, from);
+                        if let Some(reported) = message.ip {
+                            state.vote_external_ip(from, reported);
+                        }
+                        sender.send(Ok(response)).ok();
+                    } else {
+                        state.pending.insert(message.transaction, (addr, sender));
+                    }
+                }
+            }
+            Body::Error {
+                code,
+                message: text,
+            } => {
+                let mut state = self.state.lock().unwrap();
+                if let Some((addr, sender)) = state.pending.remove(&message.transaction) {
+                    if addr == from {
+                        sender
+                            .send(Err(io::Error::other(format!(
+                                "DHT error {}: {}",
+                                code, text
+                            ))))
+                            .ok();
+                    } else {
+                        state.pending.insert(message.transaction, (addr, sender));
+                    }
+                }
+            }
+        }
+        self.ping_questionable();
+    }
+
+    // Nodes that don't answer make room for the replacements waiting in
+    // their bucket
+    fn ping_questionable(&self) {
+        let addrs = std::mem::take(&mut self.state.lock().unwrap().to_ping);
+        for addr in addrs {
+            let dht = self.clone();
+            tokio::spawn(async move {
+                let answered = dht.ping(addr).await.is_ok();
+                let mut state = dht.state.lock().unwrap();
+                state.evicting.remove(&addr);
+                if !answered {
+                    state.table.evict(&addr);
+                }
+            });
+        }
+    }
+
+    fn handle_query(&self, id: NodeId, query: Query, from: SocketAddr) -> Body {
+        let mut state = self.state.lock().unwrap();
+        state.admit(id, from);
+        state.rotate_secret();
+        let own_id = *state.table.own_id();
+
+        let mut response = Response {
+            id: own_id,
+            ..Default::default()
+        };
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                response.nodes = closest_pairs(&state.table, &target);
+            }
+            Query::GetPeers { info_hash } => {
+                response.token = Some(state.token(&from));
+                let peers = state.peers.get(&info_hash);
+                if peers.is_empty() {
+                    response.nodes = closest_pairs(&state.table, &info_hash);
+                } else {
+                    response.values = peers;
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                token,
+                implied_port,
+            } => {
+                if !state.token_valid(&token, &from) {
+                    return Body::Error {
+                        code: krpc::ERROR_PROTOCOL,
+                        message: "Bad token".to_string(),
+                    };
+                }
+                let port = if implied_port { from.port() } else { port };
+                if port == 0 {
+                    return Body::Error {
+                        code: krpc::ERROR_PROTOCOL,
+                        message: "Invalid port".to_string(),
+                    };
+                }
+                state
+                    .peers
+                    .announce(info_hash, SocketAddr::new(from.ip(), port), Instant::now());
+            }
+            Query::Get { target, seq } => {
+                response.token = Some(state.token(&from));
+                response.nodes = closest_pairs(&state.table, &target);
+                match state.items.get(&target) {
+                    Some(Item::Immutable { v }) => response.v = Some(v.clone()),
+                    Some(Item::Mutable {
+                        v,
+                        k,
+                        sig,
+                        seq: stored,
+                        ..
+                    }) => {
+                        response.k = Some(*k);
+                        response.seq = Some(*stored);
+                        if seq.is_none_or(|seq| *stored > seq) {
+                            response.v = Some(v.clone());
+                            response.sig = Some(*sig);
+                        }
+                    }
+                    None => {}
+                }
+            }
+            Query::Put { token, item, cas } => {
+                if !state.token_valid(&token, &from) {
+                    return Body::Error {
+                        code: krpc::ERROR_PROTOCOL,
+                        message: "Bad token".to_string(),
+                    };
+                }
+                if let Err((code, message)) = state.items.put(item, cas, Instant::now()) {
+                    return Body::Error {
+                        code,
+                        message: message.to_string(),
+                    };
+                }
+            }
+        }
+        Body::Response(response)
+    }
+
+    async fn send_error(&self, to: SocketAddr, transaction: Vec<u8>, code: i64, text: &str) {
+        let message = KrpcMessage {
+            transaction,
+            body: Body::Error {
+                code,
+                message: text.to_string(),
+            },
+            ip: None,
+        };
+        self.socket.send_to(&message.encode(), to).await.ok();
+    }
+
+    pub async fn query(&self, addr: SocketAddr, query: Query) -> io::Result<Response> {
+        let (sender, receiver) = oneshot::channel();
+        let message = {
+            let mut state = self.state.lock().unwrap();
+            let transaction = state.next_transaction.to_be_bytes().to_vec();
+            state.next_transaction = state.next_transaction.wrapping_add(1);
+            state.pending.insert(transaction.clone(), (addr, sender));
+            KrpcMessage {
+                transaction,
+                body: Body::Query {
+                    id: *state.table.own_id(),
+                    query,
+                },
+                ip: None,
+            }
+        };
+
+        self.socket.send_to(&message.encode(), addr).await?;
+        let result = timeout(QUERY_TIMEOUT, receiver).await;
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(Ok(response)) => response,
+            _ => {
+                state.pending.remove(&message.transaction);
+                state.table.mark_failed(&addr);
+                Err(io::Error::new(io::ErrorKind::TimedOut, "DHT query timeout"))
+            }
+        }
+    }
+
+    pub async fn ping(&self, addr: SocketAddr) -> io::Result<NodeId> {
+        Ok(self.query(addr, Query::Ping).await?.id)
+    }
+
+    // Joins the network through the configured bootstrap nodes and whatever
+    // was left in the saved routing table, then looks up our own ID to fill
+    // the buckets close to us.
+    pub async fn bootstrap(&self) -> io::Result<usize> {
+        let mut addrs = Vec::new();
+        for host in &self.config.bootstrap {
+            match lookup_host(host.as_str()).await {
+                Ok(found) => addrs.extend(found.filter(|addr| addr.is_ipv4())),
+                Err(e) => println!("Failed to resolve DHT bootstrap node {}: {}", host, e),
+            }
+        }
+        addrs.extend(self.state.lock().unwrap().table.nodes().map(|n| n.addr));
+        self.bootstrap_from(&addrs).await
+    }
+
+    pub async fn bootstrap_from(&self, addrs: &[SocketAddr]) -> io::Result<usize> {
+        let mut tasks = JoinSet::new();
+        for addr in addrs.iter().copied() {
+            let dht = self.clone();
+            tasks.spawn(async move { dht.ping(addr).await });
+        }
+        while tasks.join_next().await.is_some() {}
+
+        self.find_node(self.id()).await;
+        let nodes = self.num_nodes();
+        if nodes == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "No DHT nodes reachable",
+            ));
+        }
+        Ok(nodes)
+    }
+
+    pub async fn find_node(&self, target: NodeId) -> Vec<(NodeId, SocketAddr)> {
+        self.lookup(target, Lookup::FindNode)
+            .await
+            .closest
+            .into_iter()
+            .map(|(id, addr, _)| (id, addr))
+            .collect()
+    }
+
+    pub async fn get_peers(&self, info_hash: [u8; 20]) -> Vec<SocketAddr> {
+        self.lookup(info_hash, Lookup::GetPeers).await.peers
+    }
+
+    // Finds peers and then tells the closest nodes that we have the torrent
+    pub async fn announce(&self, info_hash: [u8; 20], port: Option<u16>) -> Vec<SocketAddr> {
+        let LookupResult { closest, peers, .. } = self.lookup(info_hash, Lookup::GetPeers).await;
+        let mut tasks = JoinSet::new();
+        for (_, addr, token) in closest {
+            let Some(token) = token else {
+                continue;
+            };
+            let dht = self.clone();
+            tasks.spawn(async move {
+                let query = Query::AnnouncePeer {
+                    info_hash,
+                    port: port.unwrap_or(0),
+                    token,
+                    implied_port: port.is_none(),
+                };
+                dht.query(addr, query).await
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+        peers
+    }
+
+    // BEP 44 immutable item, checked against its hash before it is returned
+    pub async fn get_immutable(&self, target: NodeId) -> Option<BencodeValue> {
+        self.lookup(target, Lookup::Get(None))
+            .await
+            .items
+            .into_iter()
+            .filter_map(|response| response.v)
+            .find(|v| storage::immutable_target(v) == target)
+    }
+
+    // Stores a value on the nodes closest to its hash and returns the hash
+    pub async fn put_immutable(&self, v: BencodeValue) -> io::Result<NodeId> {
+        let item = Item::Immutable { v };
+        let target = item.target();
+        let closest = self.lookup(target, Lookup::Get(None)).await.closest;
+        self.store(closest, item, None).await?;
+        Ok(target)
+    }
+
+    // The newest correctly signed version of a mutable item
+    pub async fn get_mutable(&self, k: [u8; 32], salt: &[u8]) -> Option<Item> {
+        self.lookup_mutable(k, salt).await.1
+    }
+
+    // Publishes `v` under our key and salt with a sequence number above
+    // whatever the network currently holds.
+    pub async fn put_mutable(
+        &self,
+        key: &SigningKey,
+        v: BencodeValue,
+        salt: &[u8],
+    ) -> io::Result<Item> {
+        let k = key.verifying_key().to_bytes();
+        let (closest, current) = self.lookup_mutable(k, salt).await;
+        let cas = current.as_ref().and_then(|item| item.seq());
+        let item = Item::mutable(key, v, cas.map_or(0, |seq| seq + 1), salt);
+        self.store(closest, item.clone(), cas).await?;
+        Ok(item)
+    }
+
+    async fn lookup_mutable(
+        &self,
+        k: [u8; 32],
+        salt: &[u8],
+    ) -> (Vec<(NodeId, SocketAddr, Option<Vec<u8>>)>, Option<Item>) {
+        let target = storage::mutable_target(&k, salt);
+        let result = self.lookup(target, Lookup::Get(None)).await;
+        let newest = result
+            .items
+            .iter()
+            .filter_map(|response| {
+                let item = Item::Mutable {
+                    v: response.v.clone()?,
+                    k: response.k.filter(|key| *key == k)?,
+                    sig: response.sig?,
+                    seq: response.seq?,
+                    salt: salt.to_vec(),
+                };
+                item.verify().is_ok().then_some(item)
+            })
+            .max_by_key(|item| item.seq());
+        (result.closest, newest)
+    }
+
+    // Sends a put to every node from the lookup that gave us a token
+    async fn store(
+        &self,
+        closest: Vec<(NodeId, SocketAddr, Option<Vec<u8>>)>,
+        item: Item,
+        cas: Option<i64>,
+    ) -> io::Result<()> {
+        item.verify()
+            .map_err(|(_, message)| io::Error::new(io::ErrorKind::InvalidInput, message))?;
+
+        let mut tasks = JoinSet::new();
+        for (_, addr, token) in closest {
+            let Some(token) = token else {
+                continue;
+            };
+            let dht = self.clone();
+            let query = Query::Put {
+                token,
+                item: item.clone(),
+                cas,
+            };
+            tasks.spawn(async move { dht.query(addr, query).await });
+        }
+        let mut stored = 0;
+        while let Some(joined) = tasks.join_next().await {
+            if matches!(joined, Ok(Ok(_))) {
+                stored += 1;
+            }
+        }
+        if stored == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "No DHT node accepted the item",
+            ));
+        }
+        Ok(())
+    }
+
+    // Iterative Kademlia lookup. Returns the K closest nodes that answered
+    // (with their tokens for get_peers and get), any peers found on the way
+    // and the responses that carried an item.
+    async fn lookup(&self, target: NodeId, kind: Lookup) -> LookupResult {
+        let mut shortlist: BTreeMap<NodeId, (NodeId, SocketAddr)> = self
+            .state
+            .lock()
+            .unwrap()
+            .table
+            .closest(&target, K)
+            .into_iter()
+            .map(|n| (distance(&n.id, &target), (n.id, n.addr)))
+            .collect();
+        let mut queried: HashSet<SocketAddr> = HashSet::new();
+        let mut responded: BTreeMap<NodeId, (NodeId, SocketAddr, Option<Vec<u8>>)> =
+            BTreeMap::new();
+        let mut peers: HashSet<SocketAddr> = HashSet::new();
+        let mut items: Vec<Response> = Vec::new();
+        let own_id = self.id();
+
+        loop {
+            let batch: Vec<(NodeId, SocketAddr)> = shortlist
+                .values()
+                .take(K)
+                .filter(|(_, addr)| !queried.contains(addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut tasks = JoinSet::new();
+            for (id, addr) in batch {
+                queried.insert(addr);
+                let dht = self.clone();
+                let query = match kind {
+                    Lookup::FindNode => Query::FindNode { target },
+                    Lookup::GetPeers => Query::GetPeers { info_hash: target },
+                    Lookup::Get(seq) => Query::Get { target, seq },
+                };
+                tasks.spawn(async move { (id, addr, dht.query(addr, query).await) });
+            }
+
+            while let Some(joined) = tasks.join_next().await {
+                let Ok((id, addr, result)) = joined else {
+                    continue;
+                };
+                let dist = distance(&id, &target);
+                let Ok(response) = result else {
+                    shortlist.remove(&dist);
+                    continue;
+                };
+                responded.insert(
+                    distance(&response.id, &target),
+                    (response.id, addr, response.token.clone()),
+                );
+                peers.extend(response.values.iter().copied());
+                for (node_id, node_addr) in response.nodes.iter().copied() {
+                    if node_id != own_id && !queried.contains(&node_addr) {
+                        shortlist.insert(distance(&node_id, &target), (node_id, node_addr));
+                    }
+                }
+                if response.v.is_some() {
+                    items.push(response);
+                }
+            }
+        }
+
+        self.state.lock().unwrap().table.touch_bucket(&target);
+        LookupResult {
+            closest: responded.into_values().take(K).collect(),
+            peers: peers.into_iter().collect(),
+            items,
+        }
+    }
+
+    // Refreshes stale buckets, pings nodes we haven't heard from, expires
+    // announced peers and saves the table. Runs until the task is dropped.
+    pub async fn maintain(&self) {
+        loop {
+            tokio::time::sleep(MAINTENANCE_INTERVAL).await;
+            let now = Instant::now();
+            let (targets, questionable) = {
+                let mut state = self.state.lock().unwrap();
+                state.peers.expire(now);
+                state.items.expire(now);
+                let questionable: Vec<SocketAddr> = state
+                    .table
+                    .nodes()
+                    .filter(|n| now.duration_since(n.last_seen) >= routing::BUCKET_REFRESH)
+                    .map(|n| n.addr)
+                    .collect();
+                (state.table.refresh_targets(now), questionable)
+            };
+
+            for addr in questionable {
+                self.ping(addr).await.ok();
+            }
+            for target in targets {
+                self.find_node(target).await;
+            }
+            if let Err(e) = self.save() {
+                println!("Failed to save DHT state: {}", e);
+            }
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.config.state_path else {
+            return Ok(());
+        };
+        let (id, nodes) = {
+            let state = self.state.lock().unwrap();
+            let nodes: Vec<(NodeId, SocketAddr)> = state
+                .table
+                .nodes()
+                .filter(|n| !n.is_bad())
+                .map(|n| (n.id, n.addr))
+                .collect();
+            (*state.table.own_id(), nodes)
+        };
+        let data = BencodeValue::Dict(vec![
+            (b"id".to_vec(), BencodeValue::String(id.to_vec())),
+            (
+                b"nodes".to_vec(),
+                BencodeValue::String(krpc::encode_nodes(&nodes)),
+            ),
+        ])
+        .encode();
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(tmp, path)
+    }
+}
+
+impl DhtState {
+    fn admit(&mut self, id: NodeId, addr: SocketAddr) {
+        if self.enforce_node_id && !security::is_valid_id(&id, &addr.ip()) {
+            return;
+        }
+        if let Insert::Ping(questionable) = self.table.insert(id, addr) {
+            if self.evicting.insert(questionable) {
+                self.to_ping.push(questionable);
+            }
+        }
+    }
+
+    // Once the network agrees on a new external IP our ID has to follow it,
+    // otherwise BEP 42 nodes will refuse to store us.
+    fn vote_external_ip(&mut self, voter: SocketAddr, reported: SocketAddr) {
+        let Some(ip) = self.voter.vote(voter, reported) else {
+            return;
+        };
+        if !security::is_valid_id(self.table.own_id(), &ip) {
+            let id = security::generate_id(&ip);
+            println!("DHT external IP is {}, switching node ID", ip);
+            self.table = self.table.rebuild(id);
+        }
+    }
+
+    fn rotate_secret(&mut self) {
+        if self.secret_rotated.elapsed() >= TOKEN_ROTATION {
+            self.previous_secret = self.secret;
+            self.secret = rand::random();
+            self.secret_rotated = Instant::now();
+        }
+    }
+
+    // Tokens are a hash of the querying IP and a secret that rotates every
+    // five minutes, the previous secret is still accepted.
+    fn token(&self, addr: &SocketAddr) -> Vec<u8> {
+        make_token(&self.secret, addr)
+    }
+
+    fn token_valid(&self, token: &[u8], addr: &SocketAddr) -> bool {
+        token == make_token(&self.secret, addr).as_slice()
+            || token == make_token(&self.previous_secret, addr).as_slice()
+    }
+}
+
+fn make_token(secret: &[u8; 20], addr: &SocketAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    match addr.ip() {
+        std::net::IpAddr::V4(ip) => hasher.update(ip.octets()),
+        std::net::IpAddr::V6(ip) => hasher.update(ip.octets()),
+    }
+    hasher.finalize()[..8].to_vec()
+}
+
+fn closest_pairs(table: &RoutingTable, target: &NodeId) -> Vec<(NodeId, SocketAddr)> {
+    table
+        .closest(target, K)
+        .into_iter()
+        .map(|n| (n.id, n.addr))
+        .collect()
+}
+
+fn transaction_id(buf: &[u8]) -> Option<Vec<u8>> {
+    let value = BencodeParser::new(buf).parse_with_depth(MAX_DEPTH).ok()?;
+    Some(value.get(b"t")?.as_bytes()?.to_vec())
+}
+
+fn load_state(path: &Path) -> Option<(NodeId, Vec<(NodeId, SocketAddr)>)> {
+    let data = std::fs::read(path).ok()?;
+    let value = BencodeParser::new(&data).parse().ok()?;
+    let id: NodeId = value.get(b"id")?.as_bytes()?.try_into().ok()?;
+    let nodes = value
+        .get(b"nodes")
+        .and_then(|v| v.as_bytes())
+        .map(krpc::parse_nodes)
+        .unwrap_or_default();
+    Some((id, nodes))
+}
@@ -0,0 +1,392 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::security;
+
+pub type NodeId = [u8; 20];
+
+pub const K: usize = 8;
+// Buckets nobody touched for this long get refreshed with a lookup
+pub const BUCKET_REFRESH: Duration = Duration::from_secs(15 * 60);
+// Nodes that failed this many queries in a row are replaced first
+const MAX_FAILURES: u32 = 2;
+// BEP 5: a node we haven't heard from for this long is questionable, a full
+// bucket pings it before turning a new node away
+pub const QUESTIONABLE: Duration = Duration::from_secs(15 * 60);
+// Nodes waiting per bucket for a place to free up, newest kept
+const MAX_REPLACEMENTS: usize = K;
+
+// What became of a node offered to the table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Insert {
+    Added,
+    // The bucket is full and the node waits as a replacement. The node at
+    // this address hasn't been heard from in a while and should be pinged,
+    // if it doesn't answer `evict` makes room.
+    Ping(SocketAddr),
+    Dropped,
+}
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0u8; 20];
+    for i in 0..20 {
+        d[i] = a[i] ^ b[i];
+    }
+    d
+}
+
+// Number of leading bits two IDs share, 160 for identical IDs
+pub fn common_prefix(a: &NodeId, b: &NodeId) -> usize {
+    let d = distance(a, b);
+    for (i, byte) in d.iter().enumerate() {
+        if *byte != 0 {
+            return i * 8 + byte.leading_zeros() as usize;
+        }
+    }
+    160
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    pub last_seen: Instant,
+    pub failures: u32,
+}
+
+impl Node {
+    pub fn new(id: NodeId, addr: SocketAddr) -> Self {
+        Node {
+            id,
+            addr,
+            last_seen: Instant::now(),
+            failures: 0,
+        }
+    }
+
+    pub fn is_bad(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+
+    pub fn is_questionable(&self, now: Instant) -> bool {
+        self.failures > 0 || now.duration_since(self.last_seen) >= QUESTIONABLE
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    nodes: Vec<Node>,
+    replacements: Vec<Node>,
+    last_changed: Instant,
+}
+
+impl Bucket {
+    fn add_replacement(&mut self, node: Node) {
+        self.replacements
+            .retain(|n| n.id != node.id && n.addr != node.addr);
+        if self.replacements.len() >= MAX_REPLACEMENTS {
+            self.replacements.remove(0);
+        }
+        self.replacements.push(node);
+    }
+
+    // Fills a free place with the newest replacement
+    fn promote(&mut self, now: Instant) {
+        if self.nodes.len() < K {
+            if let Some(node) = self.replacements.pop() {
+                self.nodes.push(node);
+                self.last_changed = now;
+            }
+        }
+    }
+}
+
+// One bucket per shared prefix length with our own ID. Bucket 0 covers half
+// the ID space, the last one only IDs next to ours. Equivalent to a fully
+// split Kademlia table without the split bookkeeping.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        let now = Instant::now();
+        RoutingTable {
+            own_id,
+            buckets: vec![
+                Bucket {
+                    nodes: Vec::new(),
+                    replacements: Vec::new(),
+                    last_changed: now,
+                };
+                160
+            ],
+        }
+    }
+
+    pub fn own_id(&self) -> &NodeId {
+        &self.own_id
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let prefix = common_prefix(&self.own_id, id);
+        (prefix < 160).then_some(prefix)
+    }
+
+    // Adds or refreshes a node that just talked to us. A full bucket only
+    // makes room by dropping a bad node right away, or by asking for its
+    // least recently seen questionable node to be pinged first.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr) -> Insert {
+        let Some(index) = self.bucket_index(&id) else {
+            return Insert::Dropped;
+        };
+        let now = Instant::now();
+
+        if let Some(node) = self.buckets[index].nodes.iter_mut().find(|n| n.id == id) {
+            node.addr = addr;
+            node.last_seen = now;
+            node.failures = 0;
+            self.buckets[index].last_changed = now;
+            return Insert::Added;
+        }
+        if self.ip_restricted(index, &addr) {
+            return Insert::Dropped;
+        }
+
+        let bucket = &mut self.buckets[index];
+        if bucket.nodes.len() >= K {
+            if let Some(pos) = bucket.nodes.iter().position(|n| n.is_bad()) {
+                bucket.nodes.remove(pos);
+            } else {
+                bucket.add_replacement(Node::new(id, addr));
+                return match bucket
+                    .nodes
+                    .iter()
+                    .filter(|n| n.is_questionable(now))
+                    .min_by_key(|n| n.last_seen)
+                {
+                    Some(node) => Insert::Ping(node.addr),
+                    None => Insert::Dropped,
+                };
+            }
+        }
+        bucket.replacements.retain(|n| n.id != id);
+        bucket.nodes.push(Node::new(id, addr));
+        bucket.last_changed = now;
+        Insert::Added
+    }
+
+    // A questionable node didn't answer its ping, a replacement takes its
+    // place
+    pub fn evict(&mut self, addr: &SocketAddr) {
+        let now = Instant::now();
+        for bucket in self.buckets.iter_mut() {
+            if let Some(pos) = bucket.nodes.iter().position(|n| &n.addr == addr) {
+                if !bucket.replacements.is_empty() {
+                    bucket.nodes.remove(pos);
+                    bucket.promote(now);
+                }
+                return;
+            }
+        }
+    }
+
+    // Against Sybil and eclipse attacks: one entry per IP in the whole table
+    // and one per subnet in each bucket. Local addresses are exempt.
+    fn ip_restricted(&self, index: usize, addr: &SocketAddr) -> bool {
+        let ip = addr.ip();
+        if security::is_local(&ip) {
+            return false;
+        }
+        if self.nodes().any(|n| n.addr.ip() == ip) {
+            return true;
+        }
+        let net = security::subnet(&ip);
+        self.buckets[index]
+            .nodes
+            .iter()
+            .any(|n| security::subnet(&n.addr.ip()) == net)
+    }
+
+    // Same nodes under a new own ID, used when our external IP changes
+    pub fn rebuild(&self, own_id: NodeId) -> RoutingTable {
+        let mut table = RoutingTable::new(own_id);
+        for node in self.nodes() {
+            table.insert(node.id, node.addr);
+        }
+        table
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.bucket_index(id) {
+            let bucket = &mut self.buckets[index];
+            bucket.nodes.retain(|n| &n.id != id);
+            bucket.promote(Instant::now());
+        }
+    }
+
+    // A node that went bad is swapped for a replacement if one is waiting
+    pub fn mark_failed(&mut self, addr: &SocketAddr) {
+        let now = Instant::now();
+        for bucket in self.buckets.iter_mut() {
+            if let Some(pos) = bucket.nodes.iter().position(|n| &n.addr == addr) {
+                bucket.nodes[pos].failures += 1;
+                if bucket.nodes[pos].is_bad() && !bucket.replacements.is_empty() {
+                    bucket.nodes.remove(pos);
+                    bucket.promote(now);
+                }
+                return;
+            }
+        }
+    }
+
+    pub fn contains(&self, id: &NodeId) -> bool {
+        self.bucket_index(id)
+            .is_some_and(|index| self.buckets[index].nodes.iter().any(|n| &n.id == id))
+    }
+
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self
+            .nodes()
+            .filter(|node| !node.is_bad())
+            .cloned()
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.iter().flat_map(|b| b.nodes.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.nodes.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Random IDs that fall into buckets nobody has touched for a while. Only
+    // buckets up to the deepest non-empty one are worth refreshing.
+    pub fn refresh_targets(&self, now: Instant) -> Vec<NodeId> {
+        let deepest = self
+            .buckets
+            .iter()
+            .rposition(|b| !b.nodes.is_empty())
+            .unwrap_or(0);
+        (0..=deepest)
+            .filter(|&i| now.duration_since(self.buckets[i].last_changed) >= BUCKET_REFRESH)
+            .map(|i| self.random_id_in_bucket(i))
+            .collect()
+    }
+
+    // Shares exactly `prefix` leading bits with our ID
+    pub fn random_id_in_bucket(&self, prefix: usize) -> NodeId {
+        let mut id: NodeId = rand::random();
+        for bit in 0..=prefix.min(159) {
+            let byte = bit / 8;
+            let mask = 0x80u8 >> (bit % 8);
+            let own = self.own_id[byte] & mask;
+            // Copy our bits up to the prefix, then flip the next one
+            let wanted = if bit == prefix { own ^ mask } else { own };
+            id[byte] = (id[byte] & !mask) | wanted;
+        }
+        id
+    }
+
+    pub fn touch_bucket(&mut self, id: &NodeId) {
+        if let Some(index) = self.bucket_index(id) {
+            self.buckets[index].last_changed = Instant::now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // IDs in bucket 0 of a table with an all zero ID, on distinct /24s
+    fn far_node(n: u8) -> (NodeId, SocketAddr) {
+        let mut id = [0u8; 20];
+        id[0] = 0x80;
+        id[19] = n;
+        (id, SocketAddr::from(([1, 2, n, 4], 6881)))
+    }
+
+    #[test]
+    fn full_bucket_pings_questionable_node() {
+        let mut table = RoutingTable::new([0; 20]);
+        for n in 0..K as u8 {
+            let (id, addr) = far_node(n);
+            assert_eq!(table.insert(id, addr), Insert::Added);
+        }
+        let (new_id, new_addr) = far_node(100);
+        assert_eq!(table.insert(new_id, new_addr), Insert::Dropped);
+
+        let (_, stale) = far_node(3);
+        table.mark_failed(&stale);
+        assert_eq!(table.insert(new_id, new_addr), Insert::Ping(stale));
+        assert!(!table.contains(&new_id));
+
+        table.evict(&stale);
+        assert!(table.contains(&new_id));
+        assert!(!table.nodes().any(|n| n.addr == stale));
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn bad_node_swapped_for_replacement() {
+        let mut table = RoutingTable::new([0; 20]);
+        for n in 0..K as u8 {
+            let (id, addr) = far_node(n);
+            table.insert(id, addr);
+        }
+        let (new_id, new_addr) = far_node(100);
+        table.insert(new_id, new_addr);
+        let (_, failing) = far_node(0);
+        table.mark_failed(&failing);
+        table.mark_failed(&failing);
+        assert!(table.contains(&new_id));
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn one_node_per_ip_and_per_subnet_in_a_bucket() {
+        let mut table = RoutingTable::new([0; 20]);
+        let (id, addr) = far_node(1);
+        assert_eq!(table.insert(id, addr), Insert::Added);
+
+        // Same /24 in the same bucket
+        let (other, _) = far_node(2);
+        let neighbour = SocketAddr::from(([1, 2, 1, 5], 6881));
+        assert_eq!(table.insert(other, neighbour), Insert::Dropped);
+        // Same IP on another port, in another bucket
+        let mut near = [0u8; 20];
+        near[0] = 0x01;
+        let same_ip = SocketAddr::from(([1, 2, 1, 4], 6882));
+        assert_eq!(table.insert(near, same_ip), Insert::Dropped);
+        // The same /24 is fine in another bucket
+        assert_eq!(table.insert(near, neighbour), Insert::Added);
+        // A known node can still move to a new address
+        assert_eq!(
+            table.insert(id, SocketAddr::from(([1, 2, 1, 4], 7000))),
+            Insert::Added
+        );
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn local_addresses_exempt_from_ip_limits() {
+        let mut table = RoutingTable::new([0; 20]);
+        for n in 0..4u8 {
+            let (id, _) = far_node(n);
+            let addr = SocketAddr::from(([127, 0, 0, 1], 7000 + n as u16));
+            assert_eq!(table.insert(id, addr), Insert::Added);
+        }
+        assert_eq!(table.len(), 4);
+    }
+}
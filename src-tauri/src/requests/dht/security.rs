@@ -0,0 +1,344 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use super::routing::NodeId;
+
+const V4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const V6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+// Distinct nodes that must agree on our external address before we act on it
+const IP_VOTES_NEEDED: usize = 5;
+const MAX_VOTERS: usize = 50;
+
+// Incoming queries allowed per source IP: a burst, then a steady rate
+const QUERY_BURST: f64 = 20.0;
+const QUERIES_PER_SECOND: f64 = 5.0;
+const LIMITER_IDLE: Duration = Duration::from_secs(60);
+
+// CRC32-C (Castagnoli), bitwise since it only ever hashes 4 or 8 bytes
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82F6_3B78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// Loopback, private and link-local addresses are exempt from BEP 42, so LAN
+// nodes and local simulations keep working.
+pub fn is_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || (ip.segments()[0] & 0xfe00) == 0xfc00
+                || (ip.segments()[0] & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+fn id_prefix(ip: &IpAddr, r: u8) -> u32 {
+    let mut masked = match ip {
+        IpAddr::V4(ip) => {
+            let mut bytes = ip.octets().to_vec();
+            for (b, m) in bytes.iter_mut().zip(V4_MASK) {
+                *b &= m;
+            }
+            bytes
+        }
+        IpAddr::V6(ip) => {
+            let mut bytes = ip.octets()[..8].to_vec();
+            for (b, m) in bytes.iter_mut().zip(V6_MASK) {
+                *b &= m;
+            }
+            bytes
+        }
+    };
+    masked[0] |= (r & 0x07) << 5;
+    crc32c(&masked)
+}
+
+pub fn generate_id(ip: &IpAddr) -> NodeId {
+    let mut id: NodeId = rand::random();
+    let r = id[19];
+    let crc = id_prefix(ip, r);
+    id[0] = (crc >> 24) as u8;
+    id[1] = (crc >> 16) as u8;
+    id[2] = ((crc >> 8) as u8 & 0xf8) | (id[2] & 0x07);
+    id
+}
+
+// The first 21 bits of the ID must come from the CRC of the node's IP
+pub fn is_valid_id(id: &NodeId, ip: &IpAddr) -> bool {
+    if is_local(ip) {
+        return true;
+    }
+    let crc = id_prefix(ip, id[19]);
+    id[0] == (crc >> 24) as u8
+        && id[1] == (crc >> 16) as u8
+        && (id[2] & 0xf8) == ((crc >> 8) as u8 & 0xf8)
+}
+
+// /24 for IPv4 and /64 for IPv6, used to limit how many routing table
+// entries a single network can take.
+pub fn subnet(ip: &IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets()[..3].to_vec(),
+        IpAddr::V6(ip) => ip.octets()[..8].to_vec(),
+    }
+}
+
+// Learns our external address from the `ip` field other nodes put in their
+// responses. One vote per node, and a new address only wins once enough
+// distinct nodes report it.
+#[derive(Debug, Default)]
+pub struct ExternalIpVoter {
+    votes: HashMap<IpAddr, IpAddr>,
+    current: Option<IpAddr>,
+}
+
+impl ExternalIpVoter {
+    pub fn new() -> Self {
+        ExternalIpVoter::default()
+    }
+
+    pub fn current(&self) -> Option<IpAddr> {
+        self.current
+    }
+
+    // Returns the new external IP when the vote changes it. Votes are
+    // cleared once they decide, so later voters can still move the address.
+    pub fn vote(&mut self, voter: SocketAddr, reported: SocketAddr) -> Option<IpAddr> {
+        if is_local(&voter.ip()) || is_local(&reported.ip()) {
+            return None;
+        }
+        if self.votes.len() >= MAX_VOTERS && !self.votes.contains_key(&voter.ip()) {
+            return None;
+        }
+        self.votes.insert(voter.ip(), reported.ip());
+
+        let mut tally: HashMap<IpAddr, usize> = HashMap::new();
+        for ip in self.votes.values() {
+            *tally.entry(*ip).or_default() += 1;
+        }
+        let (winner, count) = tally.into_iter().max_by_key(|(_, count)| *count)?;
+        if count < IP_VOTES_NEEDED {
+            // Too split to decide, start over rather than turn voters away
+            if self.votes.len() >= MAX_VOTERS {
+                self.votes.clear();
+            }
+            return None;
+        }
+        self.votes.clear();
+        if Some(winner) == self.current {
+            return None;
+        }
+        self.current = Some(winner);
+        Some(winner)
+    }
+}
+
+// Token bucket per source IP for incoming queries
+#[derive(Debug, Default)]
+pub struct QueryRateLimiter {
+    buckets: HashMap<IpAddr, (f64, Instant)>,
+    last_cleanup: Option<Instant>,
+}
+
+impl QueryRateLimiter {
+    pub fn new() -> Self {
+        QueryRateLimiter::default()
+    }
+
+    // Loopback is never limited so in-process simulations can run many nodes
+    pub fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if ip.is_loopback() {
+            return true;
+        }
+        if self
+            .last_cleanup
+            .is_none_or(|last| now.duration_since(last) >= LIMITER_IDLE)
+        {
+            self.buckets
+                .retain(|_, (_, last)| now.duration_since(*last) < LIMITER_IDLE);
+            self.last_cleanup = Some(now);
+        }
+
+        let (tokens, last) = self.buckets.entry(ip).or_insert((QUERY_BURST, now));
+        let refill = now.duration_since(*last).as_secs_f64() * QUERIES_PER_SECOND;
+        *tokens = (*tokens + refill).min(QUERY_BURST);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vectors from BEP 42: IP, rand and the start of a valid node ID
+    const VECTORS: [(&str, u8, [u8; 3]); 5] = [
+        ("124.31.75.21", 1, [0x5f, 0xbf, 0xbf]),
+        ("21.75.31.124", 86, [0x5a, 0x3c, 0xe9]),
+        ("65.23.51.170", 22, [0xa5, 0xd4, 0x32]),
+        ("84.124.73.14", 65, [0x1b, 0x03, 0x21]),
+        ("43.213.53.83", 90, [0xe5, 0x6f, 0x6c]),
+    ];
+
+    fn addr(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 6881)
+    }
+
+    #[test]
+    fn bep42_vectors() {
+        for (ip, r, prefix) in VECTORS {
+            let ip: IpAddr = ip.parse().unwrap();
+            let crc = id_prefix(&ip, r);
+            assert_eq!(
+                crc >> 11,
+                u32::from_be_bytes([0, prefix[0], prefix[1], prefix[2]]) >> 3
+            );
+
+            let mut id = [0x55; 20];
+            id[..3].copy_from_slice(&prefix);
+            id[19] = r;
+            assert!(is_valid_id(&id, &ip), "{}", ip);
+            // Only the top 5 bits of the third byte are checked
+            id[2] ^= 0x07;
+            assert!(is_valid_id(&id, &ip));
+            id[2] ^= 0x08;
+            assert!(!is_valid_id(&id, &ip));
+        }
+    }
+
+    #[test]
+    fn generated_ids_are_valid() {
+        let ip: IpAddr = "124.31.75.21".parse().unwrap();
+        let other: IpAddr = "124.31.75.22".parse().unwrap();
+        for _ in 0..20 {
+            let id = generate_id(&ip);
+            assert!(is_valid_id(&id, &ip));
+        }
+        let id = generate_id(&ip);
+        let r = id[19];
+        assert_eq!(
+            is_valid_id(&id, &other),
+            id_prefix(&other, r) >> 11 == id_prefix(&ip, r) >> 11
+        );
+        assert!(!is_valid_id(&[0; 20], &"21.75.31.124".parse().unwrap()));
+        // Local addresses are exempt
+        assert!(is_valid_id(&[0; 20], &"192.168.1.2".parse().unwrap()));
+        assert!(is_valid_id(&[0; 20], &"::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn voter_needs_distinct_nodes() {
+        let mut voter = ExternalIpVoter::new();
+        let reported = addr("8.8.4.4");
+        // The same node voting again counts once
+        for _ in 0..10 {
+            assert_eq!(voter.vote(addr("1.1.1.1"), reported), None);
+        }
+        for n in 2..IP_VOTES_NEEDED {
+            assert_eq!(voter.vote(addr(&format!("1.1.1.{}", n)), reported), None);
+        }
+        // Local voters and local reports don't count
+        assert_eq!(voter.vote(addr("10.0.0.1"), reported), None);
+        assert_eq!(voter.vote(addr("2.2.2.2"), addr("192.168.0.5")), None);
+        assert_eq!(voter.current(), None);
+
+        let winner = voter.vote(addr("1.1.1.200"), reported);
+        assert_eq!(winner, Some(reported.ip()));
+        assert_eq!(voter.current(), Some(reported.ip()));
+    }
+
+    #[test]
+    fn voter_keeps_taking_votes_after_confirming() {
+        let mut voter = ExternalIpVoter::new();
+        let first = addr("8.8.4.4");
+        let second = addr("9.9.9.9");
+        for n in 0..IP_VOTES_NEEDED {
+            voter.vote(addr(&format!("1.1.1.{}", n)), first);
+        }
+        assert_eq!(voter.current(), Some(first.ip()));
+
+        // Far more nodes than fit confirm the address we already have
+        for n in 0..MAX_VOTERS * 2 {
+            let node = format!("2.2.{}.{}", n / 250, n % 250);
+            assert_eq!(voter.vote(addr(&node), first), None);
+        }
+        // New nodes can still move it once our address changes
+        let mut changed = None;
+        for n in 0..IP_VOTES_NEEDED {
+            changed = voter.vote(addr(&format!("3.3.3.{}", n)), second);
+        }
+        assert_eq!(changed, Some(second.ip()));
+        assert_eq!(voter.current(), Some(second.ip()));
+    }
+
+    #[test]
+    fn voter_starts_over_when_split() {
+        let mut voter = ExternalIpVoter::new();
+        // Every node reports a different address, nothing wins
+        for n in 0..MAX_VOTERS {
+            let node = format!("2.2.2.{}", n);
+            let reported = format!("4.4.4.{}", n);
+            assert_eq!(voter.vote(addr(&node), addr(&reported)), None);
+        }
+        let mut changed = None;
+        for n in 0..IP_VOTES_NEEDED {
+            changed = voter.vote(addr(&format!("3.3.3.{}", n)), addr("9.9.9.9"));
+        }
+        assert_eq!(changed, Some("9.9.9.9".parse().unwrap()));
+    }
+
+    #[test]
+    fn query_burst_then_steady_rate() {
+        let mut limiter = QueryRateLimiter::new();
+        let ip: IpAddr = "5.6.7.8".parse().unwrap();
+        let start = Instant::now();
+        for _ in 0..QUERY_BURST as usize {
+            assert!(limiter.allow(ip, start));
+        }
+        assert!(!limiter.allow(ip, start));
+        // Other sources have buckets of their own
+        assert!(limiter.allow("5.6.7.9".parse().unwrap(), start));
+
+        // One second refills five queries
+        let later = start + Duration::from_secs(1);
+        for _ in 0..QUERIES_PER_SECOND as usize {
+            assert!(limiter.allow(ip, later));
+        }
+        assert!(!limiter.allow(ip, later));
+        // Never more than the burst after a long pause
+        let idle = later + Duration::from_secs(30);
+        let allowed = (0..100).filter(|_| limiter.allow(ip, idle)).count();
+        assert_eq!(allowed, QUERY_BURST as usize);
+    }
+
+    #[test]
+    fn loopback_is_unlimited() {
+        let mut limiter = QueryRateLimiter::new();
+        let now = Instant::now();
+        for ip in ["127.0.0.1", "::1"] {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!((0..1000).all(|_| limiter.allow(ip, now)));
+        }
+    }
+}
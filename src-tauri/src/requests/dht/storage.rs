@@ -0,0 +1,353 @@
+use dirs::config_dir;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::krpc;
+use super::routing::NodeId;
+use crate::backend::file::BencodeValue;
+
+// BEP 44 limits on the bencoded value and the salt
+pub const MAX_VALUE_SIZE: usize = 1000;
+pub const MAX_SALT_SIZE: usize = 64;
+// Items have to be put again before this runs out
+pub const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+const MAX_ITEMS: usize = 1000;
+
+// Announced peers are dropped unless they announce again within this
+pub const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_TORRENTS: usize = 2000;
+const MAX_PEERS_PER_TORRENT: usize = 200;
+// Peers returned in one get_peers response
+const MAX_VALUES: usize = 50;
+
+pub type ItemError = (i64, &'static str);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    // Stored under the SHA-1 of the bencoded value, so it can never change
+    Immutable {
+        v: BencodeValue,
+    },
+    // Stored under SHA-1(public key + salt), updated by publishing a higher
+    // sequence number signed with the same key
+    Mutable {
+        v: BencodeValue,
+        k: [u8; 32],
+        sig: [u8; 64],
+        seq: i64,
+        salt: Vec<u8>,
+    },
+}
+
+impl Item {
+    pub fn mutable(key: &SigningKey, v: BencodeValue, seq: i64, salt: &[u8]) -> Item {
+        let sig = key.sign(&signature_payload(salt, seq, &v)).to_bytes();
+        Item::Mutable {
+            v,
+            k: key.verifying_key().to_bytes(),
+            sig,
+            seq,
+            salt: salt.to_vec(),
+        }
+    }
+
+    pub fn value(&self) -> &BencodeValue {
+        match self {
+            Item::Immutable { v } | Item::Mutable { v, .. } => v,
+        }
+    }
+
+    pub fn seq(&self) -> Option<i64> {
+        match self {
+            Item::Immutable { .. } => None,
+            Item::Mutable { seq, .. } => Some(*seq),
+        }
+    }
+
+    pub fn target(&self) -> NodeId {
+        match self {
+            Item::Immutable { v } => immutable_target(v),
+            Item::Mutable { k, salt, .. } => mutable_target(k, salt),
+        }
+    }
+
+    pub fn verify(&self) -> Result<(), ItemError> {
+        if self.value().encode().len() > MAX_VALUE_SIZE {
+            return Err((krpc::ERROR_MESSAGE_TOO_BIG, "Message too big"));
+        }
+        let Item::Mutable {
+            v,
+            k,
+            sig,
+            seq,
+            salt,
+        } = self
+        else {
+            return Ok(());
+        };
+        if salt.len() > MAX_SALT_SIZE {
+            return Err((krpc::ERROR_SALT_TOO_BIG, "Salt too big"));
+        }
+        let bad_signature = (krpc::ERROR_INVALID_SIGNATURE, "Invalid signature");
+        let key = VerifyingKey::from_bytes(k).map_err(|_| bad_signature)?;
+        key.verify(
+            &signature_payload(salt, *seq, v),
+            &Signature::from_bytes(sig),
+        )
+        .map_err(|_| bad_signature)
+    }
+}
+
+pub fn immutable_target(v: &BencodeValue) -> NodeId {
+    Sha1::digest(v.encode()).into()
+}
+
+pub fn mutable_target(k: &[u8; 32], salt: &[u8]) -> NodeId {
+    let mut hasher = Sha1::new();
+    hasher.update(k);
+    hasher.update(salt);
+    hasher.finalize().into()
+}
+
+// The signed buffer is the bencoded salt, seq and v keys as they would
+// appear in a dict, without the surrounding "d" and "e".
+fn signature_payload(salt: &[u8], seq: i64, v: &BencodeValue) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if !salt.is_empty() {
+        buf.extend(format!("4:salt{}:", salt.len()).as_bytes());
+        buf.extend(salt);
+    }
+    buf.extend(format!("3:seqi{}e1:v", seq).as_bytes());
+    buf.extend(v.encode());
+    buf
+}
+
+// Items other nodes asked us to store
+#[derive(Debug, Default)]
+pub struct ItemStore {
+    items: HashMap<NodeId, (Item, Instant)>,
+}
+
+impl ItemStore {
+    pub fn new() -> Self {
+        ItemStore::default()
+    }
+
+    pub fn get(&self, target: &NodeId) -> Option<&Item> {
+        self.items.get(target).map(|(item, _)| item)
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    // `cas` is the sequence number the writer expects to replace
+    pub fn put(&mut self, item: Item, cas: Option<i64>, now: Instant) -> Result<(), ItemError> {
+        item.verify()?;
+        let target = item.target();
+
+        if let Some((stored, _)) = self.items.get(&target) {
+            if let (Some(old), Some(new)) = (stored.seq(), item.seq()) {
+                if cas.is_some_and(|cas| cas != old) {
+                    return Err((krpc::ERROR_CAS_MISMATCH, "CAS mismatch"));
+                }
+                if new < old || (new == old && stored.value() != item.value()) {
+                    return Err((krpc::ERROR_SEQ_TOO_LOW, "Sequence number less than current"));
+                }
+            }
+        } else if self.items.len() >= MAX_ITEMS {
+            let oldest = self
+                .items
+                .iter()
+                .min_by_key(|(_, (_, stored))| *stored)
+                .map(|(target, _)| *target);
+            if let Some(oldest) = oldest {
+                self.items.remove(&oldest);
+            }
+        }
+        self.items.insert(target, (item, now));
+        Ok(())
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        self.items
+            .retain(|_, (_, stored)| now.duration_since(*stored) < ITEM_TTL);
+    }
+}
+
+// Peers that announced themselves to us, by info hash. When full the
+// torrent or peer that announced longest ago makes room for the new one.
+#[derive(Debug, Default)]
+pub struct PeerStore {
+    // Peers with when they last announced, and when anyone last announced
+    torrents: HashMap<[u8; 20], (HashMap<SocketAddr, Instant>, Instant)>,
+}
+
+impl PeerStore {
+    pub fn new() -> Self {
+        PeerStore::default()
+    }
+
+    pub fn get(&self, info_hash: &[u8; 20]) -> Vec<SocketAddr> {
+        self.torrents
+            .get(info_hash)
+            .map(|(peers, _)| peers.keys().take(MAX_VALUES).copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.torrents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.torrents.is_empty()
+    }
+
+    pub fn announce(&mut self, info_hash: [u8; 20], addr: SocketAddr, now: Instant) {
+        if !self.torrents.contains_key(&info_hash) && self.torrents.len() >= MAX_TORRENTS {
+            let oldest = self
+                .torrents
+                .iter()
+                .min_by_key(|(_, (_, last))| *last)
+                .map(|(info_hash, _)| *info_hash);
+            if let Some(oldest) = oldest {
+                self.torrents.remove(&oldest);
+            }
+        }
+        let (peers, last) = self
+            .torrents
+            .entry(info_hash)
+            .or_insert_with(|| (HashMap::new(), now));
+        *last = now;
+        if !peers.contains_key(&addr) && peers.len() >= MAX_PEERS_PER_TORRENT {
+            let oldest = peers
+                .iter()
+                .min_by_key(|(_, seen)| **seen)
+                .map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                peers.remove(&oldest);
+            }
+        }
+        peers.insert(addr, now);
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        for (peers, _) in self.torrents.values_mut() {
+            peers.retain(|_, seen| now.duration_since(*seen) < PEER_TTL);
+        }
+        self.torrents.retain(|_, (peers, _)| !peers.is_empty());
+    }
+}
+
+// The key mutable items are published with, created on first use and kept
+// next to the settings so the same key can update them after a restart. Only
+// the user may read it, and it is written whole or not at all.
+pub fn load_or_create_key() -> io::Result<SigningKey> {
+    let mut path = config_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No config directory"))?;
+    path.push("defttorrent");
+    path.push("dht_key.dat");
+
+    if let Ok(data) = fs::read(&path) {
+        if let Ok(seed) = <[u8; 32]>::try_from(data.as_slice()) {
+            return Ok(SigningKey::from_bytes(&seed));
+        }
+    }
+    let key = SigningKey::from_bytes(&rand::random());
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    // A leftover would keep its old permissions
+    fs::remove_file(&tmp).ok();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    file.write_all(&key.to_bytes())?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, &path)?;
+    Ok(key)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: usize) -> SocketAddr {
+        SocketAddr::from(([10, (n >> 16) as u8, (n >> 8) as u8, n as u8], 6881))
+    }
+
+    fn info_hash(n: usize) -> [u8; 20] {
+        let mut info_hash = [0; 20];
+        info_hash[..8].copy_from_slice(&(n as u64).to_be_bytes());
+        info_hash
+    }
+
+    #[test]
+    fn oldest_peer_makes_room() {
+        let mut store = PeerStore::new();
+        let start = Instant::now();
+        for n in 0..MAX_PEERS_PER_TORRENT {
+            store.announce(info_hash(0), peer(n), start + Duration::from_secs(n as u64));
+        }
+        // Announcing again keeps a peer fresh
+        let later = start + Duration::from_secs(1000);
+        store.announce(info_hash(0), peer(0), later);
+        store.announce(info_hash(0), peer(9999), later);
+
+        let (peers, _) = &store.torrents[&info_hash(0)];
+        assert_eq!(peers.len(), MAX_PEERS_PER_TORRENT);
+        assert!(peers.contains_key(&peer(0)) && peers.contains_key(&peer(9999)));
+        assert!(!peers.contains_key(&peer(1)));
+        assert_eq!(store.get(&info_hash(0)).len(), MAX_VALUES);
+    }
+
+    #[test]
+    fn oldest_torrent_makes_room() {
+        let mut store = PeerStore::new();
+        let start = Instant::now();
+        for n in 0..MAX_TORRENTS {
+            store.announce(info_hash(n), peer(n), start + Duration::from_secs(n as u64));
+        }
+        let later = start + Duration::from_secs(MAX_TORRENTS as u64);
+        store.announce(info_hash(0), peer(1), later);
+        store.announce(info_hash(MAX_TORRENTS), peer(0), later);
+
+        assert_eq!(store.len(), MAX_TORRENTS);
+        assert_eq!(store.get(&info_hash(0)).len(), 2);
+        assert!(store.get(&info_hash(1)).is_empty());
+        assert_eq!(store.get(&info_hash(MAX_TORRENTS)), vec![peer(0)]);
+
+        store.expire(later + PEER_TTL);
+        assert!(store.is_empty());
+    }
+}
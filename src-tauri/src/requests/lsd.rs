@@ -0,0 +1,401 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::net::UdpSocket;
+use tokio::time::{interval, Duration};
+
+use super::dht::storage::{from_hex, to_hex};
+use super::peer::manager::{ConnectionManager, PeerSource};
+
+pub const LSD_PORT: u16 = 6771;
+pub const GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const TICK: Duration = Duration::from_secs(5);
+// Keeps a batched announce inside a single unfragmented datagram
+const MAX_HASHES_PER_MESSAGE: usize = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announce {
+    pub port: u16,
+    pub info_hashes: Vec<[u8; 20]>,
+    pub cookie: Option<String>,
+}
+
+impl Announce {
+    pub fn encode(&self, host: &str) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            host, self.port
+        );
+        for info_hash in &self.info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", to_hex(info_hash)));
+        }
+        if let Some(cookie) = &self.cookie {
+            message.push_str(&format!("cookie: {}\r\n", cookie));
+        }
+        message.push_str("\r\n\r\n");
+        message.into_bytes()
+    }
+
+    pub fn parse(buf: &[u8]) -> Option<Announce> {
+        let text = std::str::from_utf8(buf).ok()?;
+        let mut lines = text.split("\r\n");
+        if !lines.next()?.starts_with("BT-SEARCH * HTTP/1.") {
+            return None;
+        }
+
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        let mut cookie = None;
+        for line in lines.take_while(|line| !line.is_empty()) {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse::<u16>().ok(),
+                "infohash" => {
+                    if let Some(info_hash) = from_hex(value).and_then(|h| h.try_into().ok()) {
+                        info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" => cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        let port = port.filter(|port| *port != 0)?;
+        if info_hashes.is_empty() {
+            return None;
+        }
+        Some(Announce {
+            port,
+            info_hashes,
+            cookie,
+        })
+    }
+}
+
+struct LsdTorrent {
+    manager: Arc<Mutex<ConnectionManager>>,
+    last_announce: Option<Instant>,
+}
+
+// BEP 14 Local Service Discovery. Multicasts the torrents we have to the LAN
+// and feeds peers announcing the same torrents to their connection managers.
+#[derive(Clone)]
+pub struct Lsd {
+    v4: Option<Arc<UdpSocket>>,
+    v6: Option<Arc<UdpSocket>>,
+    // Sent with every announce so we can ignore our own multicasts
+    cookie: String,
+    listen_port: u16,
+    torrents: Arc<Mutex<HashMap<[u8; 20], LsdTorrent>>>,
+}
+
+impl Lsd {
+    // Joins both multicast groups and starts announcing. Either family may be
+    // unavailable, only failing both is an error.
+    pub async fn start(listen_port: u16) -> io::Result<Lsd> {
+        let v4 = match socket_v4() {
+            Ok(socket) => Some(Arc::new(socket)),
+            Err(e) => {
+                println!("LSD IPv4 unavailable: {}", e);
+                None
+            }
+        };
+        let v6 = match socket_v6() {
+            Ok(socket) => Some(Arc::new(socket)),
+            Err(e) => {
+                println!("LSD IPv6 unavailable: {}", e);
+                None
+            }
+        };
+        if v4.is_none() && v6.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "No multicast socket for LSD",
+            ));
+        }
+
+        let cookie: [u8; 8] = rand::random();
+        let lsd = Lsd {
+            v4,
+            v6,
+            cookie: to_hex(&cookie),
+            listen_port,
+            torrents: Arc::new(Mutex::new(HashMap::new())),
+        };
+        for socket in [&lsd.v4, &lsd.v6].into_iter().flatten() {
+            let receiver = lsd.clone();
+            let socket = socket.clone();
+            tokio::spawn(async move { receiver.receive_loop(socket).await });
+        }
+        let announcer = lsd.clone();
+        tokio::spawn(async move { announcer.announce_loop().await });
+        Ok(lsd)
+    }
+
+    // Private torrents must only get peers from their trackers, so they are
+    // never announced or matched. Returns whether the torrent was added.
+    pub fn add_torrent(
+        &self,
+        info_hash: [u8; 20],
+        private: bool,
+        manager: Arc<Mutex<ConnectionManager>>,
+    ) -> bool {
+        if private {
+            return false;
+        }
+        self.torrents.lock().unwrap().insert(
+            info_hash,
+            LsdTorrent {
+                manager,
+                last_announce: None,
+            },
+        );
+        true
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8; 20]) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    async fn announce_loop(&self) {
+        let mut ticker = interval(TICK);
+        loop {
+            ticker.tick().await;
+            let now = Instant::now();
+            let due: Vec<[u8; 20]> = {
+                let mut torrents = self.torrents.lock().unwrap();
+                torrents
+                    .iter_mut()
+                    .filter(|(_, torrent)| {
+                        torrent
+                            .last_announce
+                            .is_none_or(|last| now.duration_since(last) >= ANNOUNCE_INTERVAL)
+                    })
+                    .map(|(info_hash, torrent)| {
+                        torrent.last_announce = Some(now);
+                        *info_hash
+                    })
+                    .collect()
+            };
+            for batch in due.chunks(MAX_HASHES_PER_MESSAGE) {
+                self.send(batch).await;
+            }
+        }
+    }
+
+    async fn send(&self, info_hashes: &[[u8; 20]]) {
+        let announce = Announce {
+            port: self.listen_port,
+            info_hashes: info_hashes.to_vec(),
+            cookie: Some(self.cookie.clone()),
+        };
+        if let Some(socket) = &self.v4 {
+            let host = format!("{}:{}", GROUP_V4, LSD_PORT);
+            let to = SocketAddr::from((GROUP_V4, LSD_PORT));
+            if let Err(e) = socket.send_to(&announce.encode(&host), to).await {
+                println!("LSD announce failed: {}", e);
+            }
+        }
+        if let Some(socket) = &self.v6 {
+            let host = format!("[{}]:{}", GROUP_V6, LSD_PORT);
+            let to = SocketAddr::from((GROUP_V6, LSD_PORT));
+            if let Err(e) = socket.send_to(&announce.encode(&host), to).await {
+                println!("LSD announce failed: {}", e);
+            }
+        }
+    }
+
+    async fn receive_loop(&self, socket: Arc<UdpSocket>) {
+        let mut buf = [0u8; 2048];
+        loop {
+            match socket.recv_from(&mut buf).await {
+                Ok((amt, from)) => self.handle_announce(&buf[..amt], from),
+                Err(e) => println!("LSD receive error: {}", e),
+            }
+        }
+    }
+
+    fn handle_announce(&self, buf: &[u8], from: SocketAddr) {
+        let Some(announce) = Announce::parse(buf) else {
+            return;
+        };
+        if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+            return;
+        }
+        let peer = SocketAddr::new(from.ip(), announce.port);
+        let torrents = self.torrents.lock().unwrap();
+        for info_hash in &announce.info_hashes {
+            if let Some(torrent) = torrents.get(info_hash) {
+                torrent
+                    .manager
+                    .lock()
+                    .unwrap()
+                    .add_peer(peer, PeerSource::Lsd, 0);
+            }
+        }
+    }
+}
+
+// SO_REUSEADDR lets other clients on this machine listen on 6771 as well.
+// macOS and the BSDs only share a multicast port with SO_REUSEPORT too.
+fn socket_v4() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    reuse(&socket)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, LSD_PORT)).into())?;
+    socket.join_multicast_v4(&GROUP_V4, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn socket_v6() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    reuse(&socket)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, LSD_PORT)).into())?;
+    socket.join_multicast_v6(&GROUP_V6, 0)?;
+    socket.set_multicast_loop_v6(true)?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn reuse(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_address(true)?;
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly"
+    ))]
+    socket.set_reuse_port(true)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_A: [u8; 20] = [0xab; 20];
+    const HASH_B: [u8; 20] = [0x01; 20];
+
+    // No sockets, announces are handed to it directly
+    fn lsd() -> Lsd {
+        Lsd {
+            v4: None,
+            v6: None,
+            cookie: "0011223344556677".to_string(),
+            listen_port: 6881,
+            torrents: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn manager() -> Arc<Mutex<ConnectionManager>> {
+        Arc::new(Mutex::new(ConnectionManager::new(10)))
+    }
+
+    fn announce(info_hashes: Vec<[u8; 20]>, cookie: &str) -> Vec<u8> {
+        Announce {
+            port: 7000,
+            info_hashes,
+            cookie: Some(cookie.to_string()),
+        }
+        .encode("239.192.152.143:6771")
+    }
+
+    #[test]
+    fn announce_format() {
+        let announce = Announce {
+            port: 6881,
+            info_hashes: vec![HASH_A, HASH_B],
+            cookie: Some("c00k1e".to_string()),
+        };
+        let encoded = announce.encode("239.192.152.143:6771");
+        assert_eq!(
+            String::from_utf8(encoded.clone()).unwrap(),
+            format!(
+                "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\n\
+                 Infohash: {}\r\nInfohash: {}\r\ncookie: c00k1e\r\n\r\n\r\n",
+                "ab".repeat(20),
+                "01".repeat(20)
+            )
+        );
+        assert_eq!(Announce::parse(&encoded), Some(announce));
+    }
+
+    #[test]
+    fn announce_parse() {
+        // Header names in any case, bad hashes skipped
+        let message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHOST: [ff15::efc0:988f]:6771\r\nport:  7000 \r\n\
+             infohash: {}\r\nInfohash: 1234\r\nInfoHash: {}\r\n\r\n\r\n",
+            "AB".repeat(20),
+            "01".repeat(20)
+        );
+        assert_eq!(
+            Announce::parse(message.as_bytes()),
+            Some(Announce {
+                port: 7000,
+                info_hashes: vec![HASH_A, HASH_B],
+                cookie: None,
+            })
+        );
+
+        let infohash = format!("Infohash: {}\r\n", "ab".repeat(20));
+        for message in [
+            // Not an announce
+            format!("M-SEARCH * HTTP/1.1\r\nPort: 7000\r\n{}\r\n", infohash),
+            // No usable port
+            format!("BT-SEARCH * HTTP/1.1\r\nPort: 0\r\n{}\r\n", infohash),
+            format!("BT-SEARCH * HTTP/1.1\r\nPort: 70000\r\n{}\r\n", infohash),
+            format!("BT-SEARCH * HTTP/1.1\r\n{}\r\n", infohash),
+            // No torrent
+            "BT-SEARCH * HTTP/1.1\r\nPort: 7000\r\n\r\n".to_string(),
+            // Headers after the blank line don't count
+            format!("BT-SEARCH * HTTP/1.1\r\nPort: 7000\r\n\r\n{}", infohash),
+        ] {
+            assert_eq!(Announce::parse(message.as_bytes()), None, "{:?}", message);
+        }
+        assert_eq!(Announce::parse(&[0xff, 0xfe]), None);
+    }
+
+    #[test]
+    fn own_announces_ignored() {
+        let lsd = lsd();
+        let manager = manager();
+        assert!(lsd.add_torrent(HASH_A, false, manager.clone()));
+        let from = "192.168.1.20:6771".parse().unwrap();
+
+        lsd.handle_announce(&announce(vec![HASH_A], &lsd.cookie), from);
+        assert_eq!(manager.lock().unwrap().num_candidates(), 0);
+
+        // Another client on the same machine, and torrents we don't have
+        lsd.handle_announce(&announce(vec![HASH_B, HASH_A], "ffeeddccbbaa9988"), from);
+        let manager = manager.lock().unwrap();
+        assert_eq!(manager.num_candidates(), 1);
+        let peer = "192.168.1.20:7000".parse().unwrap();
+        assert_eq!(manager.candidate(&peer).unwrap().source, PeerSource::Lsd);
+    }
+
+    #[test]
+    fn private_torrents_opt_out() {
+        let lsd = lsd();
+        let manager = manager();
+        assert!(!lsd.add_torrent(HASH_A, true, manager.clone()));
+        assert!(lsd.torrents.lock().unwrap().is_empty());
+
+        let from = "192.168.1.20:6771".parse().unwrap();
+        lsd.handle_announce(&announce(vec![HASH_A], "ffeeddccbbaa9988"), from);
+        assert_eq!(manager.lock().unwrap().num_candidates(), 0);
+    }
+}
@@ -1,10 +1,18 @@
 use std::net::{SocketAddr, ToSocketAddrs};
+pub mod compact;
+pub mod dht;
+pub mod lsd;
+pub mod peer;
+pub mod portmap;
 pub mod tracker;
+pub mod utp;
 
 pub async fn announce(
     info_hash: &[u8; 20],
     announce_url: &str,
+    peer_id: &[u8; 20],
+    listen_port: u16,
 ) -> Result<Vec<SocketAddr>, Box<dyn std::error::Error>> {
-    let peers = tracker::request(announce_url, info_hash).await?;
+    let peers = tracker::request(announce_url, info_hash, peer_id, listen_port).await?;
     Ok(peers)
 }
@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
+
+use super::message::Message;
+use crate::backend::file::{BencodeParser, BencodeValue, MAX_DEPTH};
+
+pub const HANDSHAKE_ID: u8 = 0;
+pub const CLIENT_VERSION: &str = "DeftTorrent 0.1.0";
+pub const DEFAULT_REQQ: u32 = 250;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtendedHandshake {
+    // Extension name to the ID the sender wants to receive it under
+    pub m: BTreeMap<String, u8>,
+    pub v: Option<String>,
+    pub p: Option<u16>,
+    pub reqq: Option<u32>,
+    pub yourip: Option<IpAddr>,
+    pub metadata_size: Option<i64>,
+}
+
+impl ExtendedHandshake {
+    pub fn to_bencode(&self) -> BencodeValue {
+        let m = self
+            .m
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), BencodeValue::Integer(*id as i64)))
+            .collect();
+
+        let mut dict = vec![(b"m".to_vec(), BencodeValue::Dict(m))];
+        if let Some(v) = &self.v {
+            dict.push((b"v".to_vec(), BencodeValue::String(v.as_bytes().to_vec())));
+        }
+        if let Some(p) = self.p {
+            dict.push((b"p".to_vec(), BencodeValue::Integer(p as i64)));
+        }
+        if let Some(reqq) = self.reqq {
+            dict.push((b"reqq".to_vec(), BencodeValue::Integer(reqq as i64)));
+        }
+        if let Some(ip) = self.yourip {
+            let bytes = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            dict.push((b"yourip".to_vec(), BencodeValue::String(bytes)));
+        }
+        if let Some(size) = self.metadata_size {
+            dict.push((b"metadata_size".to_vec(), BencodeValue::Integer(size)));
+        }
+        BencodeValue::Dict(dict)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        self.to_bencode().encode()
+    }
+
+    // Unknown keys and malformed optional values are ignored, only a missing
+    // or broken `m` dictionary is an error.
+    pub fn from_bytes(payload: &[u8]) -> io::Result<Self> {
+        let value = BencodeParser::new(payload)
+            .parse_with_depth(MAX_DEPTH)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let m_dict = match value.get(b"m") {
+            Some(BencodeValue::Dict(d)) => d,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Extended handshake without m dictionary",
+                ))
+            }
+        };
+
+        let mut m = BTreeMap::new();
+        for (name, id) in m_dict {
+            let (Ok(name), Some(id)) = (String::from_utf8(name.clone()), id.as_integer()) else {
+                continue;
+            };
+            if let Ok(id) = u8::try_from(id) {
+                m.insert(name, id);
+            }
+        }
+
+        let yourip = value
+            .get(b"yourip")
+            .and_then(|v| v.as_bytes())
+            .and_then(|bytes| match bytes.len() {
+                4 => Some(IpAddr::V4(Ipv4Addr::new(
+                    bytes[0], bytes[1], bytes[2], bytes[3],
+                ))),
+                16 => {
+                    let octets: [u8; 16] = bytes.try_into().ok()?;
+                    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+                }
+                _ => None,
+            });
+
+        Ok(ExtendedHandshake {
+            m,
+            v: value
+                .get(b"v")
+                .and_then(|v| v.as_bytes())
+                .map(|b| String::from_utf8_lossy(b).into_owned()),
+            p: value
+                .get(b"p")
+                .and_then(|v| v.as_integer())
+                .and_then(|p| u16::try_from(p).ok()),
+            reqq: value
+                .get(b"reqq")
+                .and_then(|v| v.as_integer())
+                .and_then(|r| u32::try_from(r).ok()),
+            yourip,
+            metadata_size: value
+                .get(b"metadata_size")
+                .and_then(|v| v.as_integer())
+                .filter(|size| *size > 0),
+        })
+    }
+}
+
+// Implemented by every extension that rides on BEP 10 (ut_metadata, ut_pex, ...)
+pub trait Extension: Send {
+    // Name used in the `m` dictionary, e.g. "ut_pex"
+    fn name(&self) -> &'static str;
+
+    // Lets the extension add its own keys to our handshake
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    // Called once the peer's handshake arrives, `enabled` is false when the
+    // peer doesn't support this extension.
+    fn on_handshake(&mut self, _handshake: &ExtendedHandshake, _enabled: bool) {}
+
+    // Handles one message and returns payloads to send back to the peer
+    fn on_message(&mut self, payload: &[u8]) -> io::Result<Vec<Vec<u8>>>;
+
+    // Called periodically so extensions can send unprompted messages
+    fn tick(&mut self, _now: Instant) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+// Extensions active on one connection. Our local IDs are assigned in
+// registration order starting at 1, the peer's come from its handshake.
+pub struct ExtensionRegistry {
+    extensions: Vec<Box<dyn Extension>>,
+    remote: Option<ExtendedHandshake>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        ExtensionRegistry {
+            extensions: Vec::new(),
+            remote: None,
+        }
+    }
+
+    pub fn register(&mut self, extension: Box<dyn Extension>) {
+        self.extensions.push(extension);
+    }
+
+    pub fn local_id(&self, name: &str) -> Option<u8> {
+        self.extensions
+            .iter()
+            .position(|e| e.name() == name)
+            .map(|i| i as u8 + 1)
+    }
+
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote
+            .as_ref()
+            .and_then(|h| h.m.get(name).copied())
+            .filter(|id| *id != 0)
+    }
+
+    pub fn remote_handshake(&self) -> Option<&ExtendedHandshake> {
+        self.remote.as_ref()
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut (dyn Extension + 'static)> {
+        self.extensions
+            .iter_mut()
+            .find(|e| e.name() == name)
+            .map(|e| e.as_mut())
+    }
+
+    pub fn handshake(&self, listen_port: Option<u16>, their_ip: Option<IpAddr>) -> Message {
+        let mut handshake = ExtendedHandshake {
+            m: BTreeMap::new(),
+            v: Some(CLIENT_VERSION.to_string()),
+            p: listen_port,
+            reqq: Some(DEFAULT_REQQ),
+            yourip: their_ip,
+            metadata_size: None,
+        };
+        for (i, extension) in self.extensions.iter().enumerate() {
+            handshake
+                .m
+                .insert(extension.name().to_string(), i as u8 + 1);
+            extension.extend_handshake(&mut handshake);
+        }
+        Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: handshake.encode(),
+        }
+    }
+
+    // Wraps an extension payload in a message using the ID the peer asked for.
+    // Returns None when the peer doesn't support the extension.
+    pub fn message(&self, name: &str, payload: Vec<u8>) -> Option<Message> {
+        self.remote_id(name)
+            .map(|id| Message::Extended { id, payload })
+    }
+
+    // Collects the messages extensions want to send on their own
+    pub fn tick(&mut self, now: Instant) -> Vec<Message> {
+        if self.remote.is_none() {
+            return Vec::new();
+        }
+        let mut outgoing = Vec::new();
+        for extension in self.extensions.iter_mut() {
+            let name = extension.name();
+            for payload in extension.tick(now) {
+                outgoing.push((name, payload));
+            }
+        }
+        outgoing
+            .into_iter()
+            .filter_map(|(name, payload)| self.message(name, payload))
+            .collect()
+    }
+
+    // Routes an incoming extended message and returns the replies to send.
+    // Messages under an ID we never handed out are ignored.
+    pub fn on_extended(&mut self, id: u8, payload: &[u8]) -> io::Result<Vec<Message>> {
+        if id == HANDSHAKE_ID {
+            let update = ExtendedHandshake::from_bytes(payload)?;
+            let handshake = match self.remote.take() {
+                Some(current) => merge(current, update),
+                None => update,
+            };
+            for extension in self.extensions.iter_mut() {
+                let enabled = handshake.m.get(extension.name()).is_some_and(|id| *id != 0);
+                extension.on_handshake(&handshake, enabled);
+            }
+            self.remote = Some(handshake);
+            return Ok(Vec::new());
+        }
+
+        let Some(extension) = (id as usize)
+            .checked_sub(1)
+            .and_then(|i| self.extensions.get_mut(i))
+        else {
+            return Ok(Vec::new());
+        };
+        let name = extension.name();
+        let replies = extension.on_message(payload)?;
+        Ok(replies
+            .into_iter()
+            .filter_map(|reply| self.message(name, reply))
+            .collect())
+    }
+}
+
+// BEP 10 lets a peer send its handshake again to change only some of its
+// settings. Extensions it doesn't mention keep their IDs, ID 0 turns one off.
+fn merge(mut current: ExtendedHandshake, update: ExtendedHandshake) -> ExtendedHandshake {
+    for (name, id) in update.m {
+        if id == 0 {
+            current.m.remove(&name);
+        } else {
+            current.m.insert(name, id);
+        }
+    }
+    current.v = update.v.or(current.v);
+    current.p = update.p.or(current.p);
+    current.reqq = update.reqq.or(current.reqq);
+    current.yourip = update.yourip.or(current.yourip);
+    current.metadata_size = update.metadata_size.or(current.metadata_size);
+    current
+}
+
+impl Default for ExtensionRegistry {
+    fn default() -> Self {
+        ExtensionRegistry::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+            Ok(vec![payload.to_vec()])
+        }
+    }
+
+    fn handshake(m: &[(&str, u8)], reqq: Option<u32>) -> Vec<u8> {
+        ExtendedHandshake {
+            m: m.iter().map(|(name, id)| (name.to_string(), *id)).collect(),
+            reqq,
+            ..Default::default()
+        }
+        .encode()
+    }
+
+    #[test]
+    fn handshake_updates_merge() {
+        let mut registry = ExtensionRegistry::new();
+        registry.register(Box::new(Echo));
+        registry
+            .on_extended(
+                HANDSHAKE_ID,
+                &handshake(&[("echo", 3), ("ut_pex", 1)], Some(100)),
+            )
+            .unwrap();
+        registry
+            .on_extended(HANDSHAKE_ID, &handshake(&[("echo", 5)], None))
+            .unwrap();
+        assert_eq!(registry.remote_id("echo"), Some(5));
+        assert_eq!(registry.remote_id("ut_pex"), Some(1));
+        assert_eq!(registry.remote_handshake().unwrap().reqq, Some(100));
+
+        registry
+            .on_extended(HANDSHAKE_ID, &handshake(&[("echo", 0)], None))
+            .unwrap();
+        assert_eq!(registry.remote_id("echo"), None);
+        assert_eq!(registry.remote_id("ut_pex"), Some(1));
+    }
+
+    #[test]
+    fn unknown_id_ignored() {
+        let mut registry = ExtensionRegistry::new();
+        registry.register(Box::new(Echo));
+        registry
+            .on_extended(HANDSHAKE_ID, &handshake(&[("echo", 7)], None))
+            .unwrap();
+        assert!(registry.on_extended(9, b"x").unwrap().is_empty());
+        assert_eq!(
+            registry.on_extended(1, b"x").unwrap(),
+            vec![Message::Extended {
+                id: 7,
+                payload: b"x".to_vec()
+            }]
+        );
+    }
+
+    #[test]
+    fn deeply_nested_handshake_rejected() {
+        let mut payload = b"d1:m".to_vec();
+        payload.extend(vec![b'l'; 1 << 20]);
+        let err = ExtendedHandshake::from_bytes(&payload).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
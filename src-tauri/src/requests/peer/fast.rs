@@ -0,0 +1,258 @@
+use sha1::{Digest, Sha1};
+use std::collections::{HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+
+use super::message::Message;
+use crate::backend::bitfield::Bitfield;
+use crate::backend::picker::{Block, PiecePicker};
+use crate::backend::pipeline::{self, RequestQueue};
+
+pub const ALLOWED_FAST_COUNT: usize = 10;
+// Suggestions beyond this are dropped, oldest first
+const MAX_SUGGESTIONS: usize = 32;
+
+// Canonical allowed-fast set from BEP 6: hash the peer's /24 together with
+// the info-hash and keep taking 4-byte words modulo the piece count.
+pub fn allowed_fast_set(ip: IpAddr, info_hash: &[u8; 20], num_pieces: u32, k: usize) -> Vec<u32> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip,
+            // The spec only defines the set for IPv4
+            None => return Vec::new(),
+        },
+    };
+    if num_pieces == 0 {
+        return Vec::new();
+    }
+
+    let k = k.min(num_pieces as usize);
+    let mut set = Vec::with_capacity(k);
+    let mut x = Vec::with_capacity(24);
+    x.extend((u32::from(ip) & 0xFFFF_FF00).to_be_bytes());
+    x.extend(info_hash);
+
+    while set.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        for word in x.chunks_exact(4) {
+            if set.len() >= k {
+                break;
+            }
+            let y = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+            let index = y % num_pieces;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+// Per-connection fast extension state
+#[derive(Debug, Default)]
+pub struct FastState {
+    pub enabled: bool,
+    // Pieces the peer lets us request while it chokes us
+    allowed_for_us: HashSet<u32>,
+    // Pieces we let the peer request while we choke it
+    allowed_for_them: HashSet<u32>,
+    suggested: VecDeque<u32>,
+}
+
+impl FastState {
+    pub fn new(enabled: bool) -> Self {
+        FastState {
+            enabled,
+            ..Default::default()
+        }
+    }
+
+    // What we send right after the handshake: HaveAll/HaveNone instead of a
+    // trivial bitfield, then the peer's allowed-fast set.
+    pub fn initial_messages(
+        &mut self,
+        have: &Bitfield,
+        peer: SocketAddr,
+        info_hash: &[u8; 20],
+    ) -> Vec<Message> {
+        let mut messages = Vec::new();
+        if !self.enabled {
+            if have.count() > 0 {
+                messages.push(Message::Bitfield(have.as_bytes().to_vec()));
+            }
+            return messages;
+        }
+
+        if have.is_complete() {
+            messages.push(Message::HaveAll);
+        } else if have.count() == 0 {
+            messages.push(Message::HaveNone);
+        } else {
+            messages.push(Message::Bitfield(have.as_bytes().to_vec()));
+        }
+
+        let set = allowed_fast_set(peer.ip(), info_hash, have.len() as u32, ALLOWED_FAST_COUNT);
+        for index in set {
+            self.allowed_for_them.insert(index);
+            messages.push(Message::AllowedFast(index));
+        }
+        messages
+    }
+
+    // Handles the BEP 6 messages that only update state. Anything else, or any
+    // fast message from a peer that didn't negotiate the extension, is
+    // returned as an error so the caller can drop the connection.
+    pub fn on_message(&mut self, message: &Message, num_pieces: u32) -> Result<(), &'static str> {
+        let index = match message {
+            Message::SuggestPiece(index) | Message::AllowedFast(index) => *index,
+            _ => return Err("Not a fast extension message"),
+        };
+        if !self.enabled {
+            return Err("Fast extension message without negotiating it");
+        }
+        if index >= num_pieces {
+            return Err("Piece index out of range");
+        }
+
+        match message {
+            Message::AllowedFast(_) => {
+                self.allowed_for_us.insert(index);
+            }
+            _ => {
+                if !self.suggested.contains(&index) {
+                    if self.suggested.len() >= MAX_SUGGESTIONS {
+                        self.suggested.pop_front();
+                    }
+                    self.suggested.push_back(index);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_allowed_for_us(&self, index: u32) -> bool {
+        self.allowed_for_us.contains(&index)
+    }
+
+    pub fn is_allowed_for_them(&self, index: u32) -> bool {
+        self.allowed_for_them.contains(&index)
+    }
+
+    // The pieces we can ask this peer for while it chokes us
+    pub fn choked_pickable(&self, peer_has: &Bitfield) -> Bitfield {
+        self.restrict(peer_has, |index| self.is_allowed_for_us(index))
+    }
+
+    // Suggested pieces are fetched before rarest-first takes over
+    pub fn suggested_pickable(&self, peer_has: &Bitfield) -> Bitfield {
+        self.restrict(peer_has, |index| self.suggested.contains(&index))
+    }
+
+    fn restrict<F: Fn(u32) -> bool>(&self, peer_has: &Bitfield, keep: F) -> Bitfield {
+        let mut bitfield = Bitfield::new(peer_has.len());
+        for index in peer_has.ones() {
+            if keep(index as u32) {
+                bitfield.set(index);
+            }
+        }
+        bitfield
+    }
+
+    pub fn remove_suggestion(&mut self, index: u32) {
+        self.suggested.retain(|i| *i != index);
+    }
+}
+
+// Translates the bitfield-like messages into the peer's piece set. HaveAll
+// and HaveNone are errors from a peer that didn't negotiate the extension.
+pub fn peer_pieces(
+    fast: &FastState,
+    message: &Message,
+    num_pieces: usize,
+) -> Option<Result<Bitfield, &'static str>> {
+    match message {
+        Message::Bitfield(bytes) => Some(Bitfield::from_bytes(bytes, num_pieces)),
+        Message::HaveAll | Message::HaveNone if !fast.enabled => {
+            Some(Err("Fast extension message without negotiating it"))
+        }
+        Message::HaveAll => Some(Ok(Bitfield::full(num_pieces))),
+        Message::HaveNone => Some(Ok(Bitfield::new(num_pieces))),
+        _ => None,
+    }
+}
+
+// Without the fast extension a choke silently discards every request, with it
+// the peer rejects each one explicitly, so we keep them until it does.
+pub fn on_choked(
+    fast: &FastState,
+    queue: &mut RequestQueue,
+    picker: &mut PiecePicker,
+    peer: SocketAddr,
+) -> Vec<Block> {
+    if fast.enabled {
+        return Vec::new();
+    }
+    let dropped = queue.clear();
+    for block in &dropped {
+        picker.block_released(peer, *block);
+    }
+    dropped
+}
+
+// Only peers that negotiated the extension may reject requests
+pub fn on_rejected(
+    fast: &FastState,
+    queue: &mut RequestQueue,
+    picker: &mut PiecePicker,
+    peer: SocketAddr,
+    block: Block,
+) -> Result<(), &'static str> {
+    if !fast.enabled {
+        return Err("Fast extension message without negotiating it");
+    }
+    pipeline::release(queue, picker, peer, block);
+    Ok(())
+}
+
+// With the fast extension every request we won't serve must be answered with
+// a reject, this builds it for a request we're dropping (choke or cancel).
+pub fn reject(fast: &FastState, index: u32, begin: u32, length: u32) -> Option<Message> {
+    fast.enabled.then_some(Message::RejectRequest {
+        index,
+        begin,
+        length,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requests::peer::message::BLOCK_LEN;
+
+    #[test]
+    fn fast_messages_need_the_extension() {
+        let (off, on) = (FastState::new(false), FastState::new(true));
+        for message in [Message::HaveAll, Message::HaveNone] {
+            assert!(matches!(peer_pieces(&off, &message, 8), Some(Err(_))));
+            assert!(matches!(peer_pieces(&on, &message, 8), Some(Ok(_))));
+        }
+        assert!(matches!(
+            peer_pieces(&off, &Message::Bitfield(vec![0xff]), 8),
+            Some(Ok(_))
+        ));
+
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut picker = PiecePicker::new(8, BLOCK_LEN, 8 * BLOCK_LEN as u64);
+        let mut queue = RequestQueue::new();
+        let block = picker.block(0, 0);
+        assert!(on_rejected(&off, &mut queue, &mut picker, peer, block).is_err());
+        assert!(on_rejected(&on, &mut queue, &mut picker, peer, block).is_ok());
+    }
+
+    #[test]
+    fn allowed_fast_reference_set() {
+        // Example from BEP 6
+        let set = allowed_fast_set("80.4.4.200".parse().unwrap(), &[0xaa; 20], 1313, 7);
+        assert_eq!(set, vec![1059, 431, 808, 1217, 287, 376, 1188]);
+    }
+}
@@ -0,0 +1,122 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub const HANDSHAKE_LEN: usize = 1 + 19 + 8 + 20 + 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Handshake {
+            reserved: [0u8; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut buf = [0u8; HANDSHAKE_LEN];
+        buf[0] = PROTOCOL.len() as u8;
+        buf[1..20].copy_from_slice(PROTOCOL);
+        buf[20..28].copy_from_slice(&self.reserved);
+        buf[28..48].copy_from_slice(&self.info_hash);
+        buf[48..68].copy_from_slice(&self.peer_id);
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; HANDSHAKE_LEN]) -> io::Result<Self> {
+        if buf[0] as usize != PROTOCOL.len() || &buf[1..20] != PROTOCOL {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unknown protocol in handshake",
+            ));
+        }
+
+        let mut reserved = [0u8; 8];
+        let mut info_hash = [0u8; 20];
+        let mut peer_id = [0u8; 20];
+        reserved.copy_from_slice(&buf[20..28]);
+        info_hash.copy_from_slice(&buf[28..48]);
+        peer_id.copy_from_slice(&buf[48..68]);
+
+        Ok(Handshake {
+            reserved,
+            info_hash,
+            peer_id,
+        })
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.to_bytes()).await?;
+        writer.flush().await
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Self> {
+        let mut buf = [0u8; HANDSHAKE_LEN];
+        reader.read_exact(&mut buf).await?;
+        Handshake::from_bytes(&buf)
+    }
+
+    // Checks the remote handshake against the torrent we are serving and, when the
+    // tracker gave us one, the peer ID we expected to find at this address.
+    pub fn validate(
+        &self,
+        info_hash: &[u8; 20],
+        expected_peer_id: Option<&[u8; 20]>,
+        own_peer_id: &[u8; 20],
+    ) -> io::Result<()> {
+        if &self.info_hash != info_hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Info hash mismatch in handshake",
+            ));
+        }
+        if &self.peer_id == own_peer_id {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                "Connected to ourselves",
+            ));
+        }
+        if let Some(expected) = expected_peer_id {
+            if &self.peer_id != expected {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Peer ID mismatch in handshake",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // BEP 10 extension protocol
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    pub fn set_extensions(&mut self) {
+        self.reserved[5] |= 0x10;
+    }
+
+    // BEP 6 fast extension
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & 0x04 != 0
+    }
+
+    pub fn set_fast(&mut self) {
+        self.reserved[7] |= 0x04;
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[7] & 0x01 != 0
+    }
+
+    pub fn set_dht(&mut self) {
+        self.reserved[7] |= 0x01;
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use super::extension::{ExtendedHandshake, Extension, ExtensionRegistry};
+
+pub const NAME: &str = "ut_holepunch";
+// Connect requests waiting for the torrent to dial them, beyond this they
+// are dropped so a relay can't make us flood someone with SYNs
+const MAX_PENDING_CONNECTS: usize = 50;
+
+const MSG_RENDEZVOUS: u8 = 0;
+const MSG_CONNECT: u8 = 1;
+const MSG_ERROR: u8 = 2;
+const ADDR_V4: u8 = 0;
+const ADDR_V6: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchError {
+    // The target endpoint is invalid
+    NoSuchPeer = 1,
+    // The relay is not connected to the target
+    NotConnected = 2,
+    // The target doesn't support holepunching
+    NoSupport = 3,
+    // The target is the sender itself
+    NoSelf = 4,
+}
+
+impl HolepunchError {
+    fn from_u32(code: u32) -> Option<HolepunchError> {
+        Some(match code {
+            1 => HolepunchError::NoSuchPeer,
+            2 => HolepunchError::NotConnected,
+            3 => HolepunchError::NoSupport,
+            4 => HolepunchError::NoSelf,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchMessage {
+    // Asks the relay to introduce us to the peer at this address
+    Rendezvous(SocketAddr),
+    // Sent by the relay to both ends, each then dials the other over uTP
+    Connect(SocketAddr),
+    Error(SocketAddr, HolepunchError),
+}
+
+impl HolepunchMessage {
+    // BEP 55 messages are plain binary, not bencoded
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, addr, error) = match self {
+            HolepunchMessage::Rendezvous(addr) => (MSG_RENDEZVOUS, addr, 0),
+            HolepunchMessage::Connect(addr) => (MSG_CONNECT, addr, 0),
+            HolepunchMessage::Error(addr, error) => (MSG_ERROR, addr, *error as u32),
+        };
+        let mut buf = vec![kind];
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                buf.push(ADDR_V4);
+                buf.extend(ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.push(ADDR_V6);
+                buf.extend(ip.octets());
+            }
+        }
+        buf.extend(addr.port().to_be_bytes());
+        buf.extend(error.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &'static str| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if payload.len() < 2 {
+            return Err(invalid("Holepunch message too short"));
+        }
+        let ip_len = match payload[1] {
+            ADDR_V4 => 4,
+            ADDR_V6 => 16,
+            _ => return Err(invalid("Unknown holepunch address type")),
+        };
+        let rest = payload
+            .get(2..2 + ip_len + 6)
+            .ok_or_else(|| invalid("Holepunch message too short"))?;
+        let ip = match ip_len {
+            4 => IpAddr::V4(Ipv4Addr::new(rest[0], rest[1], rest[2], rest[3])),
+            _ => {
+                let octets: [u8; 16] = rest[..16].try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+        };
+        let port = u16::from_be_bytes([rest[ip_len], rest[ip_len + 1]]);
+        let code = u32::from_be_bytes(rest[ip_len + 2..ip_len + 6].try_into().unwrap());
+        let addr = SocketAddr::new(ip, port);
+
+        match payload[0] {
+            MSG_RENDEZVOUS => Ok(HolepunchMessage::Rendezvous(addr)),
+            MSG_CONNECT => Ok(HolepunchMessage::Connect(addr)),
+            MSG_ERROR => HolepunchError::from_u32(code)
+                .map(|error| HolepunchMessage::Error(addr, error))
+                .ok_or_else(|| invalid("Unknown holepunch error code")),
+            _ => Err(invalid("Unknown holepunch message type")),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct HubPeer {
+    supported: bool,
+    outbox: Vec<Vec<u8>>,
+}
+
+// Shared by all connections of one torrent. As a relay it passes connect
+// messages between two of our peers, as an endpoint it collects the
+// addresses we were told to dial.
+#[derive(Debug, Default)]
+pub struct HolepunchHub {
+    peers: HashMap<SocketAddr, HubPeer>,
+    connects: Vec<SocketAddr>,
+    // Rendezvous we sent and haven't heard back about, target to relay
+    pending: HashMap<SocketAddr, SocketAddr>,
+}
+
+impl HolepunchHub {
+    pub fn new() -> Self {
+        HolepunchHub::default()
+    }
+
+    pub fn supports(&self, addr: &SocketAddr) -> bool {
+        self.peers.get(addr).is_some_and(|peer| peer.supported)
+    }
+
+    // Asks `relay`, which has to be connected to both of us, to introduce us
+    // to `target`. Returns false when the relay can't be used.
+    pub fn rendezvous(&mut self, relay: SocketAddr, target: SocketAddr) -> bool {
+        let Some(peer) = self.peers.get_mut(&relay).filter(|peer| peer.supported) else {
+            return false;
+        };
+        peer.outbox
+            .push(HolepunchMessage::Rendezvous(target).encode());
+        self.pending.insert(target, relay);
+        true
+    }
+
+    pub fn is_pending(&self, target: &SocketAddr) -> bool {
+        self.pending.contains_key(target)
+    }
+
+    // Addresses to dial over uTP. The other end is dialing us at the same
+    // time, which is what gets both NATs to let the packets through.
+    pub fn take_connects(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.connects)
+    }
+
+    fn relay(&mut self, from: SocketAddr, target: SocketAddr) -> HolepunchMessage {
+        if target.port() == 0 || target.ip().is_unspecified() {
+            return HolepunchMessage::Error(target, HolepunchError::NoSuchPeer);
+        }
+        if target == from {
+            return HolepunchMessage::Error(target, HolepunchError::NoSelf);
+        }
+        let Some(peer) = self.peers.get_mut(&target) else {
+            return HolepunchMessage::Error(target, HolepunchError::NotConnected);
+        };
+        if !peer.supported {
+            return HolepunchMessage::Error(target, HolepunchError::NoSupport);
+        }
+        peer.outbox.push(HolepunchMessage::Connect(from).encode());
+        HolepunchMessage::Connect(target)
+    }
+
+    fn on_connect(&mut self, addr: SocketAddr) {
+        self.pending.remove(&addr);
+        if self.connects.len() < MAX_PENDING_CONNECTS && !self.connects.contains(&addr) {
+            self.connects.push(addr);
+        }
+    }
+}
+
+// One instance per connection, registered with the torrent's hub until the
+// connection goes away.
+pub struct HolepunchExtension {
+    hub: Arc<Mutex<HolepunchHub>>,
+    remote: SocketAddr,
+}
+
+impl HolepunchExtension {
+    pub fn new(hub: Arc<Mutex<HolepunchHub>>, remote: SocketAddr) -> Self {
+        hub.lock().unwrap().peers.insert(remote, HubPeer::default());
+        HolepunchExtension { hub, remote }
+    }
+}
+
+impl Extension for HolepunchExtension {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn on_handshake(&mut self, _handshake: &ExtendedHandshake, enabled: bool) {
+        if let Some(peer) = self.hub.lock().unwrap().peers.get_mut(&self.remote) {
+            peer.supported = enabled;
+        }
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let message = HolepunchMessage::from_bytes(payload)?;
+        let mut hub = self.hub.lock().unwrap();
+        match message {
+            HolepunchMessage::Rendezvous(target) => {
+                Ok(vec![hub.relay(self.remote, target).encode()])
+            }
+            HolepunchMessage::Connect(addr) => {
+                hub.on_connect(addr);
+                Ok(Vec::new())
+            }
+            HolepunchMessage::Error(addr, error) => {
+                if hub.pending.get(&addr) == Some(&self.remote) {
+                    hub.pending.remove(&addr);
+                }
+                println!(
+                    "Holepunch to {} through {} failed: {:?}",
+                    addr, self.remote, error
+                );
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    fn tick(&mut self, _now: Instant) -> Vec<Vec<u8>> {
+        self.hub
+            .lock()
+            .unwrap()
+            .peers
+            .get_mut(&self.remote)
+            .map(|peer| std::mem::take(&mut peer.outbox))
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for HolepunchExtension {
+    fn drop(&mut self) {
+        let mut hub = self.hub.lock().unwrap();
+        hub.peers.remove(&self.remote);
+        let remote = self.remote;
+        hub.pending.retain(|_, relay| *relay != remote);
+    }
+}
+
+pub fn register(
+    registry: &mut ExtensionRegistry,
+    hub: Arc<Mutex<HolepunchHub>>,
+    remote: SocketAddr,
+) {
+    registry.register(Box::new(HolepunchExtension::new(hub, remote)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn decoded(mut messages: Vec<Vec<u8>>) -> HolepunchMessage {
+        assert_eq!(messages.len(), 1);
+        HolepunchMessage::from_bytes(&messages.remove(0)).unwrap()
+    }
+
+    // A connection to `remote` whose extended handshake has been seen
+    fn connected(
+        hub: &Arc<Mutex<HolepunchHub>>,
+        remote: SocketAddr,
+        supported: bool,
+    ) -> HolepunchExtension {
+        let mut extension = HolepunchExtension::new(hub.clone(), remote);
+        extension.on_handshake(&ExtendedHandshake::default(), supported);
+        extension
+    }
+
+    #[test]
+    fn codec() {
+        let v4 = addr("1.2.3.4:6881");
+        let v6 = addr("[2001:db8::1]:443");
+        assert_eq!(
+            HolepunchMessage::Rendezvous(v4).encode(),
+            [0, 0, 1, 2, 3, 4, 0x1a, 0xe1, 0, 0, 0, 0]
+        );
+        let mut error = vec![2, 1];
+        error.extend([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        error.extend([0x01, 0xbb, 0, 0, 0, 3]);
+        assert_eq!(
+            HolepunchMessage::Error(v6, HolepunchError::NoSupport).encode(),
+            error
+        );
+
+        for message in [
+            HolepunchMessage::Rendezvous(v6),
+            HolepunchMessage::Connect(v4),
+            HolepunchMessage::Connect(v6),
+            HolepunchMessage::Error(v4, HolepunchError::NoSuchPeer),
+            HolepunchMessage::Error(v4, HolepunchError::NotConnected),
+            HolepunchMessage::Error(v6, HolepunchError::NoSupport),
+            HolepunchMessage::Error(v4, HolepunchError::NoSelf),
+        ] {
+            assert_eq!(
+                HolepunchMessage::from_bytes(&message.encode()).unwrap(),
+                message
+            );
+        }
+
+        let valid = HolepunchMessage::Connect(v4).encode();
+        let mut bad = vec![
+            vec![1],
+            valid[..valid.len() - 1].to_vec(),
+            // Says IPv6 with an IPv4 address
+            [&[1, 1][..], &valid[2..]].concat(),
+            // Unknown address type, message type and error code
+            [&[1, 2][..], &valid[2..]].concat(),
+            [&[3][..], &valid[1..]].concat(),
+        ];
+        let mut unknown_error = HolepunchMessage::Error(v4, HolepunchError::NoSelf).encode();
+        *unknown_error.last_mut().unwrap() = 5;
+        bad.push(unknown_error);
+        for payload in bad {
+            let e = HolepunchMessage::from_bytes(&payload).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", payload);
+        }
+    }
+
+    #[test]
+    fn relays_connect_to_both_ends() {
+        let hub = Arc::new(Mutex::new(HolepunchHub::new()));
+        let (a, b) = (addr("1.1.1.1:6881"), addr("2.2.2.2:6881"));
+        let mut from = connected(&hub, a, true);
+        let mut target = connected(&hub, b, true);
+
+        let reply = from
+            .on_message(&HolepunchMessage::Rendezvous(b).encode())
+            .unwrap();
+        assert_eq!(decoded(reply), HolepunchMessage::Connect(b));
+        assert_eq!(
+            decoded(target.tick(Instant::now())),
+            HolepunchMessage::Connect(a)
+        );
+        assert!(from.tick(Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn relay_errors() {
+        let hub = Arc::new(Mutex::new(HolepunchHub::new()));
+        let from = addr("1.1.1.1:6881");
+        let (unsupported, unknown) = (addr("3.3.3.3:6881"), addr("4.4.4.4:6881"));
+        let mut extension = connected(&hub, from, true);
+        let mut other = connected(&hub, unsupported, false);
+
+        for (target, error) in [
+            (unknown, HolepunchError::NotConnected),
+            (unsupported, HolepunchError::NoSupport),
+            (from, HolepunchError::NoSelf),
+            (addr("0.0.0.0:6881"), HolepunchError::NoSuchPeer),
+            (addr("5.5.5.5:0"), HolepunchError::NoSuchPeer),
+        ] {
+            let reply = extension
+                .on_message(&HolepunchMessage::Rendezvous(target).encode())
+                .unwrap();
+            assert_eq!(decoded(reply), HolepunchMessage::Error(target, error));
+        }
+        // Nothing went to the peer that can't take it
+        assert!(other.tick(Instant::now()).is_empty());
+
+        // Gone once its connection closes
+        drop(other);
+        let reply = extension
+            .on_message(&HolepunchMessage::Rendezvous(unsupported).encode())
+            .unwrap();
+        assert_eq!(
+            decoded(reply),
+            HolepunchMessage::Error(unsupported, HolepunchError::NotConnected)
+        );
+    }
+
+    #[test]
+    fn endpoint_dials_or_gives_up() {
+        let hub = Arc::new(Mutex::new(HolepunchHub::new()));
+        let (relay, target) = (addr("1.1.1.1:6881"), addr("2.2.2.2:6881"));
+        assert!(!hub.lock().unwrap().rendezvous(relay, target));
+        let mut extension = connected(&hub, relay, true);
+
+        assert!(hub.lock().unwrap().rendezvous(relay, target));
+        assert!(hub.lock().unwrap().is_pending(&target));
+        assert_eq!(
+            decoded(extension.tick(Instant::now())),
+            HolepunchMessage::Rendezvous(target)
+        );
+        let error = HolepunchMessage::Error(target, HolepunchError::NotConnected);
+        extension.on_message(&error.encode()).unwrap();
+        assert!(!hub.lock().unwrap().is_pending(&target));
+
+        assert!(hub.lock().unwrap().rendezvous(relay, target));
+        extension
+            .on_message(&HolepunchMessage::Connect(target).encode())
+            .unwrap();
+        let mut hub = hub.lock().unwrap();
+        assert!(!hub.is_pending(&target));
+        assert_eq!(hub.take_connects(), vec![target]);
+        assert!(hub.take_connects().is_empty());
+    }
+}
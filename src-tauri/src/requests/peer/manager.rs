@@ -0,0 +1,270 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+// Candidates we remember per torrent, beyond this new ones are dropped
+const MAX_CANDIDATES: usize = 2000;
+const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_FAILURES: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Pex,
+    Dht,
+    Lsd,
+    Incoming,
+    // Saved in the resume data of the last session
+    Resume,
+}
+
+// Peer flags as carried in PEX `added.f`
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_UTP: u8 = 0x04;
+pub const FLAG_HOLEPUNCH: u8 = 0x08;
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub source: PeerSource,
+    pub flags: u8,
+    // Peer that told us about this one over PEX and so is connected to it,
+    // it can introduce us when a direct connection fails
+    pub relay: Option<SocketAddr>,
+    failures: u32,
+    last_attempt: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Connection {
+    flags: u8,
+    // Port the peer accepts connections on. An incoming connection comes
+    // from some other port, the peer only tells us in its extended handshake.
+    listen_port: Option<u16>,
+}
+
+// Tracks every peer address we know about for one torrent: who we could
+// connect to, who we are connected to and who we refuse to talk to.
+#[derive(Debug)]
+pub struct ConnectionManager {
+    candidates: HashMap<SocketAddr, Candidate>,
+    connected: HashMap<SocketAddr, Connection>,
+    banned: HashSet<IpAddr>,
+    max_connections: usize,
+}
+
+impl ConnectionManager {
+    pub fn new(max_connections: usize) -> Self {
+        ConnectionManager {
+            candidates: HashMap::new(),
+            connected: HashMap::new(),
+            banned: HashSet::new(),
+            max_connections,
+        }
+    }
+
+    pub fn add_peer(&mut self, addr: SocketAddr, source: PeerSource, flags: u8) {
+        if self.banned.contains(&addr.ip()) || self.connected.contains_key(&addr) {
+            return;
+        }
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.flags |= flags;
+            return;
+        }
+        if self.candidates.len() >= MAX_CANDIDATES {
+            return;
+        }
+        self.candidates.insert(
+            addr,
+            Candidate {
+                source,
+                flags,
+                relay: None,
+                failures: 0,
+                last_attempt: None,
+            },
+        );
+    }
+
+    pub fn set_relay(&mut self, addr: SocketAddr, relay: SocketAddr) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.relay = Some(relay);
+        }
+    }
+
+    // A peer told us over PEX it is no longer connected to `addr`, so it
+    // can't introduce us anymore. Candidates nobody else told us about and
+    // we never tried are likely gone and forgotten.
+    pub fn on_dropped(&mut self, addr: SocketAddr, relay: SocketAddr) {
+        let Some(candidate) = self.candidates.get_mut(&addr) else {
+            return;
+        };
+        if candidate.relay != Some(relay) {
+            return;
+        }
+        if candidate.source == PeerSource::Pex && candidate.last_attempt.is_none() {
+            self.candidates.remove(&addr);
+        } else {
+            candidate.relay = None;
+        }
+    }
+
+    pub fn add_peers(&mut self, addrs: &[SocketAddr], source: PeerSource) {
+        for addr in addrs {
+            self.add_peer(*addr, source, 0);
+        }
+    }
+
+    pub fn candidate(&self, addr: &SocketAddr) -> Option<&Candidate> {
+        self.candidates.get(addr)
+    }
+
+    // Picks up to `n` peers to dial, leaving recently tried ones alone for a
+    // while, longer after each failure. Marks them as attempted.
+    pub fn next_to_connect(&mut self, now: Instant, n: usize) -> Vec<SocketAddr> {
+        let free = self.max_connections.saturating_sub(self.connected.len());
+        let mut ready: Vec<(&SocketAddr, &mut Candidate)> = self
+            .candidates
+            .iter_mut()
+            .filter(|(addr, c)| {
+                !self.connected.contains_key(*addr)
+                    && c.last_attempt.is_none_or(|last| {
+                        now.duration_since(last) >= RETRY_BACKOFF * (c.failures + 1)
+                    })
+            })
+            .collect();
+        ready.sort_by_key(|(_, c)| c.failures);
+
+        ready
+            .into_iter()
+            .take(n.min(free))
+            .map(|(addr, c)| {
+                c.last_attempt = Some(now);
+                *addr
+            })
+            .collect()
+    }
+
+    pub fn on_connected(&mut self, addr: SocketAddr, flags: u8, incoming: bool) {
+        let listen_port = (!incoming).then_some(addr.port());
+        self.connected
+            .insert(addr, Connection { flags, listen_port });
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.failures = 0;
+        }
+    }
+
+    // From the `p` of the peer's extended handshake
+    pub fn set_listen_port(&mut self, addr: SocketAddr, port: u16) {
+        if let Some(connection) = self.connected.get_mut(&addr) {
+            if port != 0 {
+                connection.listen_port = Some(port);
+            }
+        }
+    }
+
+    pub fn on_disconnected(&mut self, addr: SocketAddr) {
+        self.connected.remove(&addr);
+    }
+
+    pub fn on_failed(&mut self, addr: SocketAddr) {
+        let remove = match self.candidates.get_mut(&addr) {
+            Some(candidate) => {
+                candidate.failures += 1;
+                candidate.failures >= MAX_FAILURES
+            }
+            None => false,
+        };
+        if remove {
+            self.candidates.remove(&addr);
+        }
+    }
+
+    pub fn ban(&mut self, ip: IpAddr) {
+        self.banned.insert(ip);
+        self.candidates.retain(|addr, _| addr.ip() != ip);
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.contains(ip)
+    }
+
+    pub fn is_connected(&self, addr: &SocketAddr) -> bool {
+        self.connected.contains_key(addr)
+    }
+
+    pub fn can_accept(&self) -> bool {
+        self.connected.len() < self.max_connections
+    }
+
+    // Where our other peers can be reached, to tell `to` over PEX. Incoming
+    // peers that never told us their listen port are left out.
+    pub fn pex_peers(&self, to: SocketAddr) -> Vec<(SocketAddr, u8)> {
+        self.connected
+            .iter()
+            .filter(|(addr, _)| **addr != to)
+            .filter_map(|(addr, connection)| {
+                let port = connection.listen_port?;
+                Some((SocketAddr::new(addr.ip(), port), connection.flags))
+            })
+            .collect()
+    }
+
+    pub fn num_connected(&self) -> usize {
+        self.connected.len()
+    }
+
+    pub fn num_candidates(&self) -> usize {
+        self.candidates.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tried_peers_wait_before_redial() {
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut manager = ConnectionManager::new(10);
+        manager.add_peer(addr, PeerSource::Tracker, 0);
+        let now = Instant::now();
+        assert_eq!(manager.next_to_connect(now, 5), vec![addr]);
+        manager.on_connected(addr, 0, false);
+        manager.on_disconnected(addr);
+        assert!(manager
+            .next_to_connect(now + Duration::from_secs(1), 5)
+            .is_empty());
+        assert_eq!(manager.next_to_connect(now + RETRY_BACKOFF, 5), vec![addr]);
+
+        manager.on_failed(addr);
+        let later = now + RETRY_BACKOFF;
+        assert!(manager.next_to_connect(later + RETRY_BACKOFF, 5).is_empty());
+        assert_eq!(
+            manager.next_to_connect(later + RETRY_BACKOFF * 2, 5),
+            vec![addr]
+        );
+    }
+
+    #[test]
+    fn dropped_by_relay() {
+        let relay: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let (fresh, tried): (SocketAddr, SocketAddr) = (
+            "10.0.0.2:6881".parse().unwrap(),
+            "10.0.0.3:6881".parse().unwrap(),
+        );
+        let mut manager = ConnectionManager::new(10);
+        manager.add_peer(tried, PeerSource::Pex, 0);
+        manager.next_to_connect(Instant::now(), 5);
+        manager.add_peer(fresh, PeerSource::Pex, 0);
+        for addr in [fresh, tried] {
+            manager.set_relay(addr, relay);
+        }
+
+        manager.on_dropped(fresh, relay);
+        manager.on_dropped(tried, relay);
+        assert!(manager.candidate(&fresh).is_none());
+        assert_eq!(manager.candidate(&tried).unwrap().relay, None);
+    }
+}
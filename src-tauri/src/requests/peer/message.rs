@@ -0,0 +1,435 @@
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Largest frame we accept from a peer. Big enough for the bitfield of a torrent
+// with millions of pieces and for 128 KiB blocks, small enough that a hostile
+// length prefix can't make us allocate gigabytes.
+pub const MAX_MESSAGE_LEN: usize = 1 << 20;
+pub const BLOCK_LEN: u32 = 16 * 1024;
+pub const MAX_REQUEST_LEN: u32 = 128 * 1024;
+
+pub const CHOKE: u8 = 0;
+pub const UNCHOKE: u8 = 1;
+pub const INTERESTED: u8 = 2;
+pub const NOT_INTERESTED: u8 = 3;
+pub const HAVE: u8 = 4;
+pub const BITFIELD: u8 = 5;
+pub const REQUEST: u8 = 6;
+pub const PIECE: u8 = 7;
+pub const CANCEL: u8 = 8;
+pub const PORT: u8 = 9;
+// BEP 6 fast extension
+pub const SUGGEST_PIECE: u8 = 0x0D;
+pub const HAVE_ALL: u8 = 0x0E;
+pub const HAVE_NONE: u8 = 0x0F;
+pub const REJECT_REQUEST: u8 = 0x10;
+pub const ALLOWED_FAST: u8 = 0x11;
+pub const EXTENDED: u8 = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Port(u16),
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast(u32),
+    // BEP 10, `id` 0 is the extended handshake
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    // Message IDs we don't understand are passed up instead of dropping the
+    // connection, the spec says they should be ignored.
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl Message {
+    pub fn id(&self) -> Option<u8> {
+        match self {
+            Message::KeepAlive => None,
+            Message::Choke => Some(CHOKE),
+            Message::Unchoke => Some(UNCHOKE),
+            Message::Interested => Some(INTERESTED),
+            Message::NotInterested => Some(NOT_INTERESTED),
+            Message::Have(_) => Some(HAVE),
+            Message::Bitfield(_) => Some(BITFIELD),
+            Message::Request { .. } => Some(REQUEST),
+            Message::Piece { .. } => Some(PIECE),
+            Message::Cancel { .. } => Some(CANCEL),
+            Message::Port(_) => Some(PORT),
+            Message::SuggestPiece(_) => Some(SUGGEST_PIECE),
+            Message::HaveAll => Some(HAVE_ALL),
+            Message::HaveNone => Some(HAVE_NONE),
+            Message::RejectRequest { .. } => Some(REJECT_REQUEST),
+            Message::AllowedFast(_) => Some(ALLOWED_FAST),
+            Message::Extended { .. } => Some(EXTENDED),
+            Message::Unknown { id, .. } => Some(*id),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        match self {
+            Message::KeepAlive
+            | Message::Choke
+            | Message::Unchoke
+            | Message::Interested
+            | Message::NotInterested
+            | Message::HaveAll
+            | Message::HaveNone => {}
+            Message::Have(index) | Message::SuggestPiece(index) | Message::AllowedFast(index) => {
+                payload.extend(index.to_be_bytes())
+            }
+            Message::Bitfield(bits) => payload.extend(bits),
+            Message::Request {
+                index,
+                begin,
+                length,
+            }
+            | Message::Cancel {
+                index,
+                begin,
+                length,
+            }
+            | Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                payload.extend(index.to_be_bytes());
+                payload.extend(begin.to_be_bytes());
+                payload.extend(length.to_be_bytes());
+            }
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                payload.extend(index.to_be_bytes());
+                payload.extend(begin.to_be_bytes());
+                payload.extend(block);
+            }
+            Message::Port(port) => payload.extend(port.to_be_bytes()),
+            Message::Extended { id, payload: p } => {
+                payload.push(*id);
+                payload.extend(p);
+            }
+            Message::Unknown { payload: p, .. } => payload.extend(p),
+        }
+
+        let mut buf = Vec::with_capacity(5 + payload.len());
+        match self.id() {
+            None => buf.extend(0u32.to_be_bytes()),
+            Some(id) => {
+                buf.extend((payload.len() as u32 + 1).to_be_bytes());
+                buf.push(id);
+                buf.extend(payload);
+            }
+        }
+        buf
+    }
+
+    // Decodes one frame from the front of `buf`. Returns `Ok(None)` when more
+    // bytes are needed, otherwise the message and how many bytes it used.
+    pub fn decode(buf: &[u8]) -> io::Result<Option<(Message, usize)>> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        if len > MAX_MESSAGE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Message exceeds maximum length",
+            ));
+        }
+        if buf.len() < 4 + len {
+            return Ok(None);
+        }
+        let message = Message::parse_body(&buf[4..4 + len])?;
+        Ok(Some((message, 4 + len)))
+    }
+
+    // Parses a frame without its length prefix.
+    pub fn parse_body(body: &[u8]) -> io::Result<Message> {
+        let (id, payload) = match body.split_first() {
+            None => return Ok(Message::KeepAlive),
+            Some((id, payload)) => (*id, payload),
+        };
+
+        let expect_len = |n: usize| {
+            if payload.len() == n {
+                Ok(())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Invalid message length",
+                ))
+            }
+        };
+
+        let message = match id {
+            CHOKE => expect_len(0).map(|_| Message::Choke)?,
+            UNCHOKE => expect_len(0).map(|_| Message::Unchoke)?,
+            INTERESTED => expect_len(0).map(|_| Message::Interested)?,
+            NOT_INTERESTED => expect_len(0).map(|_| Message::NotInterested)?,
+            HAVE => {
+                expect_len(4)?;
+                Message::Have(read_u32(payload, 0))
+            }
+            BITFIELD => Message::Bitfield(payload.to_vec()),
+            REQUEST | CANCEL | REJECT_REQUEST => {
+                expect_len(12)?;
+                let index = read_u32(payload, 0);
+                let begin = read_u32(payload, 4);
+                let length = read_u32(payload, 8);
+                if length == 0 || length > MAX_REQUEST_LEN {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Invalid request length",
+                    ));
+                }
+                match id {
+                    REQUEST => Message::Request {
+                        index,
+                        begin,
+                        length,
+                    },
+                    CANCEL => Message::Cancel {
+                        index,
+                        begin,
+                        length,
+                    },
+                    _ => Message::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    },
+                }
+            }
+            PIECE => {
+                if payload.len() < 8 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Invalid message length",
+                    ));
+                }
+                Message::Piece {
+                    index: read_u32(payload, 0),
+                    begin: read_u32(payload, 4),
+                    block: payload[8..].to_vec(),
+                }
+            }
+            PORT => {
+                expect_len(2)?;
+                Message::Port(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            SUGGEST_PIECE => {
+                expect_len(4)?;
+                Message::SuggestPiece(read_u32(payload, 0))
+            }
+            HAVE_ALL => expect_len(0).map(|_| Message::HaveAll)?,
+            HAVE_NONE => expect_len(0).map(|_| Message::HaveNone)?,
+            ALLOWED_FAST => {
+                expect_len(4)?;
+                Message::AllowedFast(read_u32(payload, 0))
+            }
+            EXTENDED => match payload.split_first() {
+                Some((ext_id, rest)) => Message::Extended {
+                    id: *ext_id,
+                    payload: rest.to_vec(),
+                },
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Invalid message length",
+                    ))
+                }
+            },
+            _ => Message::Unknown {
+                id,
+                payload: payload.to_vec(),
+            },
+        };
+        Ok(message)
+    }
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+pub async fn read_message<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Message> {
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf).await?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Message exceeds maximum length",
+        ));
+    }
+
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    Message::parse_body(&body)
+}
+
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+) -> io::Result<()> {
+    writer.write_all(&message.encode()).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn message() -> impl Strategy<Value = Message> {
+        let request = (any::<u32>(), any::<u32>(), 1..=MAX_REQUEST_LEN);
+        let bytes = || proptest::collection::vec(any::<u8>(), 0..64);
+        prop_oneof![
+            Just(Message::KeepAlive),
+            Just(Message::Choke),
+            Just(Message::Unchoke),
+            Just(Message::Interested),
+            Just(Message::NotInterested),
+            any::<u32>().prop_map(Message::Have),
+            bytes().prop_map(Message::Bitfield),
+            request
+                .clone()
+                .prop_map(|(index, begin, length)| Message::Request {
+                    index,
+                    begin,
+                    length
+                }),
+            (any::<u32>(), any::<u32>(), bytes()).prop_map(|(index, begin, block)| {
+                Message::Piece {
+                    index,
+                    begin,
+                    block,
+                }
+            }),
+            request
+                .clone()
+                .prop_map(|(index, begin, length)| Message::Cancel {
+                    index,
+                    begin,
+                    length
+                }),
+            any::<u16>().prop_map(Message::Port),
+            any::<u32>().prop_map(Message::SuggestPiece),
+            Just(Message::HaveAll),
+            Just(Message::HaveNone),
+            request.prop_map(|(index, begin, length)| Message::RejectRequest {
+                index,
+                begin,
+                length
+            }),
+            any::<u32>().prop_map(Message::AllowedFast),
+            (any::<u8>(), bytes()).prop_map(|(id, payload)| Message::Extended { id, payload }),
+            // Every ID not listed above
+            (21u8..=255, bytes()).prop_map(|(id, payload)| Message::Unknown { id, payload }),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(message in message()) {
+            let encoded = message.encode();
+            let decoded = Message::decode(&encoded).unwrap();
+            prop_assert_eq!(decoded, Some((message, encoded.len())));
+        }
+
+        #[test]
+        fn needs_whole_frame(message in message()) {
+            let encoded = message.encode();
+            for end in 0..encoded.len() {
+                prop_assert!(Message::decode(&encoded[..end]).unwrap().is_none());
+            }
+        }
+
+        // Whatever a peer sends, decoding returns instead of panicking
+        #[test]
+        fn decode_arbitrary(buf in proptest::collection::vec(any::<u8>(), 0..256)) {
+            if let Ok(Some((_, used))) = Message::decode(&buf) {
+                prop_assert!(used <= buf.len());
+            }
+        }
+
+        #[test]
+        fn parse_arbitrary_body(id in any::<u8>(), payload in proptest::collection::vec(any::<u8>(), 0..32)) {
+            let mut body = vec![id];
+            body.extend(payload);
+            if let Ok(message) = Message::parse_body(&body) {
+                prop_assert_eq!(&message.encode()[4..], &body[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        let too_long = ((MAX_MESSAGE_LEN + 1) as u32).to_be_bytes();
+        assert!(Message::decode(&too_long).is_err());
+        // Have with 3 bytes of payload
+        assert!(Message::decode(&[0, 0, 0, 4, HAVE, 0, 0, 1]).is_err());
+        // Request for 0 bytes
+        let mut zero = vec![0, 0, 0, 13, REQUEST];
+        zero.extend([0u8; 12]);
+        assert!(Message::decode(&zero).is_err());
+        // Extended without its ID
+        assert!(Message::decode(&[0, 0, 0, 1, EXTENDED]).is_err());
+    }
+
+    #[tokio::test]
+    async fn read_after_write() {
+        let (mut a, mut b) = tokio::io::duplex(1 << 16);
+        let sent = vec![
+            Message::Bitfield(vec![0xff, 0x80]),
+            Message::Request {
+                index: 1,
+                begin: BLOCK_LEN,
+                length: BLOCK_LEN,
+            },
+            Message::KeepAlive,
+        ];
+        for message in &sent {
+            write_message(&mut a, message).await.unwrap();
+        }
+        for message in &sent {
+            assert_eq!(&read_message(&mut b).await.unwrap(), message);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::time::{timeout, Duration};
+
+pub mod extension;
+pub mod fast;
+pub mod handshake;
+pub mod holepunch;
+pub mod manager;
+pub mod message;
+pub mod mse;
+pub mod pex;
+pub mod transport;
+
+use crate::backend::settings::EncryptionPolicy;
+use crate::requests::utp::UtpSocket;
+use handshake::Handshake;
+use message::Message;
+use mse::MseStream;
+use transport::PeerTransport;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Azureus-style peer ID: "-DT0100-" followed by 12 random characters
+pub fn generate_peer_id() -> [u8; 20] {
+    const CHARSET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(b"-DT0100-");
+    for byte in peer_id[8..].iter_mut() {
+        *byte = CHARSET[rand::random_range(0..CHARSET.len())];
+    }
+    peer_id
+}
+
+fn our_handshake(info_hash: [u8; 20], peer_id: [u8; 20]) -> Handshake {
+    let mut handshake = Handshake::new(info_hash, peer_id);
+    handshake.set_extensions();
+    handshake.set_fast();
+    handshake
+}
+
+pub struct PeerConnection {
+    stream: MseStream<PeerTransport>,
+    pub addr: SocketAddr,
+    pub remote: Handshake,
+    incoming: bool,
+}
+
+impl PeerConnection {
+    // Outgoing connection: we send our handshake first and wait for theirs.
+    // Goes over uTP when given a socket and the peer answers on it. With the
+    // prefer policy a peer that fails the encrypted handshake is tried again
+    // in plaintext.
+    pub async fn connect(
+        addr: SocketAddr,
+        utp: Option<&UtpSocket>,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        expected_peer_id: Option<[u8; 20]>,
+        policy: EncryptionPolicy,
+    ) -> io::Result<Self> {
+        let dial = || PeerTransport::connect(addr, utp);
+        PeerConnection::establish(dial, addr, info_hash, peer_id, expected_peer_id, policy).await
+    }
+
+    // Outgoing connection to a peer we were introduced to through
+    // ut_holepunch. Only uTP gets through the NATs, so there is no TCP fallback.
+    pub async fn connect_holepunched(
+        addr: SocketAddr,
+        utp: &UtpSocket,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        policy: EncryptionPolicy,
+    ) -> io::Result<Self> {
+        let dial = || PeerTransport::connect_utp(utp, addr);
+        PeerConnection::establish(dial, addr, info_hash, peer_id, None, policy).await
+    }
+
+    async fn establish<F, Fut>(
+        dial: F,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        expected_peer_id: Option<[u8; 20]>,
+        policy: EncryptionPolicy,
+    ) -> io::Result<Self>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = io::Result<PeerTransport>>,
+    {
+        if policy == EncryptionPolicy::Disabled {
+            let stream = MseStream::plaintext(dial().await?);
+            return PeerConnection::handshake(stream, addr, info_hash, peer_id, expected_peer_id)
+                .await;
+        }
+
+        // Only a failed MSE exchange is worth a plaintext retry. A peer that
+        // can't be reached or sends a handshake for something else won't do
+        // better without encryption. One that stays silent may just be
+        // waiting for a plaintext handshake, so a timeout counts as failed.
+        let stream = dial().await?;
+        let negotiated = timeout(HANDSHAKE_TIMEOUT, mse::initiate(stream, &info_hash, policy))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Handshake timeout")));
+        let stream = match negotiated {
+            Ok(stream) => stream,
+            Err(_) if policy == EncryptionPolicy::Prefer => MseStream::plaintext(dial().await?),
+            Err(e) => return Err(e),
+        };
+        PeerConnection::handshake(stream, addr, info_hash, peer_id, expected_peer_id).await
+    }
+
+    async fn handshake(
+        mut stream: MseStream<PeerTransport>,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        expected_peer_id: Option<[u8; 20]>,
+    ) -> io::Result<Self> {
+        let remote = timeout(HANDSHAKE_TIMEOUT, async {
+            our_handshake(info_hash, peer_id).write(&mut stream).await?;
+            Handshake::read(&mut stream).await
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Handshake timeout"))??;
+        remote.validate(&info_hash, expected_peer_id.as_ref(), &peer_id)?;
+
+        Ok(PeerConnection {
+            stream,
+            addr,
+            remote,
+            incoming: false,
+        })
+    }
+
+    // Incoming connection: read their handshake, check we are serving the torrent
+    // it names, then answer with ours. Encrypted and plaintext peers are told
+    // apart by their first bytes and checked against the policy.
+    pub async fn accept(
+        stream: PeerTransport,
+        peer_id: [u8; 20],
+        policy: EncryptionPolicy,
+        serving: &[[u8; 20]],
+    ) -> io::Result<Self> {
+        let addr = stream.peer_addr()?;
+        let (mut stream, remote) = timeout(HANDSHAKE_TIMEOUT, async {
+            let (mut stream, skey) = mse::accept(stream, policy, serving).await?;
+            let remote = Handshake::read(&mut stream).await?;
+            // An encrypted peer already named its torrent during MSE
+            if skey.is_some_and(|skey| skey != remote.info_hash) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Handshake does not match MSE torrent",
+                ));
+            }
+            Ok((stream, remote))
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Handshake timeout"))??;
+        if !serving.contains(&remote.info_hash) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Handshake for unknown torrent",
+            ));
+        }
+        remote.validate(&remote.info_hash, None, &peer_id)?;
+        our_handshake(remote.info_hash, peer_id)
+            .write(&mut stream)
+            .await?;
+
+        Ok(PeerConnection {
+            stream,
+            addr,
+            remote,
+            incoming: true,
+        })
+    }
+
+    // The peer connected to us, `addr` is then not where it listens
+    pub fn is_incoming(&self) -> bool {
+        self.incoming
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.stream.is_encrypted()
+    }
+
+    pub fn is_utp(&self) -> bool {
+        self.stream.get_ref().is_utp()
+    }
+
+    // We always offer the fast extension, so it is on when the peer offers it too
+    pub fn supports_fast(&self) -> bool {
+        self.remote.supports_fast()
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.remote.supports_extensions()
+    }
+
+    pub async fn send(&mut self, message: &Message) -> io::Result<()> {
+        message::write_message(&mut self.stream, message).await
+    }
+
+    pub async fn recv(&mut self) -> io::Result<Message> {
+        message::read_message(&mut self.stream).await
+    }
+
+    // Separate halves, so one task can wait for the next message while
+    // another writes
+    pub fn into_split(self) -> (PeerReader, PeerWriter) {
+        let (reader, writer) = tokio::io::split(self.stream);
+        (PeerReader(reader), PeerWriter(writer))
+    }
+}
+
+pub struct PeerReader(ReadHalf<MseStream<PeerTransport>>);
+
+impl PeerReader {
+    pub async fn recv(&mut self) -> io::Result<Message> {
+        message::read_message(&mut self.0).await
+    }
+}
+
+pub struct PeerWriter(WriteHalf<MseStream<PeerTransport>>);
+
+impl PeerWriter {
+    pub async fn send(&mut self, message: &Message) -> io::Result<()> {
+        message::write_message(&mut self.0, message).await
+    }
+}
@@ -0,0 +1,552 @@
+use num_bigint::BigUint;
+use sha1::{Digest, Sha1};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use super::handshake::PROTOCOL;
+use crate::backend::settings::EncryptionPolicy;
+
+// 768-bit safe prime and generator from the MSE spec
+const PRIME: [u8; KEY_LEN] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const GENERATOR: u32 = 2;
+const KEY_LEN: usize = 96;
+const VC: [u8; 8] = [0; 8];
+const MAX_PAD: usize = 512;
+
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    // MSE drops the first 1024 bytes of keystream
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (i, s) in state.iter_mut().enumerate() {
+            *s = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        let mut rc4 = Rc4 { state, i: 0, j: 0 };
+        rc4.apply(&mut [0u8; 1024]);
+        rc4
+    }
+
+    pub fn apply(&mut self, buf: &mut [u8]) {
+        for byte in buf.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state
+                [self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+// A peer stream after the MSE handshake. Plaintext connections go through it
+// too, with no ciphers, so the rest of the peer code has one stream type.
+pub struct MseStream<S> {
+    inner: S,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    // Already decrypted bytes that arrived during the handshake
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    // Encrypted bytes accepted by poll_write but not yet written
+    write_buf: Vec<u8>,
+    write_pos: usize,
+}
+
+impl<S> MseStream<S> {
+    pub fn plaintext(inner: S) -> Self {
+        MseStream::new(inner, None, None, Vec::new())
+    }
+
+    fn new(inner: S, read_cipher: Option<Rc4>, write_cipher: Option<Rc4>, prefix: Vec<u8>) -> Self {
+        MseStream {
+            inner,
+            read_cipher,
+            write_cipher,
+            read_buf: prefix,
+            read_pos: 0,
+            write_buf: Vec::new(),
+            write_pos: 0,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.write_cipher.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(
+                Pin::new(&mut self.inner).poll_write(cx, &self.write_buf[self.write_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.read_pos < this.read_buf.len() {
+            let n = buf.remaining().min(this.read_buf.len() - this.read_pos);
+            buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+            this.read_pos += n;
+            if this.read_pos == this.read_buf.len() {
+                this.read_buf.clear();
+                this.read_pos = 0;
+            }
+            return Poll::Ready(Ok(()));
+        }
+
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut this.read_cipher {
+            cipher.apply(&mut buf.filled_mut()[start..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write_cipher.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // The keystream has already advanced for anything buffered, so it has
+        // to go out before new data is encrypted.
+        ready!(this.poll_drain(cx))?;
+        let mut data = buf.to_vec();
+        if let Some(cipher) = &mut this.write_cipher {
+            cipher.apply(&mut data);
+        }
+        this.write_buf = data;
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut out = [0u8; 20];
+    for i in 0..20 {
+        out[i] = a[i] ^ b[i];
+    }
+    out
+}
+
+fn to_key_bytes(n: &BigUint) -> [u8; KEY_LEN] {
+    let bytes = n.to_bytes_be();
+    let mut out = [0u8; KEY_LEN];
+    out[KEY_LEN - bytes.len()..].copy_from_slice(&bytes);
+    out
+}
+
+// 160-bit private exponent, as the spec recommends
+fn generate_key() -> (BigUint, [u8; KEY_LEN]) {
+    let private = BigUint::from_bytes_be(&rand::random::<[u8; 20]>());
+    let public = BigUint::from(GENERATOR).modpow(&private, &BigUint::from_bytes_be(&PRIME));
+    (private, to_key_bytes(&public))
+}
+
+fn shared_secret(private: &BigUint, remote: &[u8; KEY_LEN]) -> io::Result<[u8; KEY_LEN]> {
+    let prime = BigUint::from_bytes_be(&PRIME);
+    let remote = BigUint::from_bytes_be(remote);
+    // Rules out the degenerate keys 0, 1 and p - 1
+    if remote <= BigUint::from(1u32) || remote >= &prime - 1u32 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Bad MSE public key",
+        ));
+    }
+    Ok(to_key_bytes(&remote.modpow(private, &prime)))
+}
+
+fn random_pad() -> Vec<u8> {
+    (0..rand::random_range(0..=MAX_PAD))
+        .map(|_| rand::random())
+        .collect()
+}
+
+// Reads until `pattern` shows up, allowing up to `max_skip` bytes of padding
+// in front of it.
+async fn synchronize<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    pattern: &[u8],
+    max_skip: usize,
+) -> io::Result<()> {
+    let mut window = Vec::with_capacity(max_skip + pattern.len());
+    while window.len() < max_skip + pattern.len() {
+        window.push(stream.read_u8().await?);
+        if window.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "MSE synchronization failed",
+    ))
+}
+
+async fn read_encrypted<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    cipher: &mut Rc4,
+    len: usize,
+) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    cipher.apply(&mut buf);
+    Ok(buf)
+}
+
+fn crypto_provide(policy: EncryptionPolicy) -> u32 {
+    match policy {
+        EncryptionPolicy::Require => CRYPTO_RC4,
+        _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+    }
+}
+
+// Outgoing side of the handshake. The info hash doubles as SKEY so the
+// receiver can tell which torrent we want without it going out in the clear.
+pub async fn initiate<S>(
+    mut stream: S,
+    info_hash: &[u8; 20],
+    policy: EncryptionPolicy,
+) -> io::Result<MseStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (private, public) = generate_key();
+    let mut out = public.to_vec();
+    out.extend(random_pad());
+    stream.write_all(&out).await?;
+
+    let mut remote = [0u8; KEY_LEN];
+    stream.read_exact(&mut remote).await?;
+    let secret = shared_secret(&private, &remote)?;
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    let provide = crypto_provide(policy);
+    let mut out = hash(&[b"req1", &secret]).to_vec();
+    out.extend(xor(
+        &hash(&[b"req2", info_hash]),
+        &hash(&[b"req3", &secret]),
+    ));
+    let mut payload = VC.to_vec();
+    payload.extend(provide.to_be_bytes());
+    // No padding and no initial payload, the BitTorrent handshake follows
+    payload.extend(0u16.to_be_bytes());
+    payload.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut payload);
+    out.extend(payload);
+    stream.write_all(&out).await?;
+
+    // Their reply starts with VC, encrypted, somewhere after their padding
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    synchronize(&mut stream, &vc, MAX_PAD).await?;
+    let header = read_encrypted(&mut stream, &mut decrypt, 6).await?;
+    let select = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    let pad_len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    if pad_len > MAX_PAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "MSE padding too long",
+        ));
+    }
+    read_encrypted(&mut stream, &mut decrypt, pad_len).await?;
+
+    match select {
+        CRYPTO_RC4 => Ok(MseStream::new(
+            stream,
+            Some(decrypt),
+            Some(encrypt),
+            Vec::new(),
+        )),
+        CRYPTO_PLAINTEXT if provide & CRYPTO_PLAINTEXT != 0 => Ok(MseStream::plaintext(stream)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Bad MSE crypto select",
+        )),
+    }
+}
+
+// Incoming side. Tells a plaintext handshake from an encrypted one by its
+// first 20 bytes and applies the policy to both. Returns the stream and,
+// for encrypted connections, the torrent the peer asked for.
+pub async fn accept<S>(
+    mut stream: S,
+    policy: EncryptionPolicy,
+    serving: &[[u8; 20]],
+) -> io::Result<(MseStream<S>, Option<[u8; 20]>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut first = [0u8; 20];
+    stream.read_exact(&mut first).await?;
+    if first[0] as usize == PROTOCOL.len() && first[1..] == PROTOCOL[..] {
+        if policy == EncryptionPolicy::Require {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Plaintext connection refused",
+            ));
+        }
+        return Ok((MseStream::new(stream, None, None, first.to_vec()), None));
+    }
+    if policy == EncryptionPolicy::Disabled {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Encrypted connection refused",
+        ));
+    }
+
+    let mut remote = [0u8; KEY_LEN];
+    remote[..20].copy_from_slice(&first);
+    stream.read_exact(&mut remote[20..]).await?;
+    let (private, public) = generate_key();
+    let secret = shared_secret(&private, &remote)?;
+    let mut out = public.to_vec();
+    out.extend(random_pad());
+    stream.write_all(&out).await?;
+
+    synchronize(&mut stream, &hash(&[b"req1", &secret]), MAX_PAD).await?;
+    let mut skey_hash = [0u8; 20];
+    stream.read_exact(&mut skey_hash).await?;
+    let skey_hash = xor(&skey_hash, &hash(&[b"req3", &secret]));
+    let info_hash = *serving
+        .iter()
+        .find(|info_hash| hash(&[b"req2", *info_hash]) == skey_hash)
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "MSE handshake for unknown torrent")
+        })?;
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let header = read_encrypted(&mut stream, &mut decrypt, 14).await?;
+    if header[..8] != VC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Bad MSE verification constant",
+        ));
+    }
+    let provide = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+    let pad_len = u16::from_be_bytes([header[12], header[13]]) as usize;
+    if pad_len > MAX_PAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "MSE padding too long",
+        ));
+    }
+    read_encrypted(&mut stream, &mut decrypt, pad_len).await?;
+    let ia_len = read_encrypted(&mut stream, &mut decrypt, 2).await?;
+    let ia_len = u16::from_be_bytes([ia_len[0], ia_len[1]]) as usize;
+    // The initial payload is always RC4, whatever gets selected afterwards
+    let initial_payload = read_encrypted(&mut stream, &mut decrypt, ia_len).await?;
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Require {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "No acceptable MSE crypto method",
+        ));
+    };
+    let mut reply = VC.to_vec();
+    reply.extend(select.to_be_bytes());
+    reply.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut reply);
+    stream.write_all(&reply).await?;
+
+    let stream = if select == CRYPTO_RC4 {
+        MseStream::new(stream, Some(decrypt), Some(encrypt), initial_payload)
+    } else {
+        MseStream::new(stream, None, None, initial_payload)
+    };
+    Ok((stream, Some(info_hash)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requests::peer::handshake::Handshake;
+    use tokio::io::{duplex, DuplexStream};
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    type Accepted = io::Result<(MseStream<DuplexStream>, Option<[u8; 20]>)>;
+
+    async fn negotiate(
+        initiator: EncryptionPolicy,
+        acceptor: EncryptionPolicy,
+    ) -> (io::Result<MseStream<DuplexStream>>, Accepted) {
+        let (a, b) = duplex(4096);
+        tokio::join!(
+            initiate(a, &INFO_HASH, initiator),
+            accept(b, acceptor, &[[1; 20], INFO_HASH])
+        )
+    }
+
+    async fn send(from: &mut MseStream<DuplexStream>, to: &mut MseStream<DuplexStream>) {
+        from.write_all(b"hello through MSE").await.unwrap();
+        from.flush().await.unwrap();
+        let mut buf = [0u8; 17];
+        to.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello through MSE");
+    }
+
+    // Sends a message each way and checks it arrives intact
+    async fn exchange(a: &mut MseStream<DuplexStream>, b: &mut MseStream<DuplexStream>) {
+        send(a, b).await;
+        send(b, a).await;
+    }
+
+    #[test]
+    fn rc4_drops_first_1024_bytes() {
+        // RFC 6229, 40-bit key 0x0102030405, keystream at offset 1024
+        let mut keystream = [0u8; 16];
+        Rc4::new(&[1, 2, 3, 4, 5]).apply(&mut keystream);
+        assert_eq!(
+            keystream,
+            [
+                0x30, 0xab, 0xbc, 0xc7, 0xc2, 0x0b, 0x01, 0x60, 0x9f, 0x23, 0xee, 0x2d, 0x5f, 0x6b,
+                0xb7, 0xdf
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn encrypted_under_every_policy_pair() {
+        use EncryptionPolicy::*;
+        for (initiator, acceptor) in [
+            (Prefer, Prefer),
+            (Prefer, Require),
+            (Require, Prefer),
+            (Require, Require),
+        ] {
+            let (initiated, accepted) = negotiate(initiator, acceptor).await;
+            let mut a = initiated.unwrap();
+            let (mut b, skey) = accepted.unwrap();
+            assert!(a.is_encrypted() && b.is_encrypted());
+            assert_eq!(skey, Some(INFO_HASH));
+            exchange(&mut a, &mut b).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn disabled_acceptor_refuses_encryption() {
+        use EncryptionPolicy::*;
+        for initiator in [Prefer, Require] {
+            let (initiated, accepted) = negotiate(initiator, Disabled).await;
+            assert!(initiated.is_err());
+            let Err(e) = accepted else {
+                panic!("Encrypted connection accepted");
+            };
+            assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_torrent_refused() {
+        let (a, b) = duplex(4096);
+        let (initiated, accepted) = tokio::join!(
+            initiate(a, &INFO_HASH, EncryptionPolicy::Prefer),
+            accept(b, EncryptionPolicy::Prefer, &[[1; 20]])
+        );
+        assert!(initiated.is_err());
+        assert_eq!(accepted.err().unwrap().kind(), io::ErrorKind::NotFound);
+    }
+
+    // A peer that never tried MSE, or fell back after it failed, starts with
+    // the plaintext handshake
+    #[tokio::test]
+    async fn plaintext_handshake() {
+        use EncryptionPolicy::*;
+        let handshake = Handshake::new(INFO_HASH, [2; 20]);
+        for acceptor in [Disabled, Prefer, Require] {
+            let (a, b) = duplex(4096);
+            let mut a = MseStream::plaintext(a);
+            handshake.write(&mut a).await.unwrap();
+            let accepted = accept(b, acceptor, &[INFO_HASH]).await;
+            if acceptor == Require {
+                assert_eq!(
+                    accepted.err().unwrap().kind(),
+                    io::ErrorKind::PermissionDenied
+                );
+                continue;
+            }
+            let (mut b, skey) = accepted.unwrap();
+            assert!(!b.is_encrypted());
+            assert_eq!(skey, None);
+            // The bytes read to tell it apart come back first
+            let remote = Handshake::read(&mut b).await.unwrap();
+            assert_eq!(remote.to_bytes(), handshake.to_bytes());
+            exchange(&mut a, &mut b).await;
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::extension::{ExtendedHandshake, Extension, ExtensionRegistry};
+use super::manager::{ConnectionManager, PeerSource};
+use crate::backend::file::{BencodeParser, BencodeValue, MAX_DEPTH};
+use crate::requests::compact;
+
+pub const NAME: &str = "ut_pex";
+// BEP 11: at most one message a minute, and no more than 50 added and 50
+// dropped peers in each.
+pub const SEND_INTERVAL: Duration = Duration::from_secs(60);
+pub const MAX_PEERS: usize = 50;
+// Messages arriving faster than this are floods and get ignored
+const MIN_RECEIVE_INTERVAL: Duration = Duration::from_secs(45);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, u8)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut added = Vec::new();
+        let mut added_f = Vec::new();
+        let mut added6 = Vec::new();
+        let mut added6_f = Vec::new();
+        for (addr, flags) in &self.added {
+            if addr.is_ipv4() {
+                added.extend(compact::encode(addr));
+                added_f.push(*flags);
+            } else {
+                added6.extend(compact::encode(addr));
+                added6_f.push(*flags);
+            }
+        }
+
+        let mut dropped = Vec::new();
+        let mut dropped6 = Vec::new();
+        for addr in &self.dropped {
+            if addr.is_ipv4() {
+                dropped.extend(compact::encode(addr));
+            } else {
+                dropped6.extend(compact::encode(addr));
+            }
+        }
+
+        BencodeValue::Dict(vec![
+            (b"added".to_vec(), BencodeValue::String(added)),
+            (b"added.f".to_vec(), BencodeValue::String(added_f)),
+            (b"added6".to_vec(), BencodeValue::String(added6)),
+            (b"added6.f".to_vec(), BencodeValue::String(added6_f)),
+            (b"dropped".to_vec(), BencodeValue::String(dropped)),
+            (b"dropped6".to_vec(), BencodeValue::String(dropped6)),
+        ])
+        .encode()
+    }
+
+    pub fn from_bytes(payload: &[u8]) -> io::Result<Self> {
+        let value = BencodeParser::new(payload)
+            .parse_with_depth(MAX_DEPTH)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let bytes = |key: &[u8]| {
+            value
+                .get(key)
+                .and_then(|v| v.as_bytes())
+                .unwrap_or_default()
+        };
+
+        let mut added = Vec::new();
+        for (addrs, flags) in [
+            (compact::parse_v4(bytes(b"added")), bytes(b"added.f")),
+            (compact::parse_v6(bytes(b"added6")), bytes(b"added6.f")),
+        ] {
+            for (i, addr) in addrs.into_iter().enumerate() {
+                added.push((addr, flags.get(i).copied().unwrap_or(0)));
+            }
+        }
+
+        let mut dropped = compact::parse_v4(bytes(b"dropped"));
+        dropped.extend(compact::parse_v6(bytes(b"dropped6")));
+
+        Ok(PexMessage { added, dropped })
+    }
+}
+
+// One instance per connection. Peers we learn about go to the torrent's
+// connection manager, and every minute we tell the peer who we connected to
+// or dropped since the last message.
+pub struct PexExtension {
+    manager: Arc<Mutex<ConnectionManager>>,
+    remote: SocketAddr,
+    enabled: bool,
+    advertised: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+    last_received: Option<Instant>,
+}
+
+impl PexExtension {
+    pub fn new(manager: Arc<Mutex<ConnectionManager>>, remote: SocketAddr) -> Self {
+        PexExtension {
+            manager,
+            remote,
+            enabled: false,
+            advertised: HashSet::new(),
+            last_sent: None,
+            last_received: None,
+        }
+    }
+}
+
+impl Extension for PexExtension {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtendedHandshake, enabled: bool) {
+        self.enabled = enabled;
+        if let Some(port) = handshake.p {
+            self.manager
+                .lock()
+                .unwrap()
+                .set_listen_port(self.remote, port);
+        }
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        let now = Instant::now();
+        if self
+            .last_received
+            .is_some_and(|last| now.duration_since(last) < MIN_RECEIVE_INTERVAL)
+        {
+            return Ok(Vec::new());
+        }
+        self.last_received = Some(now);
+
+        let message = PexMessage::from_bytes(payload)?;
+        let mut manager = self.manager.lock().unwrap();
+        for (addr, flags) in message.added.into_iter().take(MAX_PEERS) {
+            if addr != self.remote && addr.port() != 0 {
+                manager.add_peer(addr, PeerSource::Pex, flags);
+                manager.set_relay(addr, self.remote);
+            }
+        }
+        for addr in message.dropped.into_iter().take(MAX_PEERS) {
+            manager.on_dropped(addr, self.remote);
+        }
+        Ok(Vec::new())
+    }
+
+    fn tick(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        if !self.enabled
+            || self
+                .last_sent
+                .is_some_and(|last| now.duration_since(last) < SEND_INTERVAL)
+        {
+            return Vec::new();
+        }
+
+        let connected = self.manager.lock().unwrap().pex_peers(self.remote);
+        let current: HashSet<SocketAddr> = connected.iter().map(|(addr, _)| *addr).collect();
+
+        let added: Vec<(SocketAddr, u8)> = connected
+            .into_iter()
+            .filter(|(addr, _)| !self.advertised.contains(addr))
+            .take(MAX_PEERS)
+            .collect();
+        let dropped: Vec<SocketAddr> = self
+            .advertised
+            .iter()
+            .filter(|addr| !current.contains(addr))
+            .copied()
+            .take(MAX_PEERS)
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return Vec::new();
+        }
+
+        // Whatever didn't fit stays pending for the next round
+        for (addr, _) in &added {
+            self.advertised.insert(*addr);
+        }
+        for addr in &dropped {
+            self.advertised.remove(addr);
+        }
+        self.last_sent = Some(now);
+        vec![PexMessage { added, dropped }.encode()]
+    }
+}
+
+// PEX is never enabled for private torrents, peers may only come from the
+// private tracker.
+pub fn register(
+    registry: &mut ExtensionRegistry,
+    manager: Arc<Mutex<ConnectionManager>>,
+    remote: SocketAddr,
+    private: bool,
+) {
+    if !private {
+        registry.register(Box::new(PexExtension::new(manager, remote)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::requests::peer::manager::FLAG_ENCRYPTION;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn handshake(p: Option<u16>) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake {
+            p,
+            ..Default::default()
+        };
+        handshake.m.insert(NAME.to_string(), 1);
+        handshake
+    }
+
+    fn sent(pex: &mut PexExtension, now: Instant) -> PexMessage {
+        let mut messages = pex.tick(now);
+        assert_eq!(messages.len(), 1);
+        PexMessage::from_bytes(&messages.remove(0)).unwrap()
+    }
+
+    #[test]
+    fn advertises_listen_ports() {
+        let manager = Arc::new(Mutex::new(ConnectionManager::new(10)));
+        let remote = addr("4.4.4.4:6881");
+        let (outgoing, incoming, unknown) = (
+            addr("1.2.3.4:6881"),
+            addr("5.6.7.8:50000"),
+            addr("9.9.9.9:40000"),
+        );
+        {
+            let mut manager = manager.lock().unwrap();
+            manager.on_connected(remote, 0, false);
+            manager.on_connected(outgoing, FLAG_ENCRYPTION, false);
+            manager.on_connected(incoming, 0, true);
+            manager.on_connected(unknown, 0, true);
+        }
+        let mut pex = PexExtension::new(manager.clone(), remote);
+        pex.on_handshake(&handshake(None), true);
+        // The incoming peer's own PEX instance sees its extended handshake
+        PexExtension::new(manager.clone(), incoming).on_handshake(&handshake(Some(6882)), true);
+
+        let now = Instant::now();
+        let mut added = sent(&mut pex, now).added;
+        added.sort();
+        assert_eq!(
+            added,
+            vec![(outgoing, FLAG_ENCRYPTION), (addr("5.6.7.8:6882"), 0)]
+        );
+
+        // Once the other one tells us, it goes out too
+        PexExtension::new(manager.clone(), unknown).on_handshake(&handshake(Some(7000)), true);
+        manager.lock().unwrap().on_disconnected(incoming);
+        let message = sent(&mut pex, now + SEND_INTERVAL);
+        assert_eq!(message.added, vec![(addr("9.9.9.9:7000"), 0)]);
+        assert_eq!(message.dropped, vec![addr("5.6.7.8:6882")]);
+    }
+}
@@ -0,0 +1,98 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+
+use crate::requests::utp::{UtpSocket, UtpStream};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// uTP gets less time before we fall back to TCP
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The byte stream under a peer connection. Everything above it, MSE and the
+// wire protocol, works the same over either.
+pub enum PeerTransport {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl PeerTransport {
+    pub async fn connect_tcp(addr: SocketAddr) -> io::Result<PeerTransport> {
+        let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Connect timeout"))??;
+        Ok(PeerTransport::Tcp(stream))
+    }
+
+    pub async fn connect_utp(utp: &UtpSocket, addr: SocketAddr) -> io::Result<PeerTransport> {
+        let stream = timeout(UTP_CONNECT_TIMEOUT, utp.connect(addr))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Connect timeout"))??;
+        Ok(PeerTransport::Utp(stream))
+    }
+
+    // uTP first when we have a socket for it, TCP if the peer doesn't answer
+    pub async fn connect(addr: SocketAddr, utp: Option<&UtpSocket>) -> io::Result<PeerTransport> {
+        if let Some(utp) = utp {
+            match PeerTransport::connect_utp(utp, addr).await {
+                Ok(transport) => return Ok(transport),
+                Err(e) => println!("uTP connect to {} failed: {}, trying TCP", addr, e),
+            }
+        }
+        PeerTransport::connect_tcp(addr).await
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            PeerTransport::Tcp(stream) => stream.peer_addr(),
+            PeerTransport::Utp(stream) => Ok(stream.peer_addr()),
+        }
+    }
+
+    pub fn is_utp(&self) -> bool {
+        matches!(self, PeerTransport::Utp(_))
+    }
+}
+
+impl AsyncRead for PeerTransport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerTransport::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerTransport::Utp(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerTransport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            PeerTransport::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            PeerTransport::Utp(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerTransport::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            PeerTransport::Utp(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            PeerTransport::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerTransport::Utp(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,548 @@
+use serde::Serialize;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Duration, Instant};
+
+pub mod natpmp;
+pub mod upnp;
+
+// Requested lease, renewed at half of what the gateway grants
+const DEFAULT_LIFETIME: Duration = Duration::from_secs(2 * 60 * 60);
+const MIN_RENEW: Duration = Duration::from_secs(60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    // IANA protocol number, used by PCP
+    fn number(self) -> u8 {
+        match self {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        }
+    }
+
+    // As UPnP spells it
+    fn name(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum Method {
+    #[serde(rename = "PCP")]
+    Pcp,
+    #[serde(rename = "NAT-PMP")]
+    NatPmp,
+    #[serde(rename = "UPnP")]
+    Upnp,
+}
+
+impl Method {
+    fn name(self) -> &'static str {
+        match self {
+            Method::Pcp => "PCP",
+            Method::NatPmp => "NAT-PMP",
+            Method::Upnp => "UPnP",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MappingStatus {
+    pub protocol: Protocol,
+    pub internal_port: u16,
+    pub external_port: Option<u16>,
+    pub external_ip: Option<String>,
+    pub method: Option<Method>,
+    // Seconds until the lease runs out, None when unmapped or permanent
+    pub expires_in: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PortMapConfig {
+    // NAT-PMP/PCP server, the default gateway when None
+    pub gateway: Option<SocketAddr>,
+    pub ssdp: SocketAddr,
+    pub lifetime: Duration,
+}
+
+impl Default for PortMapConfig {
+    fn default() -> Self {
+        PortMapConfig {
+            gateway: None,
+            ssdp: upnp::SSDP_ADDR,
+            lifetime: DEFAULT_LIFETIME,
+        }
+    }
+}
+
+struct Mapping {
+    protocol: Protocol,
+    internal_port: u16,
+    // PCP identifies a mapping by its nonce across renewals
+    nonce: [u8; 12],
+    external_port: Option<u16>,
+    external_ip: Option<IpAddr>,
+    method: Option<Method>,
+    expires: Option<Instant>,
+    renew_at: Instant,
+    error: Option<String>,
+}
+
+impl Mapping {
+    fn status(&self, now: Instant) -> MappingStatus {
+        MappingStatus {
+            protocol: self.protocol,
+            internal_port: self.internal_port,
+            external_port: self.external_port,
+            external_ip: self.external_ip.map(|ip| ip.to_string()),
+            method: self.method,
+            expires_in: self
+                .expires
+                .map(|expires| expires.saturating_duration_since(now).as_secs()),
+            error: self.error.clone(),
+        }
+    }
+}
+
+// Keeps our listening ports forwarded on the gateway. PCP is tried first, then
+// NAT-PMP on the same port, then UPnP IGD. Leases are renewed in the
+// background until `unmap_all`.
+#[derive(Clone)]
+pub struct PortMapper {
+    mappings: Arc<Mutex<Vec<Mapping>>>,
+    config: PortMapConfig,
+    upnp: Arc<tokio::sync::Mutex<Option<upnp::Gateway>>>,
+    task: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl PortMapper {
+    pub fn start(ports: &[(Protocol, u16)], config: PortMapConfig) -> PortMapper {
+        let now = Instant::now();
+        let mappings = ports
+            .iter()
+            .map(|&(protocol, internal_port)| Mapping {
+                protocol,
+                internal_port,
+                nonce: rand::random(),
+                external_port: None,
+                external_ip: None,
+                method: None,
+                expires: None,
+                renew_at: now,
+                error: None,
+            })
+            .collect();
+        let mapper = PortMapper {
+            mappings: Arc::new(Mutex::new(mappings)),
+            config,
+            upnp: Arc::new(tokio::sync::Mutex::new(None)),
+            task: Arc::new(Mutex::new(None)),
+        };
+        let runner = mapper.clone();
+        *mapper.task.lock().unwrap() = Some(tokio::spawn(async move { runner.run().await }));
+        mapper
+    }
+
+    pub fn status(&self) -> Vec<MappingStatus> {
+        let now = Instant::now();
+        self.mappings
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|mapping| mapping.status(now))
+            .collect()
+    }
+
+    // Stops renewing and removes every mapping from the gateway
+    pub async fn unmap_all(&self) {
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+        let mapped: Vec<_> = self
+            .mappings
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter_map(|mapping| {
+                let method = mapping.method.take()?;
+                let external_port = mapping.external_port.take()?;
+                mapping.external_ip = None;
+                mapping.expires = None;
+                Some((
+                    method,
+                    mapping.protocol,
+                    mapping.internal_port,
+                    external_port,
+                    mapping.nonce,
+                ))
+            })
+            .collect();
+
+        // Done side by side so a dead gateway doesn't hold up shutdown for long
+        let removals: Vec<_> = mapped
+            .into_iter()
+            .map(|(method, protocol, internal_port, external_port, nonce)| {
+                let mapper = self.clone();
+                tokio::spawn(async move {
+                    let result = mapper
+                        .unmap(method, protocol, internal_port, external_port, nonce)
+                        .await;
+                    match result {
+                        Ok(()) => println!(
+                            "Removed {} port mapping for {}",
+                            protocol.name(),
+                            external_port
+                        ),
+                        Err(e) => println!(
+                            "Failed to remove {} port mapping for {}: {}",
+                            protocol.name(),
+                            external_port,
+                            e
+                        ),
+                    }
+                })
+            })
+            .collect();
+        for removal in removals {
+            removal.await.ok();
+        }
+    }
+
+    async fn run(&self) {
+        loop {
+            let now = Instant::now();
+            let due: Vec<usize> = self
+                .mappings
+                .lock()
+                .unwrap()
+                .iter()
+                .enumerate()
+                .filter(|(_, mapping)| mapping.renew_at <= now)
+                .map(|(index, _)| index)
+                .collect();
+            for index in due {
+                self.refresh(index).await;
+            }
+
+            let next = self
+                .mappings
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|mapping| mapping.renew_at)
+                .min();
+            match next {
+                Some(next) => sleep_until(next).await,
+                None => return,
+            }
+        }
+    }
+
+    async fn refresh(&self, index: usize) {
+        let (protocol, internal_port, external_port, nonce, previous) = {
+            let mappings = self.mappings.lock().unwrap();
+            let mapping = &mappings[index];
+            (
+                mapping.protocol,
+                mapping.internal_port,
+                mapping.external_port.unwrap_or(mapping.internal_port),
+                mapping.nonce,
+                mapping.method,
+            )
+        };
+        let result = self
+            .map(protocol, internal_port, external_port, nonce, previous)
+            .await;
+
+        let now = Instant::now();
+        let mut mappings = self.mappings.lock().unwrap();
+        let mapping = &mut mappings[index];
+        match result {
+            Ok((method, lease)) => {
+                if mapping.method != Some(method)
+                    || mapping.external_port != Some(lease.external_port)
+                {
+                    println!(
+                        "Mapped {} port {} to {} through {}",
+                        protocol.name(),
+                        internal_port,
+                        lease.external_port,
+                        method.name()
+                    );
+                }
+                mapping.method = Some(method);
+                mapping.external_port = Some(lease.external_port);
+                mapping.external_ip = lease.external_ip;
+                mapping.error = None;
+                if lease.lifetime.is_zero() {
+                    // Permanent, only checked again now and then
+                    mapping.expires = None;
+                    mapping.renew_at = now + self.config.lifetime;
+                } else {
+                    mapping.expires = Some(now + lease.lifetime);
+                    mapping.renew_at = now + (lease.lifetime / 2).max(MIN_RENEW);
+                }
+            }
+            Err(e) => {
+                println!(
+                    "Failed to map {} port {}: {}",
+                    protocol.name(),
+                    internal_port,
+                    e
+                );
+                mapping.method = None;
+                mapping.external_port = None;
+                mapping.external_ip = None;
+                mapping.expires = None;
+                mapping.error = Some(e.to_string());
+                mapping.renew_at = now + RETRY_INTERVAL;
+            }
+        }
+    }
+
+    // The method that worked last time goes first
+    async fn map(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        nonce: [u8; 12],
+        previous: Option<Method>,
+    ) -> io::Result<(Method, natpmp::Lease)> {
+        let mut methods = vec![Method::Pcp, Method::NatPmp, Method::Upnp];
+        if let Some(previous) = previous {
+            methods.retain(|&method| method != previous);
+            methods.insert(0, previous);
+        }
+        let lifetime = self.config.lifetime.as_secs() as u32;
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No gateway found");
+        let mut pcp_timed_out = false;
+
+        for method in methods {
+            let result = match method {
+                Method::Pcp | Method::NatPmp => {
+                    // Anything speaking NAT-PMP answers PCP as well, even if
+                    // only to say it doesn't know the version
+                    if pcp_timed_out {
+                        continue;
+                    }
+                    let Some(gateway) = self.natpmp_gateway().await else {
+                        continue;
+                    };
+                    if method == Method::Pcp {
+                        let result = natpmp::map_pcp(
+                            gateway,
+                            protocol,
+                            internal_port,
+                            external_port,
+                            lifetime,
+                            nonce,
+                        )
+                        .await;
+                        pcp_timed_out =
+                            matches!(&result, Err(e) if e.kind() == io::ErrorKind::TimedOut);
+                        result
+                    } else {
+                        natpmp::map_natpmp(
+                            gateway,
+                            protocol,
+                            internal_port,
+                            external_port,
+                            lifetime,
+                        )
+                        .await
+                    }
+                }
+                Method::Upnp => {
+                    self.map_upnp(protocol, internal_port, external_port, lifetime)
+                        .await
+                }
+            };
+            match result {
+                Ok(lease) => return Ok((method, lease)),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn map_upnp(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> io::Result<natpmp::Lease> {
+        let gateway = self.upnp_gateway().await?;
+        match upnp::add_port_mapping(&gateway, protocol, external_port, internal_port, lifetime)
+            .await
+        {
+            Ok(granted) => Ok(natpmp::Lease {
+                external_port,
+                external_ip: upnp::external_ip(&gateway).await.ok(),
+                lifetime: Duration::from_secs(granted as u64),
+            }),
+            Err(e) => {
+                // The gateway may have changed, discover it again next time
+                *self.upnp.lock().await = None;
+                Err(e)
+            }
+        }
+    }
+
+    async fn unmap(
+        &self,
+        method: Method,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        nonce: [u8; 12],
+    ) -> io::Result<()> {
+        let no_gateway = || io::Error::new(io::ErrorKind::NotFound, "No gateway found");
+        match method {
+            Method::Pcp => {
+                let gateway = self.natpmp_gateway().await.ok_or_else(no_gateway)?;
+                natpmp::map_pcp(gateway, protocol, internal_port, external_port, 0, nonce).await?;
+            }
+            Method::NatPmp => {
+                let gateway = self.natpmp_gateway().await.ok_or_else(no_gateway)?;
+                natpmp::map_natpmp(gateway, protocol, internal_port, 0, 0).await?;
+            }
+            Method::Upnp => {
+                let gateway = self.upnp.lock().await.clone().ok_or_else(no_gateway)?;
+                upnp::delete_port_mapping(&gateway, protocol, external_port).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // Reading the routing table may run a command, so it is kept off the
+    // runtime's threads
+    async fn natpmp_gateway(&self) -> Option<SocketAddr> {
+        if let Some(gateway) = self.config.gateway {
+            return Some(gateway);
+        }
+        let ip = tokio::task::spawn_blocking(default_gateway).await.ok()??;
+        Some(SocketAddr::new(IpAddr::V4(ip), natpmp::PORT))
+    }
+
+    async fn upnp_gateway(&self) -> io::Result<upnp::Gateway> {
+        let mut cached = self.upnp.lock().await;
+        if let Some(gateway) = cached.as_ref() {
+            return Ok(gateway.clone());
+        }
+        let gateway = upnp::discover(self.config.ssdp).await?;
+        *cached = Some(gateway.clone());
+        Ok(gateway)
+    }
+}
+
+// The gateway of the default route, from the system's routing table. None
+// when there is no default route or no way to read the table here.
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let routes = fs::read_to_string("/proc/net/route").ok()?;
+        for line in routes.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 3 || fields[1] != "00000000" {
+                continue;
+            }
+            // Network order bytes printed as a host order number
+            if let Ok(gateway) = u32::from_str_radix(fields[2], 16) {
+                if gateway != 0 {
+                    return Some(Ipv4Addr::from(gateway.to_ne_bytes()));
+                }
+            }
+        }
+        None
+    }
+
+    // "gateway: 192.168.1.1" in the answer for the default route
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly"
+    ))]
+    {
+        let output = route_command(&["-n", "get", "default"])?;
+        output.lines().find_map(|line| {
+            let (key, value) = line.trim().split_once(':')?;
+            (key == "gateway").then(|| value.trim().parse().ok())?
+        })
+    }
+
+    // Rows of "Network Destination  Netmask  Gateway  Interface  Metric",
+    // the default route goes to 0.0.0.0/0. The lowest metric wins.
+    #[cfg(windows)]
+    {
+        let output = route_command(&["print", "-4", "0.0.0.0"])?;
+        output
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                match fields[..] {
+                    ["0.0.0.0", "0.0.0.0", gateway, _, metric] => {
+                        Some((metric.parse::<u32>().ok()?, gateway.parse().ok()?))
+                    }
+                    _ => None,
+                }
+            })
+            .min_by_key(|(metric, _)| *metric)
+            .map(|(_, gateway)| gateway)
+    }
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly",
+        windows
+    )))]
+    {
+        None
+    }
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    windows
+))]
+fn route_command(args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new("route")
+        .args(args)
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -0,0 +1,200 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::time::Duration;
+
+use super::Protocol;
+
+pub const PORT: u16 = 5351;
+
+const NATPMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP: u8 = 1;
+const RESPONSE: u8 = 0x80;
+const RESULT_SUCCESS: u8 = 0;
+const RESULT_UNSUPPORTED_VERSION: u8 = 1;
+// 250ms doubling per attempt, the RFC keeps going longer but a gateway that
+// hasn't answered by then most likely doesn't speak the protocol
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const ATTEMPTS: u32 = 4;
+
+#[derive(Debug, Clone)]
+pub struct Lease {
+    pub external_port: u16,
+    pub external_ip: Option<IpAddr>,
+    pub lifetime: Duration,
+}
+
+async fn transact(gateway: SocketAddr, request: &[u8], opcode: u8) -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await?;
+    socket.connect(gateway).await?;
+    let mut wait = INITIAL_TIMEOUT;
+    let mut buf = [0u8; 1100];
+    for _ in 0..ATTEMPTS {
+        socket.send(request).await?;
+        let deadline = tokio::time::Instant::now() + wait;
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let amt = received?;
+            // Both protocols put the version first and the opcode second
+            if amt >= 4 && buf[1] == RESPONSE | opcode {
+                return Ok(buf[..amt].to_vec());
+            }
+            // A NAT-PMP gateway answers a PCP request with its own version
+            if amt >= 4 && buf[0] == NATPMP_VERSION && buf[3] == RESULT_UNSUPPORTED_VERSION {
+                return Ok(buf[..amt].to_vec());
+            }
+        }
+        wait *= 2;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "No answer from gateway",
+    ))
+}
+
+// Local address we reach the gateway from, PCP wants it in every request
+pub async fn local_ip(gateway: SocketAddr) -> io::Result<IpAddr> {
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await?;
+    socket.connect(gateway).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+fn mapped(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+fn unmapped(bytes: &[u8]) -> IpAddr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&bytes[..16]);
+    let ip = Ipv6Addr::from(octets);
+    match ip.to_ipv4_mapped() {
+        Some(v4) => IpAddr::V4(v4),
+        None => IpAddr::V6(ip),
+    }
+}
+
+// PCP (RFC 6887) MAP request. The nonce has to stay the same for renewals
+// and deletion of the same mapping. Fails with ErrorKind::Unsupported when
+// the gateway only speaks NAT-PMP.
+pub async fn map_pcp(
+    gateway: SocketAddr,
+    protocol: Protocol,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+    nonce: [u8; 12],
+) -> io::Result<Lease> {
+    let client = local_ip(gateway).await?;
+    let mut request = vec![PCP_VERSION, OP_MAP, 0, 0];
+    request.extend(lifetime.to_be_bytes());
+    request.extend(mapped(client));
+    request.extend(nonce);
+    request.push(protocol.number());
+    request.extend([0, 0, 0]);
+    request.extend(internal_port.to_be_bytes());
+    request.extend(external_port.to_be_bytes());
+    request.extend(mapped(IpAddr::V4(Ipv4Addr::UNSPECIFIED)));
+
+    let response = transact(gateway, &request, OP_MAP).await?;
+    if response[0] != PCP_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Gateway does not support PCP",
+        ));
+    }
+    if response[3] != RESULT_SUCCESS {
+        return Err(io::Error::other(format!(
+            "PCP mapping failed with result {}",
+            response[3]
+        )));
+    }
+    if response.len() < 60 || response[24..36] != nonce {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Malformed PCP response",
+        ));
+    }
+    Ok(Lease {
+        external_port: u16::from_be_bytes([response[42], response[43]]),
+        external_ip: Some(unmapped(&response[44..60])),
+        lifetime: Duration::from_secs(u32::from_be_bytes([
+            response[4],
+            response[5],
+            response[6],
+            response[7],
+        ]) as u64),
+    })
+}
+
+// NAT-PMP (RFC 6886) mapping. A lifetime of zero deletes it.
+pub async fn map_natpmp(
+    gateway: SocketAddr,
+    protocol: Protocol,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+) -> io::Result<Lease> {
+    let opcode = match protocol {
+        Protocol::Udp => 1,
+        Protocol::Tcp => 2,
+    };
+    let mut request = vec![NATPMP_VERSION, opcode, 0, 0];
+    request.extend(internal_port.to_be_bytes());
+    request.extend(external_port.to_be_bytes());
+    request.extend(lifetime.to_be_bytes());
+
+    let response = transact(gateway, &request, opcode).await?;
+    check_natpmp(&response, 16)?;
+    let external_ip = if lifetime > 0 {
+        external_address_natpmp(gateway).await.ok().map(IpAddr::V4)
+    } else {
+        None
+    };
+    Ok(Lease {
+        external_port: u16::from_be_bytes([response[10], response[11]]),
+        external_ip,
+        lifetime: Duration::from_secs(u32::from_be_bytes([
+            response[12],
+            response[13],
+            response[14],
+            response[15],
+        ]) as u64),
+    })
+}
+
+pub async fn external_address_natpmp(gateway: SocketAddr) -> io::Result<Ipv4Addr> {
+    let response = transact(
+        gateway,
+        &[NATPMP_VERSION, OP_EXTERNAL_ADDRESS],
+        OP_EXTERNAL_ADDRESS,
+    )
+    .await?;
+    check_natpmp(&response, 12)?;
+    Ok(Ipv4Addr::new(
+        response[8],
+        response[9],
+        response[10],
+        response[11],
+    ))
+}
+
+fn check_natpmp(response: &[u8], len: usize) -> io::Result<()> {
+    if response[0] != NATPMP_VERSION || response.len() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Malformed NAT-PMP response",
+        ));
+    }
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    if result != RESULT_SUCCESS as u16 {
+        return Err(io::Error::other(format!(
+            "NAT-PMP request failed with result {}",
+            result
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,327 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::time::{timeout, Duration};
+
+use super::Protocol;
+
+pub const SSDP_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+
+const SEARCH_TARGETS: [&str; 2] = [
+    "urn:schemas-upnp-org:device:InternetGatewayDevice:1",
+    "urn:schemas-upnp-org:device:InternetGatewayDevice:2",
+];
+const SERVICES: [&str; 2] = ["WANIPConnection", "WANPPPConnection"];
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+// Some IGDs only allow permanent mappings and refuse anything else with 725
+const ONLY_PERMANENT_LEASES: &str = "725";
+const MAX_RESPONSE: usize = 256 * 1024;
+
+// An Internet Gateway Device found through SSDP, with the control endpoint of
+// its WAN connection service.
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    pub control_url: String,
+    pub service_type: String,
+    // Our address as seen by the gateway, the mapping's internal client
+    pub local_ip: IpAddr,
+}
+
+pub async fn discover(ssdp: SocketAddr) -> io::Result<Gateway> {
+    let socket = UdpSocket::bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))).await?;
+    for target in SEARCH_TARGETS {
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+            ssdp, target
+        );
+        socket.send_to(search.as_bytes(), ssdp).await?;
+    }
+
+    let deadline = tokio::time::Instant::now() + DISCOVERY_TIMEOUT;
+    let mut buf = [0u8; 2048];
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (amt, _) = received?;
+        let response = String::from_utf8_lossy(&buf[..amt]);
+        let Some(location) = header(&response, "location") else {
+            continue;
+        };
+        // Devices that aren't a usable gateway are skipped, another may answer
+        match gateway_from_description(location).await {
+            Ok(gateway) => return Ok(gateway),
+            Err(e) => println!("Ignoring UPnP device at {}: {}", location, e),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "No UPnP gateway found",
+    ))
+}
+
+async fn gateway_from_description(location: &str) -> io::Result<Gateway> {
+    let response = http_request(location, "GET", &[], "").await?;
+    if response.status != 200 {
+        return Err(io::Error::other(format!(
+            "Description request failed with status {}",
+            response.status
+        )));
+    }
+    let base = tag(&response.body, "URLBase").unwrap_or(location).trim();
+
+    for service in blocks(&response.body, "service") {
+        let Some(service_type) = tag(service, "serviceType") else {
+            continue;
+        };
+        if !SERVICES.iter().any(|name| service_type.contains(name)) {
+            continue;
+        }
+        let Some(control_url) = tag(service, "controlURL") else {
+            continue;
+        };
+        return Ok(Gateway {
+            control_url: resolve(base, control_url.trim()),
+            service_type: service_type.trim().to_string(),
+            local_ip: response.local_ip,
+        });
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "Device has no WAN connection service",
+    ))
+}
+
+// Returns the lease actually granted, zero meaning permanent
+pub async fn add_port_mapping(
+    gateway: &Gateway,
+    protocol: Protocol,
+    external_port: u16,
+    internal_port: u16,
+    lease: u32,
+) -> io::Result<u32> {
+    let args = |lease: u32| {
+        format!(
+            "<NewRemoteHost></NewRemoteHost>\
+             <NewExternalPort>{}</NewExternalPort>\
+             <NewProtocol>{}</NewProtocol>\
+             <NewInternalPort>{}</NewInternalPort>\
+             <NewInternalClient>{}</NewInternalClient>\
+             <NewEnabled>1</NewEnabled>\
+             <NewPortMappingDescription>defttorrent</NewPortMappingDescription>\
+             <NewLeaseDuration>{}</NewLeaseDuration>",
+            external_port,
+            protocol.name(),
+            internal_port,
+            gateway.local_ip,
+            lease
+        )
+    };
+    match soap(gateway, "AddPortMapping", &args(lease)).await {
+        Err(e) if lease != 0 && e.to_string().ends_with(ONLY_PERMANENT_LEASES) => {
+            soap(gateway, "AddPortMapping", &args(0)).await?;
+            Ok(0)
+        }
+        result => result.map(|_| lease),
+    }
+}
+
+pub async fn delete_port_mapping(
+    gateway: &Gateway,
+    protocol: Protocol,
+    external_port: u16,
+) -> io::Result<()> {
+    let args = format!(
+        "<NewRemoteHost></NewRemoteHost>\
+         <NewExternalPort>{}</NewExternalPort>\
+         <NewProtocol>{}</NewProtocol>",
+        external_port,
+        protocol.name()
+    );
+    soap(gateway, "DeletePortMapping", &args).await?;
+    Ok(())
+}
+
+pub async fn external_ip(gateway: &Gateway) -> io::Result<IpAddr> {
+    let body = soap(gateway, "GetExternalIPAddress", "").await?;
+    tag(&body, "NewExternalIPAddress")
+        .and_then(|ip| ip.trim().parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Bad external IP address"))
+}
+
+// Errors from the device read "UPnP error <code>", so callers can match on
+// the code at the end
+async fn soap(gateway: &Gateway, action: &str, args: &str) -> io::Result<String> {
+    let body = format!(
+        "<?xml version=\"1.0\"?>\r\n\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{action} xmlns:u=\"{service}\">{args}</u:{action}></s:Body>\
+         </s:Envelope>\r\n",
+        action = action,
+        service = gateway.service_type,
+        args = args
+    );
+    let soap_action = format!("\"{}#{}\"", gateway.service_type, action);
+    let headers = [
+        ("Content-Type", "text/xml; charset=\"utf-8\""),
+        ("SOAPAction", soap_action.as_str()),
+    ];
+    let response = http_request(&gateway.control_url, "POST", &headers, &body).await?;
+    if response.status == 200 {
+        return Ok(response.body);
+    }
+    match tag(&response.body, "errorCode") {
+        Some(code) => Err(io::Error::other(format!("UPnP error {}", code.trim()))),
+        None => Err(io::Error::other(format!(
+            "{} failed with HTTP status {}",
+            action, response.status
+        ))),
+    }
+}
+
+struct HttpResponse {
+    status: u16,
+    body: String,
+    local_ip: IpAddr,
+}
+
+// Just enough HTTP/1.1 for talking to a gateway on the LAN
+async fn http_request(
+    url: &str,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> io::Result<HttpResponse> {
+    let invalid = |msg: &'static str| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| invalid("Only http URLs are supported"))?;
+    let (host, path) = match rest.find('/') {
+        Some(at) => (&rest[..at], &rest[at..]),
+        None => (rest, "/"),
+    };
+    let addr = if host.contains(':') {
+        host.to_socket_addrs()
+    } else {
+        (host, 80).to_socket_addrs()
+    }?
+    .next()
+    .ok_or_else(|| invalid("Could not resolve gateway host"))?;
+
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let local_ip = stream.local_addr()?.ip();
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            host,
+            body.len()
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut raw = Vec::new();
+        (&mut stream)
+            .take(MAX_RESPONSE as u64)
+            .read_to_end(&mut raw)
+            .await?;
+        Ok::<_, io::Error>((raw, local_ip))
+    };
+    let (raw, local_ip) = timeout(HTTP_TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "HTTP request timed out"))??;
+
+    let split = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| invalid("Truncated HTTP response"))?;
+    let head = String::from_utf8_lossy(&raw[..split]);
+    let status = head
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid("Bad HTTP status line"))?;
+    let mut content = raw[split + 4..].to_vec();
+    if header(&head, "transfer-encoding").is_some_and(|v| v.eq_ignore_ascii_case("chunked")) {
+        content = dechunk(&content).ok_or_else(|| invalid("Bad chunked HTTP body"))?;
+    }
+
+    Ok(HttpResponse {
+        status,
+        body: String::from_utf8_lossy(&content).into_owned(),
+        local_ip,
+    })
+}
+
+fn dechunk(mut data: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = data.windows(2).position(|w| w == b"\r\n")?;
+        let size_line = std::str::from_utf8(&data[..line_end]).ok()?;
+        // Chunk extensions after ';' are ignored
+        let size = usize::from_str_radix(size_line.split(';').next()?.trim(), 16).ok()?;
+        data = &data[line_end + 2..];
+        if size == 0 {
+            return Some(body);
+        }
+        body.extend_from_slice(data.get(..size)?);
+        data = data.get(size + 2..)?;
+    }
+}
+
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+// Text inside the first <name>...</name>, also matching namespaced tags
+fn tag<'a>(xml: &'a str, name: &'static str) -> Option<&'a str> {
+    blocks(xml, name).next()
+}
+
+fn blocks<'a>(xml: &'a str, name: &'static str) -> impl Iterator<Item = &'a str> + 'a {
+    let mut rest = xml;
+    std::iter::from_fn(move || loop {
+        let open = rest.find('<')?;
+        rest = &rest[open + 1..];
+        let end = rest.find('>')?;
+        let element = &rest[..end];
+        rest = &rest[end + 1..];
+        // Attributes and self-closing elements have no text to return
+        let element_name = element.split_whitespace().next().unwrap_or(element);
+        let local = element_name.rsplit(':').next().unwrap_or(element_name);
+        if local != name || element.ends_with('/') {
+            continue;
+        }
+        let close = rest.find(&format!("</{}>", element_name))?;
+        let content = &rest[..close];
+        rest = &rest[close..];
+        return Some(content);
+    })
+}
+
+fn resolve(base: &str, url: &str) -> String {
+    if url.starts_with("http://") {
+        return url.to_string();
+    }
+    let authority_end = base
+        .strip_prefix("http://")
+        .and_then(|rest| rest.find('/'))
+        .map(|at| at + "http://".len())
+        .unwrap_or(base.len());
+    let origin = &base[..authority_end];
+    if url.starts_with('/') {
+        format!("{}{}", origin, url)
+    } else {
+        format!("{}/{}", origin, url)
+    }
+}
@@ -43,7 +43,13 @@ fn parse_peers(buf: &[u8]) -> io::Result<Vec<SocketAddr>> {
     Ok(peers)
 }
 
-pub async fn request(url: &str, info_hash: &[u8; 20]) -> io::Result<Vec<SocketAddr>> {
+// Announces us as `peer_id`, reachable for peers on `listen_port`
+pub async fn request(
+    url: &str,
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+    listen_port: u16,
+) -> io::Result<Vec<SocketAddr>> {
     let (host, port, _) =
         parse_url(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
 
@@ -61,7 +67,8 @@ pub async fn request(url: &str, info_hash: &[u8; 20]) -> io::Result<Vec<SocketAd
     let conn_id = connect(&socket, &format!("{}:{}", host, port)).await?;
 
     // 2. Announce phase
-    let peers = announce(&socket, conn_id, &format!("{}:{}", host, port), info_hash).await?;
+    let addr = format!("{}:{}", host, port);
+    let peers = announce(&socket, conn_id, &addr, info_hash, peer_id, listen_port).await?;
 
     Ok(peers)
 }
@@ -108,6 +115,8 @@ async fn announce(
     conn_id: u64,
     addr: &str,
     info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+    port: u16,
 ) -> io::Result<Vec<SocketAddr>> {
     println!("Constructing announce payload");
     let mut payload = Vec::with_capacity(98);
@@ -115,9 +124,6 @@ async fn announce(
     payload.extend(1u32.to_be_bytes()); // action (1 = announce)
     payload.extend(rand::random::<u32>().to_be_bytes()); // transaction_id
     payload.extend(info_hash); // 20-byte info hash
-
-    // Use a valid Azureus-style peer ID
-    let peer_id = b"-UT2300-012345678901"; // Must be 20 bytes
     payload.extend(peer_id);
 
     payload.extend(0u64.to_be_bytes()); // downloaded
@@ -127,7 +133,7 @@ async fn announce(
     payload.extend(0u32.to_be_bytes()); // ip (0 = default)
     payload.extend(rand::random::<u32>().to_be_bytes()); // key
     payload.extend((-1i32).to_be_bytes()); // num_want (-1 = default)
-    payload.extend(6881u16.to_be_bytes()); // port
+    payload.extend(port.to_be_bytes()); // port
 
     println!("Sending announce payload to: {:?}", addr);
     socket.send_to(&payload, addr).await?;
@@ -153,8 +159,45 @@ async fn main() -> io::Result<()> {
     let announce_url = "http://tracker.example.com:6969/announce";
     let info_hash = [0u8; 20]; // Replace with actual info hash
 
-    let peers = request(announce_url, &info_hash).await?;
+    let peer_id = super::peer::generate_peer_id();
+
+    let peers = request(announce_url, &info_hash, &peer_id, 6881).await?;
 
     println!("Retrieved peers: {:?}", peers);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A tracker that checks who announced and hands back one peer
+    #[tokio::test]
+    async fn announces_peer_id_and_listen_port() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", tracker.local_addr().unwrap());
+        let peer_id = [b'p'; 20];
+        let server = tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (_, from) = tracker.recv_from(&mut buf).await.unwrap();
+            let mut reply = vec![0u8; 4];
+            reply.extend(&buf[12..16]);
+            reply.extend(7u64.to_be_bytes());
+            tracker.send_to(&reply, from).await.unwrap();
+
+            let (n, from) = tracker.recv_from(&mut buf).await.unwrap();
+            assert_eq!(n, 98);
+            assert_eq!(&buf[36..56], &[b'p'; 20]);
+            assert_eq!(u16::from_be_bytes([buf[96], buf[97]]), 51413);
+            let mut reply = 1u32.to_be_bytes().to_vec();
+            reply.extend(&buf[12..16]);
+            reply.extend([0u8; 12]);
+            reply.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+            tracker.send_to(&reply, from).await.unwrap();
+        });
+
+        let peers = request(&url, &[1; 20], &peer_id, 51413).await.unwrap();
+        assert_eq!(peers, vec![SocketAddr::from(([10, 0, 0, 1], 6881))]);
+        server.await.unwrap();
+    }
+}
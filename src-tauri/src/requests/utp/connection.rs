@@ -0,0 +1,632 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::Waker;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::oneshot;
+
+use super::packet::{seq_less, Packet, PacketType};
+
+// Payload per packet, keeps datagrams under common tunnel MTUs
+pub const MAX_PAYLOAD: usize = 1380;
+const RECV_BUFFER: usize = 1024 * 1024;
+const SEND_BUFFER: usize = 1024 * 1024;
+// Out-of-order packets we hold on to and report in selective ACKs
+const REORDER_WINDOW: u16 = 256;
+const MAX_SACK_BYTES: usize = 32;
+
+// LEDBAT: keep queuing delay near the target and back off before TCP does
+const TARGET_DELAY_US: f64 = 100_000.0;
+const MAX_CWND_INCREASE: f64 = 3000.0;
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const MAX_WINDOW: f64 = 1024.0 * 1024.0;
+const INITIAL_WINDOW: f64 = 4.0 * MAX_PAYLOAD as f64;
+const BASE_DELAY_HISTORY: usize = 2;
+const BASE_DELAY_PERIOD: Duration = Duration::from_secs(60);
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(60);
+const MAX_SYN_ATTEMPTS: u32 = 3;
+const MAX_TRANSMISSIONS: u32 = 8;
+const DUPLICATE_ACKS: u32 = 3;
+const KEEPALIVE: Duration = Duration::from_secs(29);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub fn now_micros() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    Connected,
+    Closed,
+}
+
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    need_resend: bool,
+}
+
+// Lowest one-way delay seen per minute over the last few minutes. The
+// timestamps come from two unsynchronised clocks, so only differences
+// against this base mean anything.
+#[derive(Default)]
+struct DelayHistory {
+    minima: VecDeque<(u32, Instant)>,
+    latest: Option<u32>,
+}
+
+impl DelayHistory {
+    fn add(&mut self, sample: u32, now: Instant) {
+        self.latest = Some(sample);
+        match self.minima.back_mut() {
+            Some((min, started)) if now.duration_since(*started) < BASE_DELAY_PERIOD => {
+                if (sample.wrapping_sub(*min) as i32) < 0 {
+                    *min = sample;
+                }
+            }
+            _ => {
+                self.minima.push_back((sample, now));
+                if self.minima.len() > BASE_DELAY_HISTORY {
+                    self.minima.pop_front();
+                }
+            }
+        }
+    }
+
+    // Queuing delay of the latest sample in microseconds
+    fn queuing_delay(&self) -> Option<f64> {
+        let latest = self.latest?;
+        let base = self.minima.iter().map(|(min, _)| *min).reduce(|a, b| {
+            if (b.wrapping_sub(a) as i32) < 0 {
+                b
+            } else {
+                a
+            }
+        })?;
+        Some((latest.wrapping_sub(base) as i32).max(0) as f64)
+    }
+}
+
+pub struct Connection {
+    socket: Arc<UdpSocket>,
+    pub addr: SocketAddr,
+    recv_id: u16,
+    send_id: u16,
+    pub state: State,
+    seq_nr: u16,
+    ack_nr: u16,
+
+    send_buf: VecDeque<u8>,
+    in_flight: VecDeque<Sent>,
+    bytes_in_flight: usize,
+    cwnd: f64,
+    peer_wnd: usize,
+    dup_acks: u32,
+    last_decrease: Option<Instant>,
+    srtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+    delay: DelayHistory,
+    reply_micro: u32,
+
+    recv_buf: VecDeque<u8>,
+    out_of_order: HashMap<u16, Vec<u8>>,
+    eof_seq: Option<u16>,
+    eof: bool,
+
+    shutdown: bool,
+    dropped: bool,
+    fin_sent: bool,
+    pub error: Option<io::ErrorKind>,
+    last_recv: Instant,
+    last_send: Instant,
+    connected: Option<oneshot::Sender<io::Result<()>>>,
+    pub read_waker: Option<Waker>,
+    pub write_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn new(
+        socket: Arc<UdpSocket>,
+        addr: SocketAddr,
+        recv_id: u16,
+        send_id: u16,
+        state: State,
+    ) -> Self {
+        let now = Instant::now();
+        Connection {
+            socket,
+            addr,
+            recv_id,
+            send_id,
+            state,
+            seq_nr: 1,
+            ack_nr: 0,
+            send_buf: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            bytes_in_flight: 0,
+            cwnd: INITIAL_WINDOW,
+            peer_wnd: RECV_BUFFER,
+            dup_acks: 0,
+            last_decrease: None,
+            srtt: None,
+            rtt_var: Duration::ZERO,
+            rto: INITIAL_RTO,
+            delay: DelayHistory::default(),
+            reply_micro: 0,
+            recv_buf: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            eof_seq: None,
+            eof: false,
+            shutdown: false,
+            dropped: false,
+            fin_sent: false,
+            error: None,
+            last_recv: now,
+            last_send: now,
+            connected: None,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    // We pick the receive ID, the peer sends to us on it and expects us to
+    // send on the next one.
+    pub fn outgoing(
+        socket: Arc<UdpSocket>,
+        addr: SocketAddr,
+        recv_id: u16,
+        connected: oneshot::Sender<io::Result<()>>,
+    ) -> Self {
+        let mut conn = Connection::new(
+            socket,
+            addr,
+            recv_id,
+            recv_id.wrapping_add(1),
+            State::SynSent,
+        );
+        conn.connected = Some(connected);
+        let syn = Packet::new(PacketType::Syn, recv_id, conn.seq_nr);
+        conn.seq_nr = conn.seq_nr.wrapping_add(1);
+        conn.send_tracked(syn, Instant::now());
+        conn
+    }
+
+    pub fn incoming(socket: Arc<UdpSocket>, addr: SocketAddr, syn: &Packet) -> Self {
+        let mut conn = Connection::new(
+            socket,
+            addr,
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+            State::Connected,
+        );
+        conn.seq_nr = rand::random();
+        conn.ack_nr = syn.seq_nr;
+        conn.reply_micro = now_micros().wrapping_sub(syn.timestamp);
+        conn.send_ack();
+        conn
+    }
+
+    pub fn recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    fn recv_window(&self) -> u32 {
+        RECV_BUFFER.saturating_sub(self.recv_buf.len()) as u32
+    }
+
+    fn transmit(&mut self, packet: &mut Packet) {
+        packet.timestamp = now_micros();
+        packet.timestamp_diff = self.reply_micro;
+        packet.wnd_size = self.recv_window();
+        packet.ack_nr = self.ack_nr;
+        // A full socket buffer is treated like a lost packet
+        self.socket.try_send_to(&packet.encode(), self.addr).ok();
+        self.last_send = Instant::now();
+    }
+
+    fn send_tracked(&mut self, mut packet: Packet, now: Instant) {
+        self.transmit(&mut packet);
+        self.bytes_in_flight += packet.payload.len();
+        self.in_flight.push_back(Sent {
+            packet,
+            sent_at: now,
+            transmissions: 1,
+            need_resend: false,
+        });
+    }
+
+    pub fn send_ack(&mut self) {
+        let mut packet = Packet::new(PacketType::State, self.send_id, self.seq_nr);
+        packet.sack = self.selective_ack();
+        self.transmit(&mut packet);
+    }
+
+    fn send_reset(&mut self) {
+        let mut packet = Packet::new(PacketType::Reset, self.send_id, self.seq_nr);
+        self.transmit(&mut packet);
+    }
+
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.out_of_order.is_empty() {
+            return None;
+        }
+        let mut mask = vec![0u8; MAX_SACK_BYTES];
+        let mut used = 0;
+        for seq in self.out_of_order.keys() {
+            let bit = seq.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+            if bit < MAX_SACK_BYTES * 8 {
+                mask[bit / 8] |= 1 << (bit % 8);
+                used = used.max(bit / 8 + 1);
+            }
+        }
+        mask.truncate(used.div_ceil(4) * 4);
+        (!mask.is_empty()).then_some(mask)
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        if self.error.is_none() {
+            self.error = Some(kind);
+        }
+        self.state = State::Closed;
+        if let Some(connected) = self.connected.take() {
+            connected
+                .send(Err(io::Error::new(kind, "uTP connection failed")))
+                .ok();
+        }
+    }
+
+    pub fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub fn on_packet(&mut self, packet: Packet, now: Instant) {
+        self.last_recv = now;
+        self.reply_micro = now_micros().wrapping_sub(packet.timestamp);
+        if packet.timestamp_diff != 0 {
+            self.delay.add(packet.timestamp_diff, now);
+        }
+        self.peer_wnd = packet.wnd_size as usize;
+
+        match packet.kind {
+            PacketType::Reset => {
+                self.fail(io::ErrorKind::ConnectionReset);
+                self.wake();
+                return;
+            }
+            PacketType::Syn => {
+                // Our ACK to their SYN got lost
+                self.send_ack();
+                return;
+            }
+            _ => {}
+        }
+
+        if self.state == State::SynSent {
+            if packet.kind != PacketType::State {
+                return;
+            }
+            self.state = State::Connected;
+            // Their first data packet reuses the sequence number of this ACK
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            if let Some(connected) = self.connected.take() {
+                connected.send(Ok(())).ok();
+            }
+        }
+
+        self.process_ack(&packet, now);
+        match packet.kind {
+            PacketType::Data => self.receive_data(packet.seq_nr, packet.payload),
+            PacketType::Fin => {
+                self.eof_seq = Some(packet.seq_nr);
+                self.receive_data(packet.seq_nr, Vec::new());
+            }
+            _ => {}
+        }
+        self.flush(now);
+        self.check_finished();
+        self.wake();
+    }
+
+    fn receive_data(&mut self, seq: u16, payload: Vec<u8>) {
+        let expected = self.ack_nr.wrapping_add(1);
+        if seq == expected {
+            self.deliver(seq, payload);
+            loop {
+                let next = self.ack_nr.wrapping_add(1);
+                match self.out_of_order.remove(&next) {
+                    Some(payload) => self.deliver(next, payload),
+                    None if self.eof_seq == Some(next) => self.deliver(next, Vec::new()),
+                    None => break,
+                }
+            }
+        } else if seq_less(self.ack_nr, seq)
+            && seq.wrapping_sub(self.ack_nr) < REORDER_WINDOW
+            && Some(seq) != self.eof_seq
+        {
+            self.out_of_order.insert(seq, payload);
+        }
+        // Duplicates are ACKed again in case our previous ACK was lost
+        self.send_ack();
+    }
+
+    fn deliver(&mut self, seq: u16, payload: Vec<u8>) {
+        self.ack_nr = seq;
+        if self.eof_seq == Some(seq) {
+            self.eof = true;
+            self.out_of_order.clear();
+        } else {
+            self.recv_buf.extend(payload);
+        }
+    }
+
+    fn process_ack(&mut self, packet: &Packet, now: Instant) {
+        let mut acked_bytes = 0;
+        let mut acked_any = false;
+        while let Some(sent) = self.in_flight.front() {
+            if seq_less(packet.ack_nr, sent.packet.seq_nr) {
+                break;
+            }
+            if let Some(sent) = self.in_flight.pop_front() {
+                acked_bytes += sent.packet.payload.len();
+                acked_any = true;
+                if sent.transmissions == 1 {
+                    self.update_rtt(now.duration_since(sent.sent_at));
+                }
+            }
+        }
+
+        if let Some(sack) = &packet.sack {
+            let base = packet.ack_nr.wrapping_add(2);
+            let is_sacked = |seq: u16| {
+                let bit = seq.wrapping_sub(base) as usize;
+                bit < sack.len() * 8 && sack[bit / 8] & (1 << (bit % 8)) != 0
+            };
+            let before = self.in_flight.len();
+            self.in_flight.retain(|sent| {
+                if is_sacked(sent.packet.seq_nr) {
+                    acked_bytes += sent.packet.payload.len();
+                    false
+                } else {
+                    true
+                }
+            });
+            acked_any |= self.in_flight.len() != before;
+
+            // Anything with three or more selectively ACKed packets after it
+            // is considered lost
+            let sacked: Vec<u16> = (0..sack.len() * 8)
+                .filter(|bit| sack[bit / 8] & (1 << (bit % 8)) != 0)
+                .map(|bit| base.wrapping_add(bit as u16))
+                .collect();
+            let mut lost = false;
+            let srtt = self.srtt.unwrap_or(INITIAL_RTO);
+            for sent in self.in_flight.iter_mut() {
+                let later = sacked
+                    .iter()
+                    .filter(|seq| seq_less(sent.packet.seq_nr, **seq))
+                    .count();
+                if later >= DUPLICATE_ACKS as usize && now.duration_since(sent.sent_at) >= srtt {
+                    sent.need_resend = true;
+                    lost = true;
+                }
+            }
+            if lost {
+                self.on_loss(now);
+            }
+        }
+        self.bytes_in_flight = self.bytes_in_flight.saturating_sub(acked_bytes);
+
+        if acked_any {
+            self.dup_acks = 0;
+            if acked_bytes > 0 {
+                self.on_acked(acked_bytes);
+            }
+        } else if packet.kind == PacketType::State && !self.in_flight.is_empty() {
+            self.dup_acks += 1;
+            if self.dup_acks == DUPLICATE_ACKS {
+                if let Some(oldest) = self.in_flight.front_mut() {
+                    oldest.need_resend = true;
+                }
+                self.on_loss(now);
+            }
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + sample) / 8);
+            }
+        }
+        let srtt = self.srtt.unwrap_or(INITIAL_RTO);
+        self.rto = (srtt + self.rtt_var * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    // LEDBAT: grow while queuing delay is under target, shrink above it, in
+    // proportion to how much of the window this ACK covered.
+    fn on_acked(&mut self, bytes: usize) {
+        let queuing = self.delay.queuing_delay().unwrap_or(0.0);
+        let off_target = (TARGET_DELAY_US - queuing) / TARGET_DELAY_US;
+        let window_factor = bytes as f64 / self.cwnd.max(bytes as f64);
+        self.cwnd = (self.cwnd + MAX_CWND_INCREASE * off_target * window_factor)
+            .clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    // Halve the window, at most once per round trip
+    fn on_loss(&mut self, now: Instant) {
+        let srtt = self.srtt.unwrap_or(INITIAL_RTO);
+        if self
+            .last_decrease
+            .is_none_or(|last| now.duration_since(last) >= srtt)
+        {
+            self.cwnd = (self.cwnd / 2.0).max(MIN_WINDOW);
+            self.last_decrease = Some(now);
+        }
+    }
+
+    // Sends what the windows allow: retransmissions first, then new data,
+    // then our FIN once everything else is out.
+    pub fn flush(&mut self, now: Instant) {
+        if self.state != State::Connected {
+            return;
+        }
+        for i in 0..self.in_flight.len() {
+            if !self.in_flight[i].need_resend {
+                continue;
+            }
+            let mut packet = self.in_flight[i].packet.clone();
+            self.transmit(&mut packet);
+            let sent = &mut self.in_flight[i];
+            sent.packet = packet;
+            sent.need_resend = false;
+            sent.transmissions += 1;
+            sent.sent_at = now;
+        }
+
+        let window = (self.cwnd as usize).min(self.peer_wnd);
+        while !self.send_buf.is_empty() {
+            let size = self.send_buf.len().min(MAX_PAYLOAD);
+            if self.bytes_in_flight > 0 && self.bytes_in_flight + size > window {
+                break;
+            }
+            let mut packet = Packet::new(PacketType::Data, self.send_id, self.seq_nr);
+            packet.payload = self.send_buf.drain(..size).collect();
+            self.seq_nr = self.seq_nr.wrapping_add(1);
+            self.send_tracked(packet, now);
+        }
+
+        if self.shutdown && !self.fin_sent && self.send_buf.is_empty() {
+            let fin = Packet::new(PacketType::Fin, self.send_id, self.seq_nr);
+            self.seq_nr = self.seq_nr.wrapping_add(1);
+            self.send_tracked(fin, now);
+            self.fin_sent = true;
+        }
+    }
+
+    // Closed once our FIN is acknowledged and the peer has finished too, or
+    // nobody is left to read what it might still send.
+    fn check_finished(&mut self) {
+        if self.fin_sent && self.in_flight.is_empty() && (self.eof || self.dropped) {
+            self.state = State::Closed;
+        }
+    }
+
+    // Retransmission timeouts, keepalives and idle detection. Returns false
+    // once the connection is closed and no longer needs the timer.
+    pub fn tick(&mut self, now: Instant) -> bool {
+        if self.state == State::Closed {
+            self.wake();
+            return false;
+        }
+
+        if let Some(oldest) = self.in_flight.front_mut() {
+            if now.duration_since(oldest.sent_at) >= self.rto {
+                let limit = if self.state == State::SynSent {
+                    MAX_SYN_ATTEMPTS
+                } else {
+                    MAX_TRANSMISSIONS
+                };
+                if oldest.transmissions >= limit {
+                    self.fail(io::ErrorKind::TimedOut);
+                    self.wake();
+                    return false;
+                }
+                if self.state == State::SynSent {
+                    let mut syn = oldest.packet.clone();
+                    oldest.transmissions += 1;
+                    oldest.sent_at = now;
+                    self.transmit(&mut syn);
+                } else {
+                    oldest.need_resend = true;
+                    self.cwnd = MIN_WINDOW;
+                }
+                self.rto = (self.rto * 2).min(MAX_RTO);
+            }
+        }
+
+        if self.state == State::Connected {
+            if now.duration_since(self.last_recv) >= IDLE_TIMEOUT {
+                self.fail(io::ErrorKind::TimedOut);
+                self.wake();
+                return false;
+            }
+            if now.duration_since(self.last_send) >= KEEPALIVE {
+                self.send_ack();
+            }
+        }
+        self.flush(now);
+        self.check_finished();
+        self.wake();
+        self.state != State::Closed
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let window_was_closed = (self.recv_window() as usize) < MAX_PAYLOAD;
+        let n = buf.len().min(self.recv_buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.recv_buf.drain(..n)) {
+            *dst = src;
+        }
+        // Let the peer know it may send again
+        if window_was_closed && n > 0 && self.state == State::Connected {
+            self.send_ack();
+        }
+        n
+    }
+
+    pub fn has_data(&self) -> bool {
+        !self.recv_buf.is_empty()
+    }
+
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    pub fn write(&mut self, data: &[u8], now: Instant) -> usize {
+        let n = data
+            .len()
+            .min(SEND_BUFFER.saturating_sub(self.send_buf.len()));
+        self.send_buf.extend(&data[..n]);
+        self.flush(now);
+        n
+    }
+
+    pub fn shutdown(&mut self, now: Instant) {
+        self.shutdown = true;
+        self.flush(now);
+        self.check_finished();
+    }
+
+    // The stream was dropped: deliver what was written, then close. A
+    // connection that never got going is simply reset.
+    pub fn drop_stream(&mut self, now: Instant) {
+        self.dropped = true;
+        if self.state == State::SynSent {
+            self.send_reset();
+            self.fail(io::ErrorKind::ConnectionAborted);
+            return;
+        }
+        self.shutdown(now);
+    }
+}
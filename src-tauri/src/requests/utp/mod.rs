@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{interval, timeout, Duration};
+
+pub mod connection;
+pub mod packet;
+
+use super::dht::{Dht, DhtConfig};
+use connection::{Connection, State};
+use packet::{Packet, PacketType};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const TICK: Duration = Duration::from_millis(100);
+// Incoming connections waiting for `accept`, beyond this they are refused
+const ACCEPT_BACKLOG: usize = 64;
+
+type Connections = Arc<Mutex<HashMap<(SocketAddr, u16), Arc<Mutex<Connection>>>>>;
+
+// BEP 29 uTP endpoint. Multiplexes any number of connections over one UDP
+// socket, which can be shared with the DHT.
+#[derive(Clone)]
+pub struct UtpSocket {
+    socket: Arc<UdpSocket>,
+    connections: Connections,
+    incoming: mpsc::Sender<UtpStream>,
+    accepted: Arc<tokio::sync::Mutex<mpsc::Receiver<UtpStream>>>,
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: SocketAddr) -> io::Result<UtpSocket> {
+        let utp = UtpSocket::with_socket(Arc::new(UdpSocket::bind(addr).await?));
+        let receiver = utp.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            loop {
+                match receiver.socket.recv_from(&mut buf).await {
+                    Ok((amt, from)) => {
+                        receiver.handle_packet(&buf[..amt], from);
+                    }
+                    Err(e) => println!("uTP receive error: {}", e),
+                }
+            }
+        });
+        Ok(utp)
+    }
+
+    // For callers that own the socket and hand packets over through
+    // `handle_packet` themselves.
+    pub fn with_socket(socket: Arc<UdpSocket>) -> UtpSocket {
+        let (incoming, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        UtpSocket {
+            socket,
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            incoming,
+            accepted: Arc::new(tokio::sync::Mutex::new(accepted)),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn num_connections(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let (sender, receiver) = oneshot::channel();
+        let conn = {
+            let mut connections = self.connections.lock().unwrap();
+            let recv_id = loop {
+                let id: u16 = rand::random();
+                if !connections.contains_key(&(addr, id)) {
+                    break id;
+                }
+            };
+            let conn = Arc::new(Mutex::new(Connection::outgoing(
+                self.socket.clone(),
+                addr,
+                recv_id,
+                sender,
+            )));
+            connections.insert((addr, recv_id), conn.clone());
+            conn
+        };
+        self.spawn_timer(conn.clone());
+
+        let stream = UtpStream { conn };
+        match timeout(CONNECT_TIMEOUT, receiver).await {
+            Ok(Ok(Ok(()))) => Ok(stream),
+            Ok(Ok(Err(e))) => Err(e),
+            _ => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "uTP connect timeout",
+            )),
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<UtpStream> {
+        self.accepted
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "uTP socket closed"))
+    }
+
+    // Returns false when the datagram is not uTP, so a shared socket can pass
+    // it on to the DHT instead.
+    pub fn handle_packet(&self, buf: &[u8], from: SocketAddr) -> bool {
+        let Ok(packet) = Packet::decode(buf) else {
+            return false;
+        };
+        let now = Instant::now();
+
+        if packet.kind == PacketType::Syn {
+            let key = (from, packet.connection_id.wrapping_add(1));
+            let existing = self.connections.lock().unwrap().get(&key).cloned();
+            match existing {
+                Some(conn) => conn.lock().unwrap().on_packet(packet, now),
+                None => self.on_syn(&packet, from),
+            }
+            return true;
+        }
+
+        let conn = self
+            .connections
+            .lock()
+            .unwrap()
+            .get(&(from, packet.connection_id))
+            .cloned();
+        match conn {
+            Some(conn) => conn.lock().unwrap().on_packet(packet, now),
+            None if packet.kind != PacketType::Reset => {
+                let mut reset = Packet::new(PacketType::Reset, packet.connection_id, 0);
+                reset.ack_nr = packet.seq_nr;
+                reset.timestamp = connection::now_micros();
+                self.socket.try_send_to(&reset.encode(), from).ok();
+            }
+            None => {}
+        }
+        true
+    }
+
+    fn on_syn(&self, syn: &Packet, from: SocketAddr) {
+        let conn = Arc::new(Mutex::new(Connection::incoming(
+            self.socket.clone(),
+            from,
+            syn,
+        )));
+        let key = (from, conn.lock().unwrap().recv_id());
+        self.connections.lock().unwrap().insert(key, conn.clone());
+        self.spawn_timer(conn.clone());
+        // A full backlog drops the stream, which closes the connection again
+        self.incoming.try_send(UtpStream { conn }).ok();
+    }
+
+    fn spawn_timer(&self, conn: Arc<Mutex<Connection>>) {
+        let connections = self.connections.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(TICK);
+            loop {
+                ticker.tick().await;
+                if !conn.lock().unwrap().tick(Instant::now()) {
+                    break;
+                }
+            }
+            let (addr, recv_id) = {
+                let conn = conn.lock().unwrap();
+                (conn.addr, conn.recv_id())
+            };
+            connections.lock().unwrap().remove(&(addr, recv_id));
+        });
+    }
+}
+
+// Binds one UDP socket for both the DHT and uTP. KRPC messages are bencoded
+// dicts, which never parse as a uTP header, so every datagram the uTP side
+// rejects goes to the DHT.
+pub async fn bind_with_dht(config: DhtConfig) -> io::Result<(Dht, UtpSocket)> {
+    let socket = Arc::new(UdpSocket::bind(config.bind).await?);
+    let dht = Dht::with_socket(socket.clone(), config);
+    let utp = UtpSocket::with_socket(socket.clone());
+
+    let (dht_receiver, utp_receiver) = (dht.clone(), utp.clone());
+    tokio::spawn(async move {
+        let mut buf = [0u8; 2048];
+        loop {
+            match socket.recv_from(&mut buf).await {
+                Ok((amt, from)) => {
+                    if !utp_receiver.handle_packet(&buf[..amt], from) {
+                        dht_receiver.handle_packet(&buf[..amt], from).await;
+                    }
+                }
+                Err(e) => println!("UDP receive error: {}", e),
+            }
+        }
+    });
+    Ok((dht, utp))
+}
+
+pub struct UtpStream {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.conn.lock().unwrap().addr
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut conn = self.conn.lock().unwrap();
+        if conn.has_data() {
+            let n = conn.read(buf.initialize_unfilled());
+            buf.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        if conn.is_eof() {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = conn.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        conn.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut conn = self.conn.lock().unwrap();
+        if let Some(kind) = conn.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if conn.state != State::Connected {
+            return Poll::Ready(Err(io::ErrorKind::NotConnected.into()));
+        }
+        match conn.write(buf, Instant::now()) {
+            0 if !buf.is_empty() => {
+                conn.write_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            n => Poll::Ready(Ok(n)),
+        }
+    }
+
+    // Data is on its way once written, delivery is uTP's job
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.conn.lock().unwrap().error {
+            Some(kind) => Poll::Ready(Err(kind.into())),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.conn.lock().unwrap().shutdown(Instant::now());
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.conn.lock().unwrap().drop_stream(Instant::now());
+    }
+}
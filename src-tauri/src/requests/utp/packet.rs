@@ -0,0 +1,135 @@
+use std::io;
+
+pub const HEADER_LEN: usize = 20;
+const VERSION: u8 = 1;
+const EXTENSION_SACK: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+impl PacketType {
+    fn from_u8(value: u8) -> Option<PacketType> {
+        Some(match value {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub kind: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32,
+    pub timestamp_diff: u32,
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    // Selective ACK bitmask, bit i is ack_nr + 2 + i, least significant first
+    pub sack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(kind: PacketType, connection_id: u16, seq_nr: u16) -> Self {
+        Packet {
+            kind,
+            connection_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: 0,
+            seq_nr,
+            ack_nr: 0,
+            sack: None,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len() + 8);
+        buf.push(((self.kind as u8) << 4) | VERSION);
+        buf.push(if self.sack.is_some() {
+            EXTENSION_SACK
+        } else {
+            0
+        });
+        buf.extend(self.connection_id.to_be_bytes());
+        buf.extend(self.timestamp.to_be_bytes());
+        buf.extend(self.timestamp_diff.to_be_bytes());
+        buf.extend(self.wnd_size.to_be_bytes());
+        buf.extend(self.seq_nr.to_be_bytes());
+        buf.extend(self.ack_nr.to_be_bytes());
+        if let Some(sack) = &self.sack {
+            buf.push(0);
+            buf.push(sack.len() as u8);
+            buf.extend(sack);
+        }
+        buf.extend(&self.payload);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Packet> {
+        let invalid = |msg: &'static str| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if buf.len() < HEADER_LEN {
+            return Err(invalid("uTP packet too short"));
+        }
+        if buf[0] & 0x0f != VERSION {
+            return Err(invalid("Unknown uTP version"));
+        }
+        let kind = PacketType::from_u8(buf[0] >> 4).ok_or_else(|| invalid("Unknown uTP type"))?;
+        let u16_at = |at: usize| u16::from_be_bytes([buf[at], buf[at + 1]]);
+        let u32_at =
+            |at: usize| u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]);
+
+        let mut sack = None;
+        let mut extension = buf[1];
+        let mut pos = HEADER_LEN;
+        while extension != 0 {
+            if pos + 2 > buf.len() {
+                return Err(invalid("Truncated uTP extension"));
+            }
+            let next = buf[pos];
+            let len = buf[pos + 1] as usize;
+            let data = buf
+                .get(pos + 2..pos + 2 + len)
+                .ok_or_else(|| invalid("Truncated uTP extension"))?;
+            // Unknown extensions are skipped
+            if extension == EXTENSION_SACK {
+                if len == 0 || !len.is_multiple_of(4) {
+                    return Err(invalid("Bad selective ACK length"));
+                }
+                sack = Some(data.to_vec());
+            }
+            extension = next;
+            pos += 2 + len;
+        }
+
+        Ok(Packet {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            sack,
+            payload: buf[pos..].to_vec(),
+        })
+    }
+}
+
+// Sequence numbers wrap, `a` is before `b` when it is less than half the
+// space behind it.
+pub fn seq_less(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) < 0
+}
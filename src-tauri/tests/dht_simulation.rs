@@ -0,0 +1,125 @@
+// A small DHT network on loopback, every node in this process
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use defttorrent_lib::backend::file::BencodeValue;
+use defttorrent_lib::requests::dht::krpc::{Body, KrpcMessage, Query, Response};
+use defttorrent_lib::requests::dht::{Dht, DhtConfig};
+use ed25519_dalek::SigningKey;
+use tokio::net::UdpSocket;
+
+const NODES: usize = 32;
+
+fn config() -> DhtConfig {
+    DhtConfig {
+        bind: SocketAddr::from(([127, 0, 0, 1], 0)),
+        bootstrap: Vec::new(),
+        state_path: None,
+        enforce_node_id: true,
+        external_ip: None,
+    }
+}
+
+async fn network() -> Vec<Dht> {
+    let mut nodes = Vec::new();
+    for _ in 0..NODES {
+        nodes.push(Dht::bind(config()).await.unwrap());
+    }
+    let entry = nodes[0].local_addr().unwrap();
+    for node in &nodes[1..] {
+        node.bootstrap_from(&[entry]).await.unwrap();
+    }
+    // A second round so the early nodes learn about the late ones
+    for node in &nodes {
+        node.find_node(node.id()).await;
+    }
+    nodes
+}
+
+#[tokio::test]
+async fn nodes_fill_their_tables() {
+    let nodes = network().await;
+    for node in &nodes {
+        assert!(
+            node.num_nodes() >= 8,
+            "only {} nodes known",
+            node.num_nodes()
+        );
+    }
+}
+
+#[tokio::test]
+async fn announced_peers_are_found() {
+    let nodes = network().await;
+    let info_hash = [0x42; 20];
+    nodes[7].announce(info_hash, Some(7777)).await;
+    let peers = nodes[NODES - 1].get_peers(info_hash).await;
+    assert!(peers.contains(&SocketAddr::from(([127, 0, 0, 1], 7777))));
+}
+
+#[tokio::test]
+async fn items_are_stored_and_found() {
+    let nodes = network().await;
+    let value = BencodeValue::String(b"Hello World!".to_vec());
+    let target = nodes[3].put_immutable(value.clone()).await.unwrap();
+    assert_eq!(nodes[NODES - 2].get_immutable(target).await, Some(value));
+
+    let key = SigningKey::from_bytes(&[7; 32]);
+    let k = key.verifying_key().to_bytes();
+    nodes[5]
+        .put_mutable(&key, BencodeValue::Integer(1), b"salt")
+        .await
+        .unwrap();
+    nodes[9]
+        .put_mutable(&key, BencodeValue::Integer(2), b"salt")
+        .await
+        .unwrap();
+    let item = nodes[20].get_mutable(k, b"salt").await.unwrap();
+    assert_eq!(item.value(), &BencodeValue::Integer(2));
+    assert_eq!(item.seq(), Some(1));
+}
+
+// An error reply for a pending query only counts from the node it was sent to
+#[tokio::test]
+async fn spoofed_error_is_ignored() {
+    let node = Dht::bind(config()).await.unwrap();
+    let remote = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let remote_addr = remote.local_addr().unwrap();
+
+    let pinging = node.clone();
+    let ping = tokio::spawn(async move { pinging.ping(remote_addr).await });
+
+    let mut buf = [0u8; 1500];
+    let (n, from) = remote.recv_from(&mut buf).await.unwrap();
+    let query = KrpcMessage::decode(&buf[..n]).unwrap();
+    assert!(matches!(
+        query.body,
+        Body::Query {
+            query: Query::Ping,
+            ..
+        }
+    ));
+
+    let error = KrpcMessage {
+        transaction: query.transaction.clone(),
+        body: Body::Error {
+            code: 201,
+            message: "spoofed".to_string(),
+        },
+        ip: None,
+    };
+    spoofer.send_to(&error.encode(), from).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let reply = KrpcMessage {
+        transaction: query.transaction,
+        body: Body::Response(Response {
+            id: [9; 20],
+            ..Default::default()
+        }),
+        ip: None,
+    };
+    remote.send_to(&reply.encode(), from).await.unwrap();
+    assert_eq!(ping.await.unwrap().unwrap(), [9; 20]);
+}
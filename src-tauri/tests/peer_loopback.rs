@@ -0,0 +1,313 @@
+// Peer connections on loopback, over TCP and over uTP through a relay that
+// loses and reorders packets
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use defttorrent_lib::backend::settings::EncryptionPolicy;
+use defttorrent_lib::requests::peer::message::{Message, BLOCK_LEN};
+use defttorrent_lib::requests::peer::transport::PeerTransport;
+use defttorrent_lib::requests::peer::{generate_peer_id, PeerConnection};
+use defttorrent_lib::requests::utp::packet::{Packet, PacketType};
+use defttorrent_lib::requests::utp::UtpSocket;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::time::timeout;
+
+const INFO_HASH: [u8; 20] = [7; 20];
+// Blocks the accepting side uploads, 1 MiB
+const BLOCKS: u32 = 64;
+
+fn block_data(index: u32) -> Vec<u8> {
+    (0..BLOCK_LEN).map(|i| (i * 31 + index * 7) as u8).collect()
+}
+
+// The accepting side: sends its pieces, unchokes and serves every request
+async fn serve(mut conn: PeerConnection) {
+    conn.send(&Message::HaveAll).await.unwrap();
+    assert_eq!(conn.recv().await.unwrap(), Message::HaveNone);
+    assert_eq!(conn.recv().await.unwrap(), Message::Interested);
+    conn.send(&Message::Unchoke).await.unwrap();
+    for _ in 0..BLOCKS {
+        let Message::Request {
+            index,
+            begin,
+            length,
+        } = conn.recv().await.unwrap()
+        else {
+            panic!("Expected a request");
+        };
+        assert_eq!((begin, length), (0, BLOCK_LEN));
+        let piece = Message::Piece {
+            index,
+            begin,
+            block: block_data(index),
+        };
+        conn.send(&piece).await.unwrap();
+    }
+    // Stays open until the other side has read everything
+    let _ = conn.recv().await;
+}
+
+// The connecting side: asks for every block at once and checks what arrives
+async fn download(conn: &mut PeerConnection) {
+    assert!(conn.supports_fast() && conn.supports_extensions());
+    conn.send(&Message::HaveNone).await.unwrap();
+    assert_eq!(conn.recv().await.unwrap(), Message::HaveAll);
+    conn.send(&Message::Interested).await.unwrap();
+    assert_eq!(conn.recv().await.unwrap(), Message::Unchoke);
+    for index in 0..BLOCKS {
+        let request = Message::Request {
+            index,
+            begin: 0,
+            length: BLOCK_LEN,
+        };
+        conn.send(&request).await.unwrap();
+    }
+    for index in 0..BLOCKS {
+        let expected = Message::Piece {
+            index,
+            begin: 0,
+            block: block_data(index),
+        };
+        assert_eq!(conn.recv().await.unwrap(), expected);
+    }
+}
+
+#[tokio::test]
+async fn tcp_handshake_and_messages() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_id = generate_peer_id();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let transport = PeerTransport::Tcp(stream);
+        let conn =
+            PeerConnection::accept(transport, server_id, EncryptionPolicy::Prefer, &[INFO_HASH])
+                .await
+                .unwrap();
+        assert!(conn.is_encrypted() && !conn.is_utp());
+        serve(conn).await;
+    });
+
+    let mut conn = PeerConnection::connect(
+        addr,
+        None,
+        INFO_HASH,
+        generate_peer_id(),
+        Some(server_id),
+        EncryptionPolicy::Prefer,
+    )
+    .await
+    .unwrap();
+    assert!(conn.is_encrypted() && !conn.is_utp());
+    assert_eq!(conn.remote.info_hash, INFO_HASH);
+    download(&mut conn).await;
+    drop(conn);
+    server.await.unwrap();
+}
+
+#[derive(Debug, Default)]
+struct RelayStats {
+    dropped: usize,
+    reordered: usize,
+    // Data packets seen before, sent again after a loss
+    retransmitted: usize,
+    // State packets carrying a selective ACK
+    sacks: usize,
+    // Most data packets either side had unacknowledged
+    max_in_flight: u16,
+}
+
+// Data packets sent by one end and the other end's latest ACK of them
+#[derive(Default)]
+struct Direction {
+    count: usize,
+    seen: HashSet<(u16, u16)>,
+    last_seq: Option<u16>,
+    last_ack: Option<u16>,
+    // Held back to go out after the next packet
+    held: Option<Vec<u8>>,
+}
+
+// Passes datagrams between two uTP sockets through a port of its own, both
+// think they talk to the relay. Every tenth data packet is dropped and
+// another held back behind the next one. Retransmissions always go through.
+async fn relay(a: SocketAddr, b: SocketAddr) -> (SocketAddr, Arc<Mutex<RelayStats>>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let stats = Arc::new(Mutex::new(RelayStats::default()));
+    let shared = stats.clone();
+    tokio::spawn(async move {
+        let mut directions = [Direction::default(), Direction::default()];
+        let mut buf = [0u8; 2048];
+        loop {
+            // A packet held back with nothing following goes out late
+            let received = timeout(Duration::from_millis(20), socket.recv_from(&mut buf)).await;
+            let Ok(Ok((n, from))) = received else {
+                for (direction, to) in directions.iter_mut().zip([b, a]) {
+                    if let Some(held) = direction.held.take() {
+                        socket.send_to(&held, to).await.unwrap();
+                    }
+                }
+                continue;
+            };
+            let (side, to) = if from == a { (0, b) } else { (1, a) };
+            let datagram = buf[..n].to_vec();
+            let forward = {
+                let packet = Packet::decode(&datagram).unwrap();
+                let mut stats = shared.lock().unwrap();
+                if packet.kind == PacketType::State {
+                    if packet.sack.is_some() {
+                        stats.sacks += 1;
+                    }
+                    directions[1 - side].last_ack = Some(packet.ack_nr);
+                }
+                let other = &directions[1 - side];
+                if let (Some(seq), Some(ack)) = (other.last_seq, other.last_ack) {
+                    stats.max_in_flight = stats.max_in_flight.max(seq.wrapping_sub(ack).min(1024));
+                }
+
+                let direction = &mut directions[side];
+                let mut forward = vec![datagram];
+                if packet.kind == PacketType::Data {
+                    if !direction.seen.insert((packet.connection_id, packet.seq_nr)) {
+                        stats.retransmitted += 1;
+                    } else {
+                        direction.count += 1;
+                        direction.last_seq = Some(packet.seq_nr);
+                        match direction.count % 10 {
+                            7 => {
+                                stats.dropped += 1;
+                                forward.clear();
+                            }
+                            3 if direction.held.is_none() => {
+                                stats.reordered += 1;
+                                direction.held = forward.pop();
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                if !forward.is_empty() {
+                    forward.extend(direction.held.take());
+                }
+                forward
+            };
+            for datagram in forward {
+                socket.send_to(&datagram, to).await.unwrap();
+            }
+        }
+    });
+    (addr, stats)
+}
+
+#[tokio::test]
+async fn utp_through_lossy_relay() {
+    let utp_a = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let utp_b = UtpSocket::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let (relay_addr, stats) = relay(utp_a.local_addr().unwrap(), utp_b.local_addr().unwrap()).await;
+
+    let server_id = generate_peer_id();
+    let server = tokio::spawn(async move {
+        let stream = utp_b.accept().await.unwrap();
+        let conn = PeerConnection::accept(
+            PeerTransport::Utp(stream),
+            server_id,
+            EncryptionPolicy::Prefer,
+            &[INFO_HASH],
+        )
+        .await
+        .unwrap();
+        assert!(conn.is_utp());
+        serve(conn).await;
+    });
+
+    // Only the fast retransmits on SACKs and duplicate ACKs make this in
+    // time, waiting out a timeout for each lost packet would take minutes
+    timeout(Duration::from_secs(30), async {
+        let mut conn = PeerConnection::connect(
+            relay_addr,
+            Some(&utp_a),
+            INFO_HASH,
+            generate_peer_id(),
+            Some(server_id),
+            EncryptionPolicy::Prefer,
+        )
+        .await
+        .unwrap();
+        assert!(conn.is_utp() && conn.is_encrypted());
+        download(&mut conn).await;
+    })
+    .await
+    .expect("Transfer over uTP timed out");
+    server.await.unwrap();
+
+    let stats = stats.lock().unwrap();
+    println!("{:?}", stats);
+    assert!(stats.dropped > 0 && stats.reordered > 0);
+    assert!(stats.retransmitted >= stats.dropped);
+    assert!(stats.sacks > 0, "No selective ACKs were sent");
+    // LEDBAT grows the window past the initial four packets while the
+    // queuing delay on loopback stays under target
+    assert!(stats.max_in_flight > 4, "Window never grew");
+}
+
+// A prefer connection to a peer that won't do MSE, the first attempt is
+// dropped or left without an answer, then retried in plaintext
+async fn plaintext_fallback(silent: bool) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_id = generate_peer_id();
+    let server = tokio::spawn(async move {
+        let (first, _) = listener.accept().await.unwrap();
+        let first = PeerTransport::Tcp(first);
+        let held = if silent {
+            Some(first)
+        } else {
+            let refused =
+                PeerConnection::accept(first, server_id, EncryptionPolicy::Disabled, &[INFO_HASH])
+                    .await;
+            assert!(refused.is_err());
+            None
+        };
+        let (stream, _) = listener.accept().await.unwrap();
+        let conn = PeerConnection::accept(
+            PeerTransport::Tcp(stream),
+            server_id,
+            EncryptionPolicy::Disabled,
+            &[INFO_HASH],
+        )
+        .await
+        .unwrap();
+        drop(held);
+        conn
+    });
+
+    let conn = PeerConnection::connect(
+        addr,
+        None,
+        INFO_HASH,
+        generate_peer_id(),
+        Some(server_id),
+        EncryptionPolicy::Prefer,
+    )
+    .await
+    .unwrap();
+    assert!(!conn.is_encrypted());
+    assert!(!server.await.unwrap().is_encrypted());
+}
+
+#[tokio::test]
+async fn plaintext_fallback_after_refusal() {
+    plaintext_fallback(false).await;
+}
+
+#[tokio::test]
+async fn plaintext_fallback_after_timeout() {
+    plaintext_fallback(true).await;
+}
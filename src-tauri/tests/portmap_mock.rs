@@ -0,0 +1,247 @@
+// Port mapping against fake gateways on loopback
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use defttorrent_lib::requests::portmap::{
+    MappingStatus, Method, PortMapConfig, PortMapper, Protocol,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+
+const EXTERNAL_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+const EXTERNAL_PORT: u16 = 40000;
+
+// A port nothing listens on, requests to it are refused right away
+async fn closed_port() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.local_addr().unwrap()
+}
+
+async fn wait_for_mapping(mapper: &PortMapper) -> Vec<MappingStatus> {
+    for _ in 0..200 {
+        let status = mapper.status();
+        if status.iter().all(|mapping| mapping.method.is_some()) {
+            return status;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("Not mapped: {:?}", mapper.status());
+}
+
+// Answers PCP MAP requests and records their lifetimes
+async fn pcp_gateway() -> (SocketAddr, Arc<Mutex<Vec<u32>>>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let lifetimes = Arc::new(Mutex::new(Vec::new()));
+    let seen = lifetimes.clone();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1100];
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+            let request = &buf[..n];
+            if n < 60 || request[0] != 2 || request[1] != 1 {
+                continue;
+            }
+            let lifetime = u32::from_be_bytes(request[4..8].try_into().unwrap());
+            seen.lock().unwrap().push(lifetime);
+            let mut response = request.to_vec();
+            response[1] = 0x81;
+            response[3] = 0;
+            response[42..44].copy_from_slice(&EXTERNAL_PORT.to_be_bytes());
+            response[44..60].copy_from_slice(&EXTERNAL_IP.to_ipv6_mapped().octets());
+            socket.send_to(&response, from).await.unwrap();
+        }
+    });
+    (addr, lifetimes)
+}
+
+// Only speaks NAT-PMP, and says so to PCP requests
+async fn natpmp_gateway() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 1100];
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await.unwrap();
+            let request = &buf[..n];
+            let epoch = 1000u32.to_be_bytes();
+            let response = match (request[0], request[1]) {
+                (0, 0) => {
+                    let mut response = vec![0, 0x80, 0, 0];
+                    response.extend(epoch);
+                    response.extend(EXTERNAL_IP.octets());
+                    response
+                }
+                (0, opcode @ (1 | 2)) => {
+                    let mut response = vec![0, 0x80 | opcode, 0, 0];
+                    response.extend(epoch);
+                    response.extend(&request[4..6]);
+                    response.extend(EXTERNAL_PORT.to_be_bytes());
+                    response.extend(&request[8..12]);
+                    response
+                }
+                _ => {
+                    let mut response = vec![0, 0x80 | request[1], 0, 1];
+                    response.extend(epoch);
+                    response
+                }
+            };
+            socket.send_to(&response, from).await.unwrap();
+        }
+    });
+    addr
+}
+
+// SSDP responder plus the device's HTTP server. Returns the SSDP address and
+// the SOAP actions called.
+async fn upnp_gateway() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+    let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let http_addr = http.local_addr().unwrap();
+    let ssdp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let ssdp_addr = ssdp.local_addr().unwrap();
+    let actions = Arc::new(Mutex::new(Vec::new()));
+
+    tokio::spawn(async move {
+        let mut buf = [0u8; 2048];
+        loop {
+            let (n, from) = ssdp.recv_from(&mut buf).await.unwrap();
+            if !buf[..n].starts_with(b"M-SEARCH") {
+                continue;
+            }
+            let reply = format!(
+                "HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                 LOCATION: http://{}/desc.xml\r\n\r\n",
+                http_addr
+            );
+            ssdp.send_to(reply.as_bytes(), from).await.unwrap();
+        }
+    });
+
+    let called = actions.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = http.accept().await.unwrap();
+            let called = called.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 4096];
+                // Read until the whole body named by Content-Length is in
+                loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request);
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("Content-Length: "))
+                            .and_then(|value| value.trim().parse().ok())
+                            .unwrap_or(0);
+                        if body.len() >= length {
+                            break;
+                        }
+                    }
+                    if n == 0 {
+                        break;
+                    }
+                }
+                let text = String::from_utf8_lossy(&request).into_owned();
+                let body = if text.starts_with("GET /desc.xml") {
+                    "<root><device><serviceList><service>\
+                     <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                     <controlURL>/ctl</controlURL>\
+                     </service></serviceList></device></root>"
+                        .to_string()
+                } else {
+                    let action = text
+                        .lines()
+                        .find_map(|line| line.strip_prefix("SOAPAction: "))
+                        .and_then(|value| value.trim_matches('"').split('#').nth(1))
+                        .unwrap_or_default()
+                        .to_string();
+                    called.lock().unwrap().push(action.clone());
+                    format!(
+                        "<s:Envelope><s:Body><u:{0}Response>\
+                         <NewExternalIPAddress>{1}</NewExternalIPAddress>\
+                         </u:{0}Response></s:Body></s:Envelope>",
+                        action, EXTERNAL_IP
+                    )
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            });
+        }
+    });
+    (ssdp_addr, actions)
+}
+
+#[tokio::test]
+async fn maps_through_pcp() {
+    let (gateway, lifetimes) = pcp_gateway().await;
+    let config = PortMapConfig {
+        gateway: Some(gateway),
+        ssdp: closed_port().await,
+        lifetime: Duration::from_secs(3600),
+    };
+    let mapper = PortMapper::start(&[(Protocol::Tcp, 6881), (Protocol::Udp, 6881)], config);
+    let status = wait_for_mapping(&mapper).await;
+    for mapping in &status {
+        assert_eq!(mapping.method, Some(Method::Pcp));
+        assert_eq!(mapping.external_port, Some(EXTERNAL_PORT));
+        assert_eq!(mapping.external_ip.as_deref(), Some("203.0.113.7"));
+        assert!(mapping.expires_in.is_some_and(|secs| secs > 3500));
+    }
+
+    mapper.unmap_all().await;
+    let lifetimes = lifetimes.lock().unwrap().clone();
+    assert_eq!(lifetimes.iter().filter(|l| **l == 3600).count(), 2);
+    assert_eq!(lifetimes.iter().filter(|l| **l == 0).count(), 2);
+    assert!(mapper
+        .status()
+        .iter()
+        .all(|mapping| mapping.method.is_none()));
+}
+
+#[tokio::test]
+async fn falls_back_to_natpmp() {
+    let config = PortMapConfig {
+        gateway: Some(natpmp_gateway().await),
+        ssdp: closed_port().await,
+        lifetime: Duration::from_secs(3600),
+    };
+    let mapper = PortMapper::start(&[(Protocol::Udp, 6881)], config);
+    let status = wait_for_mapping(&mapper).await;
+    assert_eq!(status[0].method, Some(Method::NatPmp));
+    assert_eq!(status[0].external_port, Some(EXTERNAL_PORT));
+    assert_eq!(status[0].external_ip.as_deref(), Some("203.0.113.7"));
+    mapper.unmap_all().await;
+}
+
+#[tokio::test]
+async fn falls_back_to_upnp() {
+    let (ssdp, actions) = upnp_gateway().await;
+    let config = PortMapConfig {
+        gateway: Some(closed_port().await),
+        ssdp,
+        lifetime: Duration::from_secs(3600),
+    };
+    let mapper = PortMapper::start(&[(Protocol::Tcp, 6881)], config);
+    let status = wait_for_mapping(&mapper).await;
+    assert_eq!(status[0].method, Some(Method::Upnp));
+    assert_eq!(status[0].external_port, Some(6881));
+    assert_eq!(status[0].external_ip.as_deref(), Some("203.0.113.7"));
+
+    mapper.unmap_all().await;
+    assert_eq!(
+        *actions.lock().unwrap(),
+        [
+            "AddPortMapping",
+            "GetExternalIPAddress",
+            "DeletePortMapping"
+        ]
+    );
+}
@@ -1,11 +1,66 @@
 //// filepath: /home/deftioon/Github/defttorrent/src/Settings.tsx
+import { useState, useEffect } from "react";
+import { invoke } from "@tauri-apps/api/core";
+
+interface PortMapping {
+  protocol: "tcp" | "udp";
+  internalPort: number;
+  externalPort: number | null;
+  externalIp: string | null;
+  method: "PCP" | "NAT-PMP" | "UPnP" | null;
+  expiresIn: number | null; // seconds, null when permanent or unmapped
+  error: string | null;
+}
+
+function mappingState(mapping: PortMapping): string {
+  if (mapping.method) {
+    const external = `${mapping.externalIp ?? "?"}:${mapping.externalPort}`;
+    const lease =
+      mapping.expiresIn === null
+        ? "permanent"
+        : `lease expires in ${Math.ceil(mapping.expiresIn / 60)} min`;
+    return `Mapped to ${external} via ${mapping.method} (${lease})`;
+  }
+  if (mapping.error) {
+    return `Not mapped: ${mapping.error}`;
+  }
+  return "Mapping...";
+}
+
 const Settings = () => {
-    return (
-      <div className="main-content">
-        <h2>Settings</h2>
-        <p>Settings panel content goes here...</p>
-      </div>
-    );
-  };
-  
-  export default Settings;
\ No newline at end of file
+  const [mappings, setMappings] = useState<PortMapping[] | null>(null);
+
+  useEffect(() => {
+    async function fetchMappings() {
+      try {
+        setMappings(await invoke<PortMapping[] | null>("port_mappings"));
+      } catch (error) {
+        console.error("Failed to fetch port mappings:", error);
+      }
+    }
+    fetchMappings();
+    const interval = setInterval(fetchMappings, 5000);
+    return () => clearInterval(interval);
+  }, []);
+
+  return (
+    <div className="main-content">
+      <h2>Settings</h2>
+      <h3>Port Mapping</h3>
+      {mappings === null ? (
+        <p>Port mapping is disabled.</p>
+      ) : (
+        <ul>
+          {mappings.map((mapping) => (
+            <li key={`${mapping.protocol}-${mapping.internalPort}`}>
+              {mapping.protocol.toUpperCase()} {mapping.internalPort}:{" "}
+              {mappingState(mapping)}
+            </li>
+          ))}
+        </ul>
+      )}
+    </div>
+  );
+};
+
+export default Settings;
@@ -1,5 +1,7 @@
 import { useState, useEffect } from "react";
 import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+import { open } from "@tauri-apps/plugin-dialog";
 
 export interface Torrent {
   id: number;
@@ -8,6 +10,35 @@ export interface Torrent {
   full_size: number; // in MB
 }
 
+interface RecheckProgress {
+  id: number;
+  state: "checking" | "paused" | "cancelled" | "finished" | "failed";
+  numPieces: number;
+  piecesChecked: number;
+  piecesValid: number;
+  bytesChecked: number;
+  error: string | null;
+}
+
+interface MoveProgress {
+  id: number;
+  bytesMoved: number;
+  totalBytes: number;
+  filesMoved: number;
+  numFiles: number;
+}
+
+type ConflictPolicy = "replace" | "keep" | "fail";
+
+type FilePriority = "skip" | "low" | "normal" | "high";
+
+interface FileStatus {
+  path: string;
+  length: number;
+  done: number;
+  priority: FilePriority;
+}
+
 interface TorrentItemProps {
   torrent: Torrent;
   onSimulateProgress: (id: number) => void;
@@ -20,6 +51,10 @@ function TorrentItem({
   onComplete,
 }: TorrentItemProps) {
   const [status, setStatus] = useState<string>("Loading Status...");
+  const [recheck, setRecheck] = useState<RecheckProgress | null>(null);
+  const [files, setFiles] = useState<FileStatus[] | null>(null);
+  const [moving, setMoving] = useState<MoveProgress | null>(null);
+  const [policy, setPolicy] = useState<ConflictPolicy>("fail");
 
   const percentage = ((torrent.downloaded / torrent.full_size) * 100).toFixed(
     0,
@@ -40,6 +75,94 @@ function TorrentItem({
     fetchStatus();
   }, [torrent.id]);
 
+  useEffect(() => {
+    const unlisten = listen<RecheckProgress>("recheck-progress", (event) => {
+      if (event.payload.id !== torrent.id) return;
+      setRecheck(event.payload);
+      if (event.payload.state !== "checking" && event.payload.state !== "paused") {
+        invoke<string>("torrent_status", { id: torrent.id })
+          .then(setStatus)
+          .catch(() => {});
+      }
+    });
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, [torrent.id]);
+
+  useEffect(() => {
+    const unlisten = listen<MoveProgress>("move-progress", (event) => {
+      if (event.payload.id === torrent.id) setMoving(event.payload);
+    });
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, [torrent.id]);
+
+  async function moveStorage() {
+    const path = await open({ directory: true });
+    if (!path || typeof path !== "string") return;
+    try {
+      await invoke("move_storage", { id: torrent.id, path, policy });
+    } catch (error) {
+      console.error("Failed to move torrent:", error);
+    }
+    setMoving(null);
+    setStatus(await invoke<string>("torrent_status", { id: torrent.id }));
+    if (files) loadFiles();
+  }
+
+  async function renameFile(file: number, current: string) {
+    const name = window.prompt("New path of the file", current);
+    if (!name || name === current) return;
+    try {
+      await invoke("rename_file", { id: torrent.id, file, name });
+      loadFiles();
+    } catch (error) {
+      console.error("Failed to rename file:", error);
+    }
+  }
+
+  async function renameFolder() {
+    const folder = window.prompt(
+      "Folder to rename",
+      files?.[0]?.path.split(/[\\/]/)[0],
+    );
+    if (!folder) return;
+    const name = window.prompt("New path of the folder", folder);
+    if (!name || name === folder) return;
+    try {
+      await invoke("rename_folder", { id: torrent.id, folder, name });
+      loadFiles();
+    } catch (error) {
+      console.error("Failed to rename folder:", error);
+    }
+  }
+
+  async function loadFiles() {
+    try {
+      setFiles(await invoke<FileStatus[]>("file_status", { id: torrent.id }));
+    } catch (error) {
+      console.error("Failed to fetch files:", error);
+    }
+  }
+
+  async function setPriority(file: number, priority: FilePriority) {
+    try {
+      await invoke("set_file_priority", { id: torrent.id, file, priority });
+      setStatus(await invoke<string>("torrent_status", { id: torrent.id }));
+      loadFiles();
+    } catch (error) {
+      console.error("Failed to set file priority:", error);
+    }
+  }
+
+  function recheckCommand(command: string) {
+    invoke(command, { id: torrent.id }).catch((error) =>
+      console.error(`Failed to ${command}:`, error),
+    );
+  }
+
   return (
     <li className="torrent-item">
       <div className="torrent-info">
@@ -60,6 +183,92 @@ function TorrentItem({
         <div className="torrent-status">
           <span className="status-text selectable">{status}</span>
         </div>
+        {recheck &&
+        (recheck.state === "checking" || recheck.state === "paused") ? (
+          <div className="torrent-recheck">
+            <progress value={recheck.piecesChecked} max={recheck.numPieces} />
+            <span>
+              {recheck.state === "paused" ? "Paused" : "Checking"}{" "}
+              {recheck.piecesChecked} / {recheck.numPieces} pieces (
+              {recheck.piecesValid} valid)
+            </span>
+            {recheck.state === "paused" ? (
+              <button onClick={() => recheckCommand("resume_recheck")}>
+                Resume
+              </button>
+            ) : (
+              <button onClick={() => recheckCommand("pause_recheck")}>
+                Pause
+              </button>
+            )}
+            <button onClick={() => recheckCommand("cancel_recheck")}>
+              Cancel
+            </button>
+          </div>
+        ) : (
+          <div className="torrent-recheck">
+            {recheck?.state === "failed" && (
+              <span>Check failed: {recheck.error}</span>
+            )}
+            <button onClick={() => recheckCommand("recheck_torrent")}>
+              Force Recheck
+            </button>
+          </div>
+        )}
+        {moving ? (
+          <div className="torrent-move">
+            <progress value={moving.bytesMoved} max={moving.totalBytes} />
+            <span>
+              Moving {moving.filesMoved} / {moving.numFiles} files
+            </span>
+          </div>
+        ) : (
+          <div className="torrent-move">
+            <select
+              value={policy}
+              onChange={(e) => setPolicy(e.target.value as ConflictPolicy)}
+            >
+              <option value="fail">Stop if files exist</option>
+              <option value="keep">Keep existing files</option>
+              <option value="replace">Replace existing files</option>
+            </select>
+            <button onClick={moveStorage}>Move...</button>
+          </div>
+        )}
+        <button onClick={() => (files ? setFiles(null) : loadFiles())}>
+          {files ? "Hide Files" : "Files"}
+        </button>
+        {files && <button onClick={renameFolder}>Rename Folder</button>}
+        {files && (
+          <ul className="torrent-files">
+            {files.map((file, index) => (
+              <li key={file.path}>
+                <span className="selectable">{file.path}</span>
+                <span>
+                  {" "}
+                  {file.length > 0
+                    ? ((file.done / file.length) * 100).toFixed(0)
+                    : 100}
+                  %
+                </span>
+                <select
+                  value={file.priority}
+                  onChange={(e) =>
+                    setPriority(index, e.target.value as FilePriority)
+                  }
+                >
+                  <option value="skip">Skip</option>
+                  <option value="low">Low</option>
+                  <option value="normal">Normal</option>
+                  <option value="high">High</option>
+                </select>
+                <button onClick={() => renameFile(index, file.path)}>
+                  Rename
+                </button>
+              </li>
+            ))}
+          </ul>
+        )}
       </div>
     </li>
   );